@@ -0,0 +1,581 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    hash::Hash,
+};
+
+use rayon::prelude::*;
+
+use crate::{
+    config::MemoryConfig,
+    heuristics::{Belady, RandomEviction, LFU, LRU},
+    memory::{DRAM, SRAM},
+    passes::pressure,
+    sim::{Heuristic, JitSim, Operators, SimError, SimStats, WritePolicy},
+};
+
+/// The eviction heuristics a trace can be compared under.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Policy {
+    /// Random eviction with a fixed seed
+    Random(u64),
+    LRU,
+    LFU,
+    /// LFU that halves all counts every `n` touches
+    LFUDecay(usize),
+    Belady,
+    SizeAwareBelady,
+}
+
+impl Policy {
+    pub fn name(&self) -> String {
+        match self {
+            Policy::Random(seed) => format!("random({})", seed),
+            Policy::LRU => "lru".into(),
+            Policy::LFU => "lfu".into(),
+            Policy::LFUDecay(n) => format!("lfu-decay({})", n),
+            Policy::Belady => "belady".into(),
+            Policy::SizeAwareBelady => "belady-size".into(),
+        }
+    }
+}
+
+/// Runs `trace` under `heuristic` on copies of `srams` and `dram`.
+pub fn run_one<H, D>(
+    heuristic: H,
+    trace: &Operators<D>,
+    srams: &HashMap<String, SRAM<D>>,
+    dram: &DRAM<D>,
+) -> Result<SimStats, SimError>
+where
+    H: Heuristic<D>,
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    run_writing(heuristic, WritePolicy::WriteBack, trace, srams, dram)
+}
+
+fn run_writing<H, D>(
+    heuristic: H,
+    write_policy: WritePolicy,
+    trace: &Operators<D>,
+    srams: &HashMap<String, SRAM<D>>,
+    dram: &DRAM<D>,
+) -> Result<SimStats, SimError>
+where
+    H: Heuristic<D>,
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    let mut sim = JitSim::new(heuristic).with_write_policy(write_policy);
+    let mut trace = trace.clone();
+    let mut srams = srams.clone();
+    let mut dram = dram.clone();
+    sim.run(&mut trace, &mut srams, &mut dram)?;
+    Ok(sim.sim_stats().clone())
+}
+
+/// Runs the same trace under every policy, each starting from the memory
+/// state described by `config`. The stats are in the order of `policies`.
+pub fn run_all<D>(
+    trace: &Operators<D>,
+    config: &MemoryConfig,
+    policies: &[Policy],
+) -> Result<Vec<SimStats>, SimError>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    let (srams, dram) = config.build::<D>();
+    policies
+        .iter()
+        .map(|policy| run_policy(policy, WritePolicy::WriteBack, trace, &srams, &dram))
+        .collect()
+}
+
+/// Runs `trace` under `policy` once writing back on eviction and once
+/// writing through, from the memory state described by `config`; compare the
+/// `stores` of the two.
+pub fn run_write_policies<D>(
+    trace: &Operators<D>,
+    config: &MemoryConfig,
+    policy: &Policy,
+) -> Result<Vec<(WritePolicy, SimStats)>, SimError>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    let (srams, dram) = config.build::<D>();
+    [WritePolicy::WriteBack, WritePolicy::WriteThrough]
+        .into_iter()
+        .map(|write| Ok((write, run_policy(policy, write, trace, &srams, &dram)?)))
+        .collect()
+}
+
+pub(crate) fn run_policy<D>(
+    policy: &Policy,
+    write: WritePolicy,
+    trace: &Operators<D>,
+    srams: &HashMap<String, SRAM<D>>,
+    dram: &DRAM<D>,
+) -> Result<SimStats, SimError>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    match policy {
+        Policy::Random(seed) => {
+            run_writing(RandomEviction::seeded(*seed), write, trace, srams, dram)
+        }
+        Policy::LRU => run_writing(LRU::new(), write, trace, srams, dram),
+        Policy::LFU => run_writing(LFU::new(), write, trace, srams, dram),
+        Policy::LFUDecay(n) => run_writing(LFU::with_decay(*n), write, trace, srams, dram),
+        Policy::Belady => run_writing(Belady::new(trace.accesses()), write, trace, srams, dram),
+        Policy::SizeAwareBelady => run_writing(
+            Belady::size_aware(trace.accesses()),
+            write,
+            trace,
+            srams,
+            dram,
+        ),
+    }
+}
+
+/// Result of one combination of a parameter sweep.
+#[derive(Clone, Debug)]
+pub struct SweepPoint {
+    /// Size given to every region
+    pub sram_size: usize,
+    pub policy: Policy,
+    pub stats: Result<SimStats, SimError>,
+}
+
+/// Runs `trace` for every combination of SRAM size and policy, in parallel.
+/// Each region of `config` is resized to the swept size; everything else is
+/// kept. The points are in row-major order of (`sram_sizes`, `policies`).
+pub fn sweep<D>(
+    trace: &Operators<D>,
+    config: &MemoryConfig,
+    sram_sizes: &[usize],
+    policies: &[Policy],
+) -> Vec<SweepPoint>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord + Send + Sync,
+{
+    let points = sram_sizes
+        .iter()
+        .flat_map(|size| policies.iter().map(move |policy| (*size, policy.clone())))
+        .collect::<Vec<_>>();
+    points
+        .into_par_iter()
+        .map(|(sram_size, policy)| {
+            let mut config = config.clone();
+            for region in config.regions.values_mut() {
+                region.size = sram_size;
+            }
+            let (srams, dram) = config.build::<D>();
+            let stats = run_policy(&policy, WritePolicy::WriteBack, trace, &srams, &dram);
+            SweepPoint {
+                sram_size,
+                policy,
+                stats,
+            }
+        })
+        .collect()
+}
+
+/// Bytes `trace` allocates on devices, with nothing ever freed: any region
+/// of that size runs it without evicting.
+fn device_bytes<D>(trace: &Operators<D>) -> usize
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    trace
+        .linearize()
+        .iter()
+        .map(|op| {
+            let mut bytes = 0;
+            let mut op = op;
+            loop {
+                match op {
+                    Operators::Scratch(scratch, inner) => {
+                        bytes += scratch;
+                        op = inner;
+                    }
+                    Operators::Pool(_, inner)
+                    | Operators::Backing(_, inner)
+                    | Operators::InPlace(_, inner)
+                    | Operators::Dma(_, inner) => op = inner,
+                    Operators::Compute(region, .., sizes) if region != "host" => {
+                        break bytes + sizes.output + sizes.scratch
+                    }
+                    Operators::Load(region, _, size) if region != "host" => break bytes + size,
+                    Operators::Copy(.., size) => break bytes + size,
+                    Operators::Repeat(..) | Operators::If(..) => break bytes + pressure(op),
+                    _ => break bytes,
+                }
+            }
+        })
+        .sum()
+}
+
+/// Smallest size every region of `config` can be resized to for `trace` to
+/// run under `policy`, by binary search. A size failing for lack of room
+/// (see `SimError::is_capacity`) is too small; any other error is returned.
+/// This assumes that a policy never needs more room at a larger size, which
+/// most heuristics do not guarantee.
+pub fn find_min_sram<D>(
+    trace: &Operators<D>,
+    config: &MemoryConfig,
+    policy: &Policy,
+) -> Result<usize, SimError>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    let run = |size: usize| {
+        let mut config = config.clone();
+        for region in config.regions.values_mut() {
+            region.size = size;
+        }
+        let (srams, dram) = config.build::<D>();
+        run_policy(policy, WritePolicy::WriteBack, trace, &srams, &dram)
+    };
+    let fits = |size: usize| run(size).map(|_| ());
+    // first a size that fits, growing from the estimated peak up to what
+    // never needs an eviction
+    let most = device_bytes(trace).max(1);
+    let mut high = pressure(trace).clamp(1, most);
+    loop {
+        match fits(high) {
+            Ok(()) => break,
+            // at `most` still too small, e.g. padded by the alignment
+            Err(e) if e.is_capacity() && high < most => high = (high * 2).min(most),
+            Err(e) => return Err(e),
+        }
+    }
+    let mut low = 0;
+    while low < high {
+        let mid = low + (high - low) / 2;
+        match fits(mid) {
+            Ok(()) => high = mid,
+            Err(e) if e.is_capacity() => low = mid + 1,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(high)
+}
+
+/// A region size of `pareto_frontier` and what the trace costs at it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParetoPoint {
+    pub capacity: usize,
+    pub bytes_transferred: usize,
+    pub rematerializations: usize,
+}
+
+impl ParetoPoint {
+    /// Whether `self` is at least as good as `other` everywhere and better
+    /// somewhere.
+    pub fn dominates(&self, other: &Self) -> bool {
+        let key = |x: &Self| (x.capacity, x.bytes_transferred, x.rematerializations);
+        let (a, b) = (key(self), key(other));
+        a.0 <= b.0 && a.1 <= b.1 && a.2 <= b.2 && a != b
+    }
+}
+
+/// Runs `trace` under `policy` with every region resized to each of
+/// `sram_sizes` (see `sweep`) and keeps the sizes no other one dominates in
+/// capacity, bytes transferred and rematerializations, by ascending
+/// capacity. Sizes the trace does not run at are left out.
+pub fn pareto_frontier<D>(
+    trace: &Operators<D>,
+    config: &MemoryConfig,
+    sram_sizes: &[usize],
+    policy: &Policy,
+) -> Vec<ParetoPoint>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord + Send + Sync,
+{
+    let points = sweep(trace, config, sram_sizes, std::slice::from_ref(policy))
+        .into_iter()
+        .filter_map(|point| {
+            let stats = point.stats.ok()?;
+            Some(ParetoPoint {
+                capacity: point.sram_size,
+                bytes_transferred: stats.bytes_transferred,
+                rematerializations: stats.rematerializations,
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut frontier = points
+        .iter()
+        .filter(|x| !points.iter().any(|y| y.dominates(x)))
+        .cloned()
+        .collect::<Vec<_>>();
+    frontier.sort_by_key(|x| x.capacity);
+    frontier.dedup();
+    frontier
+}
+
+/// Table of `pareto_frontier`.
+pub fn pareto_report(frontier: &[ParetoPoint]) -> String {
+    let mut table = format!("{:>12}{:>14}{:>12}\n", "capacity", "bytes", "remats");
+    for point in frontier.iter() {
+        writeln!(
+            table,
+            "{:>12}{:>14}{:>12}",
+            point.capacity, point.bytes_transferred, point.rematerializations
+        )
+        .unwrap();
+    }
+    table
+}
+
+/// Mean, sample standard deviation and range of a statistic over several
+/// runs.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Spread {
+    pub mean: f64,
+    pub stddev: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Spread {
+    pub fn of(values: &[f64]) -> Self {
+        if values.is_empty() {
+            return Self::default();
+        }
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let variance = if values.len() > 1 {
+            values.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.0)
+        } else {
+            0.0
+        };
+        Self {
+            mean,
+            stddev: variance.sqrt(),
+            min: values.iter().cloned().fold(f64::INFINITY, f64::min),
+            max: values.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+        }
+    }
+}
+
+impl std::fmt::Display for Spread {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.2} ± {:.2} [{}, {}]",
+            self.mean, self.stddev, self.min, self.max
+        )
+    }
+}
+
+/// Random eviction over several seeds, see `monte_carlo`.
+#[derive(Clone, Debug)]
+pub struct MonteCarlo {
+    /// Seed and stats of every run, in order
+    pub runs: Vec<(u64, SimStats)>,
+    /// Of `SimStats::dram_trips`
+    pub trips: Spread,
+    pub evictions: Spread,
+    pub bytes_transferred: Spread,
+}
+
+/// Runs `trace` under random eviction `runs` times, in parallel, seeded with
+/// `first_seed`, `first_seed + 1`, ..., from the memory state described by
+/// `config`, and summarizes the spread of the trip counts. Any failing run
+/// fails the whole.
+pub fn monte_carlo<D>(
+    trace: &Operators<D>,
+    config: &MemoryConfig,
+    runs: usize,
+    first_seed: u64,
+) -> Result<MonteCarlo, SimError>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord + Send + Sync,
+{
+    let (srams, dram) = config.build::<D>();
+    let runs = (first_seed..first_seed + runs as u64)
+        .into_par_iter()
+        .map(|seed| {
+            let policy = Policy::Random(seed);
+            let stats = run_policy(&policy, WritePolicy::WriteBack, trace, &srams, &dram)?;
+            Ok((seed, stats))
+        })
+        .collect::<Result<Vec<_>, SimError>>()?;
+    let spread = |f: fn(&SimStats) -> usize| {
+        Spread::of(&runs.iter().map(|x| f(&x.1) as f64).collect::<Vec<_>>())
+    };
+    Ok(MonteCarlo {
+        trips: spread(SimStats::dram_trips),
+        evictions: spread(|x| x.evictions),
+        bytes_transferred: spread(|x| x.bytes_transferred),
+        runs,
+    })
+}
+
+/// Side-by-side table of the results of `run_all`.
+pub fn report(policies: &[Policy], stats: &[SimStats]) -> String {
+    let mut table = format!(
+        "{:<16}{:>12}{:>12}{:>12}{:>12}{:>12}{:>14}\n",
+        "policy", "loads", "stores", "evictions", "remats", "recomputes", "bytes"
+    );
+    for (policy, stats) in policies.iter().zip(stats.iter()) {
+        writeln!(
+            table,
+            "{:<16}{:>12}{:>12}{:>12}{:>12}{:>12}{:>14}",
+            policy.name(),
+            stats.loads,
+            stats.stores,
+            stats.evictions,
+            stats.rematerializations,
+            stats.recomputations,
+            stats.bytes_transferred
+        )
+        .unwrap();
+    }
+    table
+}
+
+/// Table of `JitSim::region_stats`, one row per region.
+pub fn region_report(regions: &BTreeMap<String, SimStats>) -> String {
+    let mut table = format!(
+        "{:<16}{:>12}{:>12}{:>12}{:>14}{:>14}\n",
+        "region", "loads", "stores", "evictions", "bytes", "peak"
+    );
+    for (region, stats) in regions.iter() {
+        writeln!(
+            table,
+            "{:<16}{:>12}{:>12}{:>12}{:>14}{:>14}",
+            region,
+            stats.loads,
+            stats.stores,
+            stats.evictions,
+            stats.bytes_transferred,
+            stats
+                .peak_residency
+                .get(region)
+                .cloned()
+                .unwrap_or_default()
+        )
+        .unwrap();
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(alignment: usize) -> MemoryConfig {
+        let toml = format!("[regions.sram]\nsize = 64\nalignment = {}\n", alignment);
+        MemoryConfig::from_toml(&toml).unwrap()
+    }
+
+    fn load(data: u32) -> (u32, Operators<u32>) {
+        let host = Operators::Load("host".into(), (data, Box::new(Operators::NoOp)), 4);
+        (
+            data,
+            Operators::Load("sram".into(), (data, Box::new(host)), 4),
+        )
+    }
+
+    /// 3 = f(1, 2)
+    fn trace() -> Operators<u32> {
+        Operators::Compute("sram".into(), 0, 3, vec![load(1), load(2)], 4.into())
+    }
+
+    #[test]
+    fn min_sram_holds_operands_and_output() {
+        assert_eq!(find_min_sram(&trace(), &config(1), &Policy::LRU), Ok(12));
+    }
+
+    #[test]
+    fn min_sram_keeps_the_error_of_the_largest_size() {
+        let error = find_min_sram(&trace(), &config(8), &Policy::LRU).unwrap_err();
+        assert!(error.is_capacity());
+    }
+
+    #[test]
+    fn every_policy_starts_from_the_same_state() {
+        let policies = [Policy::LRU, Policy::Belady, Policy::Random(1)];
+        let stats = run_all(&trace(), &config(1), &policies).unwrap();
+        assert_eq!(stats.len(), 3);
+        assert!(stats.iter().all(|x| x.loads == 2 && x.evictions == 0));
+        let table = report(&policies, &stats);
+        let rows = table.lines().skip(1).collect::<Vec<_>>();
+        assert_eq!(rows.len(), 3);
+        assert!(rows[2].starts_with("random(1)"));
+    }
+
+    #[test]
+    fn sweeps_every_size_and_policy() {
+        fn send<T: Send>() {}
+        send::<JitSim<LRU<u32>, u32>>();
+        send::<SRAM<u32>>();
+        let policies = [Policy::LRU, Policy::LFU];
+        let points = sweep(&trace(), &config(1), &[8, 12], &policies);
+        let order = points
+            .iter()
+            .map(|x| (x.sram_size, x.policy.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            vec![
+                (8, Policy::LRU),
+                (8, Policy::LFU),
+                (12, Policy::LRU),
+                (12, Policy::LFU)
+            ]
+        );
+        assert!(points[..2]
+            .iter()
+            .all(|x| x.stats.as_ref().unwrap_err().is_capacity()));
+        assert!(points[2..].iter().all(|x| x.stats.is_ok()));
+    }
+
+    #[test]
+    fn spread_uses_the_sample_deviation() {
+        let spread = Spread::of(&[1.0, 2.0, 3.0]);
+        assert_eq!(spread.mean, 2.0);
+        assert_eq!(spread.stddev, 1.0);
+        assert_eq!((spread.min, spread.max), (1.0, 3.0));
+        assert_eq!(Spread::of(&[5.0]).stddev, 0.0);
+        assert_eq!(Spread::of(&[]), Spread::default());
+        assert_eq!(spread.to_string(), "2.00 ± 1.00 [1, 3]");
+    }
+
+    #[test]
+    fn monte_carlo_spread_covers_every_seed_in_order() {
+        let summary = monte_carlo(&trace(), &config(1), 4, 7).unwrap();
+        let seeds = summary.runs.iter().map(|x| x.0).collect::<Vec<_>>();
+        assert_eq!(seeds, vec![7, 8, 9, 10]);
+        // the trace fits, so every seed makes the same trips
+        let trips = summary.runs[0].1.dram_trips() as f64;
+        assert_eq!(summary.trips.mean, trips);
+        assert_eq!(summary.trips.stddev, 0.0);
+        assert_eq!(summary.evictions, Spread::default());
+        assert_eq!(
+            monte_carlo(&trace(), &config(1), 0, 7).unwrap().runs.len(),
+            0
+        );
+    }
+
+    #[test]
+    fn pareto_frontier_keeps_the_undominated_sizes() {
+        let point = |capacity, bytes| ParetoPoint {
+            capacity,
+            bytes_transferred: bytes,
+            rematerializations: 0,
+        };
+        assert!(point(12, 8).dominates(&point(16, 8)));
+        assert!(!point(12, 16).dominates(&point(16, 8)));
+        assert!(!point(12, 8).dominates(&point(12, 8)));
+        // the trace fails at 8 and costs the same from 12 up
+        let frontier = pareto_frontier(&trace(), &config(1), &[8, 64, 12, 16], &Policy::LRU);
+        assert_eq!(frontier.len(), 1);
+        assert_eq!(frontier[0].capacity, 12);
+        assert_eq!(frontier[0].rematerializations, 0);
+        let table = pareto_report(&frontier);
+        let rows = table.lines().collect::<Vec<_>>();
+        assert_eq!(rows.len(), 2);
+        assert!(rows[1].trim_start().starts_with("12 "));
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    memory::{DRAM, SRAM},
+    sim::EnergyModel,
+};
+
+/// Memory setup of an experiment: one SRAM per accelerator region plus the
+/// host DRAM. Usually written in TOML:
+///
+/// ```toml
+/// [dram]
+/// bandwidth = 16.0
+///
+/// [regions.vta]
+/// size = 262144
+/// alignment = 64
+///
+/// [energy]
+/// dram_per_byte = 20.0
+/// ```
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MemoryConfig {
+    #[serde(default)]
+    pub regions: BTreeMap<String, RegionConfig>,
+    #[serde(default)]
+    pub dram: DramConfig,
+    /// For `JitSim::with_energy_model`
+    pub energy: Option<EnergyModel>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RegionConfig {
+    pub size: usize,
+    #[serde(default = "default_alignment")]
+    pub alignment: usize,
+    /// Bytes per cycle; unlimited if absent
+    pub bandwidth: Option<f64>,
+    /// Cycles per transaction
+    #[serde(default)]
+    pub latency: f64,
+    /// Window into DRAM instead of a discrete scratchpad
+    #[serde(default)]
+    pub unified: bool,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DramConfig {
+    /// Unlimited if absent
+    pub capacity: Option<usize>,
+    /// Bytes per cycle; unlimited if absent
+    pub bandwidth: Option<f64>,
+    /// Cycles per transaction
+    #[serde(default)]
+    pub latency: f64,
+}
+
+fn default_alignment() -> usize {
+    1
+}
+
+impl MemoryConfig {
+    pub fn from_toml(config: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(config)
+    }
+
+    pub fn build_srams<D>(&self) -> HashMap<String, SRAM<D>>
+    where
+        D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+    {
+        self.regions
+            .iter()
+            .map(|(name, region)| (name.clone(), region.build()))
+            .collect()
+    }
+
+    pub fn build_dram<D>(&self) -> DRAM<D>
+    where
+        D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+    {
+        self.dram.build()
+    }
+
+    /// The region map and DRAM, keyed by any data type (`egg::Id` for
+    /// traces compiled from Glenside).
+    pub fn build<D>(&self) -> (HashMap<String, SRAM<D>>, DRAM<D>)
+    where
+        D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+    {
+        (self.build_srams(), self.build_dram())
+    }
+}
+
+impl RegionConfig {
+    pub fn build<D>(&self) -> SRAM<D>
+    where
+        D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+    {
+        let sram = SRAM::new(self.size)
+            .with_alignment(self.alignment)
+            .with_bandwidth(self.bandwidth.unwrap_or(f64::INFINITY))
+            .with_latency(self.latency);
+        if self.unified {
+            sram.unified()
+        } else {
+            sram
+        }
+    }
+}
+
+impl DramConfig {
+    pub fn build<D>(&self) -> DRAM<D>
+    where
+        D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+    {
+        let dram = match self.capacity {
+            Some(capacity) => DRAM::with_capacity(capacity),
+            None => DRAM::new(),
+        };
+        dram.with_bandwidth(self.bandwidth.unwrap_or(f64::INFINITY))
+            .with_latency(self.latency)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::Memory;
+
+    #[test]
+    fn builds_memories_from_toml() {
+        let config = MemoryConfig::from_toml(
+            "[dram]\ncapacity = 1024\nlatency = 2.0\n\n\
+             [regions.vta]\nsize = 256\nalignment = 64\nbandwidth = 8.0\n",
+        )
+        .unwrap();
+        let (srams, dram) = config.build::<u32>();
+        let vta = &srams["vta"];
+        assert_eq!(vta.size_total(), 256);
+        assert_eq!(vta.padded(1), 64);
+        assert_eq!(vta.bandwidth(), 8.0);
+        assert_eq!(dram.size_total(), 1024);
+        assert_eq!(dram.latency(), 2.0);
+        assert!(config.energy.is_none());
+        assert!(MemoryConfig::from_toml("[regions.vta]\nalignment = 64\n").is_err());
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Write,
+    hash::Hash,
+};
+
+use crate::sim::{Instruction, Operators};
+
+/// Index of a node in a `Dag`.
+pub type NodeId = usize;
+
+/// One instruction of a `Dag`, without its operand trees.
+#[derive(Clone, Debug)]
+pub struct Node<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    /// The instruction, as given by `Operators::shallow`
+    pub op: Operators<D>,
+    /// Nodes producing its operands
+    pub inputs: Vec<NodeId>,
+}
+
+/// A program as an arena of instructions referring to their operands by id,
+/// so that a value used several times is produced by a single node. Nodes
+/// can only use nodes added before them: ids are a topological order.
+#[derive(Clone, Debug)]
+pub struct Dag<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    nodes: Vec<Node<D>>,
+    root: Option<NodeId>,
+}
+
+impl<D> Default for Dag<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    fn default() -> Self {
+        Self {
+            nodes: vec![],
+            root: None,
+        }
+    }
+}
+
+impl<D> Dag<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the DAG of a nested program. Loads, computes and copies of the
+    /// same data into the same region become one node however many times the
+    /// tree repeats them; stores and evictions are kept as they are. An
+    /// operand left as a `NoOp` because the tree computes it elsewhere (as
+    /// `from_glenside` does for shared subexpressions) uses the node that
+    /// last produced its data.
+    pub fn from_tree(tree: &Operators<D>) -> Self {
+        let mut dag = Self::new();
+        dag.root = dag.insert(tree, &mut HashMap::default(), &mut HashMap::default());
+        dag
+    }
+
+    fn insert(
+        &mut self,
+        op: &Operators<D>,
+        seen: &mut HashMap<(&'static str, String, D), NodeId>,
+        producers: &mut HashMap<D, NodeId>,
+    ) -> Option<NodeId> {
+        if let Operators::NoOp = op {
+            return None;
+        }
+        let key = match op.kind() {
+            "store" | "evict" | "repeat" | "if" => None,
+            kind => op
+                .output()
+                .map(|(region, data)| (kind, region.clone(), data.clone())),
+        };
+        if let Some(id) = key.as_ref().and_then(|x| seen.get(x)) {
+            return Some(*id);
+        }
+        let mut inputs = operands(op)
+            .into_iter()
+            .filter_map(|x| self.insert(x, seen, producers))
+            .collect::<Vec<_>>();
+        for data in shared_operands(op) {
+            if let Some(&input) = producers.get(data) {
+                if !inputs.contains(&input) {
+                    inputs.push(input);
+                }
+            }
+        }
+        let id = self.add(op.shallow(), inputs);
+        if let Some(key) = key {
+            producers.insert(key.2.clone(), id);
+            seen.insert(key, id);
+        }
+        Some(id)
+    }
+
+    /// Appends `op` (its operand trees are dropped) using the results of
+    /// `inputs`, and makes it the root.
+    pub fn add(&mut self, op: Operators<D>, inputs: Vec<NodeId>) -> NodeId {
+        assert!(
+            inputs.iter().all(|&x| x < self.nodes.len()),
+            "inputs must be added before their users"
+        );
+        let id = self.nodes.len();
+        self.nodes.push(Node {
+            op: op.shallow(),
+            inputs,
+        });
+        self.root = Some(id);
+        id
+    }
+
+    pub fn node(&self, id: NodeId) -> &Node<D> {
+        &self.nodes[id]
+    }
+
+    pub fn nodes(&self) -> &[Node<D>] {
+        &self.nodes
+    }
+
+    /// The node whose result is the result of the program.
+    pub fn root(&self) -> Option<NodeId> {
+        self.root
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Nodes using the result of each node.
+    pub fn users(&self) -> Vec<Vec<NodeId>> {
+        let mut users = vec![vec![]; self.nodes.len()];
+        for (id, node) in self.nodes.iter().enumerate() {
+            for &input in node.inputs.iter() {
+                users[input].push(id);
+            }
+        }
+        users
+    }
+
+    /// The DAG in Graphviz format: one node per instruction, filled with the
+    /// color of its region, and one edge per operand labelled with its size
+    /// in bytes.
+    pub fn to_dot(&self) -> String {
+        const PALETTE: [&str; 8] = [
+            "#8dd3c7", "#ffffb3", "#bebada", "#fb8072", "#80b1d3", "#fdb462", "#b3de69", "#fccde5",
+        ];
+        let regions = self
+            .nodes
+            .iter()
+            .filter_map(|x| x.op.output().map(|x| x.0.clone()))
+            .filter(|x| x != "host")
+            .collect::<BTreeSet<_>>();
+        let color = |region: &str| match regions.iter().position(|x| x == region) {
+            Some(i) => PALETTE[i % PALETTE.len()],
+            None => "#d9d9d9",
+        };
+        let mut dot = String::from("digraph operators {\n  node [shape=box, style=filled];\n");
+        for (id, node) in self.nodes.iter().enumerate() {
+            let (region, label) = match node.op.unwrap_wrappers() {
+                Operators::Compute(region, kind, dst, ..) => {
+                    (region.as_str(), format!("compute {:?}\n{:?}", kind, dst))
+                }
+                Operators::Copy(src, dst, data, _) => {
+                    (dst.as_str(), format!("copy {:?}\n{} -> {}", data, src, dst))
+                }
+                Operators::Repeat(count, op) => (
+                    op.output().map_or("host", |x| x.0.as_str()),
+                    format!("repeat {}\n{}", count, op.compile()),
+                ),
+                Operators::If(cond, then, _) => (
+                    then.output().map_or("host", |x| x.0.as_str()),
+                    format!("if {:?}", cond),
+                ),
+                op => match op.output() {
+                    Some((region, data)) => (region.as_str(), format!("{} {:?}", op.kind(), data)),
+                    None => ("host", op.kind().to_string()),
+                },
+            };
+            writeln!(
+                dot,
+                "  n{} [label=\"{}\\n{}\", fillcolor=\"{}\"];",
+                id,
+                label.replace('"', "\\\"").replace('\n', "\\n"),
+                region,
+                color(region)
+            )
+            .unwrap();
+            for input in node.inputs.iter() {
+                match output_size(&self.nodes[*input].op) {
+                    Some(size) => writeln!(dot, "  n{} -> n{} [label=\"{}\"];", input, id, size),
+                    None => writeln!(dot, "  n{} -> n{};", input, id),
+                }
+                .unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// The instructions in topological order, each once, ready for
+    /// `JitSim::run_schedule`.
+    pub fn schedule(&self) -> Vec<Operators<D>> {
+        self.nodes.iter().map(|x| x.op.clone()).collect()
+    }
+}
+
+/// Bytes of the data `op` produces.
+fn output_size<D>(op: &Operators<D>) -> Option<usize>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    match op.unwrap_wrappers() {
+        Operators::Compute(.., sizes) => Some(sizes.output),
+        Operators::Load(.., size)
+        | Operators::Store(.., size)
+        | Operators::Evict(.., size)
+        | Operators::Copy(.., size) => Some(*size),
+        Operators::Repeat(_, op) | Operators::If(_, op, _) => output_size(op),
+        _ => None,
+    }
+}
+
+/// Data of the operands of `op` without a tree of their own, through its
+/// wrappers.
+fn shared_operands<D>(op: &Operators<D>) -> Vec<&D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    match op.unwrap_wrappers() {
+        Operators::Compute(_, _, _, subops, _) => subops
+            .iter()
+            .filter(|x| matches!(x.1, Operators::NoOp))
+            .map(|x| &x.0)
+            .collect(),
+        Operators::Load(_, (data, op), _) | Operators::Store(_, _, (data, op), _)
+            if matches!(op.as_ref(), Operators::NoOp) =>
+        {
+            vec![data]
+        }
+        _ => vec![],
+    }
+}
+
+/// Operand trees of `op`, through its wrappers.
+fn operands<D>(op: &Operators<D>) -> Vec<&Operators<D>>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    match op {
+        Operators::Compute(_, _, _, subops, _) => subops.iter().map(|x| &x.1).collect(),
+        Operators::Load(_, (_, op), _) | Operators::Store(_, _, (_, op), _) => vec![op.as_ref()],
+        Operators::Pool(_, op)
+        | Operators::Backing(_, op)
+        | Operators::Scratch(_, op)
+        | Operators::InPlace(_, op)
+        | Operators::Dma(_, op) => operands(op),
+        // loops and branches keep their bodies, which run as a whole
+        Operators::Repeat(..)
+        | Operators::If(..)
+        | Operators::Evict(..)
+        | Operators::Copy(..)
+        | Operators::Sync(_)
+        | Operators::Barrier
+        | Operators::NoOp => vec![],
+    }
+}
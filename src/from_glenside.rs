@@ -1,10 +1,68 @@
 use std::collections::HashMap;
 
-use egg::{EGraph, Id, RecExpr};
+use egg::{AstSize, CostFunction, EGraph, Extractor, Id, Language as _, LpCostFunction, RecExpr};
 use glenside::language::{Language, MyAnalysis, MyAnalysisData};
 use ndarray::Dimension;
 
-use crate::sim::Operators;
+use crate::{
+    compare::{run_policy, Policy},
+    config::MemoryConfig,
+    dag::Dag,
+    sim::{Operators, WritePolicy},
+};
+
+/// Compiles the program rooted at `root` into a DAG: a tensor used by several
+/// consumers is loaded and computed by a single node that all of them use,
+/// instead of once per path of the tree.
+pub fn compile_dag(
+    root: &Id,
+    expr: &RecExpr<Language>,
+    egraph: &EGraph<Language, MyAnalysis>,
+    id_translation: &HashMap<Id, Id>,
+) -> Dag<Id> {
+    match compile_instruction(root, expr, &mut HashMap::default(), egraph, id_translation) {
+        Some((tree, _)) => Dag::from_tree(&tree),
+        None => Dag::new(),
+    }
+}
+
+/// `expr` without duplicate nodes, added to an e-graph of its own analysed
+/// by `analysis` (which has to know the shapes of the tensors `expr` names),
+/// with the id translation to pass to `compile_instruction` along with them:
+/// the e-class of every node is its index.
+pub fn expr_egraph(
+    expr: &RecExpr<Language>,
+    analysis: MyAnalysis,
+) -> (
+    RecExpr<Language>,
+    EGraph<Language, MyAnalysis>,
+    HashMap<Id, Id>,
+) {
+    let mut unique = RecExpr::default();
+    let mut ids: Vec<Id> = Vec::with_capacity(expr.nodes.len());
+    let mut seen = HashMap::new();
+    for node in expr.nodes.iter() {
+        let node = node.clone().map_children(|x| ids[usize::from(x)]);
+        let id = *seen.entry(node.clone()).or_insert_with(|| unique.add(node));
+        ids.push(id);
+    }
+    // fresh e-classes are numbered in the order they are added
+    let mut egraph = EGraph::new(analysis);
+    for node in unique.nodes.iter() {
+        egraph.add(node.clone());
+    }
+    let translation = (0..unique.nodes.len())
+        .map(|x| (Id::from(x), Id::from(x)))
+        .collect();
+    (unique, egraph, translation)
+}
+
+/// Compiles the program `expr` (its last node) alone, see `expr_egraph`.
+pub fn compile_expr(expr: &RecExpr<Language>, analysis: MyAnalysis) -> Option<Operators<Id>> {
+    let (expr, egraph, translation) = expr_egraph(expr, analysis);
+    let root = Id::from(expr.nodes.len().checked_sub(1)?);
+    compile_instruction(&root, &expr, &mut HashMap::default(), &egraph, &translation).map(|x| x.0)
+}
 
 pub fn compile_instruction(
     current_id: &Id,
@@ -43,11 +101,7 @@ pub fn compile_instruction(
                     .cloned()
                     .map(|x| id_translation.get(&x).unwrap().clone())
                     .collect::<Vec<_>>();
-                // let output_size = match &egraph[current_id.clone()].data {
-                //     MyAnalysisData::AccessPattern(access) => access.as_vec().iter().product(),
-                //     MyAnalysisData::Shape(shape) => shape.shape.slice().to_vec().iter().product(),
-                //     _ => panic!(),
-                // };
+                let output_size = tensor_size(egraph, current_id.clone());
                 memo.insert(current_id.clone(), current_id.clone());
                 return Some((
                     Operators::Compute(
@@ -55,7 +109,7 @@ pub fn compile_instruction(
                         ids[0],
                         current_id.clone(),
                         mem_id.iter().cloned().zip(insn.into_iter()).collect(),
-                        1,
+                        output_size.into(),
                     ),
                     current_id.clone(),
                 ));
@@ -74,17 +128,13 @@ pub fn compile_instruction(
                 MyAnalysisData::AcceleratorFunc(func) => func.accelerator.clone().into(),
                 _ => panic!("Not a valid accelerator load: {:?}", egraph[region].data),
             };
-            // let output_size = match &egraph[data].data {
-            //     MyAnalysisData::AccessPattern(access) => access.as_vec().iter().product(),
-            //     MyAnalysisData::Shape(shape) => shape.shape.slice().to_vec().iter().product(),
-            //     _ => panic!(),
-            // };
+            let output_size = tensor_size(egraph, id_translation.get(&data).unwrap().clone());
             // (accelerator-call <region> <loads..>)
             // accelerator calls will use the ids of their direct children
             // therefore we store the id of `Load` here.
             memo.insert(current_id.clone(), src_id.clone());
             return Some((
-                Operators::Load(region, (src_id.clone(), Box::new(load_cmd)), 1),
+                Operators::Load(region, (src_id.clone(), Box::new(load_cmd)), output_size),
                 src_id.into(),
             ));
         }
@@ -96,11 +146,7 @@ pub fn compile_instruction(
                 MyAnalysisData::AcceleratorFunc(func) => func.accelerator.clone().into(),
                 _ => panic!("Not a valid accelerator store: {:?}", egraph[region].data),
             };
-            // let output_size = match &egraph[data].data {
-            //     MyAnalysisData::AccessPattern(access) => access.as_vec().iter().product(),
-            //     MyAnalysisData::Shape(shape) => shape.shape.slice().to_vec().iter().product(),
-            //     _ => panic!(),
-            // };
+            let output_size = tensor_size(egraph, id_translation.get(&data).unwrap().clone());
             // Store could be used by multiple parents
             // According to the rewrite rule, a store will be merged with a parent
             // load if and only if the load is the only parent to the store
@@ -116,7 +162,12 @@ pub fn compile_instruction(
             // } else {
             memo.insert(current_id.clone(), dst_id.clone());
             return Some((
-                Operators::Store(region, true, (dst_id.clone(), Box::new(store_cmd)), 1),
+                Operators::Store(
+                    region,
+                    true,
+                    (dst_id.clone(), Box::new(store_cmd)),
+                    output_size,
+                ),
                 dst_id.into(),
             ));
             // }
@@ -145,11 +196,7 @@ pub fn compile_instruction(
                 MyAnalysisData::AcceleratorFunc(func) => func.accelerator.clone().into(),
                 _ => panic!("Not a valid accelerator store"),
             };
-            // let output_size = match &egraph[current_id.clone()].data {
-            //     MyAnalysisData::AccessPattern(access) => access.as_vec().iter().product(),
-            //     MyAnalysisData::Shape(shape) => shape.shape.slice().to_vec().iter().product(),
-            //     _ => panic!(),
-            // };
+            let output_size = tensor_size(egraph, current_id.clone());
             memo.insert(current_id.clone(), current_id.clone());
             return Some((
                 Operators::Compute(
@@ -157,7 +204,7 @@ pub fn compile_instruction(
                     ids[0],
                     current_id.clone(),
                     mem_id.iter().cloned().zip(insn.into_iter()).collect(),
-                    1,
+                    output_size.into(),
                 ),
                 current_id.clone(),
             ));
@@ -172,7 +219,7 @@ pub fn compile_instruction(
                     op,
                     current_id.clone(),
                     vec![(id, child_op)],
-                    1,
+                    tensor_size(egraph, current_id.clone()).into(),
                 ),
                 current_id.clone(),
             ));
@@ -189,15 +236,15 @@ pub fn compile_instruction(
             {
                 child_insn.push((cdr_id, cdr_op));
             }
-            memo.insert(current_id.clone(), current_id.clone());
             if child_insn.len() > 0 {
+                memo.insert(current_id.clone(), current_id.clone());
                 return Some((
                     Operators::Compute(
                         "host".into(),
                         current_id.clone(),
                         current_id.clone(),
                         child_insn,
-                        1,
+                        tensor_size(egraph, current_id.clone()).into(),
                     ),
                     current_id.clone(),
                 ));
@@ -205,23 +252,52 @@ pub fn compile_instruction(
                 return None;
             }
         }
-        Language::AccessInsertAxis([x, _])
+        // the tuple holds all of its items
+        Language::ConstructTuple(items) => {
+            host_compute(current_id, &items, expr, memo, egraph, id_translation)
+        }
+        Language::TupleGetItem([tuple, _]) => {
+            let (tuple_op, tuple_id) =
+                compile_instruction(&tuple, expr, memo, egraph, id_translation)?;
+            memo.insert(current_id.clone(), current_id.clone());
+            return Some((
+                Operators::Compute(
+                    "host".into(),
+                    current_id.clone(),
+                    current_id.clone(),
+                    vec![(tuple_id, tuple_op)],
+                    tensor_size(egraph, current_id.clone()).into(),
+                ),
+                current_id.clone(),
+            ));
+        }
+        // rearrange the data into a new tensor
+        Language::AccessTranspose([x, _])
+        | Language::AccessSlice([x, _, _, _])
+        | Language::AccessWindows([x, _, _])
+        | Language::AccessPad([x, _, _, _, _])
+        | Language::AccessShiftRight(x) => {
+            host_compute(current_id, &[x], expr, memo, egraph, id_translation)
+        }
+        Language::AccessConcatenate([a, b, _]) | Language::AccessCartesianProduct([a, b]) => {
+            host_compute(current_id, &[a, b], expr, memo, egraph, id_translation)
+        }
+        // views of the same elements
+        Language::AccessSqueeze([x, _])
+        | Language::AccessReshape([x, _])
+        | Language::AccessInsertAxis([x, _])
         | Language::AccessBroadcast([x, _])
         | Language::Access([x, _]) => {
             return compile_instruction(&x, expr, memo, egraph, id_translation);
         }
         Language::AccessLiteral(_) | Language::AccessTensor(_) => {
-            // let output_size = match &egraph[current_id.clone()].data {
-            //     MyAnalysisData::AccessPattern(access) => access.as_vec().iter().product(),
-            //     MyAnalysisData::Shape(shape) => shape.shape.slice().to_vec().iter().product(),
-            //     _ => panic!(),
-            // };
+            let output_size = tensor_size(egraph, current_id.clone());
             memo.insert(current_id.clone(), current_id.clone());
             return Some((
                 Operators::Load(
                     "host".into(),
                     (current_id.clone(), Box::new(Operators::NoOp)),
-                    1,
+                    output_size,
                 ),
                 current_id.clone(),
             ));
@@ -234,7 +310,7 @@ pub fn compile_instruction(
                     current_id.clone(),
                     current_id.clone(),
                     vec![(op.1, op.0)],
-                    1,
+                    tensor_size(egraph, current_id.clone()).into(),
                 ),
                 current_id.clone(),
             ));
@@ -246,3 +322,200 @@ pub fn compile_instruction(
         _ => panic!("Not supported: {:?}", node),
     }
 }
+
+/// A host compute of `current_id` from the instructions of `children`, or
+/// `None` if none of them needs one.
+fn host_compute(
+    current_id: &Id,
+    children: &[Id],
+    expr: &RecExpr<Language>,
+    memo: &mut HashMap<Id, Id>,
+    egraph: &EGraph<Language, MyAnalysis>,
+    id_translation: &HashMap<Id, Id>,
+) -> Option<(Operators<Id>, Id)> {
+    let child_insn = children
+        .iter()
+        .filter_map(|x| compile_instruction(x, expr, memo, egraph, id_translation))
+        .map(|(op, id)| (id, op))
+        .collect::<Vec<_>>();
+    if child_insn.is_empty() {
+        // nothing to refer to when visited again either
+        return None;
+    }
+    memo.insert(current_id.clone(), current_id.clone());
+    Some((
+        Operators::Compute(
+            "host".into(),
+            current_id.clone(),
+            current_id.clone(),
+            child_insn,
+            tensor_size(egraph, current_id.clone()).into(),
+        ),
+        current_id.clone(),
+    ))
+}
+
+/// Number of elements of the tensor (or access pattern, or all the tensors
+/// of a tuple) in eclass `id`.
+fn tensor_size(egraph: &EGraph<Language, MyAnalysis>, id: Id) -> usize {
+    match analysis_size(&egraph[id].data) {
+        Some(size) => size,
+        None => panic!("No shape for {:?}: {:?}", id, egraph[id].data),
+    }
+}
+
+fn analysis_size(data: &MyAnalysisData) -> Option<usize> {
+    match data {
+        // both the iterated and the item dimensions
+        MyAnalysisData::AccessPattern(access) => Some(access.as_vec().iter().product()),
+        MyAnalysisData::Shape(shape) => Some(shape.shape.slice().iter().product()),
+        MyAnalysisData::Literal(tensor) => Some(tensor.len()),
+        MyAnalysisData::Tuple(items) => items.iter().map(analysis_size).sum(),
+        _ => None,
+    }
+}
+
+/// Cost of an extraction by `SimCost`: the DRAM trips, then the bytes moved,
+/// of simulating it. An extraction that does not run costs `usize::MAX`.
+#[derive(Clone, Debug)]
+pub struct SimulatedCost {
+    pub trips: usize,
+    pub bytes: usize,
+    /// The extraction, so that the parents can be simulated with it
+    pub expr: RecExpr<Language>,
+}
+
+impl PartialEq for SimulatedCost {
+    fn eq(&self, other: &Self) -> bool {
+        (self.trips, self.bytes) == (other.trips, other.bytes)
+    }
+}
+
+impl PartialOrd for SimulatedCost {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        (self.trips, self.bytes).partial_cmp(&(other.trips, other.bytes))
+    }
+}
+
+/// An egg `CostFunction` that extracts what runs with the fewest DRAM trips:
+/// every candidate e-node, over the cheapest extraction of its children, is
+/// compiled with `compile_expr` and simulated under `policy` on the memories
+/// of `config`. Candidates are simulated as a whole, so the cost does not
+/// decompose per e-node; as an `LpCostFunction`, each e-node costs the trips
+/// it adds to those of its children instead, see `node_cost`.
+pub struct SimCost {
+    /// Analysis of the compiled candidates, knowing the shapes of the tensors
+    analysis: Box<dyn Fn() -> MyAnalysis>,
+    config: MemoryConfig,
+    policy: Policy,
+    /// (trips, bytes) by candidate
+    cache: HashMap<String, (usize, usize)>,
+    /// Smallest term of every e-class, for `node_cost`
+    smallest: HashMap<Id, RecExpr<Language>>,
+}
+
+impl SimCost {
+    pub fn new(
+        analysis: impl Fn() -> MyAnalysis + 'static,
+        config: MemoryConfig,
+        policy: Policy,
+    ) -> Self {
+        Self {
+            analysis: Box::new(analysis),
+            config,
+            policy,
+            cache: HashMap::default(),
+            smallest: HashMap::default(),
+        }
+    }
+
+    fn simulate(&mut self, expr: &RecExpr<Language>) -> (usize, usize) {
+        let key = expr.to_string();
+        if let Some(cost) = self.cache.get(&key) {
+            return *cost;
+        }
+        let cost = match compile_expr(expr, (self.analysis)()) {
+            Some(program) => {
+                let (srams, dram) = self.config.build::<Id>();
+                match run_policy(
+                    &self.policy,
+                    WritePolicy::WriteBack,
+                    &program,
+                    &srams,
+                    &dram,
+                ) {
+                    Ok(stats) => (stats.dram_trips(), stats.bytes_transferred),
+                    Err(_) => (usize::MAX, usize::MAX),
+                }
+            }
+            // shapes, layouts and other nodes without instructions
+            None => (0, 0),
+        };
+        self.cache.insert(key, cost);
+        cost
+    }
+}
+
+impl CostFunction<Language> for SimCost {
+    type Cost = SimulatedCost;
+
+    fn cost<C>(&mut self, enode: &Language, mut costs: C) -> Self::Cost
+    where
+        C: FnMut(Id) -> Self::Cost,
+    {
+        let expr = graft(enode, |child| costs(child).expr);
+        let (trips, bytes) = self.simulate(&expr);
+        SimulatedCost { trips, bytes, expr }
+    }
+}
+
+impl LpCostFunction<Language, MyAnalysis> for SimCost {
+    /// The DRAM trips `enode` adds to those of its children, each taken as
+    /// the smallest term of its e-class and simulated on its own. Trips do
+    /// not add up over a program, so this only approximates `cost`. The
+    /// smallest terms are those of the e-graph of the first call.
+    fn node_cost(
+        &mut self,
+        egraph: &EGraph<Language, MyAnalysis>,
+        _eclass: Id,
+        enode: &Language,
+    ) -> f64 {
+        if self.smallest.is_empty() {
+            let extractor = Extractor::new(egraph, AstSize);
+            for class in egraph.classes() {
+                self.smallest
+                    .insert(class.id, extractor.find_best(class.id).1);
+            }
+        }
+        let expr = graft(enode, |child| self.smallest[&egraph.find(child)].clone());
+        let own = self.simulate(&expr).0;
+        let children = enode
+            .children()
+            .iter()
+            .map(|child| self.smallest[&egraph.find(*child)].clone())
+            .collect::<Vec<_>>();
+        let below = children
+            .iter()
+            .map(|child| self.simulate(child).0)
+            .fold(0, usize::saturating_add);
+        own.saturating_sub(below) as f64
+    }
+}
+
+/// `enode` over the terms `child` gives for its children.
+fn graft(enode: &Language, mut child: impl FnMut(Id) -> RecExpr<Language>) -> RecExpr<Language> {
+    let mut expr = RecExpr::default();
+    let node = enode.clone().map_children(|id| {
+        // the nodes of the child after those already added
+        let offset = expr.nodes.len();
+        for node in child(id).nodes.iter() {
+            expr.add(
+                node.clone()
+                    .map_children(|x| Id::from(usize::from(x) + offset)),
+            );
+        }
+        Id::from(expr.nodes.len() - 1)
+    });
+    expr.add(node);
+    expr
+}
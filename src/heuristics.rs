@@ -1,30 +1,49 @@
 use crate::sim::{Heuristic, Memory};
-use rand::seq::SliceRandom;
-use std::{collections::BinaryHeap, collections::HashSet, hash::Hash, time::Instant};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use std::{
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
 
-pub struct RandomEviction;
+/// Evicts a uniformly random candidate. Seeded instances make the same
+/// choices on every run; unseeded ones use the thread RNG.
+#[derive(Clone)]
+pub struct RandomEviction {
+    rng: Option<StdRng>,
+}
+
+impl Default for RandomEviction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl RandomEviction {
     pub fn new() -> Self {
-        Self {}
+        Self { rng: None }
+    }
+
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            rng: Some(StdRng::seed_from_u64(seed)),
+        }
     }
 }
 
+/// Data tagged with the logical time of its last touch.
 #[derive(Clone, Debug)]
-struct DataPair<D: Clone>(Instant, D);
+struct DataPair<D: Clone>(u64, D);
 
 impl<D: Clone> PartialEq for DataPair<D> {
     fn eq(&self, other: &Self) -> bool {
-        self.0.elapsed() == other.0.elapsed()
+        self.0 == other.0
     }
 }
 
 impl<D: Clone> PartialOrd for DataPair<D> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.0
-            .elapsed()
-            .as_nanos()
-            .partial_cmp(&other.0.elapsed().as_nanos())
+        Some(self.cmp(other))
     }
 }
 
@@ -32,15 +51,69 @@ impl<D: Clone> Eq for DataPair<D> {}
 
 impl<D: Clone> Ord for DataPair<D> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        if self.0.elapsed().as_nanos() < other.0.elapsed().as_nanos() {
-            std::cmp::Ordering::Less
-        } else {
-            std::cmp::Ordering::Greater
+        self.0.cmp(&other.0)
+    }
+}
+
+/// How to pick among eviction candidates that a heuristic scores equally.
+#[derive(Clone, Copy, Debug)]
+pub enum TieBreak {
+    SmallestId,
+    LargestSize,
+    /// Uniformly random, seeded for reproducibility
+    Random(u64),
+}
+
+#[derive(Clone)]
+pub struct TieBreaker {
+    mode: TieBreak,
+    rng: StdRng,
+}
+
+impl TieBreaker {
+    pub fn new(mode: TieBreak) -> Self {
+        let seed = match mode {
+            TieBreak::Random(seed) => seed,
+            _ => 0,
+        };
+        Self {
+            mode,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn pick<'a, D, TM>(&mut self, sram: &TM, candidates: Vec<&'a D>) -> Option<&'a D>
+    where
+        D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+        TM: Memory<D>,
+    {
+        match self.mode {
+            TieBreak::SmallestId => candidates.into_iter().min(),
+            TieBreak::LargestSize => {
+                let sizes = sram.iter().collect::<HashMap<_, _>>();
+                let size = |x: &D| sizes.get(x).cloned().unwrap_or(0);
+                candidates
+                    .into_iter()
+                    .max_by(|x, y| size(x).cmp(&size(y)).then_with(|| y.cmp(x)))
+            }
+            TieBreak::Random(_) => candidates.choose(&mut self.rng).cloned(),
         }
     }
 }
+
+impl Default for TieBreaker {
+    fn default() -> Self {
+        Self::new(TieBreak::SmallestId)
+    }
+}
+
+#[derive(Clone)]
 pub struct LRU<D: Clone> {
     member: BinaryHeap<DataPair<D>>,
+    /// Logical clock, advanced on every touch, so that the order does not
+    /// depend on wall-clock timing
+    clock: u64,
+    tie_break: TieBreaker,
 }
 
 impl<D> Heuristic<D> for RandomEviction
@@ -54,16 +127,19 @@ where
         let allowed = sram
             .to_vec()
             .iter()
-            .filter(|&&x| !exclude.contains(&x))
+            .filter(|&&x| !exclude.contains(x))
             .cloned()
             .collect::<Vec<_>>();
-        if allowed.len() > 0 {
-            let x = allowed.choose(&mut rand::thread_rng());
+        if !allowed.is_empty() {
+            let x = match self.rng.as_mut() {
+                Some(rng) => allowed.choose(rng),
+                None => allowed.choose(&mut rand::thread_rng()),
+            };
             if let Some(&x) = x {
                 return Some(x.clone());
             }
         }
-        return None;
+        None
     }
 
     fn touch(&mut self, _data: &D, _size: usize) {}
@@ -71,34 +147,54 @@ where
     fn reset(&mut self) {}
 }
 
+impl<D: Clone> Default for LRU<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<D: Clone> LRU<D> {
     pub fn new() -> Self {
         LRU {
             member: BinaryHeap::default(),
+            clock: 0,
+            tie_break: TieBreaker::default(),
         }
     }
+
+    pub fn with_tie_break(mut self, tie_break: TieBreak) -> Self {
+        self.tie_break = TieBreaker::new(tie_break);
+        self
+    }
 }
 
 impl<D> Heuristic<D> for LRU<D>
 where
-    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
 {
-    fn choose<TM>(&mut self, _sram: &TM, exclude: &HashSet<D>) -> Option<D>
+    fn choose<TM>(&mut self, sram: &TM, exclude: &HashSet<D>) -> Option<D>
     where
         TM: Memory<D>,
     {
-        let x = self
+        // touches of every region end up here: only consider `sram`'s
+        let allowed = self
             .member
             .iter()
-            .filter(|&x| !exclude.contains(&x.1))
-            .collect::<BinaryHeap<_>>();
-        let x = x.peek();
-        x.map_or(None, |&x| Some(x.1.clone()))
+            .filter(|&x| sram.contains(&x.1) && !exclude.contains(&x.1))
+            .collect::<Vec<_>>();
+        let oldest = allowed.iter().map(|x| x.0).min()?;
+        let ties = allowed
+            .into_iter()
+            .filter(|x| x.0 == oldest)
+            .map(|x| &x.1)
+            .collect::<Vec<_>>();
+        self.tie_break.pick(sram, ties).cloned()
     }
 
     fn touch(&mut self, data: &D, _size: usize) {
         self.evict(data);
-        self.member.push(DataPair(Instant::now(), data.clone()));
+        self.clock += 1;
+        self.member.push(DataPair(self.clock, data.clone()));
     }
 
     fn evict(&mut self, data: &D) {
@@ -114,3 +210,474 @@ where
         self.member.clear();
     }
 }
+
+/// Belady's optimal replacement: evicts the data whose next use is the furthest
+/// in the future. Requires the access sequence of the whole run up front
+/// (see `Operators::accesses`).
+#[derive(Clone)]
+pub struct Belady<D: Clone + Hash + Eq> {
+    next_uses: HashMap<D, VecDeque<usize>>,
+    clock: usize,
+    size_aware: bool,
+    tie_break: TieBreaker,
+}
+
+impl<D> Belady<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    pub fn new(accesses: Vec<D>) -> Self {
+        let mut next_uses: HashMap<D, VecDeque<usize>> = HashMap::default();
+        for (i, data) in accesses.into_iter().enumerate() {
+            next_uses.entry(data).or_default().push_back(i);
+        }
+        Self {
+            next_uses,
+            clock: 0,
+            size_aware: false,
+            tie_break: TieBreaker::default(),
+        }
+    }
+
+    pub fn with_tie_break(mut self, tie_break: TieBreak) -> Self {
+        self.tie_break = TieBreaker::new(tie_break);
+        self
+    }
+
+    /// Weighs the next-use distance by the size of the data, so that big
+    /// tensors that are not needed soon are evicted first.
+    pub fn size_aware(accesses: Vec<D>) -> Self {
+        Self {
+            size_aware: true,
+            ..Self::new(accesses)
+        }
+    }
+
+    /// Distance to the next use of `data`; `None` if it is never used again.
+    fn next_use(&self, data: &D) -> Option<usize> {
+        self.next_uses
+            .get(data)
+            .and_then(|uses| uses.front())
+            .map(|&t| t.saturating_sub(self.clock))
+    }
+}
+
+impl<D> Heuristic<D> for Belady<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    fn choose<TM>(&mut self, sram: &TM, exclude: &HashSet<D>) -> Option<D>
+    where
+        TM: Memory<D>,
+    {
+        let scored = sram
+            .iter()
+            .filter(|(x, _)| !exclude.contains(x))
+            .map(|(x, size)| {
+                let size = if self.size_aware { size } else { 1 };
+                match self.next_use(x) {
+                    None => ((true, size), x),
+                    Some(dist) => ((false, dist.saturating_mul(size)), x),
+                }
+            })
+            .collect::<Vec<_>>();
+        let best = scored.iter().map(|x| x.0).max()?;
+        let ties = scored
+            .into_iter()
+            .filter(|x| x.0 == best)
+            .map(|x| x.1)
+            .collect::<Vec<_>>();
+        self.tie_break.pick(sram, ties).cloned()
+    }
+
+    fn touch(&mut self, data: &D, _size: usize) {
+        if let Some(uses) = self.next_uses.get_mut(data) {
+            if let Some(t) = uses.pop_front() {
+                self.clock = t + 1;
+            }
+        }
+    }
+
+    fn evict(&mut self, _data: &D) {}
+
+    // the future does not change when the memory is flushed
+    fn reset(&mut self) {}
+}
+
+/// Evicts following a script of choices: the `k`-th eviction takes the
+/// candidate ranked `script[k]` (modulo their number) by recency, least
+/// recently used first. Decisions past the script take the least recently
+/// used candidate, as `LRU` does. Lets the searches of `crate::search` change
+/// single eviction decisions of a run.
+#[derive(Clone)]
+pub struct Scripted<D> {
+    script: Vec<usize>,
+    clock: u64,
+    touched: HashMap<D, u64>,
+    /// Number of candidates of every decision taken so far
+    options: Vec<usize>,
+}
+
+impl<D> Scripted<D> {
+    pub fn new(script: Vec<usize>) -> Self {
+        Self {
+            script,
+            clock: 0,
+            touched: HashMap::default(),
+            options: vec![],
+        }
+    }
+
+    pub fn script(&self) -> &[usize] {
+        &self.script
+    }
+
+    /// Number of candidates each decision so far chose from.
+    pub fn options(&self) -> &[usize] {
+        &self.options
+    }
+
+    /// Scripts `choice` for the next decision not taken yet, after the
+    /// defaults of the decisions taken past the script.
+    pub fn push(&mut self, choice: usize) {
+        if self.script.len() < self.options.len() {
+            self.script.resize(self.options.len(), 0);
+        }
+        self.script.push(choice);
+    }
+}
+
+impl<D> Heuristic<D> for Scripted<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    fn choose<TM>(&mut self, sram: &TM, exclude: &HashSet<D>) -> Option<D>
+    where
+        TM: Memory<D>,
+    {
+        let mut allowed = sram
+            .to_vec()
+            .into_iter()
+            .filter(|x| !exclude.contains(x))
+            .map(|x| (self.touched.get(x).cloned().unwrap_or_default(), x))
+            .collect::<Vec<_>>();
+        if allowed.is_empty() {
+            return None;
+        }
+        allowed.sort();
+        let choice = self.script.get(self.options.len()).cloned().unwrap_or(0);
+        self.options.push(allowed.len());
+        Some(allowed[choice % allowed.len()].1.clone())
+    }
+
+    fn touch(&mut self, data: &D, _size: usize) {
+        self.clock += 1;
+        self.touched.insert(data.clone(), self.clock);
+    }
+
+    fn evict(&mut self, data: &D) {
+        self.touched.remove(data);
+    }
+
+    fn reset(&mut self) {
+        self.touched.clear();
+    }
+}
+
+type ChooseObserver<D> = Arc<Mutex<dyn FnMut(Option<&D>, &[(D, usize)]) + Send>>;
+type DataObserver<D> = Arc<Mutex<dyn FnMut(&D, &[(D, usize)]) + Send>>;
+
+/// Wraps a heuristic and reports its decisions to user callbacks together
+/// with a snapshot of the residency (data, size) at that point. `on_choose`
+/// gets the contents of the region the victim is chosen from; `on_touch` and
+/// `on_evict` get what the heuristic tracks across all regions, as touches
+/// and evictions do not name their region. Clones share the callbacks.
+#[derive(Clone)]
+pub struct Observed<H, D> {
+    inner: H,
+    residency: HashMap<D, usize>,
+    on_choose: Option<ChooseObserver<D>>,
+    on_touch: Option<DataObserver<D>>,
+    on_evict: Option<DataObserver<D>>,
+}
+
+impl<H, D> Observed<H, D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+    H: Heuristic<D>,
+{
+    pub fn new(inner: H) -> Self {
+        Self {
+            inner,
+            residency: HashMap::default(),
+            on_choose: None,
+            on_touch: None,
+            on_evict: None,
+        }
+    }
+
+    /// Called with the chosen victim (if any) and the SRAM contents it was chosen from.
+    pub fn on_choose(mut self, f: impl FnMut(Option<&D>, &[(D, usize)]) + Send + 'static) -> Self {
+        self.on_choose = Some(Arc::new(Mutex::new(f)));
+        self
+    }
+
+    /// Called with the touched data after it has been recorded, and the
+    /// residency of every region.
+    pub fn on_touch(mut self, f: impl FnMut(&D, &[(D, usize)]) + Send + 'static) -> Self {
+        self.on_touch = Some(Arc::new(Mutex::new(f)));
+        self
+    }
+
+    /// Called with the evicted data after it has been removed, and the
+    /// residency of every region.
+    pub fn on_evict(mut self, f: impl FnMut(&D, &[(D, usize)]) + Send + 'static) -> Self {
+        self.on_evict = Some(Arc::new(Mutex::new(f)));
+        self
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    fn snapshot(&self) -> Vec<(D, usize)> {
+        self.residency
+            .iter()
+            .map(|(data, size)| (data.clone(), *size))
+            .collect()
+    }
+}
+
+impl<H, D> Heuristic<D> for Observed<H, D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+    H: Heuristic<D>,
+{
+    fn choose<TM>(&mut self, sram: &TM, exclude: &HashSet<D>) -> Option<D>
+    where
+        TM: Memory<D>,
+    {
+        let decision = self.inner.choose(sram, exclude);
+        if let Some(f) = self.on_choose.as_ref() {
+            let snapshot = sram
+                .iter()
+                .map(|(x, size)| (x.clone(), size))
+                .collect::<Vec<_>>();
+            (f.lock().unwrap())(decision.as_ref(), &snapshot);
+        }
+        decision
+    }
+
+    fn touch(&mut self, data: &D, size: usize) {
+        self.inner.touch(data, size);
+        self.residency.insert(data.clone(), size);
+        if let Some(f) = self.on_touch.as_ref() {
+            (f.lock().unwrap())(data, &self.snapshot());
+        }
+    }
+
+    fn evict(&mut self, data: &D) {
+        self.inner.evict(data);
+        self.residency.remove(data);
+        if let Some(f) = self.on_evict.as_ref() {
+            (f.lock().unwrap())(data, &self.snapshot());
+        }
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+        self.residency.clear();
+    }
+}
+
+/// Least-frequently-used eviction. With a decay interval, all frequency counts
+/// are halved every `interval` touches so that data that used to be hot but is
+/// no longer accessed can still leave the SRAM.
+#[derive(Clone)]
+pub struct LFU<D: Clone + Hash + Eq> {
+    frequency: HashMap<D, usize>,
+    ticks: usize,
+    decay_interval: Option<usize>,
+    tie_break: TieBreaker,
+}
+
+impl<D> LFU<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    pub fn new() -> Self {
+        Self {
+            frequency: HashMap::default(),
+            ticks: 0,
+            decay_interval: None,
+            tie_break: TieBreaker::default(),
+        }
+    }
+
+    /// Halves the counts every `interval` touches; 0 is taken as 1.
+    pub fn with_decay(interval: usize) -> Self {
+        Self {
+            decay_interval: Some(interval.max(1)),
+            ..Self::new()
+        }
+    }
+
+    pub fn with_tie_break(mut self, tie_break: TieBreak) -> Self {
+        self.tie_break = TieBreaker::new(tie_break);
+        self
+    }
+}
+
+impl<D> Default for LFU<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D> Heuristic<D> for LFU<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    fn choose<TM>(&mut self, sram: &TM, exclude: &HashSet<D>) -> Option<D>
+    where
+        TM: Memory<D>,
+    {
+        let scored = sram
+            .to_vec()
+            .into_iter()
+            .filter(|&x| !exclude.contains(x))
+            .map(|x| (self.frequency.get(x).cloned().unwrap_or(0), x))
+            .collect::<Vec<_>>();
+        let least = scored.iter().map(|x| x.0).min()?;
+        let ties = scored
+            .into_iter()
+            .filter(|x| x.0 == least)
+            .map(|x| x.1)
+            .collect::<Vec<_>>();
+        self.tie_break.pick(sram, ties).cloned()
+    }
+
+    fn touch(&mut self, data: &D, _size: usize) {
+        *self.frequency.entry(data.clone()).or_default() += 1;
+        self.ticks += 1;
+        if let Some(interval) = self.decay_interval {
+            if self.ticks.is_multiple_of(interval) {
+                self.frequency.values_mut().for_each(|count| *count /= 2);
+            }
+        }
+    }
+
+    fn evict(&mut self, data: &D) {
+        self.frequency.remove(data);
+    }
+
+    fn reset(&mut self) {
+        self.frequency.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::SRAM;
+
+    fn sram(data: &[u32]) -> SRAM<u32> {
+        let mut sram = SRAM::new(64);
+        for x in data {
+            sram.put(x, 4, false).unwrap();
+        }
+        sram
+    }
+
+    #[test]
+    fn victims_come_from_the_given_region() {
+        let chosen = Arc::new(Mutex::new(vec![]));
+        let log = chosen.clone();
+        let mut lru =
+            Observed::new(LRU::new()).on_choose(move |x, _| log.lock().unwrap().push(x.cloned()));
+        // 1 lives in another region and is the least recently used
+        lru.touch(&1, 4);
+        lru.touch(&2, 4);
+        lru.touch(&3, 4);
+        assert_eq!(lru.choose(&sram(&[2, 3]), &HashSet::default()), Some(2));
+        assert_eq!(*chosen.lock().unwrap(), vec![Some(2)]);
+    }
+
+    #[test]
+    fn lfu_with_a_zero_interval_decays_every_touch() {
+        let mut lfu = LFU::with_decay(0);
+        lfu.touch(&1, 4);
+        lfu.touch(&1, 4);
+        lfu.touch(&2, 4);
+        // counts halve after every touch, so each ends at 0
+        assert_eq!(lfu.frequency.values().sum::<usize>(), 0);
+        assert_eq!(lfu.choose(&sram(&[1, 2]), &HashSet::default()), Some(1));
+    }
+
+    #[test]
+    fn size_aware_belady_evicts_big_data_used_soon() {
+        let mut sram = SRAM::new(64);
+        sram.put(&1, 16, false).unwrap();
+        sram.put(&2, 4, false).unwrap();
+        // 1 is next used at distance 1, 2 at distance 2
+        let accesses = vec![9, 1, 2];
+        let none = HashSet::default();
+        assert_eq!(Belady::new(accesses.clone()).choose(&sram, &none), Some(2));
+        assert_eq!(Belady::size_aware(accesses).choose(&sram, &none), Some(1));
+    }
+
+    #[test]
+    fn ties_are_broken_by_the_chosen_mode() {
+        let mut sram = SRAM::new(64);
+        sram.put(&1, 4, false).unwrap();
+        sram.put(&2, 16, false).unwrap();
+        let none = HashSet::default();
+        let mut smallest = LFU::new();
+        assert_eq!(smallest.choose(&sram, &none), Some(1));
+        let mut largest = LFU::new().with_tie_break(TieBreak::LargestSize);
+        assert_eq!(largest.choose(&sram, &none), Some(2));
+        let random = |seed| {
+            let mut lfu = LFU::new().with_tie_break(TieBreak::Random(seed));
+            (0..8).map(|_| lfu.choose(&sram, &none)).collect::<Vec<_>>()
+        };
+        assert_eq!(random(7), random(7));
+    }
+
+    #[test]
+    fn seeded_eviction_repeats_its_choices() {
+        let sram = sram(&[1, 2, 3, 4]);
+        let none = HashSet::default();
+        let choices = |seed| {
+            let mut random = RandomEviction::seeded(seed);
+            (0..16)
+                .map(|_| random.choose(&sram, &none).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(choices(7), choices(7));
+        assert!(choices(7).iter().any(|&x| x != choices(7)[0]));
+        // touches in a row are ordered even within one clock tick
+        let mut lru = LRU::new();
+        for x in [3, 1, 2, 4] {
+            lru.touch(&x, 4);
+        }
+        assert_eq!(lru.choose(&sram, &none), Some(3));
+    }
+
+    #[test]
+    fn touches_and_evictions_see_every_region() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let (touched, evicted) = (seen.clone(), seen.clone());
+        let mut lru = Observed::new(LRU::new())
+            .on_touch(move |_, x| touched.lock().unwrap().push(x.len()))
+            .on_evict(move |_, x| evicted.lock().unwrap().push(x.len()));
+        // 1 lives in another region than 2 and 3
+        for data in [1, 2, 3] {
+            lru.touch(&data, 4);
+        }
+        lru.evict(&2);
+        assert_eq!(*seen.lock().unwrap(), vec![1, 2, 3, 2]);
+    }
+}
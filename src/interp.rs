@@ -0,0 +1,481 @@
+use std::{collections::HashMap, hash::Hash};
+
+use ndarray::{s, Array2, ArrayD, Ix2, Ix4};
+
+use crate::sim::Operators;
+
+/// Value of a piece of data.
+pub type Tensor = ArrayD<f32>;
+
+/// What a compute operator really computes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kernel {
+    /// Product of two matrices
+    MatMul,
+    /// 2-D convolution of an NCHW input with OIHW weights, through im2col
+    Conv2d {
+        stride: usize,
+        padding: usize,
+    },
+    /// Elementwise sum, the second operand broadcast to the first
+    Add,
+    /// Elementwise product, the second operand broadcast to the first
+    Mul,
+    Relu,
+}
+
+impl Kernel {
+    pub fn apply(&self, args: &[&Tensor]) -> Result<Tensor, InterpError> {
+        let arity = match self {
+            Kernel::Relu => 1,
+            _ => 2,
+        };
+        if args.len() != arity {
+            return Err(InterpError::Shape(format!(
+                "{:?} takes {} operands, got {}",
+                self,
+                arity,
+                args.len()
+            )));
+        }
+        let shape_error = |e: ndarray::ShapeError| InterpError::Shape(format!("{:?}: {}", self, e));
+        match self {
+            Kernel::MatMul => {
+                let a = args[0]
+                    .view()
+                    .into_dimensionality::<Ix2>()
+                    .map_err(shape_error)?;
+                let b = args[1]
+                    .view()
+                    .into_dimensionality::<Ix2>()
+                    .map_err(shape_error)?;
+                if a.ncols() != b.nrows() {
+                    return Err(InterpError::Shape(format!(
+                        "MatMul of {:?} and {:?}",
+                        a.shape(),
+                        b.shape()
+                    )));
+                }
+                Ok(a.dot(&b).into_dyn())
+            }
+            Kernel::Conv2d { stride, padding } => {
+                let (input, weights) = (args[0].shape(), args[1].shape());
+                if input.len() == 4
+                    && weights.len() == 4
+                    && (weights[1] != input[1]
+                        || weights[2] > input[2] + 2 * padding
+                        || weights[3] > input[3] + 2 * padding)
+                {
+                    return Err(InterpError::Shape(format!(
+                        "Conv2d of {:?} and {:?} with padding {}",
+                        input, weights, padding
+                    )));
+                }
+                conv2d(args[0], args[1], (*stride).max(1), *padding).map_err(shape_error)
+            }
+            Kernel::Add | Kernel::Mul => {
+                let rhs = args[1].broadcast(args[0].shape()).ok_or_else(|| {
+                    InterpError::Shape(format!(
+                        "{:?} of {:?} and {:?}",
+                        self,
+                        args[0].shape(),
+                        args[1].shape()
+                    ))
+                })?;
+                Ok(match self {
+                    Kernel::Add => args[0] + &rhs,
+                    _ => args[0] * &rhs,
+                })
+            }
+            Kernel::Relu => Ok(args[0].mapv(|x| x.max(0.0))),
+        }
+    }
+}
+
+/// Unfolds every receptive field of `input` into a column, so that the
+/// convolution becomes one matrix product with the flattened weights. The
+/// kernel has to fit into the padded input.
+fn conv2d(
+    input: &Tensor,
+    weights: &Tensor,
+    stride: usize,
+    padding: usize,
+) -> Result<Tensor, ndarray::ShapeError> {
+    let input = input.view().into_dimensionality::<Ix4>()?;
+    let weights = weights.view().into_dimensionality::<Ix4>()?;
+    let (n, c, h, w) = input.dim();
+    let (o, _, kh, kw) = weights.dim();
+    let (ph, pw) = (h + 2 * padding, w + 2 * padding);
+    let (oh, ow) = ((ph - kh) / stride + 1, (pw - kw) / stride + 1);
+    let mut padded = ndarray::Array4::<f32>::zeros((n, c, ph, pw));
+    padded
+        .slice_mut(s![.., .., padding..padding + h, padding..padding + w])
+        .assign(&input);
+    let mut columns = Array2::<f32>::zeros((c * kh * kw, n * oh * ow));
+    for b in 0..n {
+        for y in 0..oh {
+            for x in 0..ow {
+                let field = padded.slice(s![
+                    b,
+                    ..,
+                    y * stride..y * stride + kh,
+                    x * stride..x * stride + kw
+                ]);
+                columns
+                    .column_mut((b * oh + y) * ow + x)
+                    .assign(&field.iter().cloned().collect::<ndarray::Array1<_>>());
+            }
+        }
+    }
+    let kernels = weights.to_owned().into_shape((o, c * kh * kw))?;
+    // (o, n * oh * ow) -> (n, o, oh, ow)
+    let output = kernels.dot(&columns).into_shape((o, n, oh, ow))?;
+    Ok(output.permuted_axes([1, 0, 2, 3]).to_owned().into_dyn())
+}
+
+/// Why a schedule could not be interpreted, or computed something wrong.
+#[derive(Clone, Debug, PartialEq)]
+pub enum InterpError {
+    /// A host load, or an operand in the reference execution, with no value
+    MissingInput(String),
+    /// A compute whose operator has no kernel
+    UnknownKernel(String),
+    /// Operands of the wrong rank or size for their kernel
+    Shape(String),
+    /// An instruction uses data that has no value on `region`
+    NotResident {
+        step: usize,
+        region: String,
+        data: String,
+    },
+    /// A value differs from the reference execution by `error`
+    Mismatch { data: String, error: f32 },
+    /// An `InPlace` names an operand past those of its compute
+    NoOperand {
+        step: usize,
+        operand: usize,
+        operands: usize,
+    },
+}
+
+impl std::fmt::Display for InterpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InterpError::MissingInput(data) => write!(f, "no input value for {}", data),
+            InterpError::UnknownKernel(op) => write!(f, "no kernel for {}", op),
+            InterpError::Shape(e) => write!(f, "shape error: {}", e),
+            InterpError::NotResident { step, region, data } => {
+                write!(f, "step {}: {} has no value on {}", step, data, region)
+            }
+            InterpError::Mismatch { data, error } => {
+                write!(f, "{} is off by {} from the reference", data, error)
+            }
+            InterpError::NoOperand {
+                step,
+                operand,
+                operands,
+            } => write!(
+                f,
+                "step {}: in place over operand {} of a compute with {} operands",
+                step, operand, operands
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InterpError {}
+
+/// Runs programs and schedules on actual values, to check that a schedule
+/// computes what the program does. Values are copied between the host and
+/// the regions as the instructions move data around; an `Evict` writes its
+/// data back to the host.
+#[derive(Clone, Debug)]
+pub struct Interpreter<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    kernels: HashMap<D, Kernel>,
+    inputs: HashMap<D, Tensor>,
+    host: HashMap<D, Tensor>,
+    regions: HashMap<String, HashMap<D, Tensor>>,
+}
+
+impl<D> Interpreter<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    /// `kernels` gives the kernel of every compute operator, `inputs` the
+    /// values of the data loaded on the host.
+    pub fn new(kernels: HashMap<D, Kernel>, inputs: HashMap<D, Tensor>) -> Self {
+        Self {
+            kernels,
+            inputs,
+            host: HashMap::default(),
+            regions: HashMap::default(),
+        }
+    }
+
+    fn kernel(&self, op: &D) -> Result<Kernel, InterpError> {
+        self.kernels
+            .get(op)
+            .cloned()
+            .ok_or_else(|| InterpError::UnknownKernel(format!("{:?}", op)))
+    }
+
+    fn input(&self, data: &D) -> Result<Tensor, InterpError> {
+        self.inputs
+            .get(data)
+            .cloned()
+            .ok_or_else(|| InterpError::MissingInput(format!("{:?}", data)))
+    }
+
+    /// Values of every piece of data `program` produces, computed on the host
+    /// alone. A branch is taken if the first element of its condition is not
+    /// zero.
+    pub fn reference(&self, program: &Operators<D>) -> Result<HashMap<D, Tensor>, InterpError> {
+        let mut values = HashMap::default();
+        self.eval(program, &mut values)?;
+        Ok(values)
+    }
+
+    fn eval(&self, op: &Operators<D>, values: &mut HashMap<D, Tensor>) -> Result<(), InterpError> {
+        match op {
+            Operators::Load(region, (data, op), _) => {
+                self.eval(op, values)?;
+                if region == "host" {
+                    values.insert(data.clone(), self.input(data)?);
+                }
+            }
+            Operators::Compute(_, kind, dst, args, _) => {
+                for (_, op) in args.iter() {
+                    self.eval(op, values)?;
+                }
+                let operands = args
+                    .iter()
+                    .map(|(data, _)| {
+                        values
+                            .get(data)
+                            .ok_or_else(|| InterpError::MissingInput(format!("{:?}", data)))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                let value = self.kernel(kind)?.apply(&operands)?;
+                values.insert(dst.clone(), value);
+            }
+            Operators::Store(_, _, (_, op), _) => self.eval(op, values)?,
+            Operators::Pool(_, op)
+            | Operators::Backing(_, op)
+            | Operators::Scratch(_, op)
+            | Operators::InPlace(_, op)
+            | Operators::Dma(_, op) => self.eval(op, values)?,
+            Operators::Repeat(count, op) => {
+                for _ in 0..*count {
+                    self.eval(op, values)?;
+                }
+            }
+            Operators::If(cond, then, otherwise) => {
+                let taken = values
+                    .get(cond)
+                    .and_then(|x| x.iter().next())
+                    .is_none_or(|&x| x != 0.0);
+                self.eval(if taken { then } else { otherwise }, values)?;
+            }
+            Operators::Evict(..)
+            | Operators::Copy(..)
+            | Operators::Sync(_)
+            | Operators::Barrier
+            | Operators::NoOp => {}
+        }
+        Ok(())
+    }
+
+    fn value(&self, step: usize, region: &str, data: &D) -> Result<&Tensor, InterpError> {
+        let value = if region == "host" {
+            self.host.get(data)
+        } else {
+            self.regions.get(region).and_then(|x| x.get(data))
+        };
+        value.ok_or_else(|| InterpError::NotResident {
+            step,
+            region: region.into(),
+            data: format!("{:?}", data),
+        })
+    }
+
+    fn put(&mut self, region: &str, data: &D, value: Tensor) {
+        if region == "host" {
+            self.host.insert(data.clone(), value);
+        } else {
+            self.regions
+                .entry(region.into())
+                .or_default()
+                .insert(data.clone(), value);
+        }
+    }
+
+    /// Runs a flat schedule (e.g. `JitSim::schedule`) on actual values.
+    pub fn run_schedule(&mut self, schedule: &[Operators<D>]) -> Result<(), InterpError> {
+        for (step, op) in schedule.iter().enumerate() {
+            for op in op.linearize() {
+                self.perform(step, &op)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn perform(&mut self, step: usize, op: &Operators<D>) -> Result<(), InterpError> {
+        match op {
+            Operators::Load(region, (data, _), _) if region == "host" => {
+                let value = self.input(data)?;
+                self.put(region, data, value);
+            }
+            Operators::Load(region, (data, _), _) => {
+                let value = self.value(step, "host", data)?.clone();
+                self.put(region, data, value);
+            }
+            Operators::Compute(region, kind, dst, args, _) => {
+                let kernel = self.kernel(kind)?;
+                let value = {
+                    let operands = args
+                        .iter()
+                        .map(|(data, _)| self.value(step, region, data))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    kernel.apply(&operands)?
+                };
+                self.put(region, dst, value);
+            }
+            Operators::InPlace(operand, op) => {
+                let overwritten = match op.as_ref() {
+                    Operators::Compute(region, _, dst, args, _) => {
+                        let (src, _) = args.get(*operand).ok_or(InterpError::NoOperand {
+                            step,
+                            operand: *operand,
+                            operands: args.len(),
+                        })?;
+                        Some((region, src)).filter(|_| region != "host" && src != dst)
+                    }
+                    _ => None,
+                };
+                self.perform(step, op)?;
+                if let Some((region, src)) = overwritten {
+                    self.regions.entry(region.clone()).or_default().remove(src);
+                }
+            }
+            Operators::Store(region, _, (data, _), _) => {
+                self.value(step, region, data)?;
+                // everything is written back and the region emptied
+                let values = self.regions.remove(region).unwrap_or_default();
+                self.host.extend(values);
+            }
+            Operators::Evict(region, data, _) => {
+                if let Some(value) = self.regions.entry(region.clone()).or_default().remove(data) {
+                    self.host.insert(data.clone(), value);
+                }
+            }
+            Operators::Copy(src, dst, data, _) => {
+                let value = self.value(step, src, data)?.clone();
+                self.put(dst, data, value);
+            }
+            Operators::Pool(_, op)
+            | Operators::Backing(_, op)
+            | Operators::Scratch(_, op)
+            | Operators::Dma(_, op) => self.perform(step, op)?,
+            Operators::If(cond, then, otherwise) => {
+                let taken = self
+                    .get(cond)
+                    .and_then(|x| x.iter().next())
+                    .is_none_or(|&x| x != 0.0);
+                for op in if taken { then } else { otherwise }.linearize() {
+                    self.perform(step, &op)?;
+                }
+            }
+            Operators::Repeat(..) | Operators::Sync(_) | Operators::Barrier | Operators::NoOp => {}
+        }
+        Ok(())
+    }
+
+    /// Latest value of `data`: on a region if it is resident on one,
+    /// otherwise on the host.
+    pub fn get(&self, data: &D) -> Option<&Tensor> {
+        self.regions
+            .values()
+            .find_map(|x| x.get(data))
+            .or_else(|| self.host.get(data))
+    }
+
+    /// Runs `schedule` and checks that every value it ends up with matches
+    /// the host-only execution of `program` within `tolerance`.
+    pub fn verify(
+        &mut self,
+        program: &Operators<D>,
+        schedule: &[Operators<D>],
+        tolerance: f32,
+    ) -> Result<(), InterpError> {
+        let reference = self.reference(program)?;
+        self.run_schedule(schedule)?;
+        for (data, expected) in reference.iter() {
+            if let Some(value) = self.get(data) {
+                let error = if value.shape() == expected.shape() {
+                    (value - expected)
+                        .iter()
+                        .fold(0.0f32, |max, x| max.max(x.abs()))
+                } else {
+                    f32::INFINITY
+                };
+                if error > tolerance || tolerance.is_nan() {
+                    return Err(InterpError::Mismatch {
+                        data: format!("{:?}", data),
+                        error,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conv(input: &[usize], weights: &[usize], padding: usize) -> Result<Tensor, InterpError> {
+        let input = Tensor::ones(input);
+        let weights = Tensor::ones(weights);
+        Kernel::Conv2d { stride: 1, padding }.apply(&[&input, &weights])
+    }
+
+    #[test]
+    fn conv2d_rejects_kernels_larger_than_the_input() {
+        assert!(matches!(
+            conv(&[1, 1, 2, 2], &[1, 1, 3, 3], 0),
+            Err(InterpError::Shape(_))
+        ));
+        assert!(matches!(
+            conv(&[1, 2, 4, 4], &[1, 3, 3, 3], 1),
+            Err(InterpError::Shape(_))
+        ));
+        let output = conv(&[1, 1, 2, 2], &[1, 1, 3, 3], 1).unwrap();
+        assert_eq!(output.shape(), &[1, 1, 2, 2]);
+        assert_eq!(output[[0, 0, 0, 0]], 4.0);
+    }
+
+    #[test]
+    fn in_place_past_the_operands_is_an_error() {
+        let load = |region: &str| Operators::Load(region.into(), (1, Box::new(Operators::NoOp)), 4);
+        let relu = Operators::Compute("sram".into(), 0, 2, vec![(1, Operators::NoOp)], 4.into());
+        let schedule = vec![
+            load("host"),
+            load("sram"),
+            Operators::InPlace(1, Box::new(relu)),
+        ];
+        let inputs = HashMap::from([(1, Tensor::ones(&[2usize][..]))]);
+        let mut interpreter = Interpreter::new(HashMap::from([(0, Kernel::Relu)]), inputs);
+        assert_eq!(
+            interpreter.run_schedule(&schedule),
+            Err(InterpError::NoOperand {
+                step: 2,
+                operand: 1,
+                operands: 1
+            })
+        );
+    }
+}
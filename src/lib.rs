@@ -1,4 +1,15 @@
+pub mod compare;
+pub mod config;
+pub mod dag;
 pub mod from_glenside;
 pub mod heuristics;
+pub mod interp;
 pub mod memory;
+pub mod optimal;
+pub mod passes;
+pub mod plan;
+pub mod replay;
+pub mod search;
 pub mod sim;
+pub mod validate;
+pub mod viz;
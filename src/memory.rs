@@ -1,53 +1,245 @@
-use crate::sim::{self, Memory};
-use std::collections::{BTreeMap, HashSet};
+use crate::sim::{self, HostMemory, MemError, Memory, TargetMemory};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    hash::Hash,
+};
 
 use egg::Id;
+use log::info;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
-pub struct SRAM {
-    pub residence: BTreeMap<Id, usize>,
-    pub evict: HashSet<Id>,
+/// Transfers between a device memory and the host, per direction, so that
+/// asymmetric read / write bandwidths can be analyzed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Trips {
+    /// Host to device
+    pub load_trips: usize,
+    pub load_bytes: usize,
+    /// Device to host
+    pub store_trips: usize,
+    pub store_bytes: usize,
+    /// From another device region, without the host
+    #[serde(default)]
+    pub copies: usize,
+    #[serde(default)]
+    pub copy_bytes: usize,
+}
+
+impl Trips {
+    pub fn load(&mut self, size: usize) {
+        self.load_trips += 1;
+        self.load_bytes += size;
+    }
+
+    pub fn store(&mut self, size: usize) {
+        self.store_trips += 1;
+        self.store_bytes += size;
+    }
+
+    pub fn copy(&mut self, size: usize) {
+        self.copies += 1;
+        self.copy_bytes += size;
+    }
+
+    /// Round trips in either direction
+    pub fn count(&self) -> usize {
+        self.load_trips + self.store_trips
+    }
+
+    pub fn combine(&self, other: &Self) -> Self {
+        Self {
+            load_trips: self.load_trips + other.load_trips,
+            load_bytes: self.load_bytes + other.load_bytes,
+            store_trips: self.store_trips + other.store_trips,
+            store_bytes: self.store_bytes + other.store_bytes,
+            copies: self.copies + other.copies,
+            copy_bytes: self.copy_bytes + other.copy_bytes,
+        }
+    }
+}
+
+/// (De)serializes a bandwidth, writing an unlimited one as `null` since
+/// JSON has no infinity.
+mod unlimited {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(bandwidth: &f64, s: S) -> Result<S::Ok, S::Error> {
+        Some(*bandwidth).filter(|x| x.is_finite()).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<f64, D::Error> {
+        Ok(Option::<f64>::deserialize(d)?.unwrap_or(f64::INFINITY))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "D: Serialize",
+    deserialize = "D: Deserialize<'de> + Hash + Eq + Ord"
+))]
+pub struct SRAM<D = Id> {
+    pub residence: BTreeMap<D, usize>,
+    pub evict: HashSet<D>,
+    /// Sum of the padded footprints of all residents
     pub resident_size: usize,
+    /// Largest `resident_size` over the whole run (not cleared by `reset`)
+    pub peak_size: usize,
     pub mem_limit: usize,
-    pub trip_count: usize,
+    pub trips: Trips,
+    /// Bytes per cycle to and from this memory
+    #[serde(with = "unlimited")]
+    pub bandwidth: f64,
+    /// Fixed cycles per transaction (DMA setup / access latency)
+    pub latency: f64,
+    /// Allocation granularity; every allocation is rounded up to a multiple of it
+    pub alignment: usize,
+    /// Residents produced on-device that have not been written back yet
+    pub dirty: HashSet<D>,
+    /// Window into DRAM (unified memory): residents still take up capacity,
+    /// but loads and stores move no data
+    pub unified: bool,
+    /// Padded bytes of transient scratch held by the running compute;
+    /// included in `resident_size`
+    pub scratch: usize,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "D: Serialize",
+    deserialize = "D: Deserialize<'de> + Hash + Eq + Ord"
+))]
+pub struct DRAM<D = Id> {
+    pub residence: BTreeMap<D, usize>,
+    pub resident_size: usize,
+    /// Largest `resident_size` over the whole run (not cleared by `reset`)
+    pub peak_size: usize,
+    /// `None` models unlimited host memory
+    pub capacity: Option<usize>,
+    /// Bytes per cycle to and from this memory
+    #[serde(with = "unlimited")]
+    pub bandwidth: f64,
+    /// Fixed cycles per transaction (DMA setup / access latency)
+    pub latency: f64,
+    /// Compressed / original size per data, for compressed offloading
+    pub compression: HashMap<D, f64>,
+    /// Ratio for data without an entry in `compression`
+    pub default_compression: f64,
 }
 
-pub struct DRAM {
-    pub residence: BTreeMap<Id, usize>,
+impl<D> Default for DRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl DRAM {
+impl<D> DRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    /// Copy of the current state, to `restore` later.
+    pub fn snapshot(&self) -> Self {
+        self.clone()
+    }
+
+    pub fn restore(&mut self, snapshot: Self) {
+        *self = snapshot;
+    }
+
     pub fn new() -> Self {
         Self {
             residence: BTreeMap::new(),
+            resident_size: 0,
+            peak_size: 0,
+            capacity: None,
+            bandwidth: f64::INFINITY,
+            latency: 0.0,
+            compression: HashMap::default(),
+            default_compression: 1.0,
+        }
+    }
+
+    /// Stores `id` with `ratio` (compressed / original size) in host memory.
+    pub fn compress(&mut self, id: D, ratio: f64) {
+        assert!(ratio > 0.0, "compression ratio must be positive");
+        self.compression.insert(id, ratio);
+    }
+
+    pub fn with_default_compression(mut self, ratio: f64) -> Self {
+        assert!(ratio > 0.0, "compression ratio must be positive");
+        self.default_compression = ratio;
+        self
+    }
+
+    /// Bytes that `size` bytes of `id` occupy in host memory.
+    pub fn compressed_size(&self, id: &D, size: usize) -> usize {
+        let ratio = self
+            .compression
+            .get(id)
+            .cloned()
+            .unwrap_or(self.default_compression);
+        (size as f64 * ratio).ceil() as usize
+    }
+
+    /// A host memory that runs out of space. Put it in front of another level
+    /// with `Tiered` to spill to e.g. disk instead of failing.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity: Some(capacity),
+            ..Self::new()
         }
     }
+
+    pub fn with_bandwidth(mut self, bytes_per_cycle: f64) -> Self {
+        self.bandwidth = bytes_per_cycle;
+        self
+    }
+
+    pub fn with_latency(mut self, cycles: f64) -> Self {
+        self.latency = cycles;
+        self
+    }
 }
 
-impl sim::Memory<Id> for SRAM {
-    fn put(&mut self, id: &Id, size: usize, from_self: bool) -> bool {
-        if size + self.resident_size <= self.mem_limit {
+impl<D> sim::Memory<D> for SRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    fn put(&mut self, id: &D, size: usize, from_self: bool) -> Result<(), MemError> {
+        if self.padded(size) + self.resident_size <= self.mem_limit {
             assert!(!self.residence.contains_key(id));
-            self.resident_size += size;
-            if !from_self {
-                self.trip_count += 1;
+            self.resident_size += self.padded(size);
+            self.peak_size = self.peak_size.max(self.resident_size);
+            if from_self {
+                self.dirty.insert(id.clone());
+            } else {
+                self.trips.load(size);
             }
             self.residence.insert(id.clone(), size);
-            true
+            Ok(())
         } else {
-            panic!(
-                "OOM on SRAM: trying to allocate {}; usage: {} / {}",
-                size,
-                self.size_allocated(),
-                self.size_total()
-            );
+            Err(MemError::OutOfMemory {
+                memory: "SRAM",
+                requested: size,
+                allocated: self.size_allocated(),
+                total: self.size_total(),
+            })
         }
     }
 
-    fn to_vec(&self) -> Vec<&Id> {
+    fn to_vec(&self) -> Vec<&D> {
         self.residence.iter().map(|pi| pi.0).collect()
     }
 
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a D, usize)>
+    where
+        D: 'a,
+    {
+        self.residence.iter().map(|(x, size)| (x, *size))
+    }
+
     fn size_available(&self) -> usize {
         self.mem_limit - self.resident_size
     }
@@ -60,118 +252,1791 @@ impl sim::Memory<Id> for SRAM {
         self.mem_limit
     }
 
-    fn size_of(&self, data: &Id) -> Result<usize, ()> {
-        if let Some(x) = self.residence.get(data) {
-            Ok(x.clone())
+    fn peak_allocated(&self) -> usize {
+        self.peak_size
+    }
+
+    fn bandwidth(&self) -> f64 {
+        self.bandwidth
+    }
+
+    fn latency(&self) -> f64 {
+        self.latency
+    }
+
+    fn size_of(&self, data: &D) -> Option<usize> {
+        self.residence.get(data).cloned()
+    }
+
+    fn get(&self, id: &D) -> Result<usize, MemError> {
+        if let Some(size) = self.residence.get(id) {
+            Ok(*size)
         } else {
-            Err(())
+            Err(MemError::not_resident("SRAM", id))
         }
     }
 
-    fn get(&self, id: &Id) -> usize {
-        if let Some(size) = self.residence.get(id) {
-            size.clone()
+    fn reset(&mut self) {
+        self.residence.clear();
+        self.evict.clear();
+        self.dirty.clear();
+        self.resident_size = 0;
+        self.scratch = 0;
+    }
+
+    fn deallocate(&mut self, data: &D) {
+        assert!(self.residence.contains_key(data));
+        let size = self.residence.remove(data).unwrap();
+        self.resident_size -= self.padded(size);
+        self.dirty.remove(data);
+    }
+
+    fn contains(&self, data: &D) -> bool {
+        self.residence.contains_key(data)
+    }
+}
+
+impl<D> sim::TargetMemory<D> for SRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    fn can_allocate(&self, _data: &D, size: usize) -> bool {
+        self.resident_size + self.padded(size) <= self.mem_limit
+    }
+
+    fn is_dirty(&self, data: &D) -> bool {
+        self.dirty.contains(data)
+    }
+
+    fn copy_in(&mut self, data: &D, size: usize, dirty: bool) -> Result<(), MemError> {
+        self.put(data, size, true)?;
+        if !dirty {
+            self.dirty.remove(data);
+        }
+        self.trips.copy(size);
+        Ok(())
+    }
+
+    fn is_unified(&self) -> bool {
+        self.unified
+    }
+
+    fn put_scratch(&mut self, _owner: &D, size: usize) -> Result<(), MemError> {
+        if self.padded(size) + self.resident_size <= self.mem_limit {
+            self.resident_size += self.padded(size);
+            self.scratch += self.padded(size);
+            self.peak_size = self.peak_size.max(self.resident_size);
+            Ok(())
         } else {
-            panic!("no residence has id {} in SRAM", id);
+            Err(MemError::OutOfMemory {
+                memory: "SRAM",
+                requested: size,
+                allocated: self.size_allocated(),
+                total: self.size_total(),
+            })
         }
     }
 
-    fn store<DRAM: Memory<Id>>(&mut self, id: &Id, evict: bool, dram: &mut DRAM) {
+    fn end_compute(&mut self) {
+        self.resident_size -= self.scratch;
+        self.scratch = 0;
+    }
+
+    fn store<DRAM: HostMemory<D>>(
+        &mut self,
+        id: &D,
+        evict: bool,
+        dram: &mut DRAM,
+    ) -> Result<(), MemError> {
         if self.residence.contains_key(id) {
-            let size = self.residence.get(id).unwrap().clone();
+            let size = *self.residence.get(id).unwrap();
             if evict {
                 self.residence.remove(id).unwrap();
                 self.evict.insert(id.clone());
-                self.resident_size -= size;
+                self.resident_size -= self.padded(size);
+            }
+            // a clean copy is already in DRAM, no need to write it back
+            if !self.dirty.remove(id) && dram.contains(id) {
+                return Ok(());
             }
-            self.trip_count += 1;
-            dram.put(id, size, false);
+            self.trips.store(size);
+            dram.put(id, size, false)
+        } else {
+            Err(MemError::not_resident("SRAM", id))
+        }
+    }
+}
+
+impl<D> sim::Memory<D> for DRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    fn to_vec(&self) -> Vec<&D> {
+        self.residence.iter().map(|pi| pi.0).collect()
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a D, usize)>
+    where
+        D: 'a,
+    {
+        self.residence.iter().map(|(x, size)| (x, *size))
+    }
+
+    fn put(&mut self, data: &D, size: usize, _from_self: bool) -> Result<(), MemError> {
+        let previous = self
+            .residence
+            .get(data)
+            .map_or(0, |&x| self.compressed_size(data, x));
+        let stored = self.compressed_size(data, size);
+        if self.resident_size - previous + stored > self.size_total() {
+            return Err(MemError::OutOfMemory {
+                memory: "DRAM",
+                requested: stored,
+                allocated: self.size_allocated(),
+                total: self.size_total(),
+            });
+        }
+        self.resident_size = self.resident_size - previous + stored;
+        self.peak_size = self.peak_size.max(self.resident_size);
+        self.residence.insert(data.clone(), size);
+        Ok(())
+    }
+
+    fn get(&self, data: &D) -> Result<usize, MemError> {
+        if let Some(size) = self.residence.get(data) {
+            Ok(*size)
         } else {
-            panic!("Evicting non-residence: {}", id);
+            Err(MemError::not_resident("DRAM", data))
         }
     }
 
+    fn size_of(&self, data: &D) -> Option<usize> {
+        self.residence.get(data).cloned()
+    }
+
+    fn size_allocated(&self) -> usize {
+        self.resident_size
+    }
+
+    fn size_total(&self) -> usize {
+        self.capacity.unwrap_or(usize::MAX)
+    }
+
+    fn peak_allocated(&self) -> usize {
+        self.peak_size
+    }
+
+    fn bandwidth(&self) -> f64 {
+        self.bandwidth
+    }
+
+    fn latency(&self) -> f64 {
+        self.latency
+    }
+
+    fn size_available(&self) -> usize {
+        // unless limited, host memory is assumed to have no limit
+        self.size_total() - self.resident_size
+    }
+
     fn reset(&mut self) {
         self.residence.clear();
-        self.evict.clear();
         self.resident_size = 0;
     }
 
-    fn deallocate(&mut self, data: &Id) {
-        assert!(self.residence.contains_key(data));
-        self.resident_size -= self.residence.get(data).unwrap();
-        self.residence.remove(data);
+    fn deallocate(&mut self, data: &D) {
+        if let Some(size) = self.residence.remove(data) {
+            self.resident_size -= self.compressed_size(data, size);
+        }
     }
 
-    fn contains(&self, data: &Id) -> bool {
+    fn contains(&self, data: &D) -> bool {
         self.residence.contains_key(data)
     }
 }
 
-impl sim::Memory<Id> for DRAM {
-    fn to_vec(&self) -> Vec<&Id> {
-        self.residence.iter().map(|pi| pi.0).collect()
+impl<D> sim::HostMemory<D> for DRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    fn transfer_size(&self, data: &D, size: usize) -> usize {
+        self.compressed_size(data, size)
     }
+}
 
-    fn put(&mut self, data: &Id, size: usize, _from_self: bool) -> bool {
-        self.residence.insert(data.clone(), size);
-        return true;
+impl<D> SRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    /// Copy of the current state, to `restore` later.
+    pub fn snapshot(&self) -> Self {
+        self.clone()
     }
 
-    fn get(&self, data: &Id) -> usize {
-        if let Some(size) = self.residence.get(data) {
-            size.clone()
-        } else {
-            panic!("No resident has id {} in DRAM", data)
+    pub fn restore(&mut self, snapshot: Self) {
+        *self = snapshot;
+    }
+
+    pub fn new(sram_size: usize) -> Self {
+        Self {
+            residence: BTreeMap::default(),
+            evict: HashSet::default(),
+            resident_size: 0,
+            peak_size: 0,
+            mem_limit: sram_size,
+            trips: Trips::default(),
+            bandwidth: f64::INFINITY,
+            latency: 0.0,
+            alignment: 1,
+            dirty: HashSet::default(),
+            unified: false,
+            scratch: 0,
+        }
+    }
+
+    pub fn with_bandwidth(mut self, bytes_per_cycle: f64) -> Self {
+        self.bandwidth = bytes_per_cycle;
+        self
+    }
+
+    pub fn with_latency(mut self, cycles: f64) -> Self {
+        self.latency = cycles;
+        self
+    }
+
+    /// Models the region as a window into DRAM, as on SoCs with shared
+    /// memory, instead of a discrete scratchpad.
+    pub fn unified(mut self) -> Self {
+        self.unified = true;
+        self
+    }
+
+    /// Rounds every allocation up to a multiple of `alignment` bytes, e.g. a
+    /// scratchpad line or a vector word.
+    pub fn with_alignment(mut self, alignment: usize) -> Self {
+        assert!(alignment > 0, "alignment must be positive");
+        self.alignment = alignment;
+        self
+    }
+
+    /// Bytes actually occupied by an allocation of `size`.
+    pub fn padded(&self, size: usize) -> usize {
+        size.div_ceil(self.alignment) * self.alignment
+    }
+}
+
+/// Where `AddressedSRAM` places a new allocation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Placement {
+    /// Lowest free block that is large enough
+    FirstFit,
+    /// Smallest free block that is large enough
+    BestFit,
+    /// Sizes are rounded up to a power of two and blocks are aligned to their
+    /// own size, as in a binary buddy allocator
+    Buddy,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Allocation {
+    pub offset: usize,
+    /// Size requested by the tensor
+    pub size: usize,
+    /// Bytes actually reserved for it (>= `size`)
+    pub footprint: usize,
+}
+
+/// An SRAM that places every allocation at a concrete offset. Unlike `SRAM`,
+/// an allocation can fail because of fragmentation: no single free block is
+/// large enough even though the total free space would suffice.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "D: Serialize",
+    deserialize = "D: Deserialize<'de> + Hash + Eq + Ord"
+))]
+pub struct AddressedSRAM<D = Id> {
+    pub residence: BTreeMap<D, Allocation>,
+    pub evict: HashSet<D>,
+    /// Reserved bytes, i.e. the sum of the footprints
+    pub resident_size: usize,
+    /// Largest `resident_size` over the whole run (not cleared by `reset`)
+    pub peak_size: usize,
+    pub mem_limit: usize,
+    pub trips: Trips,
+    /// Bytes per cycle to and from this memory
+    #[serde(with = "unlimited")]
+    pub bandwidth: f64,
+    /// Fixed cycles per transaction (DMA setup / access latency)
+    pub latency: f64,
+    pub placement: Placement,
+    /// Cycles per byte moved by `compact`; `None` disables compaction
+    pub compaction_cost: Option<f64>,
+    pub compactions: usize,
+    /// Residents produced on-device that have not been written back yet
+    pub dirty: HashSet<D>,
+}
+
+impl<D> AddressedSRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    pub fn new(sram_size: usize) -> Self {
+        Self::with_placement(sram_size, Placement::FirstFit)
+    }
+
+    pub fn with_placement(sram_size: usize, placement: Placement) -> Self {
+        Self {
+            residence: BTreeMap::default(),
+            evict: HashSet::default(),
+            resident_size: 0,
+            peak_size: 0,
+            mem_limit: sram_size,
+            trips: Trips::default(),
+            bandwidth: f64::INFINITY,
+            latency: 0.0,
+            placement,
+            compaction_cost: None,
+            compactions: 0,
+            dirty: HashSet::default(),
+        }
+    }
+
+    /// Allows the simulator to defragment this SRAM instead of evicting,
+    /// at `cycles_per_byte` for every byte that has to move.
+    pub fn with_compaction(mut self, cycles_per_byte: f64) -> Self {
+        self.compaction_cost = Some(cycles_per_byte);
+        self
+    }
+
+    pub fn with_bandwidth(mut self, bytes_per_cycle: f64) -> Self {
+        self.bandwidth = bytes_per_cycle;
+        self
+    }
+
+    pub fn with_latency(mut self, cycles: f64) -> Self {
+        self.latency = cycles;
+        self
+    }
+
+    pub fn offset_of(&self, id: &D) -> Option<usize> {
+        self.residence.get(id).map(|x| x.offset)
+    }
+
+    /// Free blocks as (offset, size), ordered by offset.
+    pub fn free_blocks(&self) -> Vec<(usize, usize)> {
+        let mut used = self
+            .residence
+            .values()
+            .map(|x| (x.offset, x.footprint))
+            .collect::<Vec<_>>();
+        used.sort();
+        let mut blocks = vec![];
+        let mut cursor = 0;
+        for (offset, size) in used {
+            if offset > cursor {
+                blocks.push((cursor, offset - cursor));
+            }
+            cursor = cursor.max(offset + size);
+        }
+        if cursor < self.mem_limit {
+            blocks.push((cursor, self.mem_limit - cursor));
+        }
+        blocks
+    }
+
+    pub fn largest_free_block(&self) -> usize {
+        self.free_blocks().iter().map(|x| x.1).max().unwrap_or(0)
+    }
+
+    fn footprint(&self, size: usize) -> usize {
+        match self.placement {
+            Placement::Buddy => size.next_power_of_two(),
+            _ => size,
+        }
+    }
+
+    /// Offset for a new allocation of `size` bytes, if there is room for it.
+    fn place(&self, size: usize) -> Option<usize> {
+        if size == 0 {
+            return Some(0);
+        }
+        let footprint = self.footprint(size);
+        let blocks = self.free_blocks().into_iter();
+        match self.placement {
+            Placement::FirstFit => blocks
+                .filter(|&(_, len)| len >= footprint)
+                .map(|(offset, _)| offset)
+                .next(),
+            Placement::BestFit => blocks
+                .filter(|&(_, len)| len >= footprint)
+                .min_by_key(|&(offset, len)| (len, offset))
+                .map(|(offset, _)| offset),
+            Placement::Buddy => blocks
+                .map(|(offset, len)| {
+                    let aligned = offset.div_ceil(footprint) * footprint;
+                    (aligned, aligned + footprint <= offset + len)
+                })
+                .find(|&(_, fits)| fits)
+                .map(|(offset, _)| offset),
         }
     }
+}
 
-    fn size_of(&self, data: &Id) -> Result<usize, ()> {
-        if let Some(x) = self.residence.get(data) {
-            Ok(x.clone())
+impl<D> sim::Memory<D> for AddressedSRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    fn put(&mut self, id: &D, size: usize, from_self: bool) -> Result<(), MemError> {
+        if let Some(offset) = self.place(size) {
+            assert!(!self.residence.contains_key(id));
+            let footprint = self.footprint(size);
+            self.resident_size += footprint;
+            self.peak_size = self.peak_size.max(self.resident_size);
+            if from_self {
+                self.dirty.insert(id.clone());
+            } else {
+                self.trips.load(size);
+            }
+            self.residence.insert(
+                id.clone(),
+                Allocation {
+                    offset,
+                    size,
+                    footprint,
+                },
+            );
+            Ok(())
         } else {
-            Err(())
+            Err(MemError::OutOfMemory {
+                memory: "SRAM",
+                requested: size,
+                allocated: self.size_allocated(),
+                total: self.size_total(),
+            })
         }
     }
 
+    fn to_vec(&self) -> Vec<&D> {
+        self.residence.iter().map(|pi| pi.0).collect()
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a D, usize)>
+    where
+        D: 'a,
+    {
+        self.residence.iter().map(|(x, alloc)| (x, alloc.size))
+    }
+
+    fn size_available(&self) -> usize {
+        self.mem_limit - self.resident_size
+    }
+
     fn size_allocated(&self) -> usize {
-        0
+        self.resident_size
     }
 
     fn size_total(&self) -> usize {
-        usize::MAX
+        self.mem_limit
     }
 
-    fn size_available(&self) -> usize {
-        // assumption: Host memory has no limit
-        usize::MAX
+    fn peak_allocated(&self) -> usize {
+        self.peak_size
     }
 
-    fn store<DRAM: Memory<Id>>(&mut self, _: &Id, _: bool, _: &mut DRAM) {
-        return;
+    fn bandwidth(&self) -> f64 {
+        self.bandwidth
+    }
+
+    fn latency(&self) -> f64 {
+        self.latency
+    }
+
+    fn size_of(&self, data: &D) -> Option<usize> {
+        self.residence.get(data).map(|x| x.size)
+    }
+
+    fn get(&self, id: &D) -> Result<usize, MemError> {
+        if let Some(x) = self.residence.get(id) {
+            Ok(x.size)
+        } else {
+            Err(MemError::not_resident("SRAM", id))
+        }
     }
 
     fn reset(&mut self) {
         self.residence.clear();
+        self.evict.clear();
+        self.dirty.clear();
+        self.resident_size = 0;
     }
 
-    fn deallocate(&mut self, data: &Id) {
+    fn deallocate(&mut self, data: &D) {
+        assert!(self.residence.contains_key(data));
+        self.resident_size -= self.residence.get(data).unwrap().footprint;
         self.residence.remove(data);
+        self.dirty.remove(data);
     }
 
-    fn contains(&self, data: &Id) -> bool {
+    fn contains(&self, data: &D) -> bool {
         self.residence.contains_key(data)
     }
 }
 
-impl SRAM {
-    pub fn new(sram_size: usize) -> Self {
+impl<D> sim::TargetMemory<D> for AddressedSRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    fn memory_map(&self) -> Vec<(D, usize, Option<usize>)> {
+        self.residence
+            .iter()
+            .map(|(id, alloc)| (id.clone(), alloc.size, Some(alloc.offset)))
+            .collect()
+    }
+
+    fn can_allocate(&self, _data: &D, size: usize) -> bool {
+        self.place(size).is_some()
+    }
+
+    fn is_dirty(&self, data: &D) -> bool {
+        self.dirty.contains(data)
+    }
+
+    fn copy_in(&mut self, data: &D, size: usize, dirty: bool) -> Result<(), MemError> {
+        self.put(data, size, true)?;
+        if !dirty {
+            self.dirty.remove(data);
+        }
+        self.trips.copy(size);
+        Ok(())
+    }
+
+    fn fragmentation(&self) -> f64 {
+        let free = self.free_blocks().iter().map(|x| x.1).sum::<usize>();
+        if free == 0 {
+            0.0
+        } else {
+            1.0 - self.largest_free_block() as f64 / free as f64
+        }
+    }
+
+    fn compact(&mut self) -> Option<f64> {
+        let cost = self.compaction_cost?;
+        let mut allocs = self
+            .residence
+            .iter_mut()
+            .map(|(_, alloc)| alloc)
+            .collect::<Vec<_>>();
+        allocs.sort_by_key(|x| x.offset);
+        let mut cursor = 0usize;
+        let mut moved = 0;
+        for alloc in allocs {
+            let align = match self.placement {
+                Placement::Buddy => alloc.footprint.max(1),
+                _ => 1,
+            };
+            let offset = cursor.div_ceil(align) * align;
+            if offset != alloc.offset {
+                alloc.offset = offset;
+                moved += alloc.size;
+            }
+            cursor = offset + alloc.footprint;
+        }
+        self.compactions += 1;
+        Some(moved as f64 * cost)
+    }
+
+    fn store<DRAM: HostMemory<D>>(
+        &mut self,
+        id: &D,
+        evict: bool,
+        dram: &mut DRAM,
+    ) -> Result<(), MemError> {
+        if let Some(&alloc) = self.residence.get(id) {
+            if evict {
+                self.residence.remove(id).unwrap();
+                self.evict.insert(id.clone());
+                self.resident_size -= alloc.footprint;
+            }
+            // a clean copy is already in DRAM, no need to write it back
+            if !self.dirty.remove(id) && dram.contains(id) {
+                return Ok(());
+            }
+            self.trips.store(alloc.size);
+            dram.put(id, alloc.size, false)
+        } else {
+            Err(MemError::not_resident("SRAM", id))
+        }
+    }
+}
+
+/// An SRAM whose capacity is split across banks. A tensor has to fit into a
+/// single bank unless it is marked as striped, in which case it is spread
+/// evenly over all banks.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "D: Serialize",
+    deserialize = "D: Deserialize<'de> + Hash + Eq + Ord"
+))]
+pub struct BankedSRAM<D = Id> {
+    /// id -> (bank, size); bank is `None` for striped data
+    pub residence: BTreeMap<D, (Option<usize>, usize)>,
+    pub evict: HashSet<D>,
+    pub striped: HashSet<D>,
+    /// Residents produced on-device that have not been written back yet
+    pub dirty: HashSet<D>,
+    pub capacities: Vec<usize>,
+    pub used: Vec<usize>,
+    /// Largest total occupancy over the whole run (not cleared by `reset`)
+    pub peak_size: usize,
+    pub trips: Trips,
+    /// Bytes per cycle to and from this memory
+    #[serde(with = "unlimited")]
+    pub bandwidth: f64,
+    /// Fixed cycles per transaction (DMA setup / access latency)
+    pub latency: f64,
+}
+
+impl<D> BankedSRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    pub fn new(num_banks: usize, bank_size: usize) -> Self {
+        Self::with_capacities(vec![bank_size; num_banks])
+    }
+
+    pub fn with_capacities(capacities: Vec<usize>) -> Self {
+        assert!(!capacities.is_empty(), "BankedSRAM needs at least one bank");
         Self {
             residence: BTreeMap::default(),
             evict: HashSet::default(),
-            resident_size: 0,
-            mem_limit: sram_size,
-            trip_count: 0,
+            striped: HashSet::default(),
+            dirty: HashSet::default(),
+            used: vec![0; capacities.len()],
+            peak_size: 0,
+            capacities,
+            trips: Trips::default(),
+            bandwidth: f64::INFINITY,
+            latency: 0.0,
         }
     }
+
+    pub fn with_bandwidth(mut self, bytes_per_cycle: f64) -> Self {
+        self.bandwidth = bytes_per_cycle;
+        self
+    }
+
+    pub fn with_latency(mut self, cycles: f64) -> Self {
+        self.latency = cycles;
+        self
+    }
+
+    /// Spread `id` over all banks whenever it is allocated.
+    pub fn stripe(&mut self, id: D) {
+        self.striped.insert(id);
+    }
+
+    pub fn bank_of(&self, id: &D) -> Option<usize> {
+        self.residence.get(id).and_then(|x| x.0)
+    }
+
+    fn stripe_share(&self, size: usize) -> usize {
+        size.div_ceil(self.capacities.len())
+    }
+
+    fn free_in(&self, bank: usize) -> usize {
+        self.capacities[bank] - self.used[bank]
+    }
+
+    /// `Some(None)` if `id` can be striped, `Some(Some(bank))` for the bank
+    /// with the most room that fits it, `None` if it does not fit.
+    fn place(&self, id: &D, size: usize) -> Option<Option<usize>> {
+        if self.striped.contains(id) {
+            let share = self.stripe_share(size);
+            if (0..self.capacities.len()).all(|bank| self.free_in(bank) >= share) {
+                Some(None)
+            } else {
+                None
+            }
+        } else {
+            (0..self.capacities.len())
+                .filter(|&bank| self.free_in(bank) >= size)
+                .max_by_key(|&bank| (self.free_in(bank), std::cmp::Reverse(bank)))
+                .map(Some)
+        }
+    }
+
+    fn remove_resident(&mut self, id: &D) -> usize {
+        let (bank, size) = self.residence.remove(id).unwrap();
+        self.dirty.remove(id);
+        match bank {
+            Some(bank) => self.used[bank] -= size,
+            None => {
+                let share = self.stripe_share(size);
+                self.used.iter_mut().for_each(|used| *used -= share);
+            }
+        }
+        size
+    }
+}
+
+impl<D> sim::Memory<D> for BankedSRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    fn put(&mut self, id: &D, size: usize, from_self: bool) -> Result<(), MemError> {
+        if let Some(bank) = self.place(id, size) {
+            assert!(!self.residence.contains_key(id));
+            match bank {
+                Some(bank) => self.used[bank] += size,
+                None => {
+                    let share = self.stripe_share(size);
+                    self.used.iter_mut().for_each(|used| *used += share);
+                }
+            }
+            self.peak_size = self.peak_size.max(self.used.iter().sum());
+            if from_self {
+                self.dirty.insert(id.clone());
+            } else {
+                self.trips.load(size);
+            }
+            self.residence.insert(id.clone(), (bank, size));
+            Ok(())
+        } else {
+            Err(MemError::OutOfMemory {
+                memory: "SRAM",
+                requested: size,
+                allocated: self.size_allocated(),
+                total: self.size_total(),
+            })
+        }
+    }
+
+    fn to_vec(&self) -> Vec<&D> {
+        self.residence.iter().map(|pi| pi.0).collect()
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a D, usize)>
+    where
+        D: 'a,
+    {
+        self.residence.iter().map(|(x, (_, size))| (x, *size))
+    }
+
+    fn size_available(&self) -> usize {
+        self.size_total() - self.size_allocated()
+    }
+
+    fn size_allocated(&self) -> usize {
+        self.used.iter().sum()
+    }
+
+    fn size_total(&self) -> usize {
+        self.capacities.iter().sum()
+    }
+
+    fn peak_allocated(&self) -> usize {
+        self.peak_size
+    }
+
+    fn bandwidth(&self) -> f64 {
+        self.bandwidth
+    }
+
+    fn latency(&self) -> f64 {
+        self.latency
+    }
+
+    fn size_of(&self, data: &D) -> Option<usize> {
+        self.residence.get(data).map(|x| x.1)
+    }
+
+    fn get(&self, id: &D) -> Result<usize, MemError> {
+        if let Some(x) = self.residence.get(id) {
+            Ok(x.1)
+        } else {
+            Err(MemError::not_resident("SRAM", id))
+        }
+    }
+
+    fn reset(&mut self) {
+        self.residence.clear();
+        self.evict.clear();
+        self.dirty.clear();
+        self.used.iter_mut().for_each(|used| *used = 0);
+    }
+
+    fn deallocate(&mut self, data: &D) {
+        assert!(self.residence.contains_key(data));
+        self.remove_resident(data);
+    }
+
+    fn contains(&self, data: &D) -> bool {
+        self.residence.contains_key(data)
+    }
+}
+
+impl<D> sim::TargetMemory<D> for BankedSRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    fn can_allocate(&self, data: &D, size: usize) -> bool {
+        self.place(data, size).is_some()
+    }
+
+    fn max_allocation(&self, data: &D) -> usize {
+        if self.striped.contains(data) {
+            self.capacities.iter().min().unwrap() * self.capacities.len()
+        } else {
+            *self.capacities.iter().max().unwrap()
+        }
+    }
+
+    fn is_dirty(&self, data: &D) -> bool {
+        self.dirty.contains(data)
+    }
+
+    fn copy_in(&mut self, data: &D, size: usize, dirty: bool) -> Result<(), MemError> {
+        self.put(data, size, true)?;
+        if !dirty {
+            self.dirty.remove(data);
+        }
+        self.trips.copy(size);
+        Ok(())
+    }
+
+    fn store<DRAM: HostMemory<D>>(
+        &mut self,
+        id: &D,
+        evict: bool,
+        dram: &mut DRAM,
+    ) -> Result<(), MemError> {
+        if self.residence.contains_key(id) {
+            let dirty = self.dirty.remove(id);
+            let size = if evict {
+                self.evict.insert(id.clone());
+                self.remove_resident(id)
+            } else {
+                self.residence.get(id).unwrap().1
+            };
+            // a clean copy is already in DRAM, no need to write it back
+            if !dirty && dram.contains(id) {
+                return Ok(());
+            }
+            self.trips.store(size);
+            dram.put(id, size, false)
+        } else {
+            Err(MemError::not_resident("SRAM", id))
+        }
+    }
+}
+
+/// Chains two memory levels, e.g. an L2 in front of DRAM. Data put into the
+/// hierarchy lands in `upper`; when `upper` runs out of room, its oldest data
+/// spills into `lower`, which can itself be a `Tiered`. Fetching data that only
+/// lives in `lower` promotes it back into `upper`. The whole hierarchy acts
+/// as a host memory, so `upper` is a target memory in front of a host `lower`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Tiered<D, U, L> {
+    pub upper: U,
+    pub lower: L,
+    /// Data resident in `upper`, oldest first
+    order: VecDeque<D>,
+    pub spills: usize,
+    pub promotions: usize,
+}
+
+impl<D, U, L> Tiered<D, U, L>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+    U: TargetMemory<D>,
+    L: HostMemory<D>,
+{
+    pub fn new(upper: U, lower: L) -> Self {
+        Self {
+            upper,
+            lower,
+            order: VecDeque::default(),
+            spills: 0,
+            promotions: 0,
+        }
+    }
+
+    /// Makes room in `upper` for `data`; returns false if it can never fit.
+    fn make_room(&mut self, data: &D, size: usize) -> Result<bool, MemError> {
+        while !self.upper.can_allocate(data, size) {
+            if let Some(victim) = self.order.pop_front() {
+                info!("Spill {:?} to lower level", victim);
+                self.upper.store(&victim, true, &mut self.lower)?;
+                self.spills += 1;
+            } else {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<D, U, L> sim::Memory<D> for Tiered<D, U, L>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+    U: TargetMemory<D>,
+    L: HostMemory<D>,
+{
+    fn put(&mut self, data: &D, size: usize, from_self: bool) -> Result<(), MemError> {
+        if self.upper.contains(data) {
+            return Ok(());
+        }
+        if self.make_room(data, size)? {
+            self.order.push_back(data.clone());
+            self.upper.put(data, size, from_self)
+        } else {
+            self.lower.put(data, size, from_self)
+        }
+    }
+
+    fn get(&self, data: &D) -> Result<usize, MemError> {
+        if self.upper.contains(data) {
+            self.upper.get(data)
+        } else {
+            self.lower.get(data)
+        }
+    }
+
+    fn contains(&self, data: &D) -> bool {
+        self.upper.contains(data) || self.lower.contains(data)
+    }
+
+    fn size_available(&self) -> usize {
+        self.upper
+            .size_available()
+            .saturating_add(self.lower.size_available())
+    }
+
+    fn size_allocated(&self) -> usize {
+        self.upper
+            .size_allocated()
+            .saturating_add(self.lower.size_allocated())
+    }
+
+    fn size_total(&self) -> usize {
+        self.upper
+            .size_total()
+            .saturating_add(self.lower.size_total())
+    }
+
+    fn peak_allocated(&self) -> usize {
+        self.upper
+            .peak_allocated()
+            .saturating_add(self.lower.peak_allocated())
+    }
+
+    fn bandwidth(&self) -> f64 {
+        self.upper.bandwidth()
+    }
+
+    fn latency(&self) -> f64 {
+        self.upper.latency()
+    }
+
+    fn size_of(&self, data: &D) -> Option<usize> {
+        self.upper
+            .size_of(data)
+            .or_else(|| self.lower.size_of(data))
+    }
+
+    fn to_vec(&self) -> Vec<&D> {
+        let mut result = self.upper.to_vec();
+        result.extend(
+            self.lower
+                .to_vec()
+                .into_iter()
+                .filter(|x| !self.upper.contains(x)),
+        );
+        result
+    }
+
+    fn deallocate(&mut self, data: &D) {
+        if self.upper.contains(data) {
+            self.upper.deallocate(data);
+            self.order.retain(|x| x != data);
+        }
+        if self.lower.contains(data) {
+            self.lower.deallocate(data);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.upper.reset();
+        self.lower.reset();
+        self.order.clear();
+    }
+}
+
+impl<D, U, L> sim::HostMemory<D> for Tiered<D, U, L>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+    U: TargetMemory<D>,
+    L: HostMemory<D>,
+{
+    fn fetch(&mut self, data: &D) -> Result<usize, MemError> {
+        if self.upper.contains(data) {
+            return self.upper.get(data);
+        }
+        let size = self.lower.fetch(data)?;
+        if self.make_room(data, size)? {
+            info!("Promote {:?} to upper level", data);
+            self.order.push_back(data.clone());
+            self.upper.put(data, size, false)?;
+            self.promotions += 1;
+        }
+        Ok(size)
+    }
+
+    fn transfer_size(&self, data: &D, size: usize) -> usize {
+        // only data that went down to `lower` is stored transformed there
+        if self.upper.contains(data) {
+            size
+        } else {
+            self.lower.transfer_size(data, size)
+        }
+    }
+}
+
+/// An SRAM split into two halves used as a ping-pong buffer. Allocations go
+/// into the active half; after every compute the halves swap, so the loads
+/// for the next compute fill one half while the other is still being
+/// computed on. Data in either half stays readable.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "D: Serialize",
+    deserialize = "D: Deserialize<'de> + Hash + Eq + Ord"
+))]
+pub struct PingPongSRAM<D = Id> {
+    pub halves: [SRAM<D>; 2],
+    pub active: usize,
+    /// Largest combined occupancy of both halves over the whole run
+    pub peak_size: usize,
+}
+
+impl<D> PingPongSRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    /// Splits `sram_size` evenly between the two halves.
+    pub fn new(sram_size: usize) -> Self {
+        Self {
+            halves: [SRAM::new(sram_size / 2), SRAM::new(sram_size / 2)],
+            active: 0,
+            peak_size: 0,
+        }
+    }
+
+    pub fn swap(&mut self) {
+        self.active = 1 - self.active;
+    }
+
+    pub fn half_of(&self, id: &D) -> Option<usize> {
+        (0..2).find(|&i| self.halves[i].contains(id))
+    }
+
+    /// Transfers of both halves combined.
+    pub fn trips(&self) -> Trips {
+        self.halves[0].trips.combine(&self.halves[1].trips)
+    }
+}
+
+impl<D> sim::Memory<D> for PingPongSRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    fn put(&mut self, id: &D, size: usize, from_self: bool) -> Result<(), MemError> {
+        assert!(!self.contains(id));
+        self.halves[self.active].put(id, size, from_self)?;
+        self.peak_size = self.peak_size.max(self.size_allocated());
+        Ok(())
+    }
+
+    fn to_vec(&self) -> Vec<&D> {
+        self.halves.iter().flat_map(|x| x.to_vec()).collect()
+    }
+
+    fn size_available(&self) -> usize {
+        self.halves.iter().map(|x| x.size_available()).sum()
+    }
+
+    fn size_allocated(&self) -> usize {
+        self.halves.iter().map(|x| x.size_allocated()).sum()
+    }
+
+    fn size_total(&self) -> usize {
+        self.halves.iter().map(|x| x.size_total()).sum()
+    }
+
+    fn peak_allocated(&self) -> usize {
+        self.peak_size
+    }
+
+    fn bandwidth(&self) -> f64 {
+        self.halves[self.active].bandwidth()
+    }
+
+    fn latency(&self) -> f64 {
+        self.halves[self.active].latency()
+    }
+
+    fn size_of(&self, data: &D) -> Option<usize> {
+        self.halves[0]
+            .size_of(data)
+            .or_else(|| self.halves[1].size_of(data))
+    }
+
+    fn get(&self, id: &D) -> Result<usize, MemError> {
+        if let Some(half) = self.half_of(id) {
+            self.halves[half].get(id)
+        } else {
+            Err(MemError::not_resident("SRAM", id))
+        }
+    }
+
+    fn reset(&mut self) {
+        self.halves.iter_mut().for_each(|x| x.reset());
+        self.active = 0;
+    }
+
+    fn deallocate(&mut self, data: &D) {
+        let half = self.half_of(data).unwrap();
+        self.halves[half].deallocate(data);
+    }
+
+    fn contains(&self, data: &D) -> bool {
+        self.half_of(data).is_some()
+    }
+}
+
+impl<D> sim::TargetMemory<D> for PingPongSRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    fn can_allocate(&self, data: &D, size: usize) -> bool {
+        self.halves[self.active].can_allocate(data, size)
+    }
+
+    fn max_allocation(&self, data: &D) -> usize {
+        self.halves[self.active].max_allocation(data)
+    }
+
+    fn copy_in(&mut self, data: &D, size: usize, dirty: bool) -> Result<(), MemError> {
+        assert!(!self.contains(data));
+        self.halves[self.active].copy_in(data, size, dirty)?;
+        self.peak_size = self.peak_size.max(self.size_allocated());
+        Ok(())
+    }
+
+    fn is_dirty(&self, data: &D) -> bool {
+        self.half_of(data)
+            .is_some_and(|half| self.halves[half].is_dirty(data))
+    }
+
+    fn put_scratch(&mut self, owner: &D, size: usize) -> Result<(), MemError> {
+        self.halves[self.active].put_scratch(owner, size)?;
+        self.peak_size = self.peak_size.max(self.size_allocated());
+        Ok(())
+    }
+
+    /// Frees the scratch of the compute, on the half it ran on, and swaps.
+    fn end_compute(&mut self) {
+        self.halves[self.active].end_compute();
+        self.swap();
+    }
+
+    fn overlaps_loads(&self) -> bool {
+        true
+    }
+
+    fn store<DRAM: HostMemory<D>>(
+        &mut self,
+        id: &D,
+        evict: bool,
+        dram: &mut DRAM,
+    ) -> Result<(), MemError> {
+        if let Some(half) = self.half_of(id) {
+            self.halves[half].store(id, evict, dram)
+        } else {
+            Err(MemError::not_resident("SRAM", id))
+        }
+    }
+}
+
+/// An SRAM partitioned into named pools with individual capacities, e.g.
+/// separate weight and activation buffers. Data goes into the pool it was
+/// assigned to (see `Operators::Pool`), or the default pool otherwise.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "D: Serialize",
+    deserialize = "D: Deserialize<'de> + Hash + Eq + Ord"
+))]
+pub struct PooledSRAM<D = Id> {
+    pub pools: BTreeMap<String, SRAM<D>>,
+    pub assignment: HashMap<D, String>,
+    pub default_pool: String,
+    /// Largest combined occupancy of all pools over the whole run
+    pub peak_size: usize,
+}
+
+impl<D> PooledSRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    /// `pools` are (name, capacity); `default_pool` has to be one of them.
+    pub fn new(pools: Vec<(String, usize)>, default_pool: &str) -> Self {
+        let pools = pools
+            .into_iter()
+            .map(|(name, size)| (name, SRAM::new(size)))
+            .collect::<BTreeMap<_, _>>();
+        assert!(
+            pools.contains_key(default_pool),
+            "Unknown default pool {}",
+            default_pool
+        );
+        Self {
+            pools,
+            assignment: HashMap::default(),
+            default_pool: default_pool.into(),
+            peak_size: 0,
+        }
+    }
+
+    pub fn pool_name(&self, data: &D) -> &String {
+        self.assignment.get(data).unwrap_or(&self.default_pool)
+    }
+
+    /// The pool currently holding `data`.
+    fn holder(&self, data: &D) -> Option<&String> {
+        self.pools
+            .iter()
+            .find(|(_, pool)| pool.contains(data))
+            .map(|x| x.0)
+    }
+}
+
+impl<D> sim::Memory<D> for PooledSRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    fn put(&mut self, data: &D, size: usize, from_self: bool) -> Result<(), MemError> {
+        let name = self.pool_name(data).clone();
+        self.pools
+            .get_mut(&name)
+            .unwrap()
+            .put(data, size, from_self)?;
+        self.peak_size = self.peak_size.max(self.size_allocated());
+        Ok(())
+    }
+
+    fn to_vec(&self) -> Vec<&D> {
+        self.pools.values().flat_map(|x| x.to_vec()).collect()
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a D, usize)>
+    where
+        D: 'a,
+    {
+        self.pools
+            .values()
+            .flat_map(|x| x.residence.iter().map(|(x, size)| (x, *size)))
+    }
+
+    fn size_available(&self) -> usize {
+        self.pools.values().map(|x| x.size_available()).sum()
+    }
+
+    fn size_allocated(&self) -> usize {
+        self.pools.values().map(|x| x.size_allocated()).sum()
+    }
+
+    fn size_total(&self) -> usize {
+        self.pools.values().map(|x| x.size_total()).sum()
+    }
+
+    fn peak_allocated(&self) -> usize {
+        self.peak_size
+    }
+
+    fn size_of(&self, data: &D) -> Option<usize> {
+        self.holder(data)
+            .and_then(|name| self.pools[name].size_of(data))
+    }
+
+    fn assign_pool(&mut self, data: &D, pool: &str) -> Result<(), MemError> {
+        if !self.pools.contains_key(pool) {
+            return Err(MemError::UnknownPool {
+                memory: "SRAM",
+                pool: pool.into(),
+            });
+        }
+        self.assignment.insert(data.clone(), pool.into());
+        Ok(())
+    }
+
+    fn get(&self, data: &D) -> Result<usize, MemError> {
+        match self.holder(data) {
+            Some(name) => self.pools[name].get(data),
+            None => Err(MemError::not_resident("SRAM", data)),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.pools.values_mut().for_each(|x| x.reset());
+    }
+
+    fn deallocate(&mut self, data: &D) {
+        let name = self.holder(data).cloned().unwrap();
+        self.pools.get_mut(&name).unwrap().deallocate(data);
+    }
+
+    fn contains(&self, data: &D) -> bool {
+        self.holder(data).is_some()
+    }
+}
+
+impl<D> sim::TargetMemory<D> for PooledSRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    fn can_allocate(&self, data: &D, size: usize) -> bool {
+        self.pools[self.pool_name(data)].can_allocate(data, size)
+    }
+
+    fn max_allocation(&self, data: &D) -> usize {
+        self.pools[self.pool_name(data)].size_total()
+    }
+
+    fn copy_in(&mut self, data: &D, size: usize, dirty: bool) -> Result<(), MemError> {
+        let name = self.pool_name(data).clone();
+        self.pools
+            .get_mut(&name)
+            .unwrap()
+            .copy_in(data, size, dirty)?;
+        self.peak_size = self.peak_size.max(self.size_allocated());
+        Ok(())
+    }
+
+    fn is_dirty(&self, data: &D) -> bool {
+        self.holder(data)
+            .is_some_and(|name| self.pools[name].is_dirty(data))
+    }
+
+    fn store<DRAM: HostMemory<D>>(
+        &mut self,
+        data: &D,
+        evict: bool,
+        dram: &mut DRAM,
+    ) -> Result<(), MemError> {
+        match self.holder(data).cloned() {
+            Some(name) => self.pools.get_mut(&name).unwrap().store(data, evict, dram),
+            None => Err(MemError::not_resident("SRAM", data)),
+        }
+    }
+}
+
+/// Several host-side backing stores (e.g. per-chip HBM and host DDR), each
+/// with its own capacity, bandwidth and latency. Data lives in the store it was
+/// assigned to (see `Operators::Backing`), or the default store otherwise.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "D: Serialize",
+    deserialize = "D: Deserialize<'de> + Hash + Eq + Ord"
+))]
+pub struct MultiDRAM<D = Id> {
+    pub stores: BTreeMap<String, DRAM<D>>,
+    pub assignment: HashMap<D, String>,
+    pub default_store: String,
+}
+
+impl<D> MultiDRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    /// `default_store` has to be one of `stores`.
+    pub fn new(stores: Vec<(String, DRAM<D>)>, default_store: &str) -> Self {
+        let stores = stores.into_iter().collect::<BTreeMap<_, _>>();
+        assert!(
+            stores.contains_key(default_store),
+            "Unknown default store {}",
+            default_store
+        );
+        Self {
+            stores,
+            assignment: HashMap::default(),
+            default_store: default_store.into(),
+        }
+    }
+
+    pub fn store_name(&self, data: &D) -> &String {
+        self.assignment.get(data).unwrap_or(&self.default_store)
+    }
+
+    fn store_of(&self, data: &D) -> &DRAM<D> {
+        &self.stores[self.store_name(data)]
+    }
+
+    /// The store currently holding `data`.
+    fn holder(&self, data: &D) -> Option<&String> {
+        self.stores
+            .iter()
+            .find(|(_, store)| store.contains(data))
+            .map(|x| x.0)
+    }
+}
+
+impl<D> sim::Memory<D> for MultiDRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    fn to_vec(&self) -> Vec<&D> {
+        self.stores.values().flat_map(|x| x.to_vec()).collect()
+    }
+
+    fn put(&mut self, data: &D, size: usize, from_self: bool) -> Result<(), MemError> {
+        let name = self.store_name(data).clone();
+        self.stores
+            .get_mut(&name)
+            .unwrap()
+            .put(data, size, from_self)
+    }
+
+    fn get(&self, data: &D) -> Result<usize, MemError> {
+        match self.holder(data) {
+            Some(name) => self.stores[name].get(data),
+            None => Err(MemError::not_resident("DRAM", data)),
+        }
+    }
+
+    fn size_of(&self, data: &D) -> Option<usize> {
+        self.holder(data)
+            .and_then(|name| self.stores[name].size_of(data))
+    }
+
+    fn size_allocated(&self) -> usize {
+        self.stores.values().map(|x| x.size_allocated()).sum()
+    }
+
+    fn size_total(&self) -> usize {
+        self.stores
+            .values()
+            .fold(0, |acc: usize, x| acc.saturating_add(x.size_total()))
+    }
+
+    fn size_available(&self) -> usize {
+        self.stores
+            .values()
+            .fold(0, |acc: usize, x| acc.saturating_add(x.size_available()))
+    }
+
+    fn peak_allocated(&self) -> usize {
+        self.stores.values().map(|x| x.peak_allocated()).sum()
+    }
+
+    fn bandwidth_of(&self, data: &D) -> f64 {
+        self.store_of(data).bandwidth
+    }
+
+    fn latency_of(&self, data: &D) -> f64 {
+        self.store_of(data).latency
+    }
+
+    fn assign_pool(&mut self, data: &D, store: &str) -> Result<(), MemError> {
+        if !self.stores.contains_key(store) {
+            return Err(MemError::UnknownPool {
+                memory: "DRAM",
+                pool: store.into(),
+            });
+        }
+        self.assignment.insert(data.clone(), store.into());
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.stores.values_mut().for_each(|x| x.reset());
+    }
+
+    fn deallocate(&mut self, data: &D) {
+        if let Some(name) = self.holder(data).cloned() {
+            self.stores.get_mut(&name).unwrap().deallocate(data);
+        }
+    }
+
+    fn contains(&self, data: &D) -> bool {
+        self.holder(data).is_some()
+    }
+}
+
+impl<D> sim::HostMemory<D> for MultiDRAM<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    fn transfer_size(&self, data: &D, size: usize) -> usize {
+        self.store_of(data).compressed_size(data, size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{HostMemory, Memory, TargetMemory};
+
+    #[test]
+    fn ping_pong_forwards_dirtiness_and_scratch() {
+        let mut sram = PingPongSRAM::<u32>::new(16);
+        sram.put(&1, 4, true).unwrap();
+        sram.put(&2, 4, false).unwrap();
+        assert!(sram.is_dirty(&1));
+        assert!(!sram.is_dirty(&2));
+        sram.deallocate(&2);
+        sram.put_scratch(&1, 4).unwrap();
+        assert!(!sram.can_allocate(&3, 4));
+        sram.end_compute();
+        sram.swap();
+        assert!(sram.can_allocate(&3, 4));
+    }
+
+    /// Puts 1 as produced on-device, 2 as loaded and 3 as a clean copy, then
+    /// writes 1 back.
+    fn dirtiness<TM: TargetMemory<u32>>(mut sram: TM) {
+        let mut dram = DRAM::new();
+        sram.put(&1, 4, true).unwrap();
+        sram.put(&2, 4, false).unwrap();
+        sram.copy_in(&3, 4, false).unwrap();
+        assert!(sram.is_dirty(&1));
+        assert!(!sram.is_dirty(&2));
+        assert!(!sram.is_dirty(&3));
+        sram.store(&1, false, &mut dram).unwrap();
+        assert!(!sram.is_dirty(&1));
+    }
+
+    #[test]
+    fn addressed_and_banked_track_dirtiness() {
+        dirtiness(AddressedSRAM::new(64));
+        dirtiness(BankedSRAM::new(2, 32));
+    }
+
+    /// An addressed SRAM of 16 bytes with 1 to 4 of 4 bytes each, then 1 and
+    /// 3 freed: two holes of 4
+    fn holes(placement: Placement) -> AddressedSRAM<u32> {
+        let mut sram = AddressedSRAM::with_placement(16, placement);
+        for data in 1..=4 {
+            sram.put(&data, 4, false).unwrap();
+        }
+        sram.deallocate(&1);
+        sram.deallocate(&3);
+        sram
+    }
+
+    #[test]
+    fn addressed_sram_fragments() {
+        let sram = holes(Placement::FirstFit);
+        assert_eq!(sram.free_blocks(), vec![(0, 4), (8, 4)]);
+        assert_eq!(sram.size_available(), 8);
+        assert!(!sram.can_allocate(&5, 8));
+        assert_eq!(sram.fragmentation(), 0.5);
+    }
+
+    #[test]
+    fn placements_pick_different_holes() {
+        // holes of 6 at 0 and of 2 at 8
+        let place = |placement| {
+            let mut sram = AddressedSRAM::with_placement(16, placement);
+            for (data, size) in [(1, 6), (2, 2), (3, 2), (4, 6)] {
+                sram.put(&data, size, false).unwrap();
+            }
+            sram.deallocate(&1);
+            sram.deallocate(&3);
+            sram.put(&5, 2, false).unwrap();
+            sram.offset_of(&5)
+        };
+        assert_eq!(place(Placement::FirstFit), Some(0));
+        assert_eq!(place(Placement::BestFit), Some(8));
+
+        let mut buddy = AddressedSRAM::with_placement(16, Placement::Buddy);
+        buddy.put(&1, 3, false).unwrap();
+        buddy.put(&2, 5, false).unwrap();
+        assert_eq!(buddy.offset_of(&2), Some(8));
+        assert_eq!(buddy.size_allocated(), 12);
+    }
+
+    #[test]
+    fn banked_sram_keeps_data_in_one_bank_unless_striped() {
+        let mut sram = BankedSRAM::<u32>::new(2, 8);
+        sram.put(&1, 6, false).unwrap();
+        sram.put(&2, 6, false).unwrap();
+        assert_ne!(sram.bank_of(&1), sram.bank_of(&2));
+        // 4 bytes are free, but only 2 in each bank
+        assert!(!sram.can_allocate(&3, 4));
+        sram.stripe(3);
+        assert!(sram.can_allocate(&3, 4));
+        sram.put(&3, 4, false).unwrap();
+        assert_eq!(sram.bank_of(&3), None);
+        assert_eq!(sram.size_available(), 0);
+    }
+
+    #[test]
+    fn tiered_spills_the_oldest_and_promotes_on_fetch() {
+        let mut tiers = Tiered::new(SRAM::new(8), DRAM::new());
+        for data in 1..=3 {
+            tiers.put(&data, 4, false).unwrap();
+        }
+        assert!(!tiers.upper.contains(&1) && tiers.lower.contains(&1));
+        assert_eq!(tiers.fetch(&1), Ok(4));
+        assert!(tiers.upper.contains(&1) && !tiers.upper.contains(&2));
+        assert_eq!((tiers.spills, tiers.promotions), (2, 1));
+    }
+
+    #[test]
+    fn finite_dram_runs_out_of_room() {
+        let mut dram = DRAM::<u32>::with_capacity(8);
+        dram.put(&1, 4, false).unwrap();
+        dram.put(&2, 4, false).unwrap();
+        assert!(matches!(
+            dram.put(&3, 4, false),
+            Err(MemError::OutOfMemory { memory: "DRAM", .. })
+        ));
+        dram.deallocate(&1);
+        dram.put(&3, 4, false).unwrap();
+        assert_eq!(dram.size_available(), 0);
+    }
+
+    #[test]
+    fn allocations_are_padded_to_the_alignment() {
+        let mut sram = SRAM::<u32>::new(16).with_alignment(8);
+        sram.put(&1, 1, false).unwrap();
+        assert_eq!(sram.size_allocated(), 8);
+        assert!(sram.can_allocate(&2, 8));
+        assert!(!sram.can_allocate(&2, 9));
+        assert_eq!(sram.get(&1), Ok(1));
+    }
+
+    #[test]
+    fn put_reports_out_of_memory() {
+        let mut sram = SRAM::<u32>::new(4);
+        sram.put(&1, 4, false).unwrap();
+        assert_eq!(
+            sram.put(&2, 4, false),
+            Err(MemError::OutOfMemory {
+                memory: "SRAM",
+                requested: 4,
+                allocated: 4,
+                total: 4,
+            })
+        );
+        assert!(!sram.contains(&2));
+    }
+
+    #[test]
+    fn dram_occupancy_counts_rewritten_data_once() {
+        let mut dram = DRAM::<u32>::new();
+        dram.put(&1, 4, false).unwrap();
+        dram.put(&1, 4, false).unwrap();
+        assert_eq!(dram.size_allocated(), 4);
+        dram.put(&1, 8, false).unwrap();
+        dram.put(&2, 4, false).unwrap();
+        assert_eq!(dram.size_allocated(), 12);
+        dram.deallocate(&1);
+        dram.deallocate(&1);
+        assert_eq!(dram.size_allocated(), 4);
+        assert_eq!(dram.peak_allocated(), 12);
+    }
+
+    #[test]
+    fn snapshots_restore_the_earlier_state() {
+        let mut sram = SRAM::<u32>::new(16);
+        let mut dram = DRAM::<u32>::new();
+        sram.put(&1, 4, false).unwrap();
+        let (sram_before, dram_before) = (sram.snapshot(), dram.snapshot());
+        sram.store(&1, true, &mut dram).unwrap();
+        sram.put(&2, 8, true).unwrap();
+        sram.restore(sram_before);
+        dram.restore(dram_before);
+        assert_eq!(sram.to_vec(), vec![&1]);
+        assert_eq!(sram.size_allocated(), 4);
+        assert!(!dram.contains(&1));
+    }
+
+    #[test]
+    fn trips_count_each_direction_apart() {
+        let mut sram = SRAM::<u32>::new(16);
+        let mut dram = DRAM::new();
+        dram.put(&1, 4, false).unwrap();
+        sram.put(&1, 4, false).unwrap();
+        sram.put(&2, 8, true).unwrap();
+        sram.store(&2, true, &mut dram).unwrap();
+        // a clean copy of 1 is already on the host
+        sram.store(&1, true, &mut dram).unwrap();
+        let trips = sram.trips;
+        assert_eq!((trips.load_trips, trips.load_bytes), (1, 4));
+        assert_eq!((trips.store_trips, trips.store_bytes), (1, 8));
+        assert_eq!(trips.count(), 2);
+    }
+
+    #[test]
+    fn iterates_residents_with_their_sizes() {
+        let mut sram = SRAM::<u32>::new(32).with_alignment(8);
+        let mut ping_pong = PingPongSRAM::<u32>::new(32);
+        for (data, size) in [(2, 4), (1, 8)] {
+            sram.put(&data, size, true).unwrap();
+            ping_pong.put(&data, size, true).unwrap();
+        }
+        let expected = vec![(&1, 8), (&2, 4)];
+        assert_eq!(sram.iter().collect::<Vec<_>>(), expected);
+        assert_eq!(ping_pong.iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn checkpoints_roundtrip_through_json() {
+        let mut sram = SRAM::<u32>::new(32);
+        let mut dram = DRAM::<u32>::new();
+        sram.put(&1, 4, true).unwrap();
+        sram.put(&2, 8, false).unwrap();
+        dram.put(&2, 8, false).unwrap();
+        let sram: SRAM<u32> = serde_json::from_str(&serde_json::to_string(&sram).unwrap()).unwrap();
+        let dram: DRAM<u32> = serde_json::from_str(&serde_json::to_string(&dram).unwrap()).unwrap();
+        assert_eq!(sram.iter().collect::<Vec<_>>(), vec![(&1, 4), (&2, 8)]);
+        assert!(sram.is_dirty(&1) && !sram.is_dirty(&2));
+        assert_eq!(sram.bandwidth(), f64::INFINITY);
+        assert_eq!(dram.size_allocated(), 8);
+    }
+
+    #[test]
+    fn clean_stores_cost_no_trip_on_any_sram() {
+        fn store<TM: TargetMemory<u32>>(mut sram: TM, trips: fn(&TM) -> Trips) -> Trips {
+            let mut dram = DRAM::new();
+            dram.put(&1, 4, false).unwrap();
+            sram.put(&1, 4, false).unwrap();
+            sram.put(&2, 4, true).unwrap();
+            for data in [1, 2] {
+                sram.store(&data, false, &mut dram).unwrap();
+            }
+            // 2 is clean once written back
+            sram.store(&2, true, &mut dram).unwrap();
+            trips(&sram)
+        }
+        let expected = (1, 4);
+        let sram = store(SRAM::new(16), |x| x.trips);
+        assert_eq!((sram.store_trips, sram.store_bytes), expected);
+        let addressed = store(AddressedSRAM::new(16), |x| x.trips);
+        assert_eq!((addressed.store_trips, addressed.store_bytes), expected);
+        let banked = store(BankedSRAM::new(2, 8), |x| x.trips);
+        assert_eq!((banked.store_trips, banked.store_bytes), expected);
+    }
+
+    #[test]
+    fn peaks_survive_a_reset_on_every_memory() {
+        let mut sram = SRAM::<u32>::new(16);
+        let mut dram = DRAM::<u32>::new();
+        sram.put(&1, 8, false).unwrap();
+        dram.put(&1, 8, false).unwrap();
+        sram.reset();
+        dram.reset();
+        assert_eq!((sram.size_allocated(), dram.size_allocated()), (0, 0));
+        assert_eq!((sram.peak_allocated(), dram.peak_allocated()), (8, 8));
+    }
 }
@@ -0,0 +1,302 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+
+use crate::sim::Operators;
+
+/// What an instruction needs from one region.
+#[derive(Clone, Debug)]
+enum Step<D> {
+    /// `reads` must be resident at once, together with room for `writes`
+    /// and for that many transient bytes
+    Use(Vec<D>, Option<D>, usize),
+    /// A `Store`: every dirty resident is written back and the region emptied
+    Flush,
+}
+
+/// Fewest DRAM trips found by `min_trips`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Optimum {
+    /// Loads plus write-backs; `None` if the search hit its limit before
+    /// finding any schedule
+    pub trips: Option<usize>,
+    /// False if the search hit its limit before proving `trips` minimal
+    pub proven: bool,
+    /// Search states visited
+    pub explored: usize,
+}
+
+impl Optimum {
+    /// `trips` of a heuristic as a percentage of the optimum (100 is
+    /// optimal), if one was found.
+    pub fn percent(&self, trips: usize) -> Option<f64> {
+        self.trips.map(|best| {
+            if best == 0 {
+                100.0
+            } else {
+                trips as f64 / best as f64 * 100.0
+            }
+        })
+    }
+}
+
+/// Finds by branch and bound the fewest DRAM trips (loads and write-backs)
+/// with which `trace` can run on `region` with `capacity` bytes, over every
+/// possible choice of evictions. Only meant for small traces: at most `limit`
+/// search states are visited. `None` if a single instruction does not fit;
+/// running out of states instead gives an unproven `Optimum`.
+pub fn min_trips<D>(
+    trace: &Operators<D>,
+    region: &str,
+    capacity: usize,
+    limit: usize,
+) -> Option<Optimum>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    let mut sizes = HashMap::new();
+    let mut steps = vec![];
+    // the search does not branch: both ways of an `If` are taken one after
+    // the other, which bounds either from above
+    for op in trace.linearize_branches() {
+        match op.unwrap_wrappers().clone() {
+            Operators::Load(r, (data, _), size) if r == region => {
+                sizes.insert(data.clone(), size);
+                steps.push(Step::Use(vec![data], None, 0));
+            }
+            Operators::Compute(r, _, dst, args, size) if r == region => {
+                sizes.insert(dst.clone(), size.output);
+                steps.push(Step::Use(
+                    args.into_iter().map(|x| x.0).collect(),
+                    Some(dst),
+                    size.scratch + scratch(&op),
+                ));
+            }
+            Operators::Store(r, ..) if r == region => steps.push(Step::Flush),
+            // a copy in arrives without a DRAM trip, a copy out reads the data
+            Operators::Copy(src, dst, data, size) if dst == region && src != region => {
+                sizes.insert(data.clone(), size);
+                steps.push(Step::Use(vec![], Some(data), 0));
+            }
+            Operators::Copy(src, _, data, _) if src == region => {
+                steps.push(Step::Use(vec![data], None, 0));
+            }
+            _ => {}
+        }
+    }
+    let mut search = Search {
+        uses: HashMap::new(),
+        flushes: vec![],
+        steps,
+        sizes,
+        capacity,
+        limit,
+        explored: 0,
+        best: None,
+        seen: HashMap::new(),
+    };
+    for (i, step) in search.steps.iter().enumerate() {
+        match step {
+            Step::Use(reads, ..) => {
+                for data in reads.iter() {
+                    search.uses.entry(data.clone()).or_default().push(i);
+                }
+            }
+            Step::Flush => search.flushes.push(i),
+        }
+    }
+    search.visit(0, BTreeMap::new(), 0);
+    let proven = search.explored < limit;
+    if proven && search.best.is_none() {
+        return None;
+    }
+    Some(Optimum {
+        trips: search.best,
+        proven,
+        explored: search.explored,
+    })
+}
+
+/// Transient bytes of the `Scratch` wrappers around `op`.
+fn scratch<D>(op: &Operators<D>) -> usize
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    match op {
+        Operators::Scratch(bytes, op) => bytes + scratch(op),
+        Operators::Pool(_, op)
+        | Operators::Backing(_, op)
+        | Operators::InPlace(_, op)
+        | Operators::Dma(_, op) => scratch(op),
+        _ => 0,
+    }
+}
+
+struct Search<D> {
+    steps: Vec<Step<D>>,
+    sizes: HashMap<D, usize>,
+    /// Steps reading each data, ascending
+    uses: HashMap<D, Vec<usize>>,
+    flushes: Vec<usize>,
+    capacity: usize,
+    limit: usize,
+    explored: usize,
+    best: Option<usize>,
+    /// Cheapest cost each (step, residents) was reached with
+    seen: HashMap<(usize, BTreeMap<D, bool>), usize>,
+}
+
+impl<D> Search<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    fn size(&self, data: &D) -> usize {
+        self.sizes.get(data).cloned().unwrap_or_default()
+    }
+
+    fn next_use(&self, data: &D, after: usize) -> Option<usize> {
+        let uses = self.uses.get(data)?;
+        uses.get(uses.partition_point(|&x| x <= after)).cloned()
+    }
+
+    /// Whether evicting `data` at step `i` costs a write-back.
+    fn eviction_cost(&self, data: &D, dirty: bool, i: usize) -> usize {
+        let flushed_later = self.flushes.last().is_some_and(|&x| x > i);
+        (dirty && (self.next_use(data, i).is_some() || flushed_later)) as usize
+    }
+
+    fn visit(&mut self, i: usize, residents: BTreeMap<D, bool>, cost: usize) {
+        if self.explored >= self.limit || self.best.is_some_and(|best| cost >= best) {
+            return;
+        }
+        self.explored += 1;
+        if i == self.steps.len() {
+            self.best = Some(cost);
+            return;
+        }
+        let key = (i, residents);
+        match self.seen.get(&key) {
+            Some(&seen) if seen <= cost => return,
+            _ => {}
+        }
+        self.seen.insert(key.clone(), cost);
+        let (_, residents) = key;
+        match self.steps[i].clone() {
+            Step::Flush => {
+                let written = residents.values().filter(|&&dirty| dirty).count();
+                self.visit(i + 1, BTreeMap::new(), cost + written);
+            }
+            Step::Use(reads, writes, scratch) => {
+                let missing = reads
+                    .iter()
+                    .filter(|x| !residents.contains_key(x))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let mut needed = scratch + missing.iter().map(|x| self.size(x)).sum::<usize>();
+                if let Some(dst) = writes.as_ref().filter(|x| !residents.contains_key(x)) {
+                    needed += self.size(dst);
+                }
+                let locked = reads.iter().chain(writes.iter()).collect::<Vec<_>>();
+                let fixed = locked
+                    .iter()
+                    .filter(|x| residents.contains_key(x))
+                    .map(|x| self.size(x))
+                    .sum::<usize>();
+                if fixed + needed > self.capacity {
+                    // cannot fit even with everything else evicted
+                    return;
+                }
+                // victims, farthest next use first, so good bounds come early
+                let mut victims = residents
+                    .iter()
+                    .filter(|(x, _)| !locked.contains(x))
+                    .map(|(x, dirty)| (x.clone(), *dirty))
+                    .collect::<Vec<_>>();
+                victims.sort_by_key(|(x, _)| {
+                    std::cmp::Reverse(self.next_use(x, i).unwrap_or(usize::MAX))
+                });
+                let mut residents = residents;
+                for data in missing.iter() {
+                    residents.insert(data.clone(), false);
+                }
+                if let Some(dst) = writes {
+                    residents.insert(dst, true);
+                }
+                let cost = cost + missing.len();
+                self.evict(i, residents, scratch, cost, &victims, 0);
+            }
+        }
+    }
+
+    /// Tries every set of `victims[from..]` whose eviction makes the residents
+    /// fit, next to `scratch` bytes.
+    fn evict(
+        &mut self,
+        i: usize,
+        residents: BTreeMap<D, bool>,
+        scratch: usize,
+        cost: usize,
+        victims: &[(D, bool)],
+        from: usize,
+    ) {
+        let allocated = residents.keys().map(|x| self.size(x)).sum::<usize>();
+        if allocated + scratch <= self.capacity {
+            self.visit(i + 1, residents, cost);
+            return;
+        }
+        for (j, (data, dirty)) in victims.iter().enumerate().skip(from) {
+            let mut residents = residents.clone();
+            residents.remove(data);
+            let cost = cost + self.eviction_cost(data, *dirty, i);
+            self.evict(i, residents, scratch, cost, victims, j + 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(data: u32) -> (u32, Operators<u32>) {
+        let host = Operators::Load("host".into(), (data, Box::new(Operators::NoOp)), 4);
+        (
+            data,
+            Operators::Load("sram".into(), (data, Box::new(host)), 4),
+        )
+    }
+
+    /// 3 = f(1, 2)
+    fn trace() -> Operators<u32> {
+        Operators::Compute("sram".into(), 0, 3, vec![load(1), load(2)], 4.into())
+    }
+
+    #[test]
+    fn running_out_of_states_is_not_infeasible() {
+        let optimum = min_trips(&trace(), "sram", 12, 1000).unwrap();
+        assert_eq!(optimum.trips, Some(2));
+        assert!(optimum.proven);
+        assert_eq!(optimum.percent(3), Some(150.0));
+
+        let cut = min_trips(&trace(), "sram", 12, 1).unwrap();
+        assert_eq!(cut.trips, None);
+        assert!(!cut.proven);
+        assert_eq!(cut.percent(3), None);
+
+        assert_eq!(min_trips(&trace(), "sram", 8, 1000), None);
+    }
+
+    #[test]
+    fn wrapped_instructions_are_searched_with_their_scratch() {
+        let wrap = |op| Operators::Pool("weights".into(), Box::new(op));
+        let tiny = Operators::Compute("sram".into(), 0, 3, vec![load(1)], 4.into());
+        assert_eq!(min_trips(&tiny, "sram", 4, 1000), None);
+        assert_eq!(min_trips(&wrap(tiny), "sram", 4, 1000), None);
+
+        let scratch = Operators::Scratch(4, Box::new(wrap(trace())));
+        assert_eq!(min_trips(&scratch, "sram", 12, 1000), None);
+        let optimum = min_trips(&scratch, "sram", 16, 1000).unwrap();
+        assert_eq!(optimum.trips, Some(2));
+        assert!(optimum.proven);
+    }
+}
@@ -0,0 +1,335 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+use crate::sim::{Heuristic, HostMemory, JitSim, Operators, SimError, TargetMemory};
+
+/// Double-buffers the loads into `region` of a flat schedule (as produced by
+/// `JitSim::schedule`): the loads following each compute on `region` are
+/// hoisted to right after it, ahead of its write-backs, so that on a
+/// ping-pong SRAM they fill the other half while that compute runs. A load
+/// never moves above an instruction producing or evicting its data.
+///
+/// A `Store` flushes the whole region, which would drop the hoisted loads, so
+/// a `Store` that loads were hoisted above becomes `Evict`s of the output and
+/// of the operands of the compute that the next one does not load again.
+pub fn double_buffer<D>(schedule: &[Operators<D>], region: &str) -> Vec<Operators<D>>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    let mut sizes = HashMap::new();
+    for op in schedule.iter() {
+        match op {
+            Operators::Load(_, (data, _), size) => {
+                sizes.insert(data.clone(), *size);
+            }
+            Operators::Compute(_, _, data, _, size) => {
+                sizes.insert(data.clone(), size.output);
+            }
+            _ => {}
+        }
+    }
+    let mut result = Vec::with_capacity(schedule.len());
+    let mut compute: Option<&Operators<D>> = None;
+    let mut segment = vec![];
+    for op in schedule.iter() {
+        if matches!(op, Operators::Compute(r, ..) if r == region) {
+            double_buffer_segment(compute, &segment, region, &sizes, &mut result);
+            compute = Some(op);
+            segment.clear();
+        } else {
+            segment.push(op);
+        }
+    }
+    double_buffer_segment(compute, &segment, region, &sizes, &mut result);
+    result
+}
+
+/// Appends `compute` and the instructions up to the next compute on `region`.
+fn double_buffer_segment<D>(
+    compute: Option<&Operators<D>>,
+    segment: &[&Operators<D>],
+    region: &str,
+    sizes: &HashMap<D, usize>,
+    result: &mut Vec<Operators<D>>,
+) where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    let (compute, dst, args) = match compute {
+        Some(op @ Operators::Compute(_, _, dst, args, _)) => (op, dst, args),
+        _ => {
+            result.extend(segment.iter().map(|x| (*x).clone()));
+            return;
+        }
+    };
+    result.push(compute.clone());
+    let mut hoisted = vec![];
+    let mut rest = vec![];
+    for op in segment.iter() {
+        match op {
+            Operators::Load(r, (data, _), _)
+                if r == region
+                    && !rest
+                        .iter()
+                        .any(|x: &&Operators<D>| x.output().map(|x| x.1) == Some(data)) =>
+            {
+                hoisted.push(*op)
+            }
+            _ => rest.push(*op),
+        }
+    }
+    if hoisted.is_empty() {
+        result.extend(rest.into_iter().cloned());
+        return;
+    }
+    let reloaded = hoisted
+        .iter()
+        .filter_map(|x| x.output().map(|x| x.1))
+        .collect::<HashSet<_>>();
+    result.extend(hoisted.into_iter().cloned());
+    for op in rest.into_iter() {
+        match op {
+            Operators::Store(r, _, _, _) if r == region => {
+                let done = args
+                    .iter()
+                    .map(|x| &x.0)
+                    .filter(|x| !reloaded.contains(x))
+                    .chain(std::iter::once(dst));
+                for data in done {
+                    let size = sizes.get(data).cloned().unwrap_or_default();
+                    result.push(Operators::Evict(region.into(), data.clone(), size));
+                }
+            }
+            _ => result.push(op.clone()),
+        }
+    }
+}
+
+/// Timing of a schedule before and after `double_buffer`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DoubleBufferReport {
+    /// Latency of the original schedule with everything serialized
+    pub serial: f64,
+    /// Latency of the original schedule with transfers overlapping computes
+    pub before: f64,
+    /// Latency of the double-buffered schedule with transfers overlapping
+    /// computes
+    pub after: f64,
+}
+
+impl DoubleBufferReport {
+    pub fn speedup(&self) -> f64 {
+        self.before / self.after
+    }
+}
+
+/// Double-buffers `schedule` for `region`, which should be a ping-pong SRAM,
+/// and times both versions in the overlapped timing model. Each version runs
+/// on a copy of `sim` (for its heuristic and cost model) and of the memories.
+pub fn double_buffer_report<H, D, TM, HM>(
+    sim: &JitSim<H, D>,
+    schedule: &[Operators<D>],
+    region: &str,
+    srams: &HashMap<String, TM>,
+    dram: &HM,
+) -> Result<DoubleBufferReport, SimError>
+where
+    H: Heuristic<D> + Clone,
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+    TM: TargetMemory<D> + Clone,
+    HM: HostMemory<D> + Clone,
+{
+    if !srams.contains_key(region) {
+        return Err(SimError::UnknownRegion(region.into()));
+    }
+    let time = |schedule: &[Operators<D>]| {
+        let mut sim = sim.clone();
+        sim.overlap_transfers();
+        sim.run_schedule(schedule, &mut srams.clone(), &mut dram.clone())?;
+        Ok::<_, SimError>((sim.latency(), sim.overlapped_latency()))
+    };
+    let (serial, before) = time(schedule)?;
+    let (_, after) = time(&double_buffer(schedule, region))?;
+    Ok(DoubleBufferReport {
+        serial,
+        before,
+        after,
+    })
+}
+
+/// Estimated peak bytes on the devices while evaluating `op` in its current
+/// order, with every operand staying resident until its user ran.
+pub fn pressure<D>(op: &Operators<D>) -> usize
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    match op {
+        Operators::Compute(region, _, _, subops, sizes) => {
+            let mut peak = 0;
+            let mut held = 0;
+            for (_, op) in subops.iter() {
+                peak = peak.max(held + pressure(op));
+                held += resident_size(op);
+            }
+            if region != "host" {
+                held += sizes.output + sizes.scratch;
+            }
+            peak.max(held)
+        }
+        Operators::Load(region, (_, op), size) if region != "host" => pressure(op).max(*size),
+        Operators::Load(_, (_, op), _) | Operators::Store(_, _, (_, op), _) => pressure(op),
+        // iterations run one after the other
+        Operators::Pool(_, op)
+        | Operators::Backing(_, op)
+        | Operators::InPlace(_, op)
+        | Operators::Dma(_, op)
+        | Operators::Repeat(_, op) => pressure(op),
+        Operators::If(_, then, otherwise) => pressure(then).max(pressure(otherwise)),
+        Operators::Scratch(bytes, op) => pressure(op) + bytes,
+        Operators::Copy(.., size) => *size,
+        Operators::Evict(..) | Operators::Sync(_) | Operators::Barrier | Operators::NoOp => 0,
+    }
+}
+
+/// Bytes the result of `op` occupies on a device until it is used.
+fn resident_size<D>(op: &Operators<D>) -> usize
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    match op {
+        Operators::Compute(region, .., sizes) if region != "host" => sizes.output,
+        Operators::Load(region, _, size) if region != "host" => *size,
+        Operators::Copy(.., size) => *size,
+        Operators::Pool(_, op)
+        | Operators::Backing(_, op)
+        | Operators::Scratch(_, op)
+        | Operators::InPlace(_, op)
+        | Operators::Dma(_, op)
+        | Operators::Repeat(_, op)
+        | Operators::If(_, op, _) => resident_size(op),
+        _ => 0,
+    }
+}
+
+/// Reorders the operand subtrees of every compute to lower the peak SRAM
+/// pressure, Sethi–Ullman style: the subtree needing the most room beyond
+/// what its result keeps is evaluated first. Operand subtrees do not depend on
+/// each other, and the simulator identifies operands by their data rather
+/// than their position, so only the evaluation order changes.
+pub fn reorder_for_pressure<D>(op: &mut Operators<D>)
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    match op {
+        Operators::Compute(_, _, _, subops, _) => {
+            for (_, op) in subops.iter_mut() {
+                reorder_for_pressure(op);
+            }
+            if !independent(subops) {
+                return;
+            }
+            // stable, so ties keep their original order
+            subops.sort_by_key(|(_, op)| {
+                std::cmp::Reverse(pressure(op) as isize - resident_size(op) as isize)
+            });
+        }
+        Operators::Load(_, (_, op), _)
+        | Operators::Store(_, _, (_, op), _)
+        | Operators::Pool(_, op)
+        | Operators::Backing(_, op)
+        | Operators::Scratch(_, op)
+        | Operators::Dma(_, op)
+        | Operators::Repeat(_, op) => reorder_for_pressure(op),
+        Operators::If(_, then, otherwise) => {
+            reorder_for_pressure(then);
+            reorder_for_pressure(otherwise);
+        }
+        Operators::InPlace(_, op) => {
+            // the aliased operand is identified by its position, so only
+            // deeper computes are reordered
+            if let Operators::Compute(_, _, _, subops, _) = op.as_mut() {
+                for (_, op) in subops.iter_mut() {
+                    reorder_for_pressure(op);
+                }
+            }
+        }
+        Operators::Evict(..)
+        | Operators::Copy(..)
+        | Operators::Sync(_)
+        | Operators::Barrier
+        | Operators::NoOp => {}
+    }
+}
+
+/// Whether no operand subtree uses data computed in another one, e.g. through
+/// a shared subexpression.
+fn independent<D>(subops: &[(D, Operators<D>)]) -> bool
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    let subtrees = subops
+        .iter()
+        .map(|(_, op)| {
+            let steps = op.linearize_branches();
+            let produced = steps
+                .iter()
+                .filter_map(|x| match x {
+                    Operators::Compute(_, _, dst, _, _) => Some(dst.clone()),
+                    _ => None,
+                })
+                .collect::<HashSet<_>>();
+            let used = steps
+                .iter()
+                .flat_map(|x| match x {
+                    Operators::Compute(_, _, _, args, _) => {
+                        args.iter().map(|x| x.0.clone()).collect()
+                    }
+                    _ => vec![],
+                })
+                .filter(|x| !produced.contains(x))
+                .collect::<HashSet<_>>();
+            (produced, used)
+        })
+        .collect::<Vec<_>>();
+    subtrees.iter().enumerate().all(|(i, (_, used))| {
+        subtrees
+            .iter()
+            .enumerate()
+            .all(|(j, (produced, _))| i == j || used.is_disjoint(produced))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::Instruction;
+
+    fn load(data: u32) -> (u32, Operators<u32>) {
+        let host = Operators::Load("host".into(), (data, Box::new(Operators::NoOp)), 4);
+        (
+            data,
+            Operators::Load("sram".into(), (data, Box::new(host)), 4),
+        )
+    }
+
+    fn compute(dst: u32, args: Vec<(u32, Operators<u32>)>) -> Operators<u32> {
+        Operators::Compute("sram".into(), 0, dst, args, 4.into())
+    }
+
+    #[test]
+    fn hungriest_operand_is_evaluated_first() {
+        // 5 = f(1, 4 = g(2, 3))
+        let mut op = compute(5, vec![load(1), (4, compute(4, vec![load(2), load(3)]))]);
+        // 1 is held while 2, 3 and 4 are resident
+        assert_eq!(pressure(&op), 16);
+        reorder_for_pressure(&mut op);
+        assert_eq!(pressure(&op), 12);
+        let order = op
+            .linearize()
+            .iter()
+            .map(|x| x.compile())
+            .collect::<Vec<_>>();
+        assert_eq!(order[0], "(load host 2)");
+    }
+}
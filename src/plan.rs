@@ -0,0 +1,380 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+use crate::{
+    config::MemoryConfig,
+    sim::{MemError, OomReport, Operators, SimError},
+};
+
+/// How the planner picks what to evict once no dead data is left to drop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlanPolicy {
+    /// Belady: the data needed again the latest
+    FarthestNextUse,
+    LeastRecentlyUsed,
+    /// The largest data, to evict as few as possible
+    Largest,
+}
+
+/// A device-resident piece of data.
+#[derive(Clone, Debug)]
+struct Resident {
+    size: usize,
+    /// No up-to-date copy on the host
+    dirty: bool,
+    last_touch: usize,
+}
+
+struct Planner<'a, D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    config: &'a MemoryConfig,
+    policy: PlanPolicy,
+    regions: HashMap<String, HashMap<D, Resident>>,
+    host: HashSet<D>,
+    sizes: HashMap<D, usize>,
+    /// Positions of the instructions reading each data, ascending
+    uses: HashMap<D, Vec<usize>>,
+    step: usize,
+    schedule: Vec<Operators<D>>,
+}
+
+impl<'a, D> Planner<'a, D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    fn next_use(&self, data: &D) -> Option<usize> {
+        let uses = self.uses.get(data)?;
+        uses.get(uses.partition_point(|&x| x <= self.step)).cloned()
+    }
+
+    fn region(&mut self, region: &str) -> Result<&mut HashMap<D, Resident>, SimError> {
+        if !self.config.regions.contains_key(region) {
+            return Err(SimError::UnknownRegion(region.into()));
+        }
+        Ok(self.regions.entry(region.into()).or_default())
+    }
+
+    fn allocated(&self, region: &str) -> usize {
+        let alignment = self.config.regions[region].alignment.max(1);
+        self.regions.get(region).map_or(0, |residents| {
+            residents
+                .values()
+                .map(|x| x.size.div_ceil(alignment) * alignment)
+                .sum()
+        })
+    }
+
+    /// Evicts from `region` until `needed` more bytes for `data` fit, never
+    /// touching `locked`. Dead data goes first, then the policy decides.
+    fn make_room(
+        &mut self,
+        region: &str,
+        data: &D,
+        needed: usize,
+        locked: &[&D],
+    ) -> Result<(), SimError> {
+        let capacity = self.config.regions[region].size;
+        if needed > capacity {
+            return Err(SimError::AllocationTooLarge {
+                region: region.into(),
+                data: format!("{:?}", data),
+                requested: needed,
+                capacity,
+            });
+        }
+        while self.allocated(region) + needed > capacity {
+            let residents = self.region(region)?;
+            let candidates = residents
+                .iter()
+                .filter(|(data, _)| !locked.contains(data))
+                .map(|(data, resident)| (data.clone(), resident.clone()))
+                .collect::<Vec<_>>();
+            let victim = candidates
+                .into_iter()
+                .max_by_key(|(data, resident)| {
+                    let next = self.next_use(data);
+                    let rank = match self.policy {
+                        PlanPolicy::FarthestNextUse => next.unwrap_or(usize::MAX),
+                        PlanPolicy::LeastRecentlyUsed => usize::MAX - resident.last_touch,
+                        PlanPolicy::Largest => resident.size,
+                    };
+                    (next.is_none(), rank)
+                })
+                .ok_or_else(|| self.thrash(region, data, needed, locked))?;
+            let (data, resident) = victim;
+            self.region(region)?.remove(&data);
+            if resident.dirty {
+                self.host.insert(data.clone());
+            }
+            self.schedule
+                .push(Operators::Evict(region.into(), data, resident.size));
+        }
+        Ok(())
+    }
+
+    fn thrash(&self, region: &str, data: &D, needed: usize, locked: &[&D]) -> SimError {
+        let mut residents = self.regions[region]
+            .iter()
+            .map(|(data, resident)| (format!("{:?}", data), resident.size))
+            .collect::<Vec<_>>();
+        residents.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        SimError::Thrash(Box::new(OomReport {
+            region: region.into(),
+            data: format!("{:?}", data),
+            requested: needed,
+            allocated: self.allocated(region),
+            capacity: self.config.regions[region].size,
+            residents,
+            excluded: locked.iter().map(|x| format!("{:?}", x)).collect(),
+            pinned: vec![],
+            recent_evictions: vec![],
+        }))
+    }
+
+    /// Makes `data` resident on `region`, loading it from the host if needed.
+    fn ensure(&mut self, region: &str, data: &D, locked: &[&D]) -> Result<(), SimError> {
+        let step = self.step;
+        if let Some(resident) = self.region(region)?.get_mut(data) {
+            resident.last_touch = step;
+            return Ok(());
+        }
+        let size = self.sizes.get(data).cloned().unwrap_or_default();
+        self.make_room(region, data, size, locked)?;
+        self.region(region)?.insert(
+            data.clone(),
+            Resident {
+                size,
+                dirty: false,
+                last_touch: step,
+            },
+        );
+        self.schedule.push(Operators::Load(
+            region.into(),
+            (data.clone(), Box::new(Operators::NoOp)),
+            size,
+        ));
+        Ok(())
+    }
+
+    fn produce(&mut self, region: &str, data: &D, size: usize) -> Result<(), SimError> {
+        let step = self.step;
+        self.region(region)?.insert(
+            data.clone(),
+            Resident {
+                size,
+                dirty: true,
+                last_touch: step,
+            },
+        );
+        Ok(())
+    }
+
+    /// Plans the instruction `op` (without operand trees); `wrapped` is what
+    /// is emitted for it, `scratch` the transient bytes of wrappers around it
+    /// and `in_place` the operand an `InPlace` around it overwrites.
+    fn plan(
+        &mut self,
+        op: &Operators<D>,
+        wrapped: &Operators<D>,
+        scratch: usize,
+        in_place: Option<usize>,
+    ) -> Result<(), SimError> {
+        match op {
+            Operators::Load(region, (data, _), _) if region == "host" => {
+                self.host.insert(data.clone());
+                self.schedule.push(wrapped.clone());
+            }
+            Operators::Load(region, (data, _), _) => self.ensure(region, data, &[data])?,
+            Operators::Compute(region, _, dst, _, sizes) if region == "host" => {
+                self.host.insert(dst.clone());
+                self.sizes.insert(dst.clone(), sizes.output);
+                self.schedule.push(wrapped.clone());
+            }
+            Operators::Compute(region, _, dst, args, sizes) => {
+                let locked = args.iter().map(|x| &x.0).chain([dst]).collect::<Vec<_>>();
+                for (arg, _) in args.iter() {
+                    self.ensure(region, arg, &locked)?;
+                }
+                let alias = match in_place {
+                    Some(operand) => {
+                        let (src, _) = args.get(operand).ok_or(MemError::NoOperand {
+                            operand,
+                            operands: args.len(),
+                        })?;
+                        // as in the simulator, only an operand at least as
+                        // large as the output is overwritten
+                        let size = self.region(region)?.get(src).map_or(0, |x| x.size);
+                        Some(src).filter(|_| size >= sizes.output)
+                    }
+                    None => None,
+                };
+                match alias {
+                    Some(src) => {
+                        self.make_room(region, dst, sizes.scratch + scratch, &locked)?;
+                        self.region(region)?.remove(src);
+                    }
+                    None => {
+                        let needed = sizes.output + sizes.scratch + scratch;
+                        self.make_room(region, dst, needed, &locked)?;
+                    }
+                }
+                self.sizes.insert(dst.clone(), sizes.output);
+                self.produce(region, dst, sizes.output)?;
+                self.schedule.push(wrapped.clone());
+            }
+            Operators::Store(region, _, (data, _), _) => {
+                self.ensure(region, data, &[data])?;
+                // everything is written back and the region emptied
+                let residents = std::mem::take(self.region(region)?);
+                self.host.extend(residents.into_keys());
+                self.schedule.push(wrapped.clone());
+            }
+            Operators::Copy(src, dst, data, size) => {
+                self.ensure(src, data, &[data])?;
+                if !self.region(dst)?.contains_key(data) {
+                    self.make_room(dst, data, *size, &[data])?;
+                    self.produce(dst, data, *size)?;
+                    self.schedule.push(wrapped.clone());
+                }
+            }
+            Operators::Evict(region, data, size) => {
+                if let Some(resident) = self.region(region)?.remove(data) {
+                    if resident.dirty {
+                        self.host.insert(data.clone());
+                    }
+                    self.schedule
+                        .push(Operators::Evict(region.clone(), data.clone(), *size));
+                }
+            }
+            Operators::Scratch(bytes, op) => self.plan(op, wrapped, scratch + bytes, in_place)?,
+            Operators::InPlace(operand, op) => self.plan(op, wrapped, scratch, Some(*operand))?,
+            Operators::Pool(_, op) | Operators::Backing(_, op) | Operators::Dma(_, op) => {
+                self.plan(op, wrapped, scratch, in_place)?
+            }
+            // `linearize` unrolls loops, even wrapped ones
+            Operators::Repeat(..) | Operators::If(..) => {
+                return Err(SimError::Unsupported(format!(
+                    "{} has no static schedule of its own",
+                    op.kind()
+                )))
+            }
+            // synchronization points stay where they are
+            Operators::Sync(_) | Operators::Barrier => self.schedule.push(wrapped.clone()),
+            Operators::NoOp => {}
+        }
+        Ok(())
+    }
+}
+
+/// Plans `program` ahead of time for the regions of `config`: walks its
+/// instructions in execution order and decides every eviction and reload up
+/// front, dropping dead data first (liveness) and picking other victims by
+/// `policy`. The result is a flat, static schedule for a code generator, with
+/// explicit `Evict`s and reloading `Load`s, that needs no eviction decisions
+/// when run (see `replay`) and passes `validate::check`. A program with an
+/// `If` has no such schedule and is rejected.
+pub fn plan<D>(
+    program: &Operators<D>,
+    config: &MemoryConfig,
+    policy: PlanPolicy,
+) -> Result<Vec<Operators<D>>, SimError>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    let steps = program.linearize();
+    let mut planner = Planner {
+        config,
+        policy,
+        regions: HashMap::default(),
+        host: HashSet::default(),
+        sizes: HashMap::default(),
+        uses: HashMap::default(),
+        step: 0,
+        schedule: vec![],
+    };
+    for (i, op) in steps.iter().enumerate() {
+        match op.unwrap_wrappers() {
+            Operators::Load(_, (data, _), size) => {
+                planner.sizes.insert(data.clone(), *size);
+                planner.uses.entry(data.clone()).or_default().push(i);
+            }
+            Operators::Compute(_, _, _, args, sizes) => {
+                for (j, (data, _)) in args.iter().enumerate() {
+                    planner.uses.entry(data.clone()).or_default().push(i);
+                    if let Some(size) = sizes.operands.get(j) {
+                        planner.sizes.entry(data.clone()).or_insert(*size);
+                    }
+                }
+            }
+            Operators::Store(_, _, (data, _), _) | Operators::Copy(_, _, data, _) => {
+                planner.uses.entry(data.clone()).or_default().push(i);
+            }
+            _ => {}
+        }
+    }
+    for (i, op) in steps.iter().enumerate() {
+        planner.step = i;
+        planner.plan(op, op, 0, None)?;
+    }
+    Ok(planner.schedule)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(size: usize) -> MemoryConfig {
+        MemoryConfig::from_toml(&format!("[regions.sram]\nsize = {}\n", size)).unwrap()
+    }
+
+    fn load(data: u32) -> (u32, Operators<u32>) {
+        let host = Operators::Load("host".into(), (data, Box::new(Operators::NoOp)), 4);
+        (
+            data,
+            Operators::Load("sram".into(), (data, Box::new(host)), 4),
+        )
+    }
+
+    fn compute(op: u32, dst: u32, args: Vec<(u32, Operators<u32>)>) -> Operators<u32> {
+        Operators::Compute("sram".into(), op, dst, args, 4.into())
+    }
+
+    #[test]
+    fn branches_are_rejected() {
+        let branch = |op| Box::new(compute(op, 3, vec![load(1)]));
+        let program = Operators::If(0, branch(10), branch(11));
+        let error = plan(&program, &config(64), PlanPolicy::FarthestNextUse).unwrap_err();
+        assert!(matches!(error, SimError::Unsupported(_)));
+    }
+
+    #[test]
+    fn wrapped_loops_are_unrolled() {
+        let body = compute(10, 3, vec![load(1)]);
+        let program = Operators::Scratch(4, Box::new(Operators::Repeat(2, Box::new(body))));
+        let schedule = plan(&program, &config(64), PlanPolicy::FarthestNextUse).unwrap();
+        let computes = schedule.iter().filter(|x| x.kind() == "compute").count();
+        assert_eq!(computes, 2);
+    }
+
+    #[test]
+    fn in_place_survives_other_wrappers() {
+        let in_place = Operators::InPlace(0, Box::new(compute(10, 3, vec![load(1)])));
+        let program = Operators::Pool("acts".into(), Box::new(in_place));
+        let schedule = plan(&program, &config(4), PlanPolicy::FarthestNextUse).unwrap();
+        assert_eq!(schedule.len(), 3);
+        assert!(crate::validate::check(&schedule, &config(4)).is_ok());
+    }
+
+    #[test]
+    fn in_place_into_a_larger_output_needs_its_own_buffer() {
+        let wide = Operators::Compute("sram".into(), 10, 3, vec![load(1)], 8.into());
+        let program = Operators::InPlace(0, Box::new(wide));
+        assert!(plan(&program, &config(8), PlanPolicy::FarthestNextUse).is_err());
+        let schedule = plan(&program, &config(12), PlanPolicy::FarthestNextUse).unwrap();
+        assert!(crate::validate::check(&schedule, &config(12)).is_ok());
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+use crate::sim::{
+    Heuristic, HostMemory, JitSim, MemError, Memory, Operators, SimError, TargetMemory,
+};
+
+/// Heuristic of a replay: the schedule already contains every eviction, so
+/// none is ever chosen and an allocation that does not fit fails with
+/// `SimError::Thrash`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoEviction;
+
+impl<D> Heuristic<D> for NoEviction
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    fn choose<TM: Memory<D>>(&mut self, _sram: &TM, _exclude: &HashSet<D>) -> Option<D> {
+        None
+    }
+
+    fn touch(&mut self, _data: &D, _size: usize) {}
+
+    fn evict(&mut self, _data: &D) {}
+
+    fn reset(&mut self) {}
+}
+
+/// Where a replayed schedule stopped being consistent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplayError {
+    /// Position of the failing instruction in the schedule
+    pub step: usize,
+    pub error: SimError,
+}
+
+impl std::fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "step {}: {}", self.step, self.error)
+    }
+}
+
+impl std::error::Error for ReplayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Re-executes an exported schedule (e.g. `JitSim::schedule`, possibly
+/// read back with `Operators::from_json`) exactly as written, to check that
+/// it is self-consistent and recompute its statistics on `sim`. Whatever a
+/// compute, store or copy reads has to be resident already and every
+/// eviction has to be in the schedule: nothing is reloaded or evicted on
+/// demand. With
+/// `JitSim::with_use_checks`, a missing operand is reported with the step
+/// that evicted it.
+pub fn replay<D, TM, HM>(
+    sim: &mut JitSim<NoEviction, D>,
+    schedule: &[Operators<D>],
+    srams: &mut HashMap<String, TM>,
+    dram: &mut HM,
+) -> Result<(), ReplayError>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+    TM: TargetMemory<D>,
+    HM: HostMemory<D>,
+{
+    sim.plan_schedule(schedule);
+    for (step, op) in schedule.iter().enumerate() {
+        let missing = reads(op)
+            .into_iter()
+            .find(|(region, data)| srams.get(*region).is_some_and(|x| !x.contains(data)));
+        if let Some((_, data)) = missing {
+            return Err(ReplayError {
+                step,
+                error: sim.explain(data, MemError::not_resident("SRAM", data)),
+            });
+        }
+        sim.execute(op, srams, dram)
+            .map_err(|error| ReplayError { step, error })?;
+    }
+    Ok(())
+}
+
+/// The device data `op` reads, with their regions: the operands of a
+/// compute, the data of a store and the source of a copy.
+fn reads<D>(op: &Operators<D>) -> Vec<(&String, &D)>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    match op.unwrap_wrappers() {
+        Operators::Compute(region, _, _, args, _) => args.iter().map(|x| (region, &x.0)).collect(),
+        Operators::Store(region, _, (data, _), _) | Operators::Copy(region, _, data, _) => {
+            vec![(region, data)]
+        }
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heuristics::LRU;
+    use crate::memory::{DRAM, SRAM};
+
+    fn load(region: &str, data: u32) -> Operators<u32> {
+        Operators::Load(region.into(), (data, Box::new(Operators::NoOp)), 4)
+    }
+
+    fn memories() -> (HashMap<String, SRAM<u32>>, DRAM<u32>) {
+        let srams = HashMap::from([
+            ("a".to_string(), SRAM::new(16)),
+            ("b".to_string(), SRAM::new(16)),
+        ]);
+        (srams, DRAM::new())
+    }
+
+    #[test]
+    fn recorded_schedule_replays() {
+        let operand = |data| {
+            let op = Operators::Load("a".into(), (data, Box::new(load("host", data))), 4);
+            (data, op)
+        };
+        let mut program =
+            Operators::Compute("a".into(), 10, 3, vec![operand(1), operand(2)], 4.into());
+        let (mut srams, mut dram) = memories();
+        let mut sim = JitSim::new(LRU::new()).with_liveness().with_refcounting();
+        sim.run(&mut program, &mut srams, &mut dram).unwrap();
+
+        let (mut srams, mut dram) = memories();
+        let mut replayed = JitSim::new(NoEviction).with_liveness().with_refcounting();
+        replay(&mut replayed, &sim.schedule(), &mut srams, &mut dram).unwrap();
+        assert_eq!(replayed.sim_stats().loads, sim.sim_stats().loads);
+    }
+
+    #[test]
+    fn copy_of_evicted_data_is_not_reloaded() {
+        let (mut srams, mut dram) = memories();
+        let schedule = vec![
+            load("host", 1),
+            load("a", 1),
+            Operators::Evict("a".into(), 1, 4),
+            Operators::Copy("a".into(), "b".into(), 1, 4),
+        ];
+        let mut sim = JitSim::new(NoEviction);
+        let error = replay(&mut sim, &schedule, &mut srams, &mut dram).unwrap_err();
+        assert_eq!(error.step, 3);
+        assert_eq!(sim.sim_stats().rematerializations, 0);
+    }
+}
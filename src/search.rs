@@ -0,0 +1,470 @@
+use std::{collections::HashMap, hash::Hash};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    heuristics::Scripted,
+    sim::{HostMemory, JitSim, Operators, SimError, SimStats, TargetMemory},
+};
+
+/// Best eviction script found by a search, see `Scripted`.
+#[derive(Clone, Debug)]
+pub struct Searched<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    /// DRAM trips of the script-less run, i.e. of LRU
+    pub baseline: usize,
+    /// Fewest DRAM trips found
+    pub trips: usize,
+    pub script: Vec<usize>,
+    pub stats: SimStats,
+    /// The run of `script`, as given by `JitSim::schedule`
+    pub schedule: Vec<Operators<D>>,
+    /// Complete or partial runs simulated by the search
+    pub evaluations: usize,
+}
+
+/// Runs `trace` from a fork of `sim` and of the memories (see `JitSim::fork`)
+/// with its eviction decisions taken by `script`.
+fn evaluate<D, TM, HM>(
+    sim: &JitSim<Scripted<D>, D>,
+    script: Vec<usize>,
+    trace: &Operators<D>,
+    srams: &HashMap<String, TM>,
+    dram: &HM,
+) -> Result<JitSim<Scripted<D>, D>, SimError>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+    TM: TargetMemory<D> + Clone,
+    HM: HostMemory<D> + Clone,
+{
+    let (mut sim, mut srams, mut dram) = sim.fork(srams, dram);
+    sim.heuristic = Scripted::new(script);
+    sim.run(&mut trace.clone(), &mut srams, &mut dram)?;
+    Ok(sim)
+}
+
+/// Parameters of `anneal`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AnnealOptions {
+    /// Perturbed runs to simulate
+    pub iterations: usize,
+    /// In DRAM trips: how much worse a perturbation accepted with
+    /// probability 1/e can be at the start
+    pub temperature: f64,
+    /// Factor applied to the temperature after every iteration
+    pub cooling: f64,
+    pub seed: u64,
+}
+
+impl Default for AnnealOptions {
+    fn default() -> Self {
+        Self {
+            iterations: 1000,
+            temperature: 2.0,
+            cooling: 0.995,
+            seed: 0,
+        }
+    }
+}
+
+/// Searches by simulated annealing for eviction decisions with fewer DRAM
+/// trips than LRU. Starting from the LRU run, every iteration changes the
+/// candidate taken at one eviction, replays `trace` from a fork of `sim` and
+/// of the memories, and keeps the change if it is no worse or, with a
+/// probability falling with the temperature, even if it is. Perturbations
+/// that cannot run (e.g. that thrash) are rejected; an error is only
+/// returned if the LRU run fails.
+pub fn anneal<D, TM, HM>(
+    sim: &JitSim<Scripted<D>, D>,
+    trace: &Operators<D>,
+    srams: &HashMap<String, TM>,
+    dram: &HM,
+    options: &AnnealOptions,
+) -> Result<Searched<D>, SimError>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+    TM: TargetMemory<D> + Clone,
+    HM: HostMemory<D> + Clone,
+{
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let run = evaluate(sim, vec![], trace, srams, dram)?;
+    let baseline = run.sim_stats().dram_trips();
+    let mut current = (run.heuristic.options().to_vec(), baseline);
+    let mut script = vec![0; current.0.len()];
+    let mut best = (script.clone(), run);
+    let mut temperature = options.temperature;
+    let mut evaluations = 1;
+    for _ in 0..options.iterations {
+        let decisions = current
+            .0
+            .iter()
+            .enumerate()
+            .filter(|x| *x.1 > 1)
+            .map(|x| x.0)
+            .collect::<Vec<_>>();
+        if decisions.is_empty() {
+            break;
+        }
+        let k = decisions[rng.gen_range(0..decisions.len())];
+        let mut candidate = script.clone();
+        // another candidate than the current one
+        candidate[k] = (candidate[k] + rng.gen_range(1..current.0[k])) % current.0[k];
+        evaluations += 1;
+        temperature *= options.cooling;
+        let run = match evaluate(sim, candidate.clone(), trace, srams, dram) {
+            Ok(run) => run,
+            Err(_) => continue,
+        };
+        let trips = run.sim_stats().dram_trips();
+        let delta = trips as f64 - current.1 as f64;
+        if delta > 0.0 && rng.gen::<f64>() >= (-delta / temperature.max(1e-9)).exp() {
+            continue;
+        }
+        // decisions past the change may be new, or gone
+        let options = run.heuristic.options().to_vec();
+        candidate.resize(options.len(), 0);
+        if trips < best.1.sim_stats().dram_trips() {
+            best = (candidate.clone(), run);
+        }
+        script = candidate;
+        current = (options, trips);
+    }
+    let (script, run) = best;
+    Ok(Searched {
+        baseline,
+        trips: run.sim_stats().dram_trips(),
+        script,
+        stats: run.sim_stats().clone(),
+        schedule: run.schedule(),
+        evaluations,
+    })
+}
+
+/// A simulation stopped between two instructions, with the choices it still
+/// has to take in the current one.
+type State<D, TM, HM> = (JitSim<Scripted<D>, D>, HashMap<String, TM>, HM);
+
+/// Runs `op` from a fork of `state`, taking `choices` at its first
+/// eviction decisions, with the analyses `state` was planned with.
+fn step<D, TM, HM>(
+    state: &State<D, TM, HM>,
+    choices: &[usize],
+    op: &Operators<D>,
+) -> Result<State<D, TM, HM>, SimError>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+    TM: TargetMemory<D> + Clone,
+    HM: HostMemory<D> + Clone,
+{
+    let (mut sim, mut srams, mut dram) = state.0.fork(&state.1, &state.2);
+    for choice in choices.iter() {
+        sim.heuristic.push(*choice);
+    }
+    sim.execute(op, &mut srams, &mut dram)?;
+    Ok((sim, srams, dram))
+}
+
+/// Partial cost of a state: its DRAM trips so far, which the rest of the run
+/// can only add to, then its bytes moved.
+fn cost<D, TM, HM>(state: &State<D, TM, HM>) -> (usize, usize)
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+{
+    let stats = state.0.sim_stats();
+    (stats.dram_trips(), stats.bytes_transferred)
+}
+
+/// Schedules `trace` by beam search over its eviction decisions: it runs
+/// instruction by instruction (see `Operators::linearize`) from forks of
+/// `sim` and of the memories, and at every eviction branches on each
+/// candidate, keeping only the `width` branches with the fewest DRAM trips so
+/// far (with the later decisions of the instruction left to LRU). Wider
+/// beams take longer and find better schedules.
+pub fn beam_search<D, TM, HM>(
+    sim: &JitSim<Scripted<D>, D>,
+    trace: &Operators<D>,
+    srams: &HashMap<String, TM>,
+    dram: &HM,
+    width: usize,
+) -> Result<Searched<D>, SimError>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+    TM: TargetMemory<D> + Clone,
+    HM: HostMemory<D> + Clone,
+{
+    let width = width.max(1);
+    let steps = trace.linearize();
+    let mut baseline = sim.fork(srams, dram);
+    baseline
+        .0
+        .run_schedule(&steps, &mut baseline.1, &mut baseline.2)?;
+    let mut evaluations = 1;
+    let mut start = sim.fork(srams, dram);
+    // planned once, every fork carries the analyses of the whole trace
+    start.0.plan_schedule(&steps);
+    let mut beam = vec![start];
+    for op in steps.iter() {
+        let mut error = None;
+        let mut run = |start: &State<D, TM, HM>, choices: &[usize]| {
+            evaluations += 1;
+            step(start, choices, op)
+                .map_err(|e| {
+                    error.get_or_insert(e);
+                })
+                .ok()
+        };
+        // runs of the instruction, each with the choices it took so far
+        let mut pending = beam
+            .drain(..)
+            .filter_map(|start| {
+                let state = run(&start, &[])?;
+                Some((start, vec![], state))
+            })
+            .collect::<Vec<_>>();
+        let mut done = vec![];
+        while !pending.is_empty() {
+            let mut branches = vec![];
+            for (start, choices, state) in pending.drain(..) {
+                // the first decision taken past the choices, if any
+                let taken = start.0.heuristic.options().len() + choices.len();
+                let candidates = match state.0.heuristic.options().get(taken) {
+                    Some(&candidates) => candidates,
+                    None => {
+                        done.push(state);
+                        continue;
+                    }
+                };
+                for choice in 1..candidates {
+                    let mut choices = choices.clone();
+                    choices.push(choice);
+                    if let Some(state) = run(&start, &choices) {
+                        branches.push((start.clone(), choices, state));
+                    }
+                }
+                // the run so far took the first candidate
+                let mut choices = choices;
+                choices.push(0);
+                branches.push((start, choices, state));
+            }
+            branches.sort_by_key(|x| cost(&x.2));
+            branches.truncate(width);
+            pending = branches;
+        }
+        done.sort_by_key(cost);
+        done.truncate(width);
+        if done.is_empty() {
+            // every branch failed
+            return Err(error.unwrap());
+        }
+        beam = done;
+    }
+    let (run, _, _) = beam.remove(0);
+    let mut script = run.heuristic.script().to_vec();
+    script.resize(run.heuristic.options().len(), 0);
+    Ok(Searched {
+        baseline: baseline.0.sim_stats().dram_trips(),
+        trips: run.sim_stats().dram_trips(),
+        script,
+        stats: run.sim_stats().clone(),
+        schedule: run.schedule(),
+        evaluations,
+    })
+}
+
+/// Parameters of `evolve`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GeneticOptions {
+    /// Scripts per generation
+    pub population: usize,
+    pub generations: usize,
+    /// Probability for every decision of a child to be drawn anew
+    pub mutation_rate: f64,
+    pub seed: u64,
+}
+
+impl Default for GeneticOptions {
+    fn default() -> Self {
+        Self {
+            population: 32,
+            generations: 50,
+            mutation_rate: 0.05,
+            seed: 0,
+        }
+    }
+}
+
+/// Searches with a genetic algorithm for eviction decisions with fewer DRAM
+/// trips than LRU. A genome is an eviction script (see `Scripted`) and its
+/// fitness the DRAM trips of running `trace` with it from a fork of `sim`
+/// and of the memories. The first generation is LRU and mutations of it;
+/// every next one keeps the two fittest scripts and fills up with children
+/// of tournament-selected parents, crossed over at one decision and mutated.
+/// Children that cannot run (e.g. that thrash) are replaced by their first
+/// parent; an error is only returned if the LRU run fails.
+pub fn evolve<D, TM, HM>(
+    sim: &JitSim<Scripted<D>, D>,
+    trace: &Operators<D>,
+    srams: &HashMap<String, TM>,
+    dram: &HM,
+    options: &GeneticOptions,
+) -> Result<Searched<D>, SimError>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone + Ord,
+    TM: TargetMemory<D> + Clone,
+    HM: HostMemory<D> + Clone,
+{
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let size = options.population.max(2);
+    let mut evaluations = 0;
+    let mut individual = |script: Vec<usize>| {
+        evaluations += 1;
+        let run = evaluate(sim, script.clone(), trace, srams, dram)?;
+        // decisions past the script took the default
+        let mut script = script;
+        script.resize(run.heuristic.options().len(), 0);
+        Ok::<_, SimError>((script, run))
+    };
+    let lru = individual(vec![])?;
+    let baseline = lru.1.sim_stats().dram_trips();
+    let mutate = |script: &mut Vec<usize>, candidates: &[usize], rng: &mut StdRng| {
+        for (gene, &n) in script.iter_mut().zip(candidates.iter()) {
+            if n > 1 && rng.gen::<f64>() < options.mutation_rate {
+                *gene = rng.gen_range(0..n);
+            }
+        }
+    };
+    let fitness = |x: &(Vec<usize>, JitSim<Scripted<D>, D>)| x.1.sim_stats().dram_trips();
+    let mut population = vec![lru.clone()];
+    while population.len() < size {
+        let mut script = lru.0.clone();
+        mutate(&mut script, lru.1.heuristic.options(), &mut rng);
+        population.push(individual(script).unwrap_or_else(|_| lru.clone()));
+    }
+    for _ in 0..options.generations {
+        population.sort_by_key(fitness);
+        let mut next = population[..2].to_vec();
+        while next.len() < size {
+            let mut select = || {
+                let a = &population[rng.gen_range(0..size)];
+                let b = &population[rng.gen_range(0..size)];
+                if fitness(a) <= fitness(b) {
+                    a
+                } else {
+                    b
+                }
+            };
+            let (first, second) = (select(), select());
+            let cut = rng.gen_range(0..=first.0.len());
+            let mut script = first.0[..cut].to_vec();
+            script.extend(second.0.iter().skip(cut));
+            mutate(&mut script, first.1.heuristic.options(), &mut rng);
+            next.push(individual(script).unwrap_or_else(|_| first.clone()));
+        }
+        population = next;
+    }
+    let (script, run) = population.into_iter().min_by_key(fitness).unwrap();
+    Ok(Searched {
+        baseline,
+        trips: run.sim_stats().dram_trips(),
+        script,
+        stats: run.sim_stats().clone(),
+        schedule: run.schedule(),
+        evaluations,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{DRAM, SRAM};
+
+    fn load(data: u32) -> (u32, Operators<u32>) {
+        let host = Operators::Load("host".into(), (data, Box::new(Operators::NoOp)), 4);
+        (
+            data,
+            Operators::Load("sram".into(), (data, Box::new(host)), 4),
+        )
+    }
+
+    /// A chain of computes over the inputs `0..4` twice over, the second time
+    /// through operands produced earlier in the tree.
+    fn chain() -> Operators<u32> {
+        let mut prev = None;
+        for (i, x) in [0, 1, 2, 3, 0, 1, 2, 3].into_iter().enumerate() {
+            let mut args = vec![if i < 4 { load(x) } else { (x, Operators::NoOp) }];
+            args.extend(prev.take());
+            let dst = 100 + i as u32;
+            prev = Some((
+                dst,
+                Operators::Compute("sram".into(), 1, dst, args, 4.into()),
+            ));
+        }
+        prev.unwrap().1
+    }
+
+    #[test]
+    fn beam_search_plans_the_whole_trace() {
+        let srams = HashMap::from([("sram".to_string(), SRAM::new(64))]);
+        let sim = JitSim::new(Scripted::new(vec![]))
+            .with_liveness()
+            .with_refcounting();
+        let searched = beam_search(&sim, &chain(), &srams, &DRAM::new(), 2).unwrap();
+        assert_eq!(searched.stats.loads, 4);
+        assert_eq!(searched.trips, searched.baseline);
+    }
+
+    #[test]
+    fn anneal_keeps_the_best_script_it_replayed() {
+        let srams = HashMap::from([("sram".to_string(), SRAM::new(16))]);
+        let sim = JitSim::new(Scripted::new(vec![]))
+            .with_liveness()
+            .with_refcounting();
+        let options = AnnealOptions {
+            iterations: 50,
+            ..Default::default()
+        };
+        let searched = anneal(&sim, &chain(), &srams, &DRAM::new(), &options).unwrap();
+        assert!(searched.trips <= searched.baseline);
+        assert!(searched.evaluations > 1);
+        // the script replays to the reported trips
+        let run = evaluate(
+            &sim,
+            searched.script.clone(),
+            &chain(),
+            &srams,
+            &DRAM::new(),
+        );
+        assert_eq!(run.unwrap().sim_stats(), &searched.stats);
+        // and the same seed finds the same script
+        let again = anneal(&sim, &chain(), &srams, &DRAM::new(), &options).unwrap();
+        assert_eq!(again.script, searched.script);
+    }
+
+    #[test]
+    fn evolve_keeps_the_fittest_script() {
+        let srams = HashMap::from([("sram".to_string(), SRAM::new(16))]);
+        let sim = JitSim::new(Scripted::new(vec![]))
+            .with_liveness()
+            .with_refcounting();
+        let options = GeneticOptions {
+            population: 4,
+            generations: 3,
+            mutation_rate: 0.5,
+            seed: 1,
+        };
+        let searched = evolve(&sim, &chain(), &srams, &DRAM::new(), &options).unwrap();
+        assert!(searched.trips <= searched.baseline);
+        // LRU, three mutants, then two children per generation
+        assert_eq!(searched.evaluations, 4 + 3 * 2);
+        let run = evaluate(
+            &sim,
+            searched.script.clone(),
+            &chain(),
+            &srams,
+            &DRAM::new(),
+        );
+        assert_eq!(run.unwrap().sim_stats(), &searched.stats);
+    }
+}
@@ -1,13 +1,16 @@
 use std::{
-    borrow::BorrowMut,
-    collections::{HashMap, HashSet},
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
     hash::Hash,
+    io::Write,
+    sync::{Arc, Mutex},
 };
 
-use egg::Id;
-use log::info;
+use log::{info, warn};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
 
-use crate::memory::{DRAM, SRAM};
+use crate::{dag::Dag, heuristics::RandomEviction};
 
 pub trait Simulator<I, D>
 where
@@ -18,24 +21,121 @@ where
     fn run_insn(&mut self, insns: I) -> usize;
 }
 
+/// A plain step-based simulator for any `Instruction` stream: every
+/// instruction runs on one target memory and the host memory as it is, with
+/// no eviction or rematerialization, and costs a fixed number of cycles by
+/// its `InsnType` (1 unless set with `with_cost`). The first failing
+/// instruction is kept in `error`; nothing runs after it.
+pub struct StepSim<TM, HM> {
+    pub sram: TM,
+    pub dram: HM,
+    costs: HashMap<InsnType, usize>,
+    steps: usize,
+    cycles: usize,
+    error: Option<(usize, MemError)>,
+}
+
+impl<TM, HM> StepSim<TM, HM> {
+    pub fn new(sram: TM, dram: HM) -> Self {
+        Self {
+            sram,
+            dram,
+            costs: HashMap::default(),
+            steps: 0,
+            cycles: 0,
+            error: None,
+        }
+    }
+
+    /// Cycles charged for each instruction of type `insn_type`.
+    pub fn with_cost(mut self, insn_type: InsnType, cycles: usize) -> Self {
+        self.costs.insert(insn_type, cycles);
+        self
+    }
+
+    /// Instructions run so far.
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// Total cost of the instructions run so far.
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    /// The step that failed, and why.
+    pub fn error(&self) -> Option<&(usize, MemError)> {
+        self.error.as_ref()
+    }
+}
+
+impl<I, D, TM, HM> Simulator<I, D> for StepSim<TM, HM>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+    I: Instruction<D>,
+    TM: TargetMemory<D>,
+    HM: HostMemory<D>,
+{
+    fn initialize(&mut self) {
+        self.sram.reset();
+        self.dram.reset();
+        self.steps = 0;
+        self.cycles = 0;
+        self.error = None;
+    }
+
+    fn run_insn(&mut self, insn: I) -> usize {
+        if self.error.is_some() {
+            return 0;
+        }
+        info!("Step {}: {}", self.steps, insn.compile());
+        if let Err(e) = insn.run(Some(&mut self.sram), &mut self.dram) {
+            self.error = Some((self.steps, e));
+            return 0;
+        }
+        let cost = self.costs.get(&insn.insn_type()).cloned().unwrap_or(1);
+        self.steps += 1;
+        self.cycles += cost;
+        cost
+    }
+}
+
 pub trait DTR<I, D, TM, HM>
 where
     D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
     I: Instruction<D>,
-    HM: Memory<D>,
-    TM: Memory<D>,
+    HM: HostMemory<D>,
+    TM: TargetMemory<D>,
 {
-    fn rematerialize(&mut self, data: &D, sram: &mut TM, dram: &mut HM, exclude: &HashSet<D>);
+    fn rematerialize(
+        &mut self,
+        data: &D,
+        sram: &mut TM,
+        dram: &mut HM,
+        exclude: &HashSet<D>,
+    ) -> Result<(), SimError>;
     fn perform_op(
         &mut self,
         op: &I,
         srams: &mut HashMap<String, TM>,
         dram: &mut HM,
         exclude: &HashSet<D>,
-    );
-    fn allocate_buffer(&mut self, size: usize, mem: &mut TM, dram: &mut HM, exclude: &HashSet<D>);
-    fn evict_single(&mut self, exclude: &HashSet<D>, mem: &mut TM, dram: &mut HM);
-    fn deallocate(&mut self, data: &D, mem: &mut TM, dram: &mut HM);
+    ) -> Result<(), SimError>;
+    fn allocate_buffer(
+        &mut self,
+        data: &D,
+        size: usize,
+        mem: &mut TM,
+        dram: &mut HM,
+        exclude: &HashSet<D>,
+    ) -> Result<(), SimError>;
+    fn evict_single(
+        &mut self,
+        exclude: &HashSet<D>,
+        mem: &mut TM,
+        dram: &mut HM,
+    ) -> Result<(), SimError>;
+    fn deallocate(&mut self, data: &D, mem: &mut TM, dram: &mut HM) -> Result<(), SimError>;
 }
 
 pub trait Heuristic<D>
@@ -48,38 +148,638 @@ where
     fn reset(&mut self);
 }
 
+/// Callbacks on what a `JitSim` does, to collect custom metrics or drive a
+/// visualization. Every callback does nothing by default.
+#[allow(unused_variables)]
+pub trait Observer<D> {
+    /// `data` was loaded into `region` by a `Load` instruction
+    fn on_load(&mut self, region: &str, data: &D, size: usize) {}
+    /// `data` was written back from `region` to the host
+    fn on_store(&mut self, region: &str, data: &D, size: usize) {}
+    fn on_evict(&mut self, region: &str, data: &D, size: usize) {}
+    /// Evicted `data` was brought back into `region`, by a reload or, if
+    /// `recomputed`, by running its producer again
+    fn on_remat(&mut self, region: &str, data: &D, size: usize, recomputed: bool) {}
+    /// `op` computed `dst` on `region` in `cycles` (0 on the host)
+    fn on_compute(&mut self, region: &str, op: &D, dst: &D, size: usize, cycles: f64) {}
+    /// `data` was copied from region `src` to region `dst`
+    fn on_copy(&mut self, src: &str, dst: &str, data: &D, size: usize) {}
+}
+
+/// A structured measurement fed to a `MetricsSink`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Metric<'a> {
+    /// `bytes` crossed the bus between `region` and the host
+    BytesMoved { region: &'a str, bytes: usize },
+    /// An instruction of `kind` (see `Operators::kind`) finished on `region`
+    OpExecuted { region: &'a str, kind: &'static str },
+    /// `bytes` were evicted from `region`, `how` as in `Departure`
+    Eviction {
+        region: &'a str,
+        bytes: usize,
+        how: &'static str,
+    },
+}
+
+impl Metric<'_> {
+    /// Counter name and region of the metric, and how much it adds to it.
+    pub fn sample(&self) -> (&'static str, &str, f64) {
+        match self {
+            Metric::BytesMoved { region, bytes } => ("bytes_moved", region, *bytes as f64),
+            Metric::OpExecuted { region, .. } => ("ops_executed", region, 1.0),
+            Metric::Eviction { region, .. } => ("evictions", region, 1.0),
+        }
+    }
+}
+
+/// A metrics backend fed by a `JitSim` (e.g. Prometheus-style counters).
+/// Unlike an `Observer`, it does not depend on the data type.
+pub trait MetricsSink {
+    fn record(&mut self, metric: &Metric);
+}
+
+/// A `MetricsSink` summing every metric by counter name and region.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MetricCounters {
+    pub counters: BTreeMap<(String, String), f64>,
+}
+
+impl MetricCounters {
+    pub fn get(&self, name: &str, region: &str) -> f64 {
+        self.counters
+            .get(&(name.to_string(), region.to_string()))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl MetricsSink for MetricCounters {
+    fn record(&mut self, metric: &Metric) {
+        let (name, region, value) = metric.sample();
+        *self
+            .counters
+            .entry((name.to_string(), region.to_string()))
+            .or_default() += value;
+    }
+}
+
+/// What a `JitSim` did, as recorded by `JitSim::record_events`. Renders as
+/// one line of text with `Display`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SimEvent<D> {
+    /// `data` was loaded into `region` by a `Load` instruction
+    Load {
+        region: String,
+        data: D,
+        size: usize,
+    },
+    /// `data` was written back from `region` to the host
+    Store {
+        region: String,
+        data: D,
+        size: usize,
+    },
+    /// `data` left `region`, `how` as in `Departure`
+    Evict {
+        region: String,
+        data: D,
+        size: usize,
+        how: String,
+    },
+    /// Evicted `data` was brought back into `region`, by a reload or, if
+    /// `recomputed`, by running its producer again
+    Remat {
+        region: String,
+        data: D,
+        size: usize,
+        recomputed: bool,
+    },
+    /// `op` computed `dst` on `region` in `cycles`
+    Compute {
+        region: String,
+        op: D,
+        dst: D,
+        size: usize,
+        cycles: f64,
+    },
+    Copy {
+        src: String,
+        dst: String,
+        data: D,
+        size: usize,
+    },
+}
+
+impl<D> SimEvent<D> {
+    /// The data loaded, stored, evicted, rematerialized, computed or copied.
+    pub fn data(&self) -> &D {
+        match self {
+            SimEvent::Load { data, .. }
+            | SimEvent::Store { data, .. }
+            | SimEvent::Evict { data, .. }
+            | SimEvent::Remat { data, .. }
+            | SimEvent::Copy { data, .. } => data,
+            SimEvent::Compute { dst, .. } => dst,
+        }
+    }
+
+    /// The region the event happened on, the destination for copies.
+    pub fn region(&self) -> &str {
+        match self {
+            SimEvent::Load { region, .. }
+            | SimEvent::Store { region, .. }
+            | SimEvent::Evict { region, .. }
+            | SimEvent::Remat { region, .. }
+            | SimEvent::Compute { region, .. } => region,
+            SimEvent::Copy { dst, .. } => dst,
+        }
+    }
+}
+
+impl<D: std::fmt::Debug> std::fmt::Display for SimEvent<D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SimEvent::Load { region, data, size } => {
+                write!(f, "load {:?} into {} ({} B)", data, region, size)
+            }
+            SimEvent::Store { region, data, size } => {
+                write!(f, "store {:?} from {} ({} B)", data, region, size)
+            }
+            SimEvent::Evict {
+                region,
+                data,
+                size,
+                how,
+            } => write!(f, "evict {:?} from {} ({} B, {})", data, region, size, how),
+            SimEvent::Remat {
+                region,
+                data,
+                size,
+                recomputed,
+            } => write!(
+                f,
+                "{} {:?} into {} ({} B)",
+                if *recomputed { "recompute" } else { "reload" },
+                data,
+                region,
+                size
+            ),
+            SimEvent::Compute {
+                region,
+                op,
+                dst,
+                size,
+                cycles,
+            } => write!(
+                f,
+                "compute {:?} = {:?} on {} ({} B, {} cycles)",
+                dst, op, region, size, cycles
+            ),
+            SimEvent::Copy {
+                src,
+                dst,
+                data,
+                size,
+            } => write!(f, "copy {:?} from {} to {} ({} B)", data, src, dst, size),
+        }
+    }
+}
+
+/// Failures of a memory operation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MemError {
+    /// Not enough (contiguous) room for an allocation of `requested`
+    OutOfMemory {
+        memory: &'static str,
+        requested: usize,
+        allocated: usize,
+        total: usize,
+    },
+    /// The data (rendered with `Debug`) is not resident in the memory
+    NotResident { memory: &'static str, data: String },
+    /// The data was banished and is required again
+    Banished { data: String },
+    /// An `InPlace` names an operand past those of its compute
+    NoOperand { operand: usize, operands: usize },
+    /// A `Pool` or `Backing` names a pool the memory does not have
+    UnknownPool { memory: &'static str, pool: String },
+    /// An instruction on a device region ran without a memory for it
+    NoMemory { region: String },
+}
+
+impl MemError {
+    pub fn not_resident<D: std::fmt::Debug>(memory: &'static str, data: &D) -> Self {
+        MemError::NotResident {
+            memory,
+            data: format!("{:?}", data),
+        }
+    }
+}
+
+impl std::fmt::Display for MemError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MemError::OutOfMemory {
+                memory,
+                requested,
+                allocated,
+                total,
+            } => write!(
+                f,
+                "OOM on {}: trying to allocate {}; usage: {} / {}",
+                memory, requested, allocated, total
+            ),
+            MemError::NotResident { memory, data } => {
+                write!(f, "no residence has id {} in {}", data, memory)
+            }
+            MemError::Banished { data } => {
+                write!(f, "{} was banished and cannot be rematerialized", data)
+            }
+            MemError::NoOperand { operand, operands } => write!(
+                f,
+                "in place over operand {} of a compute with {} operands",
+                operand, operands
+            ),
+            MemError::UnknownPool { memory, pool } => write!(f, "no pool {} in {}", pool, memory),
+            MemError::NoMemory { region } => write!(f, "no memory provided for {}", region),
+        }
+    }
+}
+
+impl std::error::Error for MemError {}
+
+/// Why a simulation could not go on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SimError {
+    /// An allocation does not fit even after evicting everything that is
+    /// not an operand of the current instruction
+    Thrash(Box<OomReport>),
+    /// A single allocation is larger than the whole region
+    AllocationTooLarge {
+        region: String,
+        data: String,
+        requested: usize,
+        capacity: usize,
+    },
+    /// An instruction names a region without a memory
+    UnknownRegion(String),
+    /// An instruction that cannot run where it is, e.g. an `If` in a program
+    /// planned ahead of time
+    Unsupported(String),
+    Memory(MemError),
+    /// A device memory could not hold an allocation, with what it held
+    OutOfMemory(Box<OomReport>),
+    /// `error` is about data that was on the device before, see
+    /// `JitSim::with_use_checks`
+    UseAfterEvict {
+        data: String,
+        step: usize,
+        departure: Box<Departure>,
+        error: MemError,
+    },
+}
+
+/// When and how data last left a device region, see
+/// `JitSim::with_use_checks`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Departure {
+    pub step: usize,
+    pub region: String,
+    /// "written back", "deallocated" (clean), "dropped" (to recompute),
+    /// "dead" (liveness), "released" (last read) or "stored" (with its region)
+    pub how: &'static str,
+    /// Who decided it, for evictions
+    pub decision: Option<String>,
+}
+
+/// What a region held when an allocation on it failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OomReport {
+    pub region: String,
+    /// The data being allocated, rendered with `Debug`
+    pub data: String,
+    pub requested: usize,
+    pub allocated: usize,
+    pub capacity: usize,
+    /// Every resident with its size, largest first
+    pub residents: Vec<(String, usize)>,
+    /// Operands of the failing instruction, which could not be evicted
+    pub excluded: Vec<String>,
+    pub pinned: Vec<String>,
+    /// The last evictions before the failure, oldest first
+    pub recent_evictions: Vec<(String, Departure)>,
+}
+
+impl OomReport {
+    /// The smallest region the allocation fits in next to the operands and
+    /// pinned data, nothing else being resident (alignment aside).
+    pub fn min_capacity(&self) -> usize {
+        self.residents
+            .iter()
+            .filter(|x| self.excluded.contains(&x.0) || self.pinned.contains(&x.0))
+            .map(|x| x.1)
+            .sum::<usize>()
+            + self.requested
+    }
+}
+
+impl std::fmt::Display for OomReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "out of memory on {}: {} needs {} bytes, {} of {} allocated",
+            self.region, self.data, self.requested, self.allocated, self.capacity
+        )?;
+        writeln!(f, "residents:")?;
+        for (data, size) in self.residents.iter() {
+            let tag = if self.excluded.contains(data) {
+                " (operand)"
+            } else if self.pinned.contains(data) {
+                " (pinned)"
+            } else {
+                ""
+            };
+            writeln!(f, "  {} {}{}", data, size, tag)?;
+        }
+        writeln!(f, "recent evictions:")?;
+        for (data, departure) in self.recent_evictions.iter() {
+            writeln!(f, "  {} {}", data, departure)?;
+        }
+        write!(
+            f,
+            "it needs a region of at least {} bytes",
+            self.min_capacity()
+        )
+    }
+}
+
+impl std::fmt::Display for Departure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} from {} at step {}", self.how, self.region, self.step)?;
+        if let Some(decision) = self.decision.as_ref() {
+            write!(f, ", {}", decision)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<MemError> for SimError {
+    fn from(e: MemError) -> Self {
+        SimError::Memory(e)
+    }
+}
+
+impl SimError {
+    /// Whether the error means a region is too small for the program.
+    pub fn is_capacity(&self) -> bool {
+        matches!(
+            self,
+            SimError::Thrash(_)
+                | SimError::AllocationTooLarge { .. }
+                | SimError::OutOfMemory(_)
+                | SimError::Memory(MemError::OutOfMemory { .. })
+        )
+    }
+}
+
+impl std::fmt::Display for SimError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SimError::Thrash(report) => {
+                writeln!(f, "thrashing on {}: nothing left to evict", report.region)?;
+                report.fmt(f)
+            }
+            SimError::AllocationTooLarge {
+                region,
+                data,
+                requested,
+                capacity,
+            } => write!(
+                f,
+                "{} needs {} but {} only has {}",
+                data, requested, region, capacity
+            ),
+            SimError::UnknownRegion(region) => write!(f, "unknown region {}", region),
+            SimError::Unsupported(what) => write!(f, "unsupported: {}", what),
+            SimError::Memory(e) => e.fmt(f),
+            SimError::OutOfMemory(report) => report.fmt(f),
+            SimError::UseAfterEvict {
+                data,
+                departure,
+                error,
+                ..
+            } => write!(f, "{}; {} was {}", error, data, departure),
+        }
+    }
+}
+
+impl std::error::Error for SimError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SimError::Memory(e) | SimError::UseAfterEvict { error: e, .. } => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// What every memory level provides: residency, capacity and the cost of
+/// moving data in and out. Device-side scratchpads additionally implement
+/// `TargetMemory`, host-side backing stores `HostMemory`.
 pub trait Memory<D>
 where
     D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
 {
-    fn put(&mut self, data: &D, size: usize, from_self: bool) -> bool;
-    fn get(&self, data: &D) -> usize;
+    fn put(&mut self, data: &D, size: usize, from_self: bool) -> Result<(), MemError>;
+    fn get(&self, data: &D) -> Result<usize, MemError>;
     fn contains(&self, data: &D) -> bool;
     fn size_available(&self) -> usize;
     fn size_allocated(&self) -> usize;
     fn size_total(&self) -> usize;
-    fn size_of(&self, data: &D) -> Result<usize, ()>;
+    /// High-water mark of `size_allocated`, kept across `reset`
+    fn peak_allocated(&self) -> usize {
+        self.size_allocated()
+    }
+    /// Bytes per cycle to and from this memory; unlimited by default
+    fn bandwidth(&self) -> f64 {
+        f64::INFINITY
+    }
+    /// Fixed cost in cycles of every transaction touching this memory
+    fn latency(&self) -> f64 {
+        0.0
+    }
+    /// Bandwidth for transfers of `data`, for memories made of several
+    /// backing stores
+    fn bandwidth_of(&self, _data: &D) -> f64 {
+        self.bandwidth()
+    }
+    fn latency_of(&self, _data: &D) -> f64 {
+        self.latency()
+    }
+    fn size_of(&self, data: &D) -> Option<usize>;
     fn to_vec(&self) -> Vec<&D>;
-    fn store<HM: Memory<D>>(&mut self, data: &D, _evict: bool, other: &mut HM) {
-        other.put(data, self.get(data), false);
+    /// Residents together with their sizes
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a D, usize)>
+    where
+        D: 'a,
+    {
+        self.to_vec()
+            .into_iter()
+            .map(|x| (x, self.size_of(x).unwrap_or(0)))
+    }
+    /// Places `data` in the named pool or backing store; memories without
+    /// either ignore it.
+    fn assign_pool(&mut self, _data: &D, _pool: &str) -> Result<(), MemError> {
+        Ok(())
     }
     fn deallocate(&mut self, data: &D);
     fn reset(&mut self);
 }
 
-#[derive(Eq, PartialEq, Debug)]
+/// A memory next to the compute units, managed by the eviction heuristic.
+/// Memories are cloned to explore both branches of an `If`.
+pub trait TargetMemory<D>: Memory<D> + Clone
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    /// Whether a buffer of `size` for `data` can be placed right now without
+    /// evicting. Memories that model placement can refuse even if enough
+    /// bytes are free.
+    fn can_allocate(&self, _data: &D, size: usize) -> bool {
+        self.size_allocated() + size <= self.size_total()
+    }
+    /// Largest buffer for `data` that fits once everything else is gone,
+    /// e.g. one bank of a banked memory.
+    fn max_allocation(&self, _data: &D) -> usize {
+        self.size_total()
+    }
+    /// Whether `data` was modified on this memory since it was last written
+    /// back, i.e. evicting it requires a store even if the host has a copy.
+    fn is_dirty(&self, _data: &D) -> bool {
+        false
+    }
+    /// Residents as (data, size, offset); the offset is only known for
+    /// memories that model placement.
+    fn memory_map(&self) -> Vec<(D, usize, Option<usize>)> {
+        self.iter()
+            .map(|(x, size)| (x.clone(), size, None))
+            .collect()
+    }
+    /// Reserves `size` bytes of transient scratch for the compute producing
+    /// `owner`. Scratch is invisible to eviction and is reclaimed by
+    /// `end_compute`; memories that do not model it ignore the request.
+    fn put_scratch(&mut self, _owner: &D, _size: usize) -> Result<(), MemError> {
+        Ok(())
+    }
+    /// Puts `data` copied over from another device region, which is not a
+    /// transfer from the host; `dirty` if the host has no up-to-date copy.
+    /// By default it is put as if produced here.
+    fn copy_in(&mut self, data: &D, size: usize, _dirty: bool) -> Result<(), MemError> {
+        self.put(data, size, true)
+    }
+    /// Called after a compute on this memory has finished.
+    fn end_compute(&mut self) {}
+    /// Defragments the memory; returns the cost in cycles, or `None` if this
+    /// memory cannot compact.
+    fn compact(&mut self) -> Option<f64> {
+        None
+    }
+    /// Fraction of the free bytes outside the largest free block; memories
+    /// without addresses never fragment.
+    fn fragmentation(&self) -> f64 {
+        0.0
+    }
+    /// Whether this memory shares its storage with the host, making loads
+    /// and stores free.
+    fn is_unified(&self) -> bool {
+        false
+    }
+    /// Whether loads into this memory can overlap with the previous compute,
+    /// e.g. because it is double buffered.
+    fn overlaps_loads(&self) -> bool {
+        false
+    }
+    fn store<HM: HostMemory<D>>(
+        &mut self,
+        data: &D,
+        _evict: bool,
+        other: &mut HM,
+    ) -> Result<(), MemError> {
+        other.put(data, self.get(data)?, false)
+    }
+}
+
+/// A backing store on the host side that target memories load from and
+/// store to.
+pub trait HostMemory<D>: Memory<D> + Clone
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    /// Like `get`, but used when the data is about to be copied to a faster
+    /// memory; hierarchies use it to move the data up their levels.
+    fn fetch(&mut self, data: &D) -> Result<usize, MemError> {
+        self.get(data)
+    }
+    /// Bytes that actually cross the bus when `size` bytes of `data` are
+    /// moved to or from this memory (e.g. after compression).
+    fn transfer_size(&self, _data: &D, size: usize) -> usize {
+        size
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
 pub enum InsnType {
     Compute,
     MMIO,
+    /// A transfer issued on a DMA queue
+    DMA,
+    /// Waits for one DMA queue
+    Sync,
+    /// Waits for every engine
+    Barrier,
 }
 
-#[derive(Clone, Debug)]
+/// Allocation sizes of a compute.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ComputeSizes {
+    pub output: usize,
+    /// Size of every operand, in order, or empty if unknown
+    #[serde(default)]
+    pub operands: Vec<usize>,
+    /// Transient bytes the compute needs while it runs, on top of any
+    /// `Scratch` around it
+    #[serde(default)]
+    pub scratch: usize,
+}
+
+impl ComputeSizes {
+    pub fn with_operands(mut self, operands: Vec<usize>) -> Self {
+        self.operands = operands;
+        self
+    }
+
+    pub fn with_scratch(mut self, scratch: usize) -> Self {
+        self.scratch = scratch;
+        self
+    }
+}
+
+/// Only the output size is known.
+impl From<usize> for ComputeSizes {
+    fn from(output: usize) -> Self {
+        Self {
+            output,
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Operators<D>
 where
     D: std::fmt::Debug,
 {
     /// Execute a sequence of computes
-    Compute(String, D, D, Vec<(D, Operators<D>)>, usize),
+    Compute(String, D, D, Vec<(D, Operators<D>)>, ComputeSizes),
     /// (Load region data)
     /// Loading data from host to device
     Load(String, (D, Box<Operators<D>>), usize),
@@ -87,166 +787,3063 @@ where
     /// Storing result back to device
     /// If the second field is set to true, on-device memory will be evicted
     Store(String, bool, (D, Box<Operators<D>>), usize),
+    /// (Pool pool-name op)
+    /// Places the data produced by `op` in the named pool of its region
+    Pool(String, Box<Operators<D>>),
+    /// (Evict region data)
+    /// Pushes data out of a region, writing it back only if needed
+    Evict(String, D, usize),
+    /// (Scratch bytes op)
+    /// The compute `op` needs `bytes` of transient scratch while it runs
+    Scratch(usize, Box<Operators<D>>),
+    /// (Backing store-name op)
+    /// The data loaded or stored by `op` lives in the named host-side store
+    Backing(String, Box<Operators<D>>),
+    /// (InPlace operand op)
+    /// The compute `op` writes its output over the buffer of its operand at
+    /// position `operand` (e.g. ReLU, bias-add), which has no later users
+    InPlace(usize, Box<Operators<D>>),
+    /// (Copy src-region dst-region data)
+    /// Copies data between two accelerator regions, without going through
+    /// the host
+    Copy(String, String, D, usize),
+    /// (Repeat count body)
+    /// Runs `body` `count` times, e.g. once per tile or batch; the data it
+    /// names are the same in every iteration
+    Repeat(usize, Box<Operators<D>>),
+    /// (If cond then else)
+    /// Runs one of two programs producing the same data, depending on the
+    /// value of `cond`; see `BranchPolicy` for which one the simulator takes
+    If(D, Box<Operators<D>>, Box<Operators<D>>),
+    /// (Dma queue op)
+    /// Issues the transfer `op` (a load, store or copy) on the numbered DMA
+    /// queue, for timing models with several queues
+    Dma(usize, Box<Operators<D>>),
+    /// (Sync queue)
+    /// Waits until every transfer issued on the DMA queue has completed
+    Sync(usize),
+    /// (Barrier)
+    /// Waits until the compute units and every DMA queue are idle
+    Barrier,
     NoOp,
 }
 
-pub struct JitSim<H, D>
+impl<D> Operators<D>
 where
     D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
-    H: Heuristic<D>,
 {
-    pub(crate) heuristic: H,
-    pub(crate) trace: Vec<Operators<D>>,
-}
+    /// The order in which `JitSim` touches on-device data when running `self`.
+    /// Used by heuristics that need to look into the future (e.g. Belady).
+    pub fn accesses(&self) -> Vec<D> {
+        let mut result = vec![];
+        self.collect_accesses(&mut result);
+        result
+    }
 
-impl<H, D> JitSim<H, D>
-where
-    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
-    H: Heuristic<D>,
-{
-    pub fn new(heuristic: H) -> Self {
-        Self {
-            heuristic,
-            trace: Vec::default(),
+    /// Region and data produced by this instruction, if any.
+    pub fn output(&self) -> Option<(&String, &D)> {
+        match self {
+            Operators::Compute(region, _, dst, _, _) => Some((region, dst)),
+            Operators::Load(region, (data, _), _) => Some((region, data)),
+            Operators::Store(region, _, (data, _), _) => Some((region, data)),
+            Operators::Pool(_, op)
+            | Operators::Backing(_, op)
+            | Operators::Scratch(_, op)
+            | Operators::InPlace(_, op)
+            | Operators::Dma(_, op)
+            | Operators::Repeat(_, op)
+            | Operators::If(_, op, _) => op.output(),
+            Operators::Evict(region, data, _) => Some((region, data)),
+            Operators::Copy(_, dst, data, _) => Some((dst, data)),
+            Operators::Sync(_) | Operators::Barrier | Operators::NoOp => None,
         }
     }
 
-    pub fn run<TM: Memory<D>, HM: Memory<D>>(
-        &mut self,
-        ops: &mut Operators<D>,
-        srams: &mut HashMap<String, TM>,
-        dram: &mut HM,
-        pin: &HashSet<D>,
-    ) {
-        match ops {
-            Operators::NoOp => {}
-            Operators::Load(_region, meta_data, _size) => {
-                self.run(meta_data.1.borrow_mut(), srams, dram, pin);
-                self.perform_op(ops, srams, dram, &HashSet::default());
+    /// The instructions of this program in execution order, operands before
+    /// their users, each without its operand tree. Loops are unrolled, but an
+    /// `If` stays a single instruction: which branch runs is only known when
+    /// it does.
+    pub fn linearize(&self) -> Vec<Self> {
+        let mut result = vec![];
+        self.collect_steps(&mut result);
+        result
+    }
+
+    /// `linearize` with the instructions of both branches of every `If` in
+    /// its place, one after the other, for analyses that have to cover
+    /// whichever runs.
+    pub fn linearize_branches(&self) -> Vec<Self> {
+        let mut result = vec![];
+        for op in self.linearize() {
+            match op.unwrap_wrappers() {
+                Operators::If(_, then, otherwise) => {
+                    result.extend(then.linearize_branches());
+                    result.extend(otherwise.linearize_branches());
+                }
+                _ => result.push(op),
             }
-            Operators::Store(_region, _evict, meta_data, _size) => {
-                self.run(meta_data.1.borrow_mut(), srams, dram, pin);
-                self.perform_op(ops, srams, dram, &HashSet::default());
+        }
+        result
+    }
+
+    fn collect_steps(&self, result: &mut Vec<Self>) {
+        let program = self.hoist_loops();
+        // an explicit stack, deep programs would overflow the native one;
+        // `true` once the operands of the instruction are collected
+        let mut stack = vec![(program.as_ref(), false)];
+        while let Some((op, visited)) = stack.pop() {
+            if visited {
+                result.push(op.shallow());
+                continue;
             }
-            Operators::Compute(_region, _op, _dst, subops, _size) => {
-                let pin = subops.iter().map(|x| &x.0).cloned().collect::<HashSet<_>>();
-                for op in subops.iter_mut() {
-                    self.run(&mut op.1, srams, dram, &pin);
-                }
-                self.perform_op(ops, srams, dram, &HashSet::default());
+            match op {
+                Operators::NoOp => continue,
+                // a loop is its iterations
+                Operators::Repeat(..) => {}
+                _ => stack.push((op, true)),
             }
+            stack.extend(op.operand_trees().into_iter().rev().map(|x| (x, false)));
         }
     }
-}
 
-impl<H, D, TM, HM> DTR<Operators<D>, D, TM, HM> for JitSim<H, D>
-where
-    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
-    TM: Memory<D>,
-    HM: Memory<D>,
-    H: Heuristic<D>,
-{
-    fn rematerialize(
-        &mut self,
-        data: &D,
-        sram: &mut TM,
-        dram: &mut HM,
-        evict_exclude: &HashSet<D>,
-    ) {
-        if !sram.contains(data) {
-            info!("Rematerialize {:?}", data);
-            let data_size = dram.get(data);
-            self.allocate_buffer(data_size, sram, dram, evict_exclude);
-            sram.put(data, data_size.clone(), false);
-            // self.trace.push(Operators::Load())
+    /// This program with every pool, backing, scratch, in-place or DMA
+    /// wrapping a loop moved into its body, so that it applies to each
+    /// iteration: `(pool p (repeat n x))` is `(repeat n (pool p x))`.
+    pub fn hoist_loops(&self) -> Cow<'_, Self> {
+        match self.hoisted() {
+            Some(program) => Cow::Owned(program),
+            None => Cow::Borrowed(self),
         }
-        self.heuristic.touch(data, sram.size_of(data).unwrap());
     }
 
-    fn perform_op(
-        &mut self,
-        op: &Operators<D>,
-        srams: &mut HashMap<String, TM>,
-        dram: &mut HM,
-        exclude: &HashSet<D>,
-    ) {
-        match op {
-            Operators::Compute(region, _, dst, ids, size) => {
-                if *region == String::from("host") {
-                    op.run(None as Option<&mut TM>, dram);
-                } else {
-                    let mem = srams.get_mut(region).unwrap();
-                    let evict_lock = ids.iter().map(|x| &x.0).cloned().collect::<HashSet<_>>();
-                    for arg in ids.iter().map(|x| x.0.clone()) {
-                        if !mem.contains(&arg) {
-                            self.rematerialize(&arg, mem, dram, &evict_lock);
-                        } else {
-                            self.heuristic.touch(&arg, mem.size_of(&arg).unwrap());
-                        }
-                    }
-                    self.allocate_buffer(size.clone(), mem, dram, &evict_lock);
-                    op.run(Some(mem), dram);
-                    self.heuristic.touch(dst, size.clone());
+    /// `hoist_loops`, if there is a wrapped loop to hoist.
+    fn hoisted(&self) -> Option<Self> {
+        let boxed = |op: &Self| op.hoisted().map(Box::new);
+        let or_clone = |new: Option<Self>, op: &Self| new.unwrap_or_else(|| op.clone());
+        match self {
+            Operators::Compute(region, op, dst, subops, size) => {
+                let hoisted = subops.iter().map(|x| x.1.hoisted()).collect::<Vec<_>>();
+                if hoisted.iter().all(Option::is_none) {
+                    return None;
                 }
+                let subops = subops
+                    .iter()
+                    .zip(hoisted)
+                    .map(|((data, op), new)| (data.clone(), or_clone(new, op)))
+                    .collect();
+                Some(Operators::Compute(
+                    region.clone(),
+                    op.clone(),
+                    dst.clone(),
+                    subops,
+                    size.clone(),
+                ))
             }
-            Operators::Load(region, (id, _op), size) => {
-                if *region == String::from("host") {
-                    op.run(None as Option<&mut TM>, dram);
-                } else {
-                    let mem = srams.get_mut(region).unwrap();
-                    if !mem.contains(id) {
-                        self.allocate_buffer(size.clone(), mem, dram, exclude);
-                        op.run(Some(mem), dram);
+            Operators::Load(region, (data, op), size) => Some(Operators::Load(
+                region.clone(),
+                (data.clone(), boxed(op)?),
+                *size,
+            )),
+            Operators::Store(region, evict, (data, op), size) => Some(Operators::Store(
+                region.clone(),
+                *evict,
+                (data.clone(), boxed(op)?),
+                *size,
+            )),
+            Operators::Repeat(count, op) => Some(Operators::Repeat(*count, boxed(op)?)),
+            Operators::If(cond, then, otherwise) => {
+                let (new_then, new_otherwise) = (then.hoisted(), otherwise.hoisted());
+                if new_then.is_none() && new_otherwise.is_none() {
+                    return None;
+                }
+                Some(Operators::If(
+                    cond.clone(),
+                    Box::new(or_clone(new_then, then)),
+                    Box::new(or_clone(new_otherwise, otherwise)),
+                ))
+            }
+            Operators::Pool(_, op)
+            | Operators::Backing(_, op)
+            | Operators::Scratch(_, op)
+            | Operators::InPlace(_, op)
+            | Operators::Dma(_, op) => {
+                let hoisted = op.hoisted();
+                match hoisted.as_ref().unwrap_or(op) {
+                    Operators::Repeat(count, body) => {
+                        // the body may be a loop in turn
+                        let body = self.rewrap(body.as_ref().clone());
+                        let body = body.hoisted().unwrap_or(body);
+                        Some(Operators::Repeat(*count, Box::new(body)))
                     }
-                    self.heuristic.touch(id, mem.size_of(id).unwrap());
+                    _ => Some(self.rewrap(hoisted?)),
                 }
             }
-            Operators::Store(region, _evict, (_data, _op), _size) => {
-                if *region == String::from("host") {
-                    panic!("Store should not performed on host");
-                } else {
-                    let mem = srams.get_mut(region).unwrap();
-                    op.run(Some(mem), dram);
-                    mem.to_vec().iter().for_each(|&data| {
-                        if !dram.contains(data) {
-                            dram.put(data, mem.get(data), false);
-                        }
-                    });
-                    mem.reset();
-                    self.heuristic.reset();
+            _ => None,
+        }
+    }
+
+    /// This wrapper around `op` instead of what it wraps.
+    fn rewrap(&self, op: Self) -> Self {
+        let op = Box::new(op);
+        match self {
+            Operators::Pool(pool, _) => Operators::Pool(pool.clone(), op),
+            Operators::Backing(store, _) => Operators::Backing(store.clone(), op),
+            Operators::Scratch(bytes, _) => Operators::Scratch(*bytes, op),
+            Operators::InPlace(operand, _) => Operators::InPlace(*operand, op),
+            Operators::Dma(queue, _) => Operators::Dma(*queue, op),
+            _ => *op,
+        }
+    }
+
+    /// The trees run before this instruction, in order.
+    fn operand_trees(&self) -> Vec<&Self> {
+        match self.unwrap_wrappers() {
+            Operators::Compute(_, _, _, subops, _) => subops.iter().map(|x| &x.1).collect(),
+            Operators::Load(_, (_, op), _) | Operators::Store(_, _, (_, op), _) => vec![op],
+            Operators::Repeat(count, op) => vec![op.as_ref(); *count],
+            _ => vec![],
+        }
+    }
+
+    /// Name of the instruction, without its operands
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Operators::Compute(..) => "compute",
+            Operators::Load(..) => "load",
+            Operators::Store(..) => "store",
+            Operators::Evict(..) => "evict",
+            Operators::Copy(..) => "copy",
+            Operators::Repeat(..) => "repeat",
+            Operators::If(..) => "if",
+            Operators::Sync(_) => "sync",
+            Operators::Barrier => "barrier",
+            Operators::Pool(_, op)
+            | Operators::Backing(_, op)
+            | Operators::Scratch(_, op)
+            | Operators::InPlace(_, op)
+            | Operators::Dma(_, op) => op.kind(),
+            Operators::NoOp => "noop",
+        }
+    }
+
+    /// This program in Graphviz format, shared subexpressions drawn once;
+    /// see `Dag::to_dot`.
+    pub fn to_dot(&self) -> String {
+        Dag::from_tree(self).to_dot()
+    }
+
+    /// Saves a trace (e.g. a `JitSim::schedule`) as JSON.
+    pub fn to_json(ops: &[Self]) -> serde_json::Result<String>
+    where
+        D: Serialize,
+    {
+        serde_json::to_string(ops)
+    }
+
+    pub fn from_json<'de>(json: &'de str) -> serde_json::Result<Vec<Self>>
+    where
+        D: Deserialize<'de>,
+    {
+        serde_json::from_str(json)
+    }
+
+    /// This instruction with the instructions producing its operands replaced
+    /// by `NoOp`.
+    pub fn shallow(&self) -> Self {
+        let noop = || Box::new(Operators::NoOp);
+        match self {
+            Operators::Compute(region, op, dst, subops, size) => Operators::Compute(
+                region.clone(),
+                op.clone(),
+                dst.clone(),
+                subops
+                    .iter()
+                    .map(|x| (x.0.clone(), Operators::NoOp))
+                    .collect(),
+                size.clone(),
+            ),
+            Operators::Load(region, (data, _), size) => {
+                Operators::Load(region.clone(), (data.clone(), noop()), *size)
+            }
+            Operators::Store(region, evict, (data, _), size) => {
+                Operators::Store(region.clone(), *evict, (data.clone(), noop()), *size)
+            }
+            Operators::Pool(pool, op) => Operators::Pool(pool.clone(), Box::new(op.shallow())),
+            Operators::Backing(store, op) => {
+                Operators::Backing(store.clone(), Box::new(op.shallow()))
+            }
+            Operators::Scratch(bytes, op) => Operators::Scratch(*bytes, Box::new(op.shallow())),
+            Operators::InPlace(operand, op) => Operators::InPlace(*operand, Box::new(op.shallow())),
+            Operators::Dma(queue, op) => Operators::Dma(*queue, Box::new(op.shallow())),
+            Operators::Sync(queue) => Operators::Sync(*queue),
+            Operators::Evict(region, data, size) => {
+                Operators::Evict(region.clone(), data.clone(), *size)
+            }
+            Operators::Copy(src, dst, data, size) => {
+                Operators::Copy(src.clone(), dst.clone(), data.clone(), *size)
+            }
+            // the body is what the loop runs
+            Operators::Repeat(..) | Operators::If(..) => self.clone(),
+            Operators::Barrier => Operators::Barrier,
+            Operators::NoOp => Operators::NoOp,
+        }
+    }
+
+    /// Position in `linearize` of the last instruction reading each piece of
+    /// data: a compute taking it as an operand, a store writing it back, a
+    /// copy to another region, or an `If` either branch of which reads it.
+    pub fn last_uses(&self) -> HashMap<D, usize> {
+        Self::last_uses_in(&self.linearize())
+    }
+
+    /// `last_uses` of instructions run one after the other, e.g. a schedule
+    /// or several linearized programs.
+    pub fn last_uses_in(steps: &[Self]) -> HashMap<D, usize> {
+        let mut result = HashMap::default();
+        for (i, op) in steps.iter().enumerate() {
+            for data in op.step_reads(false) {
+                result.insert(data, i);
+            }
+        }
+        result
+    }
+
+    /// How many instructions of the DAG read each piece of data: computes on
+    /// any region through their operands (shared operands once per user),
+    /// loads from the host copy, and stores and copies from the device copy.
+    /// Both branches of an `If` count; the one not taken is discounted when
+    /// it is skipped.
+    pub fn read_counts(&self) -> HashMap<D, usize> {
+        Self::read_counts_in(&self.linearize())
+    }
+
+    /// `read_counts` of instructions run one after the other.
+    pub fn read_counts_in(steps: &[Self]) -> HashMap<D, usize> {
+        let mut result = HashMap::default();
+        for op in steps.iter() {
+            for data in op.step_reads(true) {
+                *result.entry(data).or_default() += 1;
+            }
+        }
+        result
+    }
+
+    /// Data read by this instruction of `linearize`, device loads included
+    /// if `loads`; an `If` reads what the instructions of both its branches do.
+    fn step_reads(&self, loads: bool) -> Vec<D> {
+        match self.unwrap_wrappers() {
+            Operators::Compute(_, _, _, args, _) => args.iter().map(|x| x.0.clone()).collect(),
+            Operators::Load(region, (data, _), _) if loads && region != "host" => {
+                vec![data.clone()]
+            }
+            Operators::Store(_, _, (data, _), _) | Operators::Copy(_, _, data, _) => {
+                vec![data.clone()]
+            }
+            Operators::If(..) => self
+                .linearize_branches()
+                .iter()
+                .flat_map(|x| x.step_reads(loads))
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// The instruction inside any `Pool`, `Backing`, `Scratch`, `InPlace` or
+    /// `Dma`.
+    pub(crate) fn unwrap_wrappers(&self) -> &Self {
+        match self {
+            Operators::Pool(_, op)
+            | Operators::Backing(_, op)
+            | Operators::Scratch(_, op)
+            | Operators::InPlace(_, op)
+            | Operators::Dma(_, op) => op.unwrap_wrappers(),
+            _ => self,
+        }
+    }
+
+    /// How many on-device computes and stores use each piece of data.
+    pub fn use_counts(&self) -> HashMap<D, usize> {
+        let mut result = HashMap::default();
+        self.collect_uses(&mut result);
+        result
+    }
+
+    fn collect_uses(&self, result: &mut HashMap<D, usize>) {
+        match self {
+            Operators::NoOp
+            | Operators::Evict(..)
+            | Operators::Copy(..)
+            | Operators::Sync(_)
+            | Operators::Barrier => {}
+            Operators::Pool(_, op)
+            | Operators::Backing(_, op)
+            | Operators::Scratch(_, op)
+            | Operators::InPlace(_, op)
+            | Operators::Dma(_, op) => op.collect_uses(result),
+            Operators::Load(_, (_, op), _) => op.collect_uses(result),
+            Operators::If(_, then, otherwise) => {
+                then.collect_uses(result);
+                otherwise.collect_uses(result);
+            }
+            Operators::Repeat(count, op) => {
+                let mut body = HashMap::default();
+                op.collect_uses(&mut body);
+                for (data, uses) in body {
+                    *result.entry(data).or_default() += uses * count;
+                }
+            }
+            Operators::Store(_, _, (data, op), _) => {
+                op.collect_uses(result);
+                *result.entry(data.clone()).or_default() += 1;
+            }
+            Operators::Compute(region, _, _, subops, _) => {
+                for (data, op) in subops.iter() {
+                    op.collect_uses(result);
+                    if *region != "host" {
+                        *result.entry(data.clone()).or_default() += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    fn collect_accesses(&self, result: &mut Vec<D>) {
+        match self {
+            Operators::NoOp | Operators::Evict(..) | Operators::Sync(_) | Operators::Barrier => {}
+            Operators::Copy(_, _, data, _) => result.push(data.clone()),
+            Operators::Pool(_, op)
+            | Operators::Backing(_, op)
+            | Operators::Scratch(_, op)
+            | Operators::InPlace(_, op)
+            | Operators::Dma(_, op) => op.collect_accesses(result),
+            Operators::Repeat(count, op) => {
+                for _ in 0..*count {
+                    op.collect_accesses(result);
+                }
+            }
+            Operators::If(_, then, otherwise) => {
+                then.collect_accesses(result);
+                otherwise.collect_accesses(result);
+            }
+            Operators::Load(region, (id, op), _) => {
+                op.collect_accesses(result);
+                if *region != "host" {
+                    result.push(id.clone());
+                }
+            }
+            Operators::Store(_, _, (_, op), _) => op.collect_accesses(result),
+            Operators::Compute(region, _, dst, subops, _) => {
+                for (_, op) in subops.iter() {
+                    op.collect_accesses(result);
+                }
+                if *region != "host" {
+                    result.extend(subops.iter().map(|x| x.0.clone()));
+                    result.push(dst.clone());
+                }
+            }
+        }
+    }
+}
+
+/// How often a single piece of data was pushed out of / brought back into SRAM.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TensorCounters {
+    pub evictions: usize,
+    pub reloads: usize,
+    /// Removed for good; using it again is an error
+    pub banished: bool,
+}
+
+/// Occupancy of one region, sampled after every executed instruction.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MemoryStats {
+    pub samples: usize,
+    pub utilization_sum: f64,
+    pub fragmentation_sum: f64,
+    pub peak_allocated: usize,
+    /// Allocations that did not fit without evicting or compacting first
+    pub failed_allocations: usize,
+}
+
+impl MemoryStats {
+    fn sample<D, TM>(&mut self, mem: &TM)
+    where
+        D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+        TM: TargetMemory<D>,
+    {
+        self.samples += 1;
+        if mem.size_total() > 0 {
+            self.utilization_sum += mem.size_allocated() as f64 / mem.size_total() as f64;
+        }
+        self.fragmentation_sum += mem.fragmentation();
+        self.peak_allocated = self.peak_allocated.max(mem.peak_allocated());
+    }
+
+    /// Average fraction of the region in use.
+    pub fn average_utilization(&self) -> f64 {
+        if self.samples == 0 {
+            0.0
+        } else {
+            self.utilization_sum / self.samples as f64
+        }
+    }
+
+    /// Average fragmentation of the free space, in percent.
+    pub fn fragmentation_percent(&self) -> f64 {
+        if self.samples == 0 {
+            0.0
+        } else {
+            100.0 * self.fragmentation_sum / self.samples as f64
+        }
+    }
+}
+
+/// `size_allocated` of every region after each executed instruction.
+#[derive(Clone, Debug, Default)]
+pub struct OccupancyTimeline {
+    pub regions: BTreeSet<String>,
+    /// (step, region -> bytes allocated)
+    pub samples: Vec<(usize, BTreeMap<String, usize>)>,
+    /// Simulated time of each sample (overlapped, if enabled)
+    pub times: Vec<f64>,
+    /// `size_total` of every region
+    pub capacities: BTreeMap<String, usize>,
+    /// Evictions and rematerializations, in order
+    pub markers: Vec<Marker>,
+}
+
+/// An eviction or rematerialization on an `OccupancyTimeline`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Marker {
+    pub time: f64,
+    pub region: String,
+    /// "evict", "reload" or "recompute"
+    pub kind: &'static str,
+    /// The data, rendered with `Debug`
+    pub data: String,
+}
+
+impl OccupancyTimeline {
+    fn record<D, TM>(&mut self, step: usize, time: f64, srams: &HashMap<String, TM>)
+    where
+        D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+        TM: TargetMemory<D>,
+    {
+        let sample = srams
+            .iter()
+            .map(|(region, mem)| (region.clone(), mem.size_allocated()))
+            .collect::<BTreeMap<_, _>>();
+        self.regions.extend(sample.keys().cloned());
+        for (region, mem) in srams.iter() {
+            self.capacities.insert(region.clone(), mem.size_total());
+        }
+        self.samples.push((step, sample));
+        self.times.push(time);
+    }
+
+    /// One row per step, one column per region.
+    pub fn write_csv(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let header = std::iter::once("step")
+            .chain(self.regions.iter().map(|x| x.as_str()))
+            .collect::<Vec<_>>();
+        writeln!(out, "{}", header.join(","))?;
+        for (step, sample) in self.samples.iter() {
+            let row = std::iter::once(step.to_string())
+                .chain(
+                    self.regions
+                        .iter()
+                        .map(|x| sample.get(x).map(|x| x.to_string()).unwrap_or_default()),
+                )
+                .collect::<Vec<_>>();
+            writeln!(out, "{}", row.join(","))?;
+        }
+        Ok(())
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut out = vec![];
+        self.write_csv(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+}
+
+/// Energy per unit of work, in any consistent unit (e.g. pJ).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EnergyModel {
+    /// Reading or writing one byte of on-device memory
+    pub sram_per_byte: f64,
+    /// Reading or writing one byte of host memory
+    pub dram_per_byte: f64,
+    /// Moving one byte over the DMA engine / interconnect
+    pub dma_per_byte: f64,
+    /// One device compute
+    pub per_op: f64,
+}
+
+/// Energy spent so far, split by phase.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EnergyReport {
+    pub compute: f64,
+    pub sram: f64,
+    pub dram: f64,
+    pub dma: f64,
+}
+
+impl EnergyReport {
+    pub fn total(&self) -> f64 {
+        self.compute + self.sram + self.dram + self.dma
+    }
+}
+
+/// Totals over the runs so far.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SimStats {
+    /// Loads of data not yet on the device
+    pub loads: usize,
+    /// Write-backs to DRAM, including those of evictions
+    pub stores: usize,
+    pub evictions: usize,
+    /// Reloads of data that had been evicted
+    pub rematerializations: usize,
+    /// Dropped data computed again instead of reloaded
+    pub recomputations: usize,
+    /// Residents dropped without a write-back, because the host had a copy or
+    /// they were dead
+    pub deallocations: usize,
+    /// Bytes moved between SRAM and DRAM, as they cross the bus
+    pub bytes_transferred: usize,
+    /// Loads issued ahead of their use by the prefetcher
+    #[serde(default)]
+    pub prefetches: usize,
+    /// Demand loads that found their data already prefetched
+    #[serde(default)]
+    pub prefetch_hits: usize,
+    /// Loads and prefetches of data that had never been on the device, the
+    /// cold-start part of the traffic
+    #[serde(default)]
+    pub cold_loads: usize,
+    /// Loads that found their data preloaded by `JitSim::warm_start`
+    #[serde(default)]
+    pub warm_hits: usize,
+    /// Copies between device regions, which bypass DRAM
+    #[serde(default)]
+    pub copies: usize,
+    #[serde(default)]
+    pub bytes_copied: usize,
+    /// Computes run on the host instead of a device
+    #[serde(default)]
+    pub host_computes: usize,
+    /// Data placed in DRAM by host loads and computes
+    #[serde(default)]
+    pub host_allocations: usize,
+    #[serde(default)]
+    pub host_bytes_allocated: usize,
+    /// Largest `size_allocated` seen per region
+    pub peak_residency: BTreeMap<String, usize>,
+}
+
+impl SimStats {
+    /// Transfers between SRAM and DRAM: loads of any kind plus write-backs.
+    pub fn dram_trips(&self) -> usize {
+        self.loads + self.rematerializations + self.prefetches + self.stores
+    }
+
+    /// Transfers once every piece of data was on the device at least once.
+    pub fn steady_state_trips(&self) -> usize {
+        self.dram_trips() - self.cold_loads
+    }
+
+    /// Adds `delta` (e.g. from `since`) `times` over, peaks aside.
+    pub(crate) fn add_scaled(&mut self, delta: &Self, times: usize) {
+        self.loads += delta.loads * times;
+        self.stores += delta.stores * times;
+        self.evictions += delta.evictions * times;
+        self.rematerializations += delta.rematerializations * times;
+        self.recomputations += delta.recomputations * times;
+        self.deallocations += delta.deallocations * times;
+        self.bytes_transferred += delta.bytes_transferred * times;
+        self.prefetches += delta.prefetches * times;
+        self.prefetch_hits += delta.prefetch_hits * times;
+        self.cold_loads += delta.cold_loads * times;
+        self.warm_hits += delta.warm_hits * times;
+        self.copies += delta.copies * times;
+        self.bytes_copied += delta.bytes_copied * times;
+        self.host_computes += delta.host_computes * times;
+        self.host_allocations += delta.host_allocations * times;
+        self.host_bytes_allocated += delta.host_bytes_allocated * times;
+    }
+
+    /// Counts accumulated since `earlier`, a copy of these stats taken before.
+    /// The peaks are kept as they are.
+    pub fn since(&self, earlier: &Self) -> Self {
+        Self {
+            loads: self.loads - earlier.loads,
+            stores: self.stores - earlier.stores,
+            evictions: self.evictions - earlier.evictions,
+            rematerializations: self.rematerializations - earlier.rematerializations,
+            recomputations: self.recomputations - earlier.recomputations,
+            deallocations: self.deallocations - earlier.deallocations,
+            bytes_transferred: self.bytes_transferred - earlier.bytes_transferred,
+            prefetches: self.prefetches - earlier.prefetches,
+            prefetch_hits: self.prefetch_hits - earlier.prefetch_hits,
+            cold_loads: self.cold_loads - earlier.cold_loads,
+            warm_hits: self.warm_hits - earlier.warm_hits,
+            copies: self.copies - earlier.copies,
+            bytes_copied: self.bytes_copied - earlier.bytes_copied,
+            host_computes: self.host_computes - earlier.host_computes,
+            host_allocations: self.host_allocations - earlier.host_allocations,
+            host_bytes_allocated: self.host_bytes_allocated - earlier.host_bytes_allocated,
+            peak_residency: self.peak_residency.clone(),
+        }
+    }
+}
+
+/// Result of one program of `JitSim::run_batch`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProgramStats {
+    pub latency: f64,
+    /// Counts and peaks of this program alone
+    pub stats: SimStats,
+}
+
+/// What a single executed instruction did.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InstructionRow {
+    pub step: usize,
+    pub kind: String,
+    pub region: String,
+    /// Bytes that crossed the bus between SRAM and DRAM, including the
+    /// reloads and evictions the instruction caused
+    pub bytes_moved: usize,
+    /// `size_allocated` of the region once the instruction finished
+    pub occupancy: usize,
+}
+
+/// Per-instruction statistics of the runs so far.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SimReport {
+    pub rows: Vec<InstructionRow>,
+}
+
+impl SimReport {
+    pub fn write_csv(&self, out: &mut impl Write) -> std::io::Result<()> {
+        writeln!(out, "step,kind,region,bytes_moved,occupancy")?;
+        for row in self.rows.iter() {
+            writeln!(
+                out,
+                "{},{},{},{},{}",
+                row.step, row.kind, row.region, row.bytes_moved, row.occupancy
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut out = vec![];
+        self.write_csv(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+}
+
+/// One activity on the simulated timeline.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TimelineEvent {
+    pub name: String,
+    /// Region name, or "dma" for transfers
+    pub track: String,
+    pub start: f64,
+    pub cycles: f64,
+}
+
+/// Device loads of the program that are yet to run, for prefetching.
+#[derive(Clone, Debug)]
+pub(crate) struct Prefetcher<D> {
+    /// How many upcoming loads are considered
+    pub(crate) depth: usize,
+    /// (region, data, size), in execution order
+    pub(crate) upcoming: VecDeque<(String, D, usize)>,
+    /// Prefetched data not used yet
+    pub(crate) pending: HashSet<D>,
+}
+
+/// Last uses of the data of the running program, for eager deallocation.
+#[derive(Clone, Debug)]
+pub(crate) struct Liveness<D> {
+    /// Data dead after each instruction, by position (see
+    /// `Operators::last_uses`)
+    pub(crate) dead_after: HashMap<usize, Vec<D>>,
+    /// `steps` when the program started
+    pub(crate) start: usize,
+}
+
+/// Times at which the compute units and the DMA engine become free, when
+/// transfers may overlap computes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct EngineClocks {
+    pub(crate) compute: f64,
+    pub(crate) dma: f64,
+}
+
+/// Transfers in flight on the asynchronous DMA queue, see
+/// `JitSim::with_dma_queue`.
+#[derive(Clone, Debug)]
+pub(crate) struct DmaQueue<D> {
+    /// Most transfers in flight at once
+    pub(crate) depth: usize,
+    /// Completion times of the transfers in flight, in issue order
+    pub(crate) in_flight: VecDeque<f64>,
+    /// When each data becomes usable on the device: its transfer completes
+    /// or the compute producing it finishes
+    pub(crate) ready: HashMap<D, f64>,
+    /// Time the next instruction is issued at
+    pub(crate) issue: f64,
+    pub(crate) stats: DmaStats,
+}
+
+impl<D> DmaQueue<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    /// Drops the transfers completed by the issue time.
+    fn retire(&mut self) {
+        while self
+            .in_flight
+            .front()
+            .is_some_and(|&done| done <= self.issue)
+        {
+            self.in_flight.pop_front();
+        }
+    }
+
+    /// Enqueues a transfer of `data` on an engine free at `dma` and returns
+    /// its start. Issuing waits for a slot if the queue is full; the
+    /// transfer waits for `data` to be ready.
+    fn transfer(&mut self, data: &D, cycles: f64, dma: f64, arrives: bool) -> f64 {
+        self.retire();
+        if self.in_flight.len() >= self.depth {
+            let done = self.in_flight.pop_front().unwrap();
+            self.stats.full_cycles += done - self.issue;
+            self.issue = done;
+            self.retire();
+        }
+        let ready = self.ready.get(data).cloned().unwrap_or_default();
+        let start = self.issue.max(dma).max(ready);
+        self.in_flight.push_back(start + cycles);
+        if arrives {
+            self.ready.insert(data.clone(), start + cycles);
+        }
+        self.stats.transfers += 1;
+        self.stats.occupancy_sum += self.in_flight.len();
+        self.stats.peak_occupancy = self.stats.peak_occupancy.max(self.in_flight.len());
+        start
+    }
+}
+
+/// How the asynchronous DMA queue behaved so far.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DmaStats {
+    pub transfers: usize,
+    /// Cycles computes spent waiting for the transfers of their operands
+    pub stall_cycles: f64,
+    /// Cycles issuing spent waiting for a slot in the full queue
+    pub full_cycles: f64,
+    /// Most transfers in flight at once
+    pub peak_occupancy: usize,
+    /// Transfers in flight when each transfer was issued, summed
+    pub occupancy_sum: usize,
+}
+
+impl DmaStats {
+    /// Average number of transfers in flight when one is issued.
+    pub fn mean_occupancy(&self) -> f64 {
+        if self.transfers == 0 {
+            0.0
+        } else {
+            self.occupancy_sum as f64 / self.transfers as f64
+        }
+    }
+}
+
+/// When data computed on a device reaches DRAM.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// On eviction, if the host has no up-to-date copy
+    #[default]
+    WriteBack,
+    /// As soon as it is produced, so that evictions are free
+    WriteThrough,
+}
+
+/// Which branch of an `If` the simulator runs.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BranchPolicy<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    /// Tries both branches on copies of the memories and runs the slower
+    /// one, as an upper bound
+    WorstCase,
+    /// Probability that each condition holds, e.g. from profiling; the more
+    /// likely branch runs, the first one for unknown conditions
+    Profile(HashMap<D, f64>),
+    /// A uniformly random branch, seeded for reproducibility
+    Random(u64),
+}
+
+/// Evictions kept for `OomReport`s.
+const EVICTION_HISTORY: usize = 8;
+
+/// Work left on the stack of `JitSim::run`, innermost on top.
+enum Frame<'a, D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    /// Runs the operands of an instruction, then the instruction
+    Enter(&'a Operators<D>),
+    /// Executes an instruction whose operands have run
+    Perform(&'a Operators<D>),
+    /// Iterations of a loop left to run
+    Repeat(usize, &'a Operators<D>),
+    /// Between the first two iterations of a fast loop of that many
+    Measure(usize, &'a Operators<D>),
+    /// Scales the iteration run since the progress up by that many
+    Scale(Box<Progress>, usize, &'a Operators<D>),
+}
+
+/// Counters of a `JitSim` at some point of a run, see `with_fast_repeat`.
+struct Progress {
+    summary: SimStats,
+    regions: BTreeMap<String, SimStats>,
+    /// Transfer, access, compaction, overlappable, compute, recompute and
+    /// host compute
+    cycles: [f64; 7],
+    energy: EnergyReport,
+    clocks: Option<EngineClocks>,
+}
+
+/// Cycles of a compute by region, operator and output size.
+type ComputeCost<D> = Arc<dyn Fn(&str, &D, usize) -> f64 + Send + Sync>;
+
+#[derive(Clone)]
+pub struct JitSim<H, D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+    H: Heuristic<D>,
+{
+    pub(crate) heuristic: H,
+    /// Every executed instruction, including evictions and reloads
+    pub(crate) trace: Vec<Operators<D>>,
+    /// Region of the instruction being performed
+    pub(crate) current_region: String,
+    pub(crate) counters: HashMap<D, TensorCounters>,
+    pub(crate) transfer_cycles: f64,
+    pub(crate) access_cycles: f64,
+    pub(crate) compaction_cycles: f64,
+    pub(crate) overlappable_cycles: f64,
+    pub(crate) compute_cycles: f64,
+    pub(crate) recompute_cycles: f64,
+    pub(crate) host_compute_cycles: f64,
+    /// Producing compute per device-computed data, when rematerialization by
+    /// recomputation is enabled
+    pub(crate) producers: Option<HashMap<D, Operators<D>>>,
+    pub(crate) banished: HashSet<D>,
+    pub(crate) energy_model: Option<EnergyModel>,
+    pub(crate) energy: EnergyReport,
+    pub(crate) compute_cost: Option<ComputeCost<D>>,
+    pub(crate) steps: usize,
+    pub(crate) stats: BTreeMap<String, MemoryStats>,
+    pub(crate) timeline: Option<OccupancyTimeline>,
+    pub(crate) events: Option<Vec<TimelineEvent>>,
+    pub(crate) report: SimReport,
+    pub(crate) summary: SimStats,
+    pub(crate) region_summary: BTreeMap<String, SimStats>,
+    /// Bytes moved since the last executed instruction was reported
+    pub(crate) bytes_moved: usize,
+    pub(crate) failed_allocations: usize,
+    /// Scratch bytes requested for the next compute
+    pub(crate) scratch: usize,
+    /// Operand the next compute writes its output over
+    pub(crate) in_place: Option<usize>,
+    pub(crate) dump: Option<Arc<Mutex<dyn Write + Send>>>,
+    /// Sink of the executed instructions, and how many of `trace` went to it
+    pub(crate) log: Option<(Arc<Mutex<dyn Write + Send>>, usize)>,
+    pub(crate) observers: Vec<Arc<Mutex<dyn Observer<D> + Send>>>,
+    pub(crate) metrics: Vec<Arc<Mutex<dyn MetricsSink + Send>>>,
+    pub(crate) event_log: Option<Vec<SimEvent<D>>>,
+    pub(crate) overlap: Option<EngineClocks>,
+    pub(crate) dma_queue: Option<DmaQueue<D>>,
+    pub(crate) prefetcher: Option<Prefetcher<D>>,
+    pub(crate) liveness: Option<Liveness<D>>,
+    /// Reads left per data, when freeing by reference counting
+    pub(crate) refcounts: Option<HashMap<D, usize>>,
+    /// Data never evicted, see `pin`
+    pub(crate) pinned: HashSet<D>,
+    /// Data loaded on the device at some point, to tell cold loads
+    pub(crate) seen: HashSet<D>,
+    /// Data put on the device by `warm_start` and not loaded since
+    pub(crate) preloaded: HashSet<D>,
+    pub(crate) fast_repeat: bool,
+    pub(crate) branches: BranchPolicy<D>,
+    pub(crate) write_policy: WritePolicy,
+    /// Last departure of every piece of data, if checking uses
+    pub(crate) departures: Option<HashMap<D, Departure>>,
+    /// Why the eviction about to happen was decided
+    pub(crate) decision: Option<String>,
+    /// The last `EVICTION_HISTORY` evictions, for `OomReport`s
+    pub(crate) recent_evictions: VecDeque<(D, Departure)>,
+    pub(crate) branch_rng: StdRng,
+}
+
+impl<H, D> JitSim<H, D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+    H: Heuristic<D>,
+{
+    pub fn new(heuristic: H) -> Self {
+        Self {
+            heuristic,
+            trace: Vec::default(),
+            current_region: String::default(),
+            counters: HashMap::default(),
+            transfer_cycles: 0.0,
+            access_cycles: 0.0,
+            compaction_cycles: 0.0,
+            overlappable_cycles: 0.0,
+            compute_cycles: 0.0,
+            recompute_cycles: 0.0,
+            host_compute_cycles: 0.0,
+            producers: None,
+            banished: HashSet::default(),
+            energy_model: None,
+            energy: EnergyReport::default(),
+            compute_cost: None,
+            steps: 0,
+            stats: BTreeMap::default(),
+            timeline: None,
+            events: None,
+            report: SimReport::default(),
+            summary: SimStats::default(),
+            region_summary: BTreeMap::default(),
+            bytes_moved: 0,
+            failed_allocations: 0,
+            scratch: 0,
+            in_place: None,
+            dump: None,
+            log: None,
+            observers: Vec::default(),
+            metrics: Vec::default(),
+            event_log: None,
+            overlap: None,
+            dma_queue: None,
+            prefetcher: None,
+            liveness: None,
+            refcounts: None,
+            pinned: HashSet::default(),
+            seen: HashSet::default(),
+            preloaded: HashSet::default(),
+            fast_repeat: false,
+            branches: BranchPolicy::WorstCase,
+            write_policy: WritePolicy::default(),
+            departures: None,
+            decision: None,
+            recent_evictions: VecDeque::default(),
+            branch_rng: StdRng::seed_from_u64(0),
+        }
+    }
+
+    /// Also times the run with the DMA engine working alongside the compute
+    /// units: loads for the next instructions proceed while the current
+    /// compute runs, a compute waits for the transfers issued before it, and
+    /// a write-back waits for the computes issued before it. See
+    /// `overlapped_latency`.
+    pub fn overlap_transfers(&mut self) {
+        self.overlap = Some(EngineClocks::default());
+    }
+
+    /// Times transfers on an asynchronous DMA queue holding up to `depth`
+    /// transfers in flight: like `overlap_transfers`, but a compute only
+    /// waits for the transfers of its own operands, a write-back for the
+    /// compute producing its data, and issuing stalls while the queue is
+    /// full. See `dma_stats`.
+    pub fn with_dma_queue(mut self, depth: usize) -> Self {
+        self.overlap_transfers();
+        self.dma_queue = Some(DmaQueue {
+            depth: depth.max(1),
+            in_flight: VecDeque::default(),
+            ready: HashMap::default(),
+            issue: 0.0,
+            stats: DmaStats::default(),
+        });
+        self
+    }
+
+    /// Stall cycles and occupancy of the DMA queue, if `with_dma_queue` is
+    /// enabled.
+    pub fn dma_stats(&self) -> Option<&DmaStats> {
+        self.dma_queue.as_ref().map(|x| &x.stats)
+    }
+
+    /// Estimated latency with transfers overlapping computes, or `latency`
+    /// (everything serialized) if `overlap_transfers` is not enabled.
+    pub fn overlapped_latency(&self) -> f64 {
+        match self.overlap.as_ref() {
+            Some(clocks) => clocks.compute.max(clocks.dma),
+            None => self.latency(),
+        }
+    }
+
+    /// Registers `observer` to be called on every load, store, eviction,
+    /// rematerialization and compute. Keep a clone of the `Arc` to read it
+    /// back after the run.
+    pub fn observe(&mut self, observer: Arc<Mutex<dyn Observer<D> + Send>>) {
+        self.observers.push(observer);
+    }
+
+    /// Feeds `sink` with the bytes moved, instructions executed and
+    /// evictions of the run, by region.
+    pub fn report_metrics(&mut self, sink: Arc<Mutex<dyn MetricsSink + Send>>) {
+        self.metrics.push(sink);
+    }
+
+    /// Writes the residency map of every region to `sink` after each
+    /// executed instruction.
+    pub fn dump_memory_to(&mut self, sink: impl Write + Send + 'static) {
+        self.dump = Some(Arc::new(Mutex::new(sink)));
+    }
+
+    /// Streams every executed instruction, reloads and evictions included,
+    /// to `sink` as it runs, one line each (see `InsnLogger`).
+    pub fn log_instructions_to(&mut self, sink: impl Write + Send + 'static) {
+        self.log = Some((Arc::new(Mutex::new(sink)), self.trace.len()));
+    }
+
+    /// Estimated cycles spent moving data between SRAM and DRAM so far.
+    pub fn transfer_cycles(&self) -> f64 {
+        self.transfer_cycles
+    }
+
+    /// Part of `transfer_cycles` spent loading into double-buffered memories,
+    /// which can be hidden behind the compute on the other buffer.
+    pub fn overlappable_cycles(&self) -> f64 {
+        self.overlappable_cycles
+    }
+
+    /// Cycles of a device compute, given its region, operator and output
+    /// size. Without a cost model computes are free.
+    pub fn with_compute_cost(
+        mut self,
+        cost: impl Fn(&str, &D, usize) -> f64 + Send + Sync + 'static,
+    ) -> Self {
+        self.compute_cost = Some(Arc::new(cost));
+        self
+    }
+
+    /// Fixed cycles per operator, e.g. from profiling; operators missing
+    /// from `table` are free.
+    pub fn with_compute_costs(self, table: HashMap<D, f64>) -> Self
+    where
+        D: Send + Sync + 'static,
+    {
+        self.with_compute_cost(move |_, op, _| table.get(op).cloned().unwrap_or(0.0))
+    }
+
+    pub fn with_energy_model(mut self, model: EnergyModel) -> Self {
+        self.energy_model = Some(model);
+        self
+    }
+
+    /// Estimated energy of the runs so far; all zero without an energy model.
+    pub fn energy(&self) -> &EnergyReport {
+        &self.energy
+    }
+
+    /// Estimated cycles spent computing on the devices so far.
+    pub fn compute_cycles(&self) -> f64 {
+        self.compute_cycles
+    }
+
+    /// Lets evictions drop data computed on the device without writing it
+    /// back; when it is needed again, it is recomputed from its operands
+    /// (rematerializing them in turn), as in DTR.
+    pub fn with_recomputation(mut self) -> Self {
+        self.producers = Some(HashMap::default());
+        self
+    }
+
+    /// After every instruction, loads operands of the next `depth` device
+    /// loads of the program into the slack of their regions, provided they
+    /// are already on the host. Prefetches never evict; their use is counted
+    /// as `SimStats::prefetch_hits`.
+    pub fn with_prefetch(mut self, depth: usize) -> Self {
+        self.prefetcher = Some(Prefetcher {
+            depth,
+            upcoming: VecDeque::default(),
+            pending: HashSet::default(),
+        });
+        self
+    }
+
+    /// Frees data from the device right after its last use in the program
+    /// (see `Operators::last_uses`) instead of leaving it resident until
+    /// it is evicted. Dead data is never written back.
+    pub fn with_liveness(mut self) -> Self {
+        self.liveness = Some(Liveness {
+            dead_after: HashMap::default(),
+            start: 0,
+        });
+        self
+    }
+
+    /// Counts the reads left of every piece of data of the program (see
+    /// `Operators::read_counts`); once the last one is done the data is freed
+    /// from the device and the host. The result of the program is kept.
+    pub fn with_refcounting(mut self) -> Self {
+        self.refcounts = Some(HashMap::default());
+        self
+    }
+
+    /// Runs only three iterations of a `Repeat`: the first (cold), the
+    /// second, whose stats, cycles and energy stand for every other one, and
+    /// the last. Per-data counters, reports and timelines only see the
+    /// iterations that ran.
+    pub fn with_fast_repeat(mut self) -> Self {
+        self.fast_repeat = true;
+        self
+    }
+
+    /// Writes device results back to DRAM on eviction (the default) or as
+    /// soon as they are computed; `SimStats::stores` counts the trips.
+    pub fn with_write_policy(mut self, policy: WritePolicy) -> Self {
+        self.write_policy = policy;
+        self
+    }
+
+    /// Records when, how and on whose decision data leaves the device, so
+    /// that a failure on missing data (e.g. banished, or absent from a
+    /// replayed schedule) comes as a `SimError::UseAfterEvict` telling it.
+    pub fn with_use_checks(mut self) -> Self {
+        self.departures = Some(HashMap::default());
+        self
+    }
+
+    /// When and how `data` last left the device, with `with_use_checks`.
+    pub fn departure(&self, data: &D) -> Option<&Departure> {
+        self.departures.as_ref()?.get(data)
+    }
+
+    /// How the branch of an `If` is picked; `BranchPolicy::WorstCase` by
+    /// default.
+    pub fn with_branch_policy(mut self, policy: BranchPolicy<D>) -> Self {
+        if let BranchPolicy::Random(seed) = policy {
+            self.branch_rng = StdRng::seed_from_u64(seed);
+        }
+        self.branches = policy;
+        self
+    }
+
+    /// Puts `data` ((id, size) pairs) into `region` before running, like
+    /// weights loaded at deployment time. This is not counted as traffic in
+    /// `sim_stats` (the memories' own trip counters do see it); the host
+    /// keeps a copy of each.
+    pub fn warm_start<TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &mut self,
+        region: &str,
+        data: &[(D, usize)],
+        srams: &mut HashMap<String, TM>,
+        dram: &mut HM,
+    ) -> Result<(), SimError> {
+        let mem = srams
+            .get_mut(region)
+            .ok_or_else(|| SimError::UnknownRegion(region.into()))?;
+        for (id, size) in data.iter() {
+            if !dram.contains(id) {
+                dram.put(id, *size, false)?;
+            }
+            if !mem.contains(id) {
+                mem.put(id, *size, false)?;
+                self.heuristic.touch(id, *size);
+                self.seen.insert(id.clone());
+                self.preloaded.insert(id.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Protects `data` from eviction for the rest of the simulation, on top of
+    /// the operands of the running compute. Stores keep it in its region
+    /// too. Pinning more than fits makes allocations fail with `Thrash`.
+    pub fn pin(&mut self, data: D) {
+        self.pinned.insert(data);
+    }
+
+    pub fn unpin(&mut self, data: &D) {
+        self.pinned.remove(data);
+    }
+
+    pub fn pinned(&self) -> &HashSet<D> {
+        &self.pinned
+    }
+
+    /// Removes `data` from every memory with no way to rematerialize it,
+    /// e.g. an input that is known to be dead. Instructions that need it
+    /// afterwards fail with `MemError::Banished`.
+    pub fn banish<TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &mut self,
+        data: &D,
+        srams: &mut HashMap<String, TM>,
+        dram: &mut HM,
+    ) {
+        info!("Banish: {:?}", data);
+        for mem in srams.values_mut() {
+            if mem.contains(data) {
+                mem.deallocate(data);
+            }
+        }
+        if dram.contains(data) {
+            dram.deallocate(data);
+        }
+        self.heuristic.evict(data);
+        if let Some(producers) = self.producers.as_mut() {
+            producers.remove(data);
+        }
+        self.banished.insert(data.clone());
+        self.counters.entry(data.clone()).or_default().banished = true;
+    }
+
+    pub fn is_banished(&self, data: &D) -> bool {
+        self.banished.contains(data)
+    }
+
+    /// Estimated cycles spent computing on the host so far, by the compute
+    /// cost model. They are not part of `latency`, which is the device's:
+    /// add them to weigh running computes on the host against offloading.
+    pub fn host_compute_cycles(&self) -> f64 {
+        self.host_compute_cycles
+    }
+
+    /// Estimated cycles spent recomputing dropped data so far.
+    pub fn recompute_cycles(&self) -> f64 {
+        self.recompute_cycles
+    }
+
+    /// Estimated end-to-end latency so far: computes and recomputes,
+    /// transfers, on-device accesses and compactions.
+    pub fn latency(&self) -> f64 {
+        self.compute_cycles
+            + self.recompute_cycles
+            + self.transfer_cycles
+            + self.access_cycles
+            + self.compaction_cycles
+    }
+
+    /// Copy of the simulator state (heuristic included) to branch the
+    /// simulation; pair it with snapshots of the memories.
+    pub fn snapshot(&self) -> Self
+    where
+        H: Clone,
+    {
+        self.clone()
+    }
+
+    pub fn restore(&mut self, snapshot: Self) {
+        *self = snapshot;
+    }
+
+    /// Copies of the simulator (with its heuristic and rematerialization
+    /// state) and of the memories it runs on, to explore one branch of a
+    /// decision and `restore` it if it turns out better. Observers and
+    /// metrics sinks are shared with the original.
+    pub fn fork<TM, HM>(
+        &self,
+        srams: &HashMap<String, TM>,
+        dram: &HM,
+    ) -> (Self, HashMap<String, TM>, HM)
+    where
+        H: Clone,
+        TM: Clone,
+        HM: Clone,
+    {
+        (self.clone(), srams.clone(), dram.clone())
+    }
+
+    /// Instructions executed so far, in order, without their operand trees.
+    /// Reloads show up as `Load`s and evictions as `Evict`s.
+    pub fn trace(&self) -> &[Operators<D>] {
+        &self.trace
+    }
+
+    /// The runs so far as a flat program for a code generator: one
+    /// instruction per element, operands before their users, with the
+    /// evictions and rematerializations decided by the heuristic inserted.
+    /// Replaying it needs no further eviction decisions.
+    pub fn schedule(&self) -> Vec<Operators<D>> {
+        self.trace.clone()
+    }
+
+    /// Per-data eviction and reload (rematerialization) counts of the runs so far.
+    pub fn tensor_report(&self) -> &HashMap<D, TensorCounters> {
+        &self.counters
+    }
+
+    /// Utilization, fragmentation and allocation failures of every region
+    /// over the runs so far.
+    pub fn memory_stats(&self) -> &BTreeMap<String, MemoryStats> {
+        &self.stats
+    }
+
+    /// Starts sampling the occupancy of every region after each executed
+    /// instruction.
+    pub fn record_occupancy(&mut self) {
+        self.timeline = Some(OccupancyTimeline::default());
+    }
+
+    pub fn occupancy(&self) -> Option<&OccupancyTimeline> {
+        self.timeline.as_ref()
+    }
+
+    /// Starts logging computes, transfers, evictions and compactions with
+    /// their simulated start times.
+    pub fn record_timeline(&mut self) {
+        self.events = Some(Vec::default());
+    }
+
+    pub fn sim_stats(&self) -> &SimStats {
+        &self.summary
+    }
+
+    /// `sim_stats` split by the region the traffic went to (the keys of the
+    /// memories); each has its own peak residency only.
+    pub fn region_stats(&self) -> &BTreeMap<String, SimStats> {
+        &self.region_summary
+    }
+
+    pub fn report(&self) -> &SimReport {
+        &self.report
+    }
+
+    pub fn timeline(&self) -> &[TimelineEvent] {
+        self.events.as_deref().unwrap_or_default()
+    }
+
+    /// Starts collecting every load, store, eviction, rematerialization,
+    /// compute and copy as a `SimEvent`.
+    pub fn record_events(&mut self) {
+        self.event_log = Some(Vec::default());
+    }
+
+    pub fn events(&self) -> &[SimEvent<D>] {
+        self.event_log.as_deref().unwrap_or_default()
+    }
+
+    /// Writes the recorded timeline in the Chrome trace event format (open
+    /// with `about://tracing` or Perfetto): one track per region and one for
+    /// the DMA engine. Cycles are shown as microseconds.
+    pub fn write_chrome_trace(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let tracks = self
+            .timeline()
+            .iter()
+            .map(|x| x.track.as_str())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .enumerate()
+            .map(|(i, x)| (x, i))
+            .collect::<BTreeMap<_, _>>();
+        let mut events = tracks
+            .iter()
+            .map(|(name, tid)| {
+                serde_json::json!({
+                    "name": "thread_name",
+                    "ph": "M",
+                    "pid": 0,
+                    "tid": tid,
+                    "args": { "name": name },
+                })
+            })
+            .collect::<Vec<_>>();
+        events.extend(self.timeline().iter().map(|x| {
+            serde_json::json!({
+                "name": x.name,
+                "ph": if x.cycles > 0.0 { "X" } else { "i" },
+                "ts": x.start,
+                "dur": x.cycles,
+                "pid": 0,
+                "tid": tracks[x.track.as_str()],
+            })
+        }));
+        serde_json::to_writer(&mut *out, &serde_json::json!({ "traceEvents": events }))?;
+        Ok(())
+    }
+
+    pub fn run<TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &mut self,
+        ops: &mut Operators<D>,
+        srams: &mut HashMap<String, TM>,
+        dram: &mut HM,
+    ) -> Result<f64, SimError> {
+        self.plan(&ops.linearize(), ops.output().map(|x| x.1));
+        self.execute(ops, srams, dram)
+    }
+
+    /// Runs `ops` with the analyses of the last `plan`, which covered it.
+    pub(crate) fn execute<TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &mut self,
+        ops: &Operators<D>,
+        srams: &mut HashMap<String, TM>,
+        dram: &mut HM,
+    ) -> Result<f64, SimError> {
+        let start = self.latency();
+        // wrappers of a loop apply to every iteration
+        let ops = ops.hoist_loops();
+        // an explicit stack rather than recursion, so that traces as deep as
+        // hundreds of sequential layers do not overflow the native stack
+        let mut stack = vec![Frame::Enter(ops.as_ref())];
+        while let Some(frame) = stack.pop() {
+            self.run_frame(frame, &mut stack, srams, dram)?;
+        }
+        // estimated latency of `ops`
+        Ok(self.latency() - start)
+    }
+
+    fn run_frame<'a, TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &mut self,
+        frame: Frame<'a, D>,
+        stack: &mut Vec<Frame<'a, D>>,
+        srams: &mut HashMap<String, TM>,
+        dram: &mut HM,
+    ) -> Result<(), SimError> {
+        let ops = match frame {
+            Frame::Enter(ops) => ops,
+            Frame::Perform(ops) => {
+                if let Err(e) = self.perform_op(ops, srams, dram, &HashSet::default()) {
+                    return Err(self.diagnose(e, ops, srams));
+                }
+                return self.finish_step(ops, srams, dram);
+            }
+            Frame::Repeat(0, _) => return Ok(()),
+            Frame::Repeat(count, body) => {
+                stack.push(Frame::Repeat(count - 1, body));
+                stack.push(Frame::Enter(body));
+                return Ok(());
+            }
+            Frame::Measure(count, body) => {
+                let before = self.progress();
+                stack.push(Frame::Enter(body));
+                stack.push(Frame::Scale(Box::new(before), count - 3, body));
+                stack.push(Frame::Enter(body));
+                return Ok(());
+            }
+            Frame::Scale(before, times, body) => {
+                self.skip_iterations(body, &before, times);
+                return Ok(());
+            }
+        };
+        match ops {
+            Operators::NoOp => {}
+            // a conditional runs as one instruction, see `linearize`
+            Operators::Evict(..) | Operators::Copy(..) | Operators::If(..) => {
+                stack.push(Frame::Perform(ops))
+            }
+            Operators::Repeat(count, body) if self.fast_repeat && *count > 3 => {
+                // cold first iteration, steady second one scaled up, and the
+                // last one run for real so data dies where it should
+                stack.push(Frame::Measure(*count, body));
+                stack.push(Frame::Enter(body));
+            }
+            Operators::Repeat(count, body) => stack.push(Frame::Repeat(*count, body)),
+            Operators::Sync(_) | Operators::Barrier => stack.push(Frame::Perform(ops)),
+            // wrappers apply when the instruction runs, after the operands of
+            // the instruction they wrap, however deeply
+            Operators::Pool(..)
+            | Operators::Backing(..)
+            | Operators::Dma(..)
+            | Operators::Scratch(..)
+            | Operators::InPlace(..) => {
+                stack.push(Frame::Perform(ops));
+                match ops.unwrap_wrappers() {
+                    Operators::Compute(_, _, _, subops, _) => {
+                        stack.extend(subops.iter().rev().map(|x| Frame::Enter(&x.1)))
+                    }
+                    Operators::Load(_, (_, op), _) | Operators::Store(_, _, (_, op), _) => {
+                        stack.push(Frame::Enter(op))
+                    }
+                    _ => {}
+                }
+            }
+            Operators::Load(_, (_, op), _) | Operators::Store(_, _, (_, op), _) => {
+                stack.push(Frame::Perform(ops));
+                stack.push(Frame::Enter(op));
+            }
+            Operators::Compute(_, _, _, subops, _) => {
+                stack.push(Frame::Perform(ops));
+                stack.extend(subops.iter().rev().map(|x| Frame::Enter(&x.1)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `programs` back to back on the same memories, e.g. an encoder
+    /// then a decoder: what is resident at the end of one stays for the next,
+    /// and `sim_stats` adds them all up. Liveness, reference counts and
+    /// prefetches cover the whole batch, so what a program leaves for a
+    /// later one is not freed; the results of every program are kept.
+    pub fn run_batch<TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &mut self,
+        programs: &mut [Operators<D>],
+        srams: &mut HashMap<String, TM>,
+        dram: &mut HM,
+    ) -> Result<Vec<ProgramStats>, SimError> {
+        let steps = programs
+            .iter()
+            .flat_map(|x| x.linearize())
+            .collect::<Vec<_>>();
+        self.plan(
+            &steps,
+            programs.iter().filter_map(|x| x.output()).map(|x| x.1),
+        );
+        let mut result = vec![];
+        for program in programs.iter() {
+            let before = self.summary.clone();
+            let peaks = std::mem::take(&mut self.summary.peak_residency);
+            let latency = self.execute(program, srams, dram);
+            let stats = self.summary.since(&before);
+            for (region, peak) in peaks {
+                let total = self.summary.peak_residency.entry(region).or_default();
+                *total = (*total).max(peak);
+            }
+            let latency = latency?;
+            result.push(ProgramStats { latency, stats });
+        }
+        Ok(result)
+    }
+
+    /// Runs a flat schedule, e.g. from `schedule`, instruction by instruction,
+    /// with the analyses of the whole schedule.
+    pub fn run_schedule<TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &mut self,
+        schedule: &[Operators<D>],
+        srams: &mut HashMap<String, TM>,
+        dram: &mut HM,
+    ) -> Result<f64, SimError> {
+        self.plan_schedule(schedule);
+        let start = self.latency();
+        for op in schedule.iter() {
+            self.execute(op, srams, dram)?;
+        }
+        Ok(self.latency() - start)
+    }
+
+    /// Runs the nodes of `dag` in topological order, so that data shared by
+    /// several users is produced once, and returns the cycles it took.
+    pub fn run_dag<TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &mut self,
+        dag: &Dag<D>,
+        srams: &mut HashMap<String, TM>,
+        dram: &mut HM,
+    ) -> Result<f64, SimError> {
+        self.run_schedule(&dag.schedule(), srams, dram)
+    }
+
+    /// Executes `ops` one instruction at a time; see `Stepper`.
+    pub fn stepper<'a, TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &'a mut self,
+        ops: &Operators<D>,
+        srams: &'a mut HashMap<String, TM>,
+        dram: &'a mut HM,
+    ) -> Stepper<'a, H, D, TM, HM> {
+        let steps = ops.linearize();
+        self.plan(&steps, ops.output().map(|x| x.1));
+        Stepper {
+            sim: self,
+            srams,
+            dram,
+            pending: steps.into(),
+        }
+    }
+}
+
+/// Runs a program instruction by instruction, operands first, as `run` would.
+/// Between steps, the simulator and the memories can be inspected.
+pub struct Stepper<'a, H, D, TM, HM>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+    H: Heuristic<D>,
+{
+    sim: &'a mut JitSim<H, D>,
+    srams: &'a mut HashMap<String, TM>,
+    dram: &'a mut HM,
+    pending: VecDeque<Operators<D>>,
+}
+
+impl<'a, H, D, TM, HM> Stepper<'a, H, D, TM, HM>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+    H: Heuristic<D>,
+    TM: TargetMemory<D>,
+    HM: HostMemory<D>,
+{
+    pub fn sim(&self) -> &JitSim<H, D> {
+        self.sim
+    }
+
+    pub fn srams(&self) -> &HashMap<String, TM> {
+        self.srams
+    }
+
+    pub fn dram(&self) -> &HM {
+        self.dram
+    }
+
+    /// The instruction the next step will execute.
+    pub fn peek(&self) -> Option<&Operators<D>> {
+        self.pending.front()
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+impl<'a, H, D, TM, HM> Iterator for Stepper<'a, H, D, TM, HM>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+    H: Heuristic<D>,
+    TM: TargetMemory<D>,
+    HM: HostMemory<D>,
+{
+    /// The executed instruction
+    type Item = Result<Operators<D>, SimError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let op = self.pending.pop_front()?;
+        if let Err(e) = self
+            .sim
+            .perform_op(&op, self.srams, self.dram, &HashSet::default())
+        {
+            // nothing after a failed instruction can run
+            self.pending.clear();
+            return Some(Err(e));
+        }
+        if let Err(e) = self.sim.finish_step(&op, self.srams, self.dram) {
+            self.pending.clear();
+            return Some(Err(e));
+        }
+        Some(Ok(op))
+    }
+}
+
+impl<H, D> JitSim<H, D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+    H: Heuristic<D>,
+{
+    /// Applies `f` to the totals and to those of the current region.
+    fn count(&mut self, f: impl Fn(&mut SimStats)) {
+        let region = self.current_region.clone();
+        self.count_in(&region, f);
+    }
+
+    fn count_in(&mut self, region: &str, f: impl Fn(&mut SimStats)) {
+        f(&mut self.summary);
+        f(self.region_summary.entry(region.into()).or_default());
+    }
+
+    fn depart(&mut self, data: &D, region: &str, how: &'static str) -> Departure {
+        let departure = Departure {
+            step: self.steps,
+            region: region.into(),
+            how,
+            decision: self.decision.take(),
+        };
+        if let Some(departures) = self.departures.as_mut() {
+            departures.insert(data.clone(), departure.clone());
+        }
+        departure
+    }
+
+    /// `error` with a report of the region, if it is a device allocation
+    /// failure of `op`.
+    fn diagnose<TM: TargetMemory<D>>(
+        &self,
+        error: SimError,
+        op: &Operators<D>,
+        srams: &HashMap<String, TM>,
+    ) -> SimError {
+        let (requested, allocated, capacity) = match error {
+            SimError::Memory(MemError::OutOfMemory {
+                memory: "SRAM",
+                requested,
+                allocated,
+                total,
+            }) => (requested, allocated, total),
+            _ => return error,
+        };
+        let (region, data) = match op.output() {
+            Some((region, data)) if srams.contains_key(region) => (region, data),
+            _ => return error,
+        };
+        let excluded = match op.unwrap_wrappers() {
+            Operators::Compute(_, _, _, args, _) => args.iter().map(|x| &x.0).collect(),
+            _ => vec![],
+        };
+        let mut report = self.oom_report(Some(data), requested, &srams[region], &excluded);
+        report.region = region.clone();
+        report.allocated = allocated;
+        report.capacity = capacity;
+        SimError::OutOfMemory(Box::new(report))
+    }
+
+    /// What `mem`, the current region, holds when allocating `requested`
+    /// bytes for `data` (if known) fails.
+    fn oom_report<TM: TargetMemory<D>>(
+        &self,
+        data: Option<&D>,
+        requested: usize,
+        mem: &TM,
+        excluded: &[&D],
+    ) -> OomReport {
+        let mut residents = mem
+            .iter()
+            .map(|(data, size)| (format!("{:?}", data), size))
+            .collect::<Vec<_>>();
+        residents.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        // sets, listed in a stable order
+        let names = |data: &mut dyn Iterator<Item = &D>| {
+            let mut names = data.map(|x| format!("{:?}", x)).collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        OomReport {
+            region: self.current_region.clone(),
+            data: data.map_or_else(String::new, |x| format!("{:?}", x)),
+            requested,
+            allocated: mem.size_allocated(),
+            capacity: mem.size_total(),
+            residents,
+            excluded: names(&mut excluded.iter().cloned()),
+            pinned: names(&mut self.pinned.iter()),
+            recent_evictions: self
+                .recent_evictions
+                .iter()
+                .map(|(data, departure)| (format!("{:?}", data), departure.clone()))
+                .collect(),
+        }
+    }
+
+    /// `error` about `data`, with the last departure of `data` if known.
+    pub(crate) fn explain(&self, data: &D, error: MemError) -> SimError {
+        match self.departure(data) {
+            Some(departure) => SimError::UseAfterEvict {
+                data: format!("{:?}", data),
+                step: self.steps,
+                departure: Box::new(departure.clone()),
+                error,
+            },
+            None => error.into(),
+        }
+    }
+
+    fn count_host_allocation(&mut self, size: usize) {
+        self.count_in("host", |x| {
+            x.host_allocations += 1;
+            x.host_bytes_allocated += size;
+        });
+    }
+
+    /// Puts a marker on the occupancy timeline, if it is recorded.
+    fn mark(&mut self, kind: &'static str, data: &D) {
+        let time = self.overlapped_latency();
+        if let Some(timeline) = self.timeline.as_mut() {
+            timeline.markers.push(Marker {
+                time,
+                region: self.current_region.clone(),
+                kind,
+                data: format!("{:?}", data),
+            });
+        }
+    }
+
+    fn log_step(&mut self) {
+        if let Some((sink, logged)) = self.log.as_mut() {
+            let mut sink = sink.lock().unwrap();
+            if let Err(e) = Operators::write_logs(&self.trace[*logged..], &mut *sink) {
+                warn!("Failed to log instructions: {}", e);
+            }
+            *logged = self.trace.len();
+        }
+    }
+
+    fn dump_step<TM: TargetMemory<D>>(&mut self, op: &Operators<D>, srams: &HashMap<String, TM>) {
+        if let Some(dump) = self.dump.as_ref() {
+            let mut text = format!("step {}: {}\n", self.steps, op.compile());
+            let regions = srams.iter().collect::<BTreeMap<_, _>>();
+            for (region, mem) in regions {
+                let entries = mem
+                    .memory_map()
+                    .iter()
+                    .map(|(data, size, offset)| match offset {
+                        Some(offset) => format!("{:?} ({} @ {})", data, size, offset),
+                        None => format!("{:?} ({})", data, size),
+                    })
+                    .collect::<Vec<_>>();
+                text.push_str(&format!(
+                    "  {} [{} / {}]: {}\n",
+                    region,
+                    mem.size_allocated(),
+                    mem.size_total(),
+                    entries.join(", ")
+                ));
+            }
+            if let Err(e) = dump.lock().unwrap().write_all(text.as_bytes()) {
+                warn!("Failed to dump memory map: {}", e);
+            }
+        }
+    }
+
+    fn notify(&self, f: impl Fn(&mut dyn Observer<D>)) {
+        for observer in self.observers.iter() {
+            f(&mut *observer.lock().unwrap());
+        }
+    }
+
+    fn log_event(&mut self, event: SimEvent<D>) {
+        info!("{}", event);
+        if let Some(log) = self.event_log.as_mut() {
+            log.push(event);
+        }
+    }
+
+    fn emit(&self, metric: Metric) {
+        for sink in self.metrics.iter() {
+            sink.lock().unwrap().record(&metric);
+        }
+    }
+
+    /// Bookkeeping after every executed instruction.
+    fn finish_step<TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &mut self,
+        op: &Operators<D>,
+        srams: &mut HashMap<String, TM>,
+        dram: &HM,
+    ) -> Result<(), SimError> {
+        self.free_dead(srams);
+        self.steps += 1;
+        self.emit(Metric::OpExecuted {
+            region: op.output().map_or("host", |x| x.0.as_str()),
+            kind: op.kind(),
+        });
+        self.record_row(op, srams, dram);
+        self.record_stats(op, srams);
+        self.dump_step(op, srams);
+        self.log_step();
+        self.prefetch(srams, dram)
+    }
+
+    fn progress(&self) -> Progress {
+        Progress {
+            summary: self.summary.clone(),
+            regions: self.region_summary.clone(),
+            cycles: [
+                self.transfer_cycles,
+                self.access_cycles,
+                self.compaction_cycles,
+                self.overlappable_cycles,
+                self.compute_cycles,
+                self.recompute_cycles,
+                self.host_compute_cycles,
+            ],
+            energy: self.energy,
+            clocks: self.overlap.clone(),
+        }
+    }
+
+    /// Accounts for `times` more iterations of `body` as if each did what
+    /// was done since `before`, without running them.
+    fn skip_iterations(&mut self, body: &Operators<D>, before: &Progress, times: usize) {
+        let now = self.progress();
+        let delta = now.summary.since(&before.summary);
+        self.summary.add_scaled(&delta, times);
+        for (region, stats) in self.region_summary.iter_mut() {
+            if let Some(earlier) = before.regions.get(region) {
+                stats.add_scaled(&now.regions[region].since(earlier), times);
+            }
+        }
+        let scale = |now: f64, before: f64| now + (now - before) * times as f64;
+        self.transfer_cycles = scale(now.cycles[0], before.cycles[0]);
+        self.access_cycles = scale(now.cycles[1], before.cycles[1]);
+        self.compaction_cycles = scale(now.cycles[2], before.cycles[2]);
+        self.overlappable_cycles = scale(now.cycles[3], before.cycles[3]);
+        self.compute_cycles = scale(now.cycles[4], before.cycles[4]);
+        self.recompute_cycles = scale(now.cycles[5], before.cycles[5]);
+        self.host_compute_cycles = scale(now.cycles[6], before.cycles[6]);
+        self.energy.compute = scale(now.energy.compute, before.energy.compute);
+        self.energy.sram = scale(now.energy.sram, before.energy.sram);
+        self.energy.dram = scale(now.energy.dram, before.energy.dram);
+        self.energy.dma = scale(now.energy.dma, before.energy.dma);
+        if let (Some(clocks), Some(earlier)) = (self.overlap.as_mut(), before.clocks.as_ref()) {
+            clocks.compute = scale(clocks.compute, earlier.compute);
+            clocks.dma = scale(clocks.dma, earlier.dma);
+        }
+        self.skip_steps(body, times);
+    }
+
+    /// Whether the first branch of `(if cond then otherwise)` runs.
+    fn take_then<TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &mut self,
+        cond: &D,
+        then: &Operators<D>,
+        otherwise: &Operators<D>,
+        srams: &HashMap<String, TM>,
+        dram: &HM,
+    ) -> bool {
+        match &self.branches {
+            BranchPolicy::WorstCase => {
+                let then = self.trial(then, srams, dram);
+                let otherwise = self.trial(otherwise, srams, dram);
+                info!("Branch on {:?}: {} vs {} cycles", cond, then, otherwise);
+                then >= otherwise
+            }
+            BranchPolicy::Profile(profile) => profile.get(cond).is_none_or(|&p| p >= 0.5),
+            BranchPolicy::Random(_) => self.branch_rng.gen_bool(0.5),
+        }
+    }
+
+    /// Latency of `ops` run on copies of the memories by a scratch
+    /// simulator (random eviction, same cost model); infinite if it fails.
+    fn trial<TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &self,
+        ops: &Operators<D>,
+        srams: &HashMap<String, TM>,
+        dram: &HM,
+    ) -> f64 {
+        let mut sim =
+            JitSim::new(RandomEviction::seeded(0)).with_branch_policy(self.branches.clone());
+        sim.compute_cost = self.compute_cost.clone();
+        sim.fast_repeat = self.fast_repeat;
+        let mut srams = srams.clone();
+        let mut dram = dram.clone();
+        sim.run(&mut ops.clone(), &mut srams, &mut dram)
+            .unwrap_or(f64::INFINITY)
+    }
+
+    /// Moves the analyses of `plan` past `times` runs of `ops` that will not
+    /// happen, so that their positions stay in step with the program.
+    fn skip_steps(&mut self, ops: &Operators<D>, times: usize) {
+        let steps = ops.linearize();
+        self.steps += steps.len() * times;
+        self.skip_reads(ops, times);
+        if let Some(prefetcher) = self.prefetcher.as_mut() {
+            let loads = steps
+                .iter()
+                .filter(|x| matches!(x.unwrap_wrappers(), Operators::Load(r, ..) if r != "host"))
+                .count();
+            let skipped = (loads * times).min(prefetcher.upcoming.len());
+            prefetcher.upcoming.drain(..skipped);
+        }
+    }
+
+    /// Discounts the reads of `times` runs of `ops` that will not happen
+    /// from the reference counts.
+    fn skip_reads(&mut self, ops: &Operators<D>, times: usize) {
+        if let Some(refcounts) = self.refcounts.as_mut() {
+            for (data, reads) in ops.read_counts() {
+                if let Some(left) = refcounts.get_mut(&data) {
+                    *left = left.saturating_sub(reads * times);
+                }
+            }
+        }
+    }
+
+    /// Analyses the instructions `steps` before running them, as many
+    /// programs as they span; `results` are never freed.
+    pub(crate) fn plan<'a>(
+        &mut self,
+        steps: &[Operators<D>],
+        results: impl IntoIterator<Item = &'a D>,
+    ) where
+        D: 'a,
+    {
+        if let Some(refcounts) = self.refcounts.as_mut() {
+            *refcounts = Operators::read_counts_in(steps);
+            for result in results {
+                refcounts.remove(result);
+            }
+        }
+        if let Some(liveness) = self.liveness.as_mut() {
+            liveness.dead_after.clear();
+            for (data, i) in Operators::last_uses_in(steps) {
+                liveness.dead_after.entry(i).or_default().push(data);
+            }
+            liveness.start = self.steps;
+        }
+        if let Some(prefetcher) = self.prefetcher.as_mut() {
+            // wrapped loads too, as `skip_steps` counts them
+            prefetcher.upcoming = steps
+                .iter()
+                .filter_map(|x| match x.unwrap_wrappers() {
+                    Operators::Load(region, (data, _), size) if region != "host" => {
+                        Some((region.clone(), data.clone(), *size))
+                    }
+                    _ => None,
+                })
+                .collect();
+        }
+    }
+
+    /// `plan` for a flat schedule, whose last instruction gives the result.
+    pub(crate) fn plan_schedule(&mut self, schedule: &[Operators<D>]) {
+        let steps = schedule
+            .iter()
+            .flat_map(|x| x.linearize())
+            .collect::<Vec<_>>();
+        self.plan(
+            &steps,
+            schedule.last().and_then(|x| x.output()).map(|x| x.1),
+        );
+    }
+
+    /// Deallocates the data whose last use was the instruction just executed.
+    fn free_dead<TM: TargetMemory<D>>(&mut self, srams: &mut HashMap<String, TM>) {
+        let dead = match self.liveness.as_mut() {
+            Some(liveness) => liveness
+                .dead_after
+                .remove(&(self.steps - liveness.start))
+                .unwrap_or_default(),
+            None => return,
+        };
+        for data in dead.iter() {
+            for (region, mem) in srams.iter_mut().filter(|x| x.1.contains(data)) {
+                info!("Dead: {:?}", data);
+                mem.deallocate(data);
+                self.count_in(region, |x| x.deallocations += 1);
+                self.depart(data, region, "dead");
+                self.heuristic.evict(data);
+            }
+        }
+    }
+
+    /// Counts a read of each of `data`; what was read for the last time is
+    /// freed from every memory.
+    fn count_reads<'a, TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &mut self,
+        data: impl Iterator<Item = &'a D>,
+        srams: &mut HashMap<String, TM>,
+        dram: &mut HM,
+    ) where
+        D: 'a,
+    {
+        let refcounts = match self.refcounts.as_mut() {
+            Some(refcounts) => refcounts,
+            None => return,
+        };
+        let mut dead = vec![];
+        for data in data {
+            if let Some(count) = refcounts.get_mut(data).filter(|x| **x > 0) {
+                *count -= 1;
+                if *count == 0 {
+                    dead.push(data.clone());
+                }
+            }
+        }
+        for data in dead.iter() {
+            info!("Free: {:?}", data);
+            for (region, mem) in srams.iter_mut().filter(|x| x.1.contains(data)) {
+                mem.deallocate(data);
+                self.count_in(region, |x| x.deallocations += 1);
+                self.depart(data, region, "released");
+                self.heuristic.evict(data);
+            }
+            if dram.contains(data) {
+                dram.deallocate(data);
+            }
+        }
+    }
+
+    /// Loads upcoming operands into free room, without evicting anything.
+    fn prefetch<TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &mut self,
+        srams: &mut HashMap<String, TM>,
+        dram: &HM,
+    ) -> Result<(), SimError> {
+        let candidates = match self.prefetcher.as_ref() {
+            Some(prefetcher) => prefetcher
+                .upcoming
+                .iter()
+                .take(prefetcher.depth)
+                .cloned()
+                .collect::<Vec<_>>(),
+            None => return Ok(()),
+        };
+        for (region, data, size) in candidates {
+            let mem = match srams.get_mut(&region) {
+                Some(mem) => mem,
+                None => continue,
+            };
+            if mem.contains(&data)
+                || !dram.contains(&data)
+                || self.banished.contains(&data)
+                || !mem.can_allocate(&data, size)
+            {
+                continue;
+            }
+            info!("Prefetch {:?}", data);
+            self.current_region = region.clone();
+            mem.put(&data, size, false)?;
+            self.record_load(&data, size, mem, dram);
+            self.heuristic.touch(&data, size);
+            self.count_in(&region, |x| x.prefetches += 1);
+            if self.seen.insert(data.clone()) {
+                self.count_in(&region, |x| x.cold_loads += 1);
+            }
+            self.trace.push(Operators::Load(
+                region,
+                (data.clone(), Box::new(Operators::NoOp)),
+                size,
+            ));
+            if let Some(prefetcher) = self.prefetcher.as_mut() {
+                prefetcher.pending.insert(data);
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks the demand load of `data` into `region` as done, counting a hit
+    /// if a prefetch brought it in already.
+    fn consume_prefetch(&mut self, region: &str, data: &D, resident: bool) {
+        if let Some(prefetcher) = self.prefetcher.as_mut() {
+            if let Some(i) = prefetcher
+                .upcoming
+                .iter()
+                .position(|x| x.0 == region && x.1 == *data)
+            {
+                prefetcher.upcoming.drain(..=i);
+            }
+            if prefetcher.pending.remove(data) && resident {
+                self.count(|x| x.prefetch_hits += 1);
+            }
+        }
+    }
+
+    /// Wraps the instruction traced last, if any was traced since `start`
+    /// (e.g. to keep its pool assignment).
+    fn wrap_traced(&mut self, start: usize, wrap: impl FnOnce(Box<Operators<D>>) -> Operators<D>) {
+        if self.trace.len() > start {
+            let last = self.trace.pop().unwrap();
+            self.trace.push(wrap(Box::new(last)));
+        }
+    }
+
+    /// Schedules a transfer on the DMA engine in overlapped timing.
+    /// With the DMA queue, a transfer only waits for `data` itself, which is
+    /// ready once it arrives unless `after_compute` (a write-back).
+    fn schedule_dma(&mut self, data: &D, cycles: f64, after_compute: bool) {
+        if let Some(clocks) = self.overlap.as_mut() {
+            let start = match self.dma_queue.as_mut() {
+                Some(queue) => queue.transfer(data, cycles, clocks.dma, !after_compute),
+                None if after_compute => clocks.dma.max(clocks.compute),
+                None => clocks.dma,
+            };
+            clocks.dma = start + cycles;
+        }
+    }
+
+    /// Schedules work on the compute units in overlapped timing; it starts
+    /// once every transfer issued so far is done, or with the DMA queue once
+    /// `operands` are ready, `output` being ready when it finishes.
+    fn schedule_compute(&mut self, operands: &[&D], output: Option<&D>, cycles: f64) {
+        if let Some(clocks) = self.overlap.as_mut() {
+            match self.dma_queue.as_mut() {
+                Some(queue) => {
+                    let free = clocks.compute.max(queue.issue);
+                    let start = operands
+                        .iter()
+                        .filter_map(|x| queue.ready.get(*x))
+                        .fold(free, |x, &y| x.max(y));
+                    queue.stats.stall_cycles += start - free;
+                    clocks.compute = start + cycles;
+                    if let Some(output) = output {
+                        queue.ready.insert(output.clone(), clocks.compute);
+                    }
+                }
+                None => clocks.compute = clocks.compute.max(clocks.dma) + cycles,
+            }
+        }
+    }
+
+    /// Waits for every transfer in flight, e.g. before moving data around.
+    fn drain_dma(&mut self) {
+        if let (Some(clocks), Some(queue)) = (self.overlap.as_mut(), self.dma_queue.as_mut()) {
+            let free = clocks.compute.max(queue.issue);
+            queue.stats.stall_cycles += (clocks.dma - free).max(0.0);
+            clocks.compute = free.max(clocks.dma);
+        }
+    }
+
+    /// Lets the compute units wait for the transfers in flight, and with
+    /// `barrier` the transfers wait for the compute units as well. There is a
+    /// single DMA engine, so a `Sync` of any queue waits for all of them.
+    fn synchronize(&mut self, barrier: bool) {
+        self.drain_dma();
+        if let Some(clocks) = self.overlap.as_mut() {
+            clocks.compute = clocks.compute.max(clocks.dma);
+            if barrier {
+                clocks.dma = clocks.compute;
+            }
+        }
+    }
+
+    /// Logs an activity of `cycles` on `track`, if the timeline is recorded.
+    fn record_event(&mut self, name: String, track: &str, cycles: f64) {
+        let start = match self.overlap.as_ref() {
+            // already scheduled on its engine
+            Some(clocks) if track == "dma" => clocks.dma - cycles,
+            Some(clocks) => clocks.compute - cycles,
+            None => self.latency(),
+        };
+        if let Some(events) = self.events.as_mut() {
+            events.push(TimelineEvent {
+                name,
+                track: track.into(),
+                start,
+                cycles,
+            });
+        }
+    }
+
+    fn record_row<TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &mut self,
+        op: &Operators<D>,
+        srams: &HashMap<String, TM>,
+        dram: &HM,
+    ) {
+        let region = op.output().map(|x| x.0.clone()).unwrap_or_default();
+        let occupancy = match srams.get(&region) {
+            Some(mem) => mem.size_allocated(),
+            None => dram.size_allocated(),
+        };
+        self.report.rows.push(InstructionRow {
+            step: self.steps,
+            kind: op.kind().into(),
+            region,
+            bytes_moved: std::mem::take(&mut self.bytes_moved),
+            occupancy,
+        });
+    }
+
+    fn record_stats<TM: TargetMemory<D>>(
+        &mut self,
+        op: &Operators<D>,
+        srams: &HashMap<String, TM>,
+    ) {
+        for (region, mem) in srams.iter() {
+            self.stats.entry(region.clone()).or_default().sample(mem);
+            let peak = self
+                .summary
+                .peak_residency
+                .entry(region.clone())
+                .or_default();
+            *peak = (*peak).max(mem.size_allocated());
+            let peak = self
+                .region_summary
+                .entry(region.clone())
+                .or_default()
+                .peak_residency
+                .entry(region.clone())
+                .or_default();
+            *peak = (*peak).max(mem.size_allocated());
+        }
+        let time = self.overlapped_latency();
+        if let Some(timeline) = self.timeline.as_mut() {
+            timeline.record(self.steps, time, srams);
+        }
+        // allocations of `op` all happen in its own region
+        let failed = std::mem::take(&mut self.failed_allocations);
+        if let Some((region, _)) = op.output() {
+            if let Some(stats) = self.stats.get_mut(region) {
+                stats.failed_allocations += failed;
+            }
+        }
+    }
+
+    fn record_load<TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &mut self,
+        data: &D,
+        size: usize,
+        sram: &TM,
+        dram: &HM,
+    ) {
+        let cycles = transfer_time(data, size, sram, dram);
+        self.schedule_dma(data, cycles, false);
+        self.record_event(format!("load {:?}", data), "dma", cycles);
+        self.transfer_cycles += cycles;
+        if sram.overlaps_loads() {
+            self.overlappable_cycles += cycles;
+        }
+        self.account_transfer(data, size, sram, dram);
+    }
+
+    fn check_banished(&self, data: &D) -> Result<(), SimError> {
+        if self.banished.contains(data) {
+            let banished = MemError::Banished {
+                data: format!("{:?}", data),
+            };
+            Err(self.explain(data, banished))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Whether `data` can be recomputed on the current region instead of
+    /// being written back.
+    fn recomputable(&self, data: &D) -> bool {
+        match self.producers.as_ref().and_then(|x| x.get(data)) {
+            Some(Operators::Compute(region, ..)) => *region == self.current_region,
+            _ => false,
+        }
+    }
+
+    /// Pushes `ev` out of `mem`, writing it back only if the host has no
+    /// up-to-date copy and it cannot be recomputed.
+    fn evict_resident<TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &mut self,
+        ev: &D,
+        mem: &mut TM,
+        dram: &mut HM,
+    ) -> Result<(), SimError> {
+        let size = mem.get(ev)?;
+        self.count(|x| x.evictions += 1);
+        let how = if dram.contains(ev) && !mem.is_dirty(ev) {
+            self.count(|x| x.deallocations += 1);
+            mem.deallocate(ev);
+            "deallocated"
+        } else if !dram.contains(ev) && self.recomputable(ev) {
+            // cheaper to recompute later than to write back now
+            self.count(|x| x.deallocations += 1);
+            mem.deallocate(ev);
+            "dropped"
+        } else {
+            mem.store(ev, true, dram)?;
+            self.record_store(ev, size, mem, dram);
+            "written back"
+        };
+        let region = self.current_region.clone();
+        let departure = self.depart(ev, &region, how);
+        self.recent_evictions.push_back((ev.clone(), departure));
+        if self.recent_evictions.len() > EVICTION_HISTORY {
+            self.recent_evictions.pop_front();
+        }
+        self.mark("evict", ev);
+        self.notify(|x| x.on_evict(&region, ev, size));
+        self.log_event(SimEvent::Evict {
+            region: region.clone(),
+            data: ev.clone(),
+            size,
+            how: how.into(),
+        });
+        self.emit(Metric::Eviction {
+            region: &region,
+            bytes: size,
+            how,
+        });
+        self.record_event(format!("evict {:?}", ev), &region, 0.0);
+        self.trace.push(Operators::Evict(
+            self.current_region.clone(),
+            ev.clone(),
+            size,
+        ));
+        self.heuristic.evict(ev);
+        if let Some(prefetcher) = self.prefetcher.as_mut() {
+            // evicted before its use
+            prefetcher.pending.remove(ev);
+        }
+        self.counters.entry(ev.clone()).or_default().evictions += 1;
+        Ok(())
+    }
+
+    fn record_store<TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &mut self,
+        data: &D,
+        size: usize,
+        sram: &TM,
+        dram: &HM,
+    ) {
+        let cycles = transfer_time(data, size, sram, dram);
+        self.count(|x| x.stores += 1);
+        self.notify(|x| x.on_store(&self.current_region, data, size));
+        self.log_event(SimEvent::Store {
+            region: self.current_region.clone(),
+            data: data.clone(),
+            size,
+        });
+        // the data may be the output of a compute still running
+        self.schedule_dma(data, cycles, true);
+        self.record_event(format!("store {:?}", data), "dma", cycles);
+        self.transfer_cycles += cycles;
+        self.account_transfer(data, size, sram, dram);
+    }
+
+    fn account_transfer<TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &mut self,
+        data: &D,
+        size: usize,
+        sram: &TM,
+        dram: &HM,
+    ) {
+        if !sram.is_unified() {
+            let bytes = dram.transfer_size(data, size);
+            self.bytes_moved += bytes;
+            self.count(|x| x.bytes_transferred += bytes);
+            self.emit(Metric::BytesMoved {
+                region: &self.current_region,
+                bytes,
+            });
+        }
+        if let Some(model) = self.energy_model.as_ref() {
+            if !sram.is_unified() {
+                let bytes = dram.transfer_size(data, size) as f64;
+                self.energy.sram += size as f64 * model.sram_per_byte;
+                self.energy.dram += bytes * model.dram_per_byte;
+                self.energy.dma += bytes * model.dma_per_byte;
+            }
+        }
+    }
+}
+
+fn assign_pool<D, TM>(
+    pool: &str,
+    op: &Operators<D>,
+    srams: &mut HashMap<String, TM>,
+) -> Result<(), MemError>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+    TM: Memory<D>,
+{
+    if let Some((region, data)) = op.output() {
+        if let Some(mem) = srams.get_mut(region) {
+            mem.assign_pool(data, pool)?;
+        }
+    }
+    Ok(())
+}
+
+/// Cycles needed to move `size` bytes of `data` between `sram` and `dram`:
+/// the fixed latencies of both ends plus the bytes on the bus at the slower
+/// of the two bandwidths.
+fn transfer_time<D, TM, HM>(data: &D, size: usize, sram: &TM, dram: &HM) -> f64
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+    TM: TargetMemory<D>,
+    HM: HostMemory<D>,
+{
+    if sram.is_unified() {
+        return 0.0;
+    }
+    let bandwidth = sram.bandwidth_of(data).min(dram.bandwidth_of(data));
+    let setup = sram.latency_of(data) + dram.latency_of(data);
+    if bandwidth.is_infinite() {
+        setup
+    } else {
+        setup + dram.transfer_size(data, size) as f64 / bandwidth
+    }
+}
+
+impl<H, D, TM, HM> DTR<Operators<D>, D, TM, HM> for JitSim<H, D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+    TM: TargetMemory<D>,
+    HM: HostMemory<D>,
+    H: Heuristic<D>,
+{
+    fn rematerialize(
+        &mut self,
+        data: &D,
+        sram: &mut TM,
+        dram: &mut HM,
+        evict_exclude: &HashSet<D>,
+    ) -> Result<(), SimError> {
+        self.check_banished(data)?;
+        let producer = match self.producers.as_ref() {
+            Some(producers) if !sram.contains(data) && !dram.contains(data) => {
+                producers.get(data).cloned()
+            }
+            _ => None,
+        };
+        if let Some(Operators::Compute(region, kind, _, args, sizes)) = producer.as_ref() {
+            let size = &sizes.output;
+            self.counters.entry(data.clone()).or_default().reloads += 1;
+            self.count(|x| x.recomputations += 1);
+            let mut lock = evict_exclude.clone();
+            lock.extend(args.iter().map(|x| x.0.clone()));
+            for (arg, _) in args.iter() {
+                self.rematerialize(arg, sram, dram, &lock)?;
+            }
+            self.allocate_buffer(data, *size, sram, dram, &lock)?;
+            sram.put(data, *size, true)?;
+            let access = (args.len() + 1) as f64 * sram.latency();
+            let compute = match self.compute_cost.as_ref() {
+                Some(cost) => cost(region, kind, *size),
+                None => 0.0,
+            };
+            let operands = args.iter().map(|x| &x.0).collect::<Vec<_>>();
+            self.schedule_compute(&operands, Some(data), access + compute);
+            self.record_event(format!("recompute {:?}", data), region, access + compute);
+            self.recompute_cycles += access + compute;
+            self.mark("recompute", data);
+            self.notify(|x| x.on_remat(region, data, *size, true));
+            self.log_event(SimEvent::Remat {
+                region: region.clone(),
+                data: data.clone(),
+                size: *size,
+                recomputed: true,
+            });
+            self.trace.push(producer.unwrap());
+        } else if !sram.contains(data) {
+            self.counters.entry(data.clone()).or_default().reloads += 1;
+            self.count(|x| x.rematerializations += 1);
+            let data_size = dram.fetch(data).map_err(|e| self.explain(data, e))?;
+            self.allocate_buffer(data, data_size, sram, dram, evict_exclude)?;
+            sram.put(data, data_size, false)?;
+            self.record_load(data, data_size, sram, dram);
+            self.mark("reload", data);
+            self.notify(|x| x.on_remat(&self.current_region, data, data_size, false));
+            self.log_event(SimEvent::Remat {
+                region: self.current_region.clone(),
+                data: data.clone(),
+                size: data_size,
+                recomputed: false,
+            });
+            self.trace.push(Operators::Load(
+                self.current_region.clone(),
+                (data.clone(), Box::new(Operators::NoOp)),
+                data_size,
+            ));
+        }
+        self.heuristic.touch(data, sram.get(data)?);
+        Ok(())
+    }
+
+    fn perform_op(
+        &mut self,
+        op: &Operators<D>,
+        srams: &mut HashMap<String, TM>,
+        dram: &mut HM,
+        exclude: &HashSet<D>,
+    ) -> Result<(), SimError> {
+        let start = self.trace.len();
+        match op {
+            Operators::Compute(region, kind, dst, ids, sizes) => {
+                let size = &sizes.output;
+                for (arg, _) in ids.iter() {
+                    self.check_banished(arg)?;
+                }
+                if *region == "host" {
+                    op.run(None as Option<&mut TM>, dram)?;
+                    let compute = match self.compute_cost.as_ref() {
+                        Some(cost) => cost(region, kind, *size),
+                        None => 0.0,
+                    };
+                    self.host_compute_cycles += compute;
+                    self.count_in(region, |x| x.host_computes += 1);
+                    self.count_host_allocation(*size);
+                    self.notify(|x| x.on_compute(region, kind, dst, *size, compute));
+                    self.log_event(SimEvent::Compute {
+                        region: region.clone(),
+                        op: kind.clone(),
+                        dst: dst.clone(),
+                        size: *size,
+                        cycles: compute,
+                    });
+                } else {
+                    self.current_region = region.clone();
+                    let mem = srams
+                        .get_mut(region)
+                        .ok_or_else(|| SimError::UnknownRegion(region.clone()))?;
+                    let evict_lock = ids.iter().map(|x| &x.0).cloned().collect::<HashSet<_>>();
+                    for arg in ids.iter().map(|x| x.0.clone()) {
+                        if !mem.contains(&arg) {
+                            self.rematerialize(&arg, mem, dram, &evict_lock)?;
+                        } else {
+                            self.heuristic.touch(&arg, mem.get(&arg)?);
+                        }
+                    }
+                    // operands are read and the output written once
+                    let mut bytes = *size;
+                    if sizes.operands.len() == ids.len() {
+                        bytes += sizes.operands.iter().sum::<usize>();
+                    } else {
+                        for arg in ids.iter() {
+                            bytes += mem.get(&arg.0)?;
+                        }
+                    }
+                    // an output no larger than the operand it overwrites
+                    // needs no buffer of its own
+                    let alias = match self.in_place.take() {
+                        Some(i) if i >= ids.len() => {
+                            return Err(MemError::NoOperand {
+                                operand: i,
+                                operands: ids.len(),
+                            }
+                            .into());
+                        }
+                        Some(i) if mem.get(&ids[i].0)? >= *size => Some(&ids[i].0),
+                        _ => None,
+                    };
+                    // room for the output and the scratch of this compute at once
+                    let scratch = std::mem::take(&mut self.scratch) + sizes.scratch;
+                    match alias {
+                        Some(src) => {
+                            self.allocate_buffer(dst, scratch, mem, dram, &evict_lock)?;
+                            mem.deallocate(src);
+                            mem.put(dst, *size, true)?;
+                            self.heuristic.evict(src);
+                        }
+                        None => {
+                            self.allocate_buffer(dst, size + scratch, mem, dram, &evict_lock)?;
+                            op.run(Some(mem), dram)?;
+                        }
+                    }
+                    if scratch > 0 {
+                        mem.put_scratch(dst, scratch)?;
+                    }
+                    let access = (ids.len() + 1) as f64 * mem.latency();
+                    let compute = match self.compute_cost.as_ref() {
+                        Some(cost) => cost(region, kind, *size),
+                        None => 0.0,
+                    };
+                    let operands = ids.iter().map(|x| &x.0).collect::<Vec<_>>();
+                    self.schedule_compute(&operands, Some(dst), access + compute);
+                    self.record_event(format!("compute {:?}", dst), region, access + compute);
+                    self.access_cycles += access;
+                    self.compute_cycles += compute;
+                    self.notify(|x| x.on_compute(region, kind, dst, *size, access + compute));
+                    self.log_event(SimEvent::Compute {
+                        region: region.clone(),
+                        op: kind.clone(),
+                        dst: dst.clone(),
+                        size: *size,
+                        cycles: access + compute,
+                    });
+                    if let Some(model) = self.energy_model.as_ref() {
+                        self.energy.compute += model.per_op;
+                        self.energy.sram += bytes as f64 * model.sram_per_byte;
+                    }
+                    mem.end_compute();
+                    if self.write_policy == WritePolicy::WriteThrough {
+                        mem.store(dst, false, dram)?;
+                        self.record_store(dst, *size, mem, dram);
+                    }
+                    self.heuristic.touch(dst, *size);
+                    if let Some(producers) = self.producers.as_mut() {
+                        producers.insert(dst.clone(), op.shallow());
+                    }
+                }
+                self.trace.push(op.shallow());
+                self.count_reads(ids.iter().map(|x| &x.0), srams, dram);
+            }
+            Operators::Load(region, (id, _op), size) => {
+                self.check_banished(id)?;
+                if *region == "host" {
+                    op.run(None as Option<&mut TM>, dram)?;
+                    self.count_host_allocation(*size);
+                    self.trace.push(op.shallow());
+                } else {
+                    self.current_region = region.clone();
+                    let mem = srams
+                        .get_mut(region)
+                        .ok_or_else(|| SimError::UnknownRegion(region.clone()))?;
+                    self.consume_prefetch(region, id, mem.contains(id));
+                    if self.preloaded.remove(id) && mem.contains(id) {
+                        self.count(|x| x.warm_hits += 1);
+                    }
+                    if !mem.contains(id) {
+                        self.allocate_buffer(id, *size, mem, dram, exclude)?;
+                        op.run(Some(mem), dram).map_err(|e| self.explain(id, e))?;
+                        self.count(|x| x.loads += 1);
+                        if self.seen.insert(id.clone()) {
+                            self.count(|x| x.cold_loads += 1);
+                        }
+                        self.record_load(id, *size, mem, dram);
+                        self.notify(|x| x.on_load(region, id, *size));
+                        self.log_event(SimEvent::Load {
+                            region: region.clone(),
+                            data: id.clone(),
+                            size: *size,
+                        });
+                        self.trace.push(op.shallow());
+                    }
+                    self.heuristic.touch(id, mem.get(id)?);
+                    self.count_reads(std::iter::once(id), srams, dram);
+                }
+            }
+            Operators::Store(region, _evict, (data, _op), _size) => {
+                self.check_banished(data)?;
+                if *region == "host" {
+                    return Err(SimError::Unsupported("a store from the host".into()));
+                } else {
+                    let mem = srams
+                        .get_mut(region)
+                        .ok_or_else(|| SimError::UnknownRegion(region.clone()))?;
+                    let data_size = mem.get(data).map_err(|e| self.explain(data, e))?;
+                    let write_back = mem.is_dirty(data) || !dram.contains(data);
+                    op.run(Some(mem), dram)?;
+                    if write_back {
+                        self.record_store(data, data_size, mem, dram);
+                    }
+                    // residents DRAM has no up-to-date copy of are written back
+                    for data in mem.to_vec().into_iter().cloned().collect::<Vec<_>>() {
+                        if mem.is_dirty(&data) || !dram.contains(&data) {
+                            let size = mem.get(&data)?;
+                            mem.store(&data, false, dram)?;
+                            self.record_store(&data, size, mem, dram);
+                        }
+                    }
+                    if self.departures.is_some() {
+                        for data in mem.to_vec().into_iter().cloned().collect::<Vec<_>>() {
+                            if !self.pinned.contains(&data) {
+                                self.depart(&data, region, "stored");
+                            }
+                        }
+                    }
+                    if self.pinned.is_empty() {
+                        mem.reset();
+                        self.heuristic.reset();
+                    } else {
+                        // everything but the pinned data leaves the region
+                        for data in mem.to_vec().into_iter().cloned().collect::<Vec<_>>() {
+                            if !self.pinned.contains(&data) {
+                                mem.deallocate(&data);
+                            }
+                        }
+                        self.heuristic.reset();
+                        for data in mem.to_vec().into_iter().cloned().collect::<Vec<_>>() {
+                            self.heuristic.touch(&data, mem.get(&data)?);
+                        }
+                    }
+                    self.trace.push(op.shallow());
+                    self.count_reads(std::iter::once(data), srams, dram);
+                }
+            }
+            Operators::Pool(pool, op) => {
+                assign_pool(pool, op, srams)?;
+                self.perform_op(op, srams, dram, exclude)?;
+                self.wrap_traced(start, |x| Operators::Pool(pool.clone(), x));
+            }
+            Operators::Backing(store, op) => {
+                if let Some((_, data)) = op.output() {
+                    dram.assign_pool(data, store)?;
+                }
+                self.perform_op(op, srams, dram, exclude)?;
+                self.wrap_traced(start, |x| Operators::Backing(store.clone(), x));
+            }
+            Operators::Dma(queue, op) => {
+                self.perform_op(op, srams, dram, exclude)?;
+                self.wrap_traced(start, |x| Operators::Dma(*queue, x));
+            }
+            Operators::Sync(_) | Operators::Barrier => {
+                self.synchronize(matches!(op, Operators::Barrier));
+                self.trace.push(op.shallow());
+            }
+            Operators::Scratch(bytes, op) => {
+                self.scratch = *bytes;
+                self.perform_op(op, srams, dram, exclude)?;
+                self.scratch = 0;
+                self.wrap_traced(start, |x| Operators::Scratch(*bytes, x));
+            }
+            Operators::InPlace(operand, op) => {
+                self.in_place = Some(*operand);
+                self.perform_op(op, srams, dram, exclude)?;
+                self.in_place = None;
+                self.wrap_traced(start, |x| Operators::InPlace(*operand, x));
+            }
+            Operators::Repeat(count, op) => {
+                let steps = op.linearize();
+                for _ in 0..*count {
+                    for step in steps.iter() {
+                        self.perform_op(step, srams, dram, exclude)?;
+                    }
+                }
+            }
+            Operators::If(cond, then, otherwise) => {
+                let (taken, skipped) = if self.take_then(cond, then, otherwise, srams, dram) {
+                    (then, otherwise)
+                } else {
+                    (otherwise, then)
+                };
+                self.skip_reads(skipped, 1);
+                for step in taken.linearize() {
+                    self.perform_op(&step, srams, dram, exclude)?;
+                }
+            }
+            Operators::Evict(region, data, _) => {
+                if *region == "host" {
+                    return Err(SimError::Unsupported("an eviction from the host".into()));
+                }
+                self.current_region = region.clone();
+                let mem = srams
+                    .get_mut(region)
+                    .ok_or_else(|| SimError::UnknownRegion(region.clone()))?;
+                if mem.contains(data) && !self.pinned.contains(data) {
+                    self.decision = Some("by an evict instruction".into());
+                    self.evict_resident(data, mem, dram)?;
+                }
+            }
+            Operators::Copy(src, dst, data, size) => {
+                self.check_banished(data)?;
+                if *src == "host" || *dst == "host" {
+                    return Err(SimError::Unsupported(
+                        "a copy from or to the host, which loads and stores do".into(),
+                    ));
+                }
+                self.current_region = src.clone();
+                let from = srams
+                    .get_mut(src)
+                    .ok_or_else(|| SimError::UnknownRegion(src.clone()))?;
+                // a reload touches the data already
+                let reloaded = !from.contains(data);
+                if reloaded {
+                    self.rematerialize(data, from, dram, exclude)?;
+                }
+                // the copy needs a write-back as much as the original does
+                let dirty = from.is_dirty(data) || !dram.contains(data);
+                let (bandwidth, latency) = (from.bandwidth_of(data), from.latency_of(data));
+                self.current_region = dst.clone();
+                let to = srams
+                    .get_mut(dst)
+                    .ok_or_else(|| SimError::UnknownRegion(dst.clone()))?;
+                if !to.contains(data) {
+                    self.allocate_buffer(data, *size, to, dram, exclude)?;
+                    to.copy_in(data, *size, dirty)?;
+                    let bandwidth = bandwidth.min(to.bandwidth_of(data));
+                    let mut cycles = latency + to.latency_of(data);
+                    if !bandwidth.is_infinite() {
+                        cycles += *size as f64 / bandwidth;
+                    }
+                    self.schedule_dma(data, cycles, false);
+                    self.record_event(format!("copy {:?}", data), "dma", cycles);
+                    self.transfer_cycles += cycles;
+                    self.count(|x| x.copies += 1);
+                    let bytes = size;
+                    self.count(|x| x.bytes_copied += bytes);
+                    if let Some(model) = self.energy_model.as_ref() {
+                        // read from one SRAM, written to the other
+                        self.energy.sram += 2.0 * *size as f64 * model.sram_per_byte;
+                        self.energy.dma += *size as f64 * model.dma_per_byte;
+                    }
+                    self.notify(|x| x.on_copy(src, dst, data, *size));
+                    self.log_event(SimEvent::Copy {
+                        src: src.clone(),
+                        dst: dst.clone(),
+                        data: data.clone(),
+                        size: *size,
+                    });
+                    self.trace.push(op.shallow());
+                }
+                if !reloaded {
+                    self.heuristic.touch(data, *size);
                 }
             }
             Operators::NoOp => {}
         }
+        Ok(())
     }
 
-    fn allocate_buffer(&mut self, size: usize, mem: &mut TM, dram: &mut HM, exclude: &HashSet<D>) {
-        while mem.size_allocated() + size > mem.size_total() {
-            self.evict_single(exclude, mem, dram);
+    fn allocate_buffer(
+        &mut self,
+        data: &D,
+        size: usize,
+        mem: &mut TM,
+        dram: &mut HM,
+        exclude: &HashSet<D>,
+    ) -> Result<(), SimError> {
+        let capacity = mem.max_allocation(data);
+        if size > capacity {
+            return Err(SimError::AllocationTooLarge {
+                region: self.current_region.clone(),
+                data: format!("{:?}", data),
+                requested: size,
+                capacity,
+            });
+        }
+        let mut compacted = false;
+        if !mem.can_allocate(data, size) {
+            self.failed_allocations += 1;
+        }
+        while !mem.can_allocate(data, size) {
+            // enough free bytes, but not in one piece
+            if !compacted && mem.size_available() >= size {
+                if let Some(cost) = mem.compact() {
+                    info!("Compact for {:?}", data);
+                    let region = self.current_region.clone();
+                    // residents move, so in-flight transfers must land first
+                    self.drain_dma();
+                    self.schedule_compute(&[], None, cost);
+                    self.record_event("compact".into(), &region, cost);
+                    self.compaction_cycles += cost;
+                    compacted = true;
+                    continue;
+                }
+            }
+            self.decision = Some(format!(
+                "chosen by {} to make room for {:?} ({} bytes)",
+                std::any::type_name::<H>(),
+                data,
+                size
+            ));
+            if let Err(e) = self.evict_single(exclude, mem, dram) {
+                return Err(match e {
+                    SimError::Thrash(_) => {
+                        let excluded = exclude.iter().collect::<Vec<_>>();
+                        SimError::Thrash(Box::new(self.oom_report(
+                            Some(data),
+                            size,
+                            mem,
+                            &excluded,
+                        )))
+                    }
+                    e => e,
+                });
+            }
+            compacted = false;
         }
+        Ok(())
     }
 
-    fn evict_single(&mut self, exclude: &HashSet<D>, mem: &mut TM, dram: &mut HM) {
-        if let Some(ev) = self.heuristic.choose(mem, exclude) {
-            if dram.contains(&ev) {
-                info!("Deallocate: {:?}", ev);
-                mem.deallocate(&ev);
-            } else {
-                info!("Evict: {:?}", ev);
-                mem.store(&ev, true, dram);
-            }
-            self.heuristic.evict(&ev);
+    fn evict_single(
+        &mut self,
+        exclude: &HashSet<D>,
+        mem: &mut TM,
+        dram: &mut HM,
+    ) -> Result<(), SimError> {
+        let choice = if self.pinned.is_empty() {
+            self.heuristic.choose(mem, exclude)
+        } else {
+            let exclude = exclude.union(&self.pinned).cloned().collect();
+            self.heuristic.choose(mem, &exclude)
+        };
+        if let Some(ev) = choice {
+            self.evict_resident(&ev, mem, dram)
         } else {
-            panic!("Thrashes here...")
+            self.decision = None;
+            let excluded = exclude.iter().collect::<Vec<_>>();
+            Err(SimError::Thrash(Box::new(
+                self.oom_report(None, 0, mem, &excluded),
+            )))
         }
     }
 
-    fn deallocate(&mut self, data: &D, mem: &mut TM, dram: &mut HM) {
+    fn deallocate(&mut self, data: &D, mem: &mut TM, dram: &mut HM) -> Result<(), SimError> {
         assert!(mem.contains(data));
-        mem.store(data, true, dram);
+        Ok(mem.store(data, true, dram)?)
     }
 }
 
+/// The memory an instruction on the device `region` runs on.
+fn on_chip<'a, TM>(mem: Option<&'a mut TM>, region: &str) -> Result<&'a mut TM, MemError> {
+    mem.ok_or_else(|| MemError::NoMemory {
+        region: region.to_string(),
+    })
+}
+
 impl<D> Instruction<D> for Operators<D>
 where
     D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
@@ -254,14 +3851,27 @@ where
     fn insn_type(&self) -> InsnType {
         match self {
             &Operators::Compute(_, _, _, _, _) => InsnType::Compute,
+            Operators::Dma(..) => InsnType::DMA,
+            Operators::Sync(_) => InsnType::Sync,
+            Operators::Barrier => InsnType::Barrier,
+            Operators::Pool(_, op)
+            | Operators::Backing(_, op)
+            | Operators::Scratch(_, op)
+            | Operators::InPlace(_, op)
+            | Operators::Repeat(_, op)
+            | Operators::If(_, op, _) => op.insn_type(),
             _ => InsnType::MMIO,
         }
     }
 
-    fn run<TM: Memory<D>, HM: Memory<D>>(&self, mem: Option<&mut TM>, dram: &mut HM) {
+    fn run<TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &self,
+        mem: Option<&mut TM>,
+        dram: &mut HM,
+    ) -> Result<(), MemError> {
         match self {
-            Self::Compute(region, _, output_id, ids, size) => {
-                let _op = &ids[0].0;
+            Self::Compute(region, _, output_id, ids, sizes) => {
+                let size = &sizes.output;
                 info!(
                     "Current Op: Compute {} {:?} dst: {:?}",
                     region,
@@ -270,41 +3880,94 @@ where
                 );
                 // TODO: could do interpreter here but not necessary
                 // we are only generating schedule a la DTR
-                if *region == String::from("host") {
-                    assert!(ids.iter().all(|x| dram.contains(&x.0)));
-                    dram.put(output_id, size.clone(), true);
+                if *region == "host" {
+                    if let Some((data, _)) = ids.iter().find(|x| !dram.contains(&x.0)) {
+                        return Err(MemError::not_resident("DRAM", data));
+                    }
+                    dram.put(output_id, *size, true)?;
                 } else {
-                    if let Some(mem) = mem {
-                        assert!(ids.iter().all(|x| mem.contains(&x.0)));
-                        assert!(mem.size_total() >= mem.size_allocated() + size);
-                        mem.put(output_id, size.clone(), true);
-                    } else {
-                        panic!("No SRAM provided");
+                    let mem = on_chip(mem, region)?;
+                    if let Some((data, _)) = ids.iter().find(|x| !mem.contains(&x.0)) {
+                        return Err(MemError::not_resident("SRAM", data));
                     }
+                    mem.put(output_id, *size, true)?;
                 }
             }
             Self::Load(region, (data, _op), size) => {
                 info!("Current Op: Load {} {:?}", region, data);
-                if *region == String::from("host") {
-                    dram.put(data, size.clone(), true);
+                if *region == "host" {
+                    dram.put(data, *size, true)?;
                 } else {
-                    assert!(dram.contains(data));
-                    assert!(mem.is_some());
-                    let mem = mem.unwrap();
-                    assert!(mem.size_total() >= mem.size_allocated() + size);
-                    mem.put(data, size.clone(), false);
+                    if !dram.contains(data) {
+                        return Err(MemError::not_resident("DRAM", data));
+                    }
+                    let mem = on_chip(mem, region)?;
+                    mem.put(data, *size, false)?;
                 }
             }
             Self::Store(region, evict, (data, _op), _) => {
                 info!("Current Op: Store {} {:?} evict: {}", region, data, evict);
-                assert!(mem.is_some());
-                let mem = mem.unwrap();
-                assert!(mem.contains(data));
-                mem.store(data, *evict, dram);
+                let mem = on_chip(mem, region)?;
+                if !mem.contains(data) {
+                    return Err(MemError::not_resident("SRAM", data));
+                }
+                mem.store(data, *evict, dram)?;
                 // mem.reset();
             }
-            Self::NoOp => {}
+            Self::Evict(region, data, _) => {
+                info!("Current Op: Evict {} {:?}", region, data);
+                let mem = on_chip(mem, region)?;
+                if !mem.contains(data) {
+                    return Err(MemError::not_resident("SRAM", data));
+                }
+                if dram.contains(data) && !mem.is_dirty(data) {
+                    mem.deallocate(data);
+                } else {
+                    mem.store(data, true, dram)?;
+                }
+            }
+            Self::Pool(_, op) => op.run(mem, dram)?,
+            Self::Scratch(_, op) => op.run(mem, dram)?,
+            Self::Dma(_, op) => op.run(mem, dram)?,
+            Self::InPlace(operand, op) => match op.as_ref() {
+                Self::Compute(region, _, output_id, ids, sizes) if *region != "host" => {
+                    let (src, _) = ids.get(*operand).ok_or(MemError::NoOperand {
+                        operand: *operand,
+                        operands: ids.len(),
+                    })?;
+                    info!("Current Op: InPlace {:?} over {:?}", output_id, src);
+                    let mem = on_chip(mem, region)?;
+                    if let Some((data, _)) = ids.iter().find(|x| !mem.contains(&x.0)) {
+                        return Err(MemError::not_resident("SRAM", data));
+                    }
+                    mem.deallocate(src);
+                    mem.put(output_id, sizes.output, true)?;
+                }
+                op => op.run(mem, dram)?,
+            },
+            Self::Backing(store, op) => {
+                if let Some((_, data)) = op.output() {
+                    dram.assign_pool(data, store)?;
+                }
+                op.run(mem, dram)?
+            }
+            Self::Copy(src, dst, data, size) => {
+                info!("Current Op: Copy {} -> {} {:?}", src, dst, data);
+                let mem = on_chip(mem, dst)?;
+                mem.copy_in(data, *size, !dram.contains(data))?;
+            }
+            Self::Repeat(count, op) => {
+                let mut mem = mem;
+                for _ in 0..*count {
+                    op.run(mem.as_deref_mut(), dram)?;
+                }
+            }
+            // without a branch policy, the first branch
+            Self::If(_, then, _) => then.run(mem, dram)?,
+            // nothing to wait for without timing
+            Self::Sync(_) | Self::Barrier | Self::NoOp => {}
         }
+        Ok(())
     }
 
     fn compile(&self) -> String {
@@ -323,14 +3986,30 @@ where
             Operators::Store(region, evict, (data, _op), _) => {
                 format!("(store {} {} {:?})", region, evict, data)
             }
+            Operators::Pool(pool, op) => format!("(pool {} {})", pool, op.compile()),
+            Operators::Backing(store, op) => format!("(backing {} {})", store, op.compile()),
+            Operators::Scratch(bytes, op) => format!("(scratch {} {})", bytes, op.compile()),
+            Operators::InPlace(operand, op) => format!("(in-place {} {})", operand, op.compile()),
+            Operators::Dma(queue, op) => format!("(dma {} {})", queue, op.compile()),
+            Operators::Sync(queue) => format!("(sync {})", queue),
+            Operators::Barrier => "(barrier)".into(),
+            Operators::Evict(region, data, _) => format!("(evict {} {:?})", region, data),
+            Operators::Copy(src, dst, data, _) => format!("(copy {} {} {:?})", src, dst, data),
+            Operators::Repeat(count, op) => format!("(repeat {} {})", count, op.compile()),
+            Operators::If(cond, then, otherwise) => {
+                format!("(if {:?} {} {})", cond, then.compile(), otherwise.compile())
+            }
             Operators::NoOp => "Skip".into(),
         }
     }
 }
 
-impl InsnLogger<Id, SRAM, DRAM> for Operators<Id> {
-    fn write_log(self, logs: &mut Vec<String>) {
-        logs.push(self.compile());
+impl<D> InsnLogger<D> for Operators<D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    fn write_log<W: Write + ?Sized>(&self, sink: &mut W) -> std::io::Result<()> {
+        writeln!(sink, "{}", self.compile())
     }
 }
 
@@ -339,15 +4018,1355 @@ where
     D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
 {
     fn insn_type(&self) -> InsnType;
-    fn run<TM: Memory<D>, HM: Memory<D>>(&self, mem: Option<&mut TM>, dram: &mut HM);
+    fn run<TM: TargetMemory<D>, HM: HostMemory<D>>(
+        &self,
+        mem: Option<&mut TM>,
+        dram: &mut HM,
+    ) -> Result<(), MemError>;
     fn compile(&self) -> String;
 }
 
-pub trait InsnLogger<D, TM, HM>
+pub trait InsnLogger<D>
 where
     D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
-    TM: Memory<D>,
-    HM: Memory<D>,
 {
-    fn write_log(self, logs: &mut Vec<String>);
+    /// Writes the instruction to `sink` as one line.
+    fn write_log<W: Write + ?Sized>(&self, sink: &mut W) -> std::io::Result<()>;
+
+    /// Writes every instruction of `insns`, one per line, as it goes.
+    fn write_logs<'a, W: Write + ?Sized>(
+        insns: impl IntoIterator<Item = &'a Self>,
+        sink: &mut W,
+    ) -> std::io::Result<()>
+    where
+        Self: 'a,
+    {
+        for insn in insns {
+            insn.write_log(sink)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heuristics::LRU;
+    use crate::memory::{DRAM, SRAM};
+
+    /// Loads `data` from the host into the region `sram`.
+    fn load(data: u32, size: usize) -> (u32, Operators<u32>) {
+        let host = Operators::Load("host".into(), (data, Box::new(Operators::NoOp)), size);
+        let op = Operators::Load("sram".into(), (data, Box::new(host)), size);
+        (data, op)
+    }
+
+    fn compute(op: u32, dst: u32, args: Vec<(u32, Operators<u32>)>) -> Operators<u32> {
+        Operators::Compute("sram".into(), op, dst, args, 4.into())
+    }
+
+    fn memories(size: usize) -> (HashMap<String, SRAM<u32>>, DRAM<u32>) {
+        let srams = HashMap::from([("sram".to_string(), SRAM::new(size))]);
+        (srams, DRAM::new())
+    }
+
+    /// 4 = g(3 = f(1, 2), 1), with 1 read by both computes
+    fn read_twice() -> Operators<u32> {
+        let inner = compute(10, 3, vec![load(1, 4), load(2, 4)]);
+        compute(11, 4, vec![(3, inner), (1, Operators::NoOp)])
+    }
+
+    fn freeing() -> JitSim<LRU<u32>, u32> {
+        JitSim::new(LRU::new()).with_liveness().with_refcounting()
+    }
+
+    #[test]
+    fn schedule_is_planned_as_a_whole() {
+        let (mut srams, mut dram) = memories(64);
+        let mut sim = freeing();
+        sim.run_schedule(&read_twice().linearize(), &mut srams, &mut dram)
+            .unwrap();
+        assert_eq!(sim.sim_stats().loads, 2);
+        assert_eq!(srams["sram"].to_vec(), vec![&4]);
+    }
+
+    #[test]
+    fn batch_keeps_what_later_programs_read() {
+        let (mut srams, mut dram) = memories(64);
+        let mut sim = freeing();
+        let first = compute(10, 3, vec![load(1, 4), load(2, 4)]);
+        let second = compute(11, 4, vec![(3, Operators::NoOp), (1, Operators::NoOp)]);
+        let stats = sim
+            .run_batch(&mut [first, second], &mut srams, &mut dram)
+            .unwrap();
+        assert_eq!(stats[0].stats.loads, 2);
+        assert_eq!(stats[1].stats.loads + stats[1].stats.rematerializations, 0);
+    }
+
+    #[test]
+    fn dag_keeps_shared_operands_until_their_last_user() {
+        let (mut srams, mut dram) = memories(64);
+        let mut sim = freeing();
+        let inner = compute(10, 3, vec![load(1, 4), load(2, 4)]);
+        let tree = compute(11, 4, vec![(3, inner), load(1, 4)]);
+        sim.run_dag(&Dag::from_tree(&tree), &mut srams, &mut dram)
+            .unwrap();
+        assert_eq!(sim.sim_stats().loads, 2);
+        assert_eq!(sim.sim_stats().rematerializations, 0);
+        assert_eq!(srams["sram"].to_vec(), vec![&4]);
+    }
+
+    #[test]
+    fn stepping_through_matches_run() {
+        // 5 = h(4, 1), with room for three buffers: 4 and 5 evict
+        let tree = compute(12, 5, vec![(4, read_twice()), (1, Operators::NoOp)]);
+        let (mut srams, mut dram) = memories(12);
+        let mut sim = JitSim::new(LRU::new());
+        sim.run(&mut tree.clone(), &mut srams, &mut dram).unwrap();
+        assert!(sim.sim_stats().evictions > 0);
+
+        let (mut srams, mut dram) = memories(12);
+        let mut stepped = JitSim::new(LRU::new());
+        for step in stepped.stepper(&tree, &mut srams, &mut dram) {
+            step.unwrap();
+        }
+        assert_eq!(stepped.sim_stats(), sim.sim_stats());
+    }
+
+    #[test]
+    fn stepper_runs_one_branch_of_an_if() {
+        let branch = |op| Box::new(compute(op, 3, vec![load(1, 4)]));
+        let tree = Operators::If(0, branch(10), branch(11));
+        assert_eq!(tree.linearize().len(), 1);
+        assert_eq!(tree.last_uses(), HashMap::from([(1, 0)]));
+        let (mut srams, mut dram) = memories(64);
+        let profile = HashMap::from([(0, 0.0)]);
+        let mut sim = JitSim::new(LRU::new()).with_branch_policy(BranchPolicy::Profile(profile));
+        for step in sim.stepper(&tree, &mut srams, &mut dram) {
+            step.unwrap();
+        }
+        let ran = |op| {
+            sim.trace()
+                .iter()
+                .any(|x| matches!(x, Operators::Compute(_, x, ..) if *x == op))
+        };
+        assert!(!ran(10));
+        assert!(ran(11));
+        assert_eq!(sim.sim_stats().loads, 1);
+    }
+
+    #[test]
+    fn wrapped_loops_run_once_per_iteration() {
+        // each iteration writes its result back and empties the region
+        let body = (3, Box::new(compute(10, 3, vec![load(1, 4)])));
+        let store = Operators::Store("sram".into(), true, body, 4);
+        let looped = Operators::Repeat(2, Box::new(store));
+        let tree = Operators::Pool("p".into(), Box::new(Operators::Dma(0, Box::new(looped))));
+        let steps = tree.linearize();
+        assert_eq!(steps.iter().filter(|x| x.kind() == "compute").count(), 2);
+        assert!(steps.iter().all(|x| x.kind() != "repeat"));
+
+        let (mut srams, mut dram) = memories(64);
+        let mut sim = JitSim::new(LRU::new());
+        for step in sim.stepper(&tree, &mut srams, &mut dram) {
+            step.unwrap();
+        }
+        let computes = sim.trace().iter().filter(|x| x.kind() == "compute").count();
+        assert_eq!(computes, 2);
+    }
+
+    /// Counts its touches, never evicts.
+    #[derive(Clone, Default)]
+    struct Touches(usize);
+
+    impl Heuristic<u32> for Touches {
+        fn choose<TM: Memory<u32>>(&mut self, _sram: &TM, _exclude: &HashSet<u32>) -> Option<u32> {
+            None
+        }
+
+        fn touch(&mut self, _data: &u32, _size: usize) {
+            self.0 += 1;
+        }
+
+        fn evict(&mut self, _data: &u32) {}
+
+        fn reset(&mut self) {}
+    }
+
+    /// Loads 1 into region `a`, then copies it to `b`.
+    fn copy_over(evict: bool) -> (Vec<Operators<u32>>, HashMap<String, SRAM<u32>>) {
+        let load = |region: &str| Operators::Load(region.into(), (1, Box::new(Operators::NoOp)), 4);
+        let mut schedule = vec![load("host"), load("a")];
+        if evict {
+            schedule.push(Operators::Evict("a".into(), 1, 4));
+        }
+        schedule.push(Operators::Copy("a".into(), "b".into(), 1, 4));
+        let srams = HashMap::from([
+            ("a".to_string(), SRAM::new(16)),
+            ("b".to_string(), SRAM::new(16)),
+        ]);
+        (schedule, srams)
+    }
+
+    #[test]
+    fn copies_are_not_host_loads() {
+        let (schedule, mut srams) = copy_over(false);
+        let mut sim = JitSim::new(LRU::new());
+        sim.run_schedule(&schedule, &mut srams, &mut DRAM::new())
+            .unwrap();
+        let to = &srams["b"];
+        assert_eq!(to.trips.load_trips, 0);
+        assert_eq!(to.trips.copies, 1);
+        assert!(!to.is_dirty(&1));
+        assert_eq!(sim.sim_stats().copies, 1);
+        assert_eq!(sim.sim_stats().bytes_copied, 4);
+        // a copy does not cross the bus to DRAM
+        let copy = sim.report().rows.last().unwrap();
+        assert_eq!((copy.kind.as_str(), copy.bytes_moved), ("copy", 0));
+    }
+
+    #[test]
+    fn copy_of_reloaded_data_touches_once() {
+        let (schedule, mut srams) = copy_over(true);
+        let mut sim = JitSim::new(Touches::default());
+        sim.run_schedule(&schedule, &mut srams, &mut DRAM::new())
+            .unwrap();
+        // the load into `a`, then the reload of the copy
+        assert_eq!(sim.heuristic.0, 2);
+    }
+
+    #[test]
+    fn copy_to_the_host_is_an_error() {
+        let (mut srams, mut dram) = memories(64);
+        let copy = Operators::Copy("sram".into(), "host".into(), 1, 4);
+        let error = JitSim::new(LRU::new())
+            .run(&mut copy.clone(), &mut srams, &mut dram)
+            .unwrap_err();
+        assert!(matches!(error, SimError::Unsupported(_)));
+    }
+
+    #[test]
+    fn host_stores_and_evictions_are_errors() {
+        let (mut srams, mut dram) = memories(64);
+        let mut sim = JitSim::new(LRU::new());
+        let store = Operators::Store("host".into(), true, (1, Box::new(Operators::NoOp)), 4);
+        let evict = Operators::Evict("host".into(), 1, 4);
+        for mut op in [store, evict] {
+            let error = sim.run(&mut op, &mut srams, &mut dram).unwrap_err();
+            assert!(matches!(error, SimError::Unsupported(_)));
+        }
+    }
+
+    #[test]
+    fn compute_without_operands() {
+        let (mut srams, mut dram) = memories(64);
+        let mut sim = JitSim::new(LRU::new());
+        sim.run(&mut compute(10, 3, vec![]), &mut srams, &mut dram)
+            .unwrap();
+        assert!(srams["sram"].contains(&3));
+    }
+
+    #[test]
+    fn in_place_past_the_operands_is_an_error() {
+        let past = MemError::NoOperand {
+            operand: 2,
+            operands: 2,
+        };
+        let op = Operators::InPlace(2, Box::new(compute(10, 3, vec![load(1, 4), load(2, 4)])));
+        let (mut srams, mut dram) = memories(64);
+        let error = JitSim::new(LRU::new())
+            .run(&mut op.clone(), &mut srams, &mut dram)
+            .unwrap_err();
+        assert_eq!(error, SimError::Memory(past.clone()));
+
+        let mut step = StepSim::new(SRAM::new(64), DRAM::new());
+        for data in [1, 2] {
+            step.sram.put(&data, 4, false).unwrap();
+        }
+        Simulator::<Operators<u32>, u32>::run_insn(&mut step, op);
+        assert_eq!(step.error(), Some(&(0, past)));
+    }
+
+    #[test]
+    fn buffers_larger_than_a_bank_are_rejected_up_front() {
+        use crate::memory::BankedSRAM;
+        let op = Operators::Compute("sram".into(), 10, 3, vec![load(1, 4)], 12.into());
+        let mut srams = HashMap::from([("sram".to_string(), BankedSRAM::new(2, 8))]);
+        let error = JitSim::new(LRU::new())
+            .run(&mut op.clone(), &mut srams, &mut DRAM::new())
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            SimError::AllocationTooLarge {
+                requested: 12,
+                capacity: 8,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn unknown_pools_are_errors() {
+        use crate::memory::PooledSRAM;
+        let op = Operators::Pool("nope".into(), Box::new(compute(10, 3, vec![load(1, 4)])));
+        let pools = vec![("weights".to_string(), 16), ("acts".to_string(), 16)];
+        let mut srams = HashMap::from([("sram".to_string(), PooledSRAM::new(pools, "acts"))]);
+        let error = JitSim::new(LRU::new())
+            .run(&mut op.clone(), &mut srams, &mut DRAM::new())
+            .unwrap_err();
+        assert_eq!(
+            error,
+            SimError::Memory(MemError::UnknownPool {
+                memory: "SRAM",
+                pool: "nope".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_backing_stores_are_errors() {
+        use crate::memory::MultiDRAM;
+        let op = Operators::Backing("nope".into(), Box::new(compute(10, 3, vec![load(1, 4)])));
+        let mut dram = MultiDRAM::new(vec![("hbm".to_string(), DRAM::new())], "hbm");
+        let (mut srams, _) = memories(64);
+        let error = JitSim::new(LRU::new())
+            .run(&mut op.clone(), &mut srams, &mut dram)
+            .unwrap_err();
+        assert_eq!(
+            error,
+            SimError::Memory(MemError::UnknownPool {
+                memory: "DRAM",
+                pool: "nope".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn refcounting_frees_after_the_last_read_everywhere() {
+        let (mut srams, mut dram) = memories(64);
+        let mut sim = JitSim::new(LRU::new()).with_refcounting();
+        sim.run(&mut read_twice(), &mut srams, &mut dram).unwrap();
+        assert_eq!(sim.sim_stats().loads, 2);
+        assert_eq!(srams["sram"].to_vec(), vec![&4]);
+        assert!(!dram.contains(&1) && !dram.contains(&2));
+    }
+
+    /// 6 = k(5 = h(4, 1), 2) over `read_twice`; with room for three buffers
+    /// 2, 3 and 4 are evicted and 2 is reloaded
+    fn tight() -> Operators<u32> {
+        let tree = compute(12, 5, vec![(4, read_twice()), (1, Operators::NoOp)]);
+        compute(13, 6, vec![(5, tree), (2, Operators::NoOp)])
+    }
+
+    #[test]
+    fn counts_evictions_and_reloads_per_tensor() {
+        let (mut srams, mut dram) = memories(12);
+        let mut sim = JitSim::new(LRU::new());
+        sim.run(&mut tight(), &mut srams, &mut dram).unwrap();
+        let counts = |data| {
+            let counters = &sim.tensor_report()[&data];
+            (counters.evictions, counters.reloads)
+        };
+        assert_eq!(counts(2), (1, 1));
+        assert_eq!(counts(3), (1, 0));
+        assert!(!sim.tensor_report().contains_key(&5));
+    }
+
+    #[test]
+    fn transfers_take_bytes_over_the_slower_bandwidth() {
+        let sram = SRAM::new(64).with_bandwidth(2.0);
+        let mut srams = HashMap::from([("sram".to_string(), sram)]);
+        let mut dram = DRAM::new().with_bandwidth(4.0);
+        let mut sim = JitSim::new(LRU::new());
+        let mut program = compute(10, 3, vec![load(1, 4), load(2, 4)]);
+        sim.run(&mut program, &mut srams, &mut dram).unwrap();
+        assert_eq!(sim.transfer_cycles(), 4.0);
+    }
+
+    #[test]
+    fn transfers_and_accesses_pay_the_latencies() {
+        let sram = SRAM::new(64).with_latency(3.0);
+        let mut srams = HashMap::from([("sram".to_string(), sram)]);
+        let mut dram = DRAM::new().with_latency(1.0);
+        let mut sim = JitSim::new(LRU::new());
+        let mut program = compute(10, 3, vec![load(1, 4), load(2, 4)]);
+        sim.run(&mut program, &mut srams, &mut dram).unwrap();
+        // two loads through both ends, three accesses by the compute
+        assert_eq!(sim.transfer_cycles(), 8.0);
+        assert_eq!(sim.latency(), 8.0 + 9.0);
+    }
+
+    #[test]
+    fn tracks_the_high_water_mark_of_each_region() {
+        let (mut srams, mut dram) = memories(64);
+        let mut sim = JitSim::new(LRU::new()).with_liveness();
+        sim.run(&mut tight(), &mut srams, &mut dram).unwrap();
+        // sampled between instructions: 1, 2 and 3 after computing 3
+        assert_eq!(sim.sim_stats().peak_residency["sram"], 12);
+        // 1, 2, 4 and 5 while computing 5, before 4 is freed
+        assert_eq!(srams["sram"].peak_allocated(), 16);
+        assert!(srams["sram"].size_allocated() < 12);
+    }
+
+    /// An in-memory sink that can still be read once handed to the simulator.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn dumps_the_memory_map_after_each_instruction() {
+        let (mut srams, mut dram) = memories(64);
+        let mut sim = JitSim::new(LRU::new());
+        let sink = Shared::default();
+        sim.dump_memory_to(sink.clone());
+        sim.run(&mut read_twice(), &mut srams, &mut dram).unwrap();
+        let text = sink.text();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 12);
+        assert_eq!(lines[8], "step 5: (compute sram 10 3 1 2)");
+        assert_eq!(lines[9], "  sram [12 / 64]: 1 (4), 2 (4), 3 (4)");
+    }
+
+    #[test]
+    fn compressed_data_moves_fewer_bytes() {
+        let (mut srams, _) = memories(64);
+        let mut dram = DRAM::new().with_bandwidth(1.0);
+        dram.compress(1, 0.5);
+        let mut sim = JitSim::new(LRU::new());
+        let mut program = compute(10, 3, vec![load(1, 4), load(2, 4)]);
+        sim.run(&mut program, &mut srams, &mut dram).unwrap();
+        assert_eq!(sim.sim_stats().bytes_transferred, 2 + 4);
+        assert_eq!(sim.transfer_cycles(), 6.0);
+        // the host holds 1 compressed
+        assert_eq!(dram.size_allocated(), 2 + 4);
+    }
+
+    #[test]
+    fn samples_utilization_and_failed_allocations() {
+        let (mut srams, mut dram) = memories(64);
+        let mut sim = JitSim::new(LRU::new());
+        sim.run(&mut read_twice(), &mut srams, &mut dram).unwrap();
+        let stats = sim.memory_stats()["sram"];
+        assert_eq!(stats.samples, 6);
+        // 0, 4, 4, 8, 12 and 16 out of 64
+        assert_eq!(stats.average_utilization(), 44.0 / 64.0 / 6.0);
+        assert_eq!(stats.fragmentation_percent(), 0.0);
+        assert_eq!(stats.failed_allocations, 0);
+
+        let (mut srams, mut dram) = memories(12);
+        let mut sim = JitSim::new(LRU::new());
+        sim.run(&mut tight(), &mut srams, &mut dram).unwrap();
+        // each of them evicts exactly one tensor here
+        let stats = sim.memory_stats()["sram"];
+        assert_eq!(stats.failed_allocations, sim.sim_stats().evictions);
+    }
+
+    #[test]
+    fn unified_memory_moves_nothing_but_still_fills_up() {
+        let sram = SRAM::new(12).with_bandwidth(1.0).unified();
+        let mut srams = HashMap::from([("sram".to_string(), sram)]);
+        let mut dram = DRAM::new();
+        let mut sim = JitSim::new(LRU::new());
+        sim.run(&mut tight(), &mut srams, &mut dram).unwrap();
+        assert_eq!(sim.transfer_cycles(), 0.0);
+        assert_eq!(sim.sim_stats().bytes_transferred, 0);
+        assert_eq!(sim.sim_stats().evictions, 4);
+    }
+
+    #[test]
+    fn scratch_is_reclaimed_after_its_compute() {
+        let (mut srams, mut dram) = memories(64);
+        let mut sim = JitSim::new(LRU::new());
+        let inner = compute(10, 3, vec![load(1, 4), load(2, 4)]);
+        let mut program = Operators::Scratch(8, Box::new(inner));
+        sim.run(&mut program, &mut srams, &mut dram).unwrap();
+        assert_eq!(srams["sram"].peak_allocated(), 12 + 8);
+        assert_eq!(srams["sram"].size_allocated(), 12);
+        assert_eq!(srams["sram"].to_vec().len(), 3);
+    }
+
+    #[test]
+    fn records_occupancy_as_csv() {
+        let (mut srams, mut dram) = memories(64);
+        let mut sim = JitSim::new(LRU::new());
+        sim.record_occupancy();
+        sim.run(&mut read_twice(), &mut srams, &mut dram).unwrap();
+        let timeline = sim.occupancy().unwrap();
+        assert_eq!(timeline.capacities["sram"], 64);
+        let mut csv = vec![];
+        timeline.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv, "step,sram\n1,0\n2,4\n3,4\n4,8\n5,12\n6,16\n");
+    }
+
+    #[test]
+    fn computes_cost_cycles_by_operator() {
+        let (mut srams, mut dram) = memories(64);
+        let mut sim =
+            JitSim::new(LRU::new()).with_compute_costs(HashMap::from([(10, 5.0), (11, 7.0)]));
+        sim.run(&mut read_twice(), &mut srams, &mut dram).unwrap();
+        assert_eq!(sim.compute_cycles(), 12.0);
+        assert_eq!(sim.latency(), 12.0);
+
+        let (mut srams, mut dram) = memories(64);
+        let mut sim = JitSim::new(LRU::new()).with_compute_cost(|_, _, size| size as f64);
+        sim.run(&mut read_twice(), &mut srams, &mut dram).unwrap();
+        assert_eq!(sim.compute_cycles(), 8.0);
+    }
+
+    #[test]
+    fn charges_energy_by_phase() {
+        let (mut srams, mut dram) = memories(64);
+        let model = EnergyModel {
+            sram_per_byte: 1.0,
+            dram_per_byte: 10.0,
+            dma_per_byte: 100.0,
+            per_op: 1000.0,
+        };
+        let mut sim = JitSim::new(LRU::new()).with_energy_model(model);
+        sim.run(&mut read_twice(), &mut srams, &mut dram).unwrap();
+        let energy = sim.energy();
+        assert_eq!(energy.compute, 2000.0);
+        // two loads of 4 bytes, then operands and output of both computes
+        assert_eq!(energy.sram, 8.0 + 24.0);
+        assert_eq!(energy.dram, 80.0);
+        assert_eq!(energy.dma, 800.0);
+        assert_eq!(energy.total(), 2912.0);
+    }
+
+    #[test]
+    fn traces_every_executed_instruction() {
+        let (mut srams, mut dram) = memories(12);
+        let mut sim = JitSim::new(LRU::new());
+        sim.run(&mut tight(), &mut srams, &mut dram).unwrap();
+        let trace = sim.trace().iter().map(|x| x.compile()).collect::<Vec<_>>();
+        assert_eq!(
+            trace[4..],
+            [
+                "(compute sram 10 3 1 2)",
+                "(evict sram 2)",
+                "(compute sram 11 4 3 1)",
+                "(evict sram 3)",
+                "(compute sram 12 5 4 1)",
+                "(evict sram 4)",
+                "(load sram 2)",
+                "(evict sram 1)",
+                "(compute sram 13 6 5 2)",
+            ]
+        );
+    }
+
+    #[test]
+    fn schedule_replays_without_new_decisions() {
+        let (mut srams, mut dram) = memories(12);
+        let mut sim = JitSim::new(LRU::new());
+        sim.run(&mut tight(), &mut srams, &mut dram).unwrap();
+        let schedule = sim.schedule();
+        for op in schedule.iter() {
+            if let Operators::Compute(_, _, _, args, _) = op {
+                assert!(args.iter().all(|x| matches!(x.1, Operators::NoOp)));
+            }
+        }
+        let (mut srams, mut dram) = memories(12);
+        let mut replay = JitSim::new(LRU::new());
+        replay
+            .run_schedule(&schedule, &mut srams, &mut dram)
+            .unwrap();
+        let compiled = |x: &[Operators<u32>]| x.iter().map(|x| x.compile()).collect::<Vec<_>>();
+        assert_eq!(compiled(replay.trace()), compiled(&schedule));
+        assert_eq!(replay.sim_stats().evictions, sim.sim_stats().evictions);
+    }
+
+    #[test]
+    fn traces_roundtrip_through_json() {
+        let scratch = Operators::Scratch(8, Box::new(read_twice()));
+        let program = vec![scratch, Operators::Evict("sram".into(), 4, 4)];
+        let json = Operators::to_json(&program).unwrap();
+        let loaded = Operators::<u32>::from_json(&json).unwrap();
+        let compiled = |x: &[Operators<u32>]| x.iter().map(|x| x.compile()).collect::<Vec<_>>();
+        assert_eq!(compiled(&loaded), compiled(&program));
+        assert!(Operators::<u32>::from_json("[{\"Unknown\": 1}]").is_err());
+    }
+
+    #[test]
+    fn exports_a_chrome_trace_per_track() {
+        let sram = SRAM::new(64).with_bandwidth(1.0);
+        let mut srams = HashMap::from([("sram".to_string(), sram)]);
+        let mut dram = DRAM::new();
+        let mut sim = JitSim::new(LRU::new()).with_compute_costs(HashMap::from([(10, 5.0)]));
+        sim.record_timeline();
+        let mut program = compute(10, 3, vec![load(1, 4), load(2, 4)]);
+        sim.run(&mut program, &mut srams, &mut dram).unwrap();
+        let mut out = vec![];
+        sim.write_chrome_trace(&mut out).unwrap();
+        let trace: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let events = trace["traceEvents"].as_array().unwrap();
+        // a name for each of the "dma" and "sram" tracks, then the spans
+        assert_eq!(events.len(), 2 + 3);
+        assert_eq!(events[1]["args"]["name"], "sram");
+        let compute = &events[4];
+        assert_eq!(compute["name"], "compute 3");
+        assert_eq!(compute["tid"], 1);
+        assert_eq!(
+            (compute["ts"].as_f64(), compute["dur"].as_f64()),
+            (Some(8.0), Some(5.0))
+        );
+    }
+
+    #[test]
+    fn reports_each_instruction_as_csv() {
+        let (mut srams, mut dram) = memories(64);
+        let mut sim = JitSim::new(LRU::new());
+        let mut program = compute(10, 3, vec![load(1, 4), load(2, 4)]);
+        sim.run(&mut program, &mut srams, &mut dram).unwrap();
+        // host loads report the occupancy of the host
+        assert_eq!(
+            sim.report().to_csv(),
+            "step,kind,region,bytes_moved,occupancy\n\
+             1,load,host,0,4\n\
+             2,load,sram,4,4\n\
+             3,load,host,0,8\n\
+             4,load,sram,4,8\n\
+             5,compute,sram,0,12\n"
+        );
+    }
+
+    #[test]
+    fn summarizes_the_traffic_of_a_run() {
+        let (mut srams, mut dram) = memories(12);
+        let mut sim = JitSim::new(LRU::new());
+        sim.run(&mut tight(), &mut srams, &mut dram).unwrap();
+        let stats = sim.sim_stats();
+        assert_eq!((stats.loads, stats.stores, stats.evictions), (2, 2, 4));
+        // 3 and 4 are written back, 1 and 2 have a copy on the host
+        assert_eq!((stats.rematerializations, stats.deallocations), (1, 2));
+        assert_eq!(stats.bytes_transferred, 4 * (2 + 2 + 1));
+        assert_eq!(stats.peak_residency["sram"], 12);
+    }
+
+    #[test]
+    fn recomputes_dropped_data_from_its_operands() {
+        let (mut srams, mut dram) = memories(12);
+        let mut sim = JitSim::new(LRU::new())
+            .with_recomputation()
+            .with_compute_costs(HashMap::from([(10, 5.0)]));
+        let operands = || vec![(1, Operators::NoOp), (2, Operators::NoOp)];
+        let schedule = vec![
+            load(1, 2).1,
+            load(2, 2).1,
+            compute(10, 3, operands()),
+            compute(11, 4, operands()),
+            // 3 is dropped to make room
+            compute(12, 5, operands()),
+            compute(13, 6, vec![(5, Operators::NoOp), (3, Operators::NoOp)]),
+        ];
+        sim.run_schedule(&schedule, &mut srams, &mut dram).unwrap();
+        let stats = sim.sim_stats();
+        assert_eq!((stats.recomputations, stats.stores), (1, 0));
+        assert_eq!(sim.tensor_report()[&3].reloads, 1);
+        assert_eq!(sim.recompute_cycles(), 5.0);
+        let computes = sim
+            .trace()
+            .iter()
+            .filter(|x| x.compile() == "(compute sram 10 3 1 2)");
+        assert_eq!(computes.count(), 2);
+    }
+
+    #[test]
+    fn banished_data_cannot_be_used_again() {
+        let (mut srams, mut dram) = memories(64);
+        let mut sim = JitSim::new(LRU::new());
+        let mut first = compute(10, 3, vec![load(1, 4), load(2, 4)]);
+        sim.run(&mut first, &mut srams, &mut dram).unwrap();
+        sim.banish(&1, &mut srams, &mut dram);
+        assert!(sim.is_banished(&1) && sim.tensor_report()[&1].banished);
+        assert!(!srams["sram"].contains(&1) && !dram.contains(&1));
+        let mut second = compute(11, 4, vec![(3, Operators::NoOp), (1, Operators::NoOp)]);
+        let error = sim.run(&mut second, &mut srams, &mut dram).unwrap_err();
+        assert!(
+            matches!(error, SimError::Memory(MemError::Banished { ref data }) if data == "1"),
+            "{:?}",
+            error
+        );
+    }
+
+    /// Every callback but `on_copy`, one line each.
+    #[derive(Default)]
+    struct Recorder(Vec<String>);
+
+    impl Observer<u32> for Recorder {
+        fn on_load(&mut self, region: &str, data: &u32, _: usize) {
+            self.0.push(format!("load {} {}", region, data));
+        }
+
+        fn on_store(&mut self, region: &str, data: &u32, _: usize) {
+            self.0.push(format!("store {} {}", region, data));
+        }
+
+        fn on_evict(&mut self, region: &str, data: &u32, _: usize) {
+            self.0.push(format!("evict {} {}", region, data));
+        }
+
+        fn on_remat(&mut self, region: &str, data: &u32, _: usize, recomputed: bool) {
+            self.0
+                .push(format!("remat {} {} {}", region, data, recomputed));
+        }
+
+        fn on_compute(&mut self, region: &str, _: &u32, dst: &u32, _: usize, _: f64) {
+            self.0.push(format!("compute {} {}", region, dst));
+        }
+    }
+
+    #[test]
+    fn observers_see_every_load_store_eviction_and_compute() {
+        let (mut srams, mut dram) = memories(12);
+        let mut sim = JitSim::new(LRU::new());
+        let recorder = Arc::new(Mutex::new(Recorder::default()));
+        sim.observe(recorder.clone());
+        sim.run(&mut tight(), &mut srams, &mut dram).unwrap();
+        assert_eq!(
+            recorder.lock().unwrap().0,
+            [
+                "load sram 1",
+                "load sram 2",
+                "compute sram 3",
+                "evict sram 2",
+                "compute sram 4",
+                "store sram 3",
+                "evict sram 3",
+                "compute sram 5",
+                "store sram 4",
+                "evict sram 4",
+                "remat sram 2 false",
+                "evict sram 1",
+                "compute sram 6",
+            ]
+        );
+    }
+
+    #[test]
+    fn loads_overlap_the_compute_before_them() {
+        let sram = SRAM::new(64).with_bandwidth(1.0);
+        let mut srams = HashMap::from([("sram".to_string(), sram)]);
+        let mut dram = DRAM::new();
+        let mut sim =
+            JitSim::new(LRU::new()).with_compute_costs(HashMap::from([(10, 10.0), (11, 10.0)]));
+        sim.overlap_transfers();
+        let schedule = vec![
+            load(1, 4).1,
+            load(2, 4).1,
+            compute(10, 3, vec![(1, Operators::NoOp), (2, Operators::NoOp)]),
+            load(5, 4).1,
+            compute(11, 4, vec![(5, Operators::NoOp), (3, Operators::NoOp)]),
+        ];
+        sim.run_schedule(&schedule, &mut srams, &mut dram).unwrap();
+        assert_eq!(sim.latency(), 4.0 + 4.0 + 10.0 + 4.0 + 10.0);
+        // 5 is loaded while 3 is computed
+        assert_eq!(sim.overlapped_latency(), 4.0 + 4.0 + 10.0 + 10.0);
+    }
+
+    #[test]
+    fn prefetches_operands_already_on_the_host() {
+        let (mut srams, mut dram) = memories(64);
+        let device_load = |data| {
+            let op = Operators::Load("sram".into(), (data, Box::new(Operators::NoOp)), 4);
+            (data, op)
+        };
+        for data in [1, 2, 5] {
+            dram.put(&data, 4, false).unwrap();
+        }
+        let mut sim = JitSim::new(LRU::new()).with_prefetch(1);
+        let inner = compute(10, 3, vec![device_load(1), device_load(2)]);
+        let mut program = compute(11, 4, vec![(3, inner), device_load(5)]);
+        sim.run(&mut program, &mut srams, &mut dram).unwrap();
+        let stats = sim.sim_stats();
+        // 2 while 1 is loaded, 5 while 3 is computed
+        assert_eq!((stats.prefetches, stats.prefetch_hits), (2, 2));
+        assert_eq!(stats.loads, 1);
+        assert_eq!(stats.bytes_transferred, 3 * 4);
+    }
+
+    #[test]
+    fn liveness_frees_data_after_its_last_use() {
+        let program = read_twice();
+        // host 1, sram 1, host 2, sram 2, compute 3, compute 4
+        let last_uses = program.last_uses();
+        assert_eq!((last_uses[&1], last_uses[&2], last_uses[&3]), (5, 4, 5));
+        let (mut srams, mut dram) = memories(64);
+        let mut sim = JitSim::new(LRU::new()).with_liveness();
+        sim.run(&mut program.clone(), &mut srams, &mut dram)
+            .unwrap();
+        assert_eq!(srams["sram"].to_vec(), vec![&4]);
+        assert_eq!(sim.sim_stats().stores, 0);
+        // 2 is gone before 4 is allocated; it would peak at 16 without
+        assert_eq!(srams["sram"].peak_allocated(), 12);
+    }
+
+    #[test]
+    fn computes_allocate_their_own_output_and_scratch_sizes() {
+        let (mut srams, mut dram) = memories(64);
+        let model = EnergyModel {
+            sram_per_byte: 1.0,
+            ..EnergyModel::default()
+        };
+        let mut sim = JitSim::new(LRU::new()).with_energy_model(model);
+        let sizes = ComputeSizes::from(8)
+            .with_operands(vec![4, 4])
+            .with_scratch(4);
+        let args = vec![load(1, 4), load(2, 4)];
+        let mut program = Operators::Compute("sram".into(), 10, 3, args, sizes);
+        sim.run(&mut program, &mut srams, &mut dram).unwrap();
+        assert_eq!(srams["sram"].peak_allocated(), 4 + 4 + 8 + 4);
+        assert_eq!(srams["sram"].size_of(&3), Some(8));
+        // two loads, then the operands read and the output written
+        assert_eq!(sim.energy().sram, 8.0 + 16.0);
+    }
+
+    #[test]
+    fn pinned_data_is_never_evicted() {
+        let run = |pin: bool| {
+            let (mut srams, mut dram) = memories(16);
+            let mut sim = JitSim::new(LRU::new());
+            if pin {
+                sim.pin(2);
+            }
+            sim.run(&mut tight(), &mut srams, &mut dram).unwrap();
+            sim.tensor_report().get(&2).cloned().unwrap_or_default()
+        };
+        // 2 is the least recently used when 5 is computed
+        assert_eq!((run(false).evictions, run(false).reloads), (1, 1));
+        assert_eq!(run(true), TensorCounters::default());
+
+        let (mut srams, mut dram) = memories(12);
+        let mut sim = JitSim::new(LRU::new());
+        sim.pin(1);
+        sim.pin(2);
+        sim.unpin(&2);
+        assert_eq!(sim.pinned().len(), 1);
+        // 1, 5 and 2 leave no room for 6
+        let error = sim.run(&mut tight(), &mut srams, &mut dram).unwrap_err();
+        assert!(matches!(error, SimError::Thrash(_)), "{:?}", error);
+    }
+
+    #[test]
+    fn warm_started_data_is_not_cold_traffic() {
+        let (mut srams, mut dram) = memories(64);
+        let mut sim = JitSim::new(LRU::new());
+        sim.warm_start("sram", &[(1, 4)], &mut srams, &mut dram)
+            .unwrap();
+        assert!(srams["sram"].contains(&1) && dram.contains(&1));
+        let mut program = compute(10, 3, vec![load(1, 4), load(2, 4)]);
+        sim.run(&mut program, &mut srams, &mut dram).unwrap();
+        let stats = sim.sim_stats();
+        assert_eq!((stats.loads, stats.cold_loads, stats.warm_hits), (1, 1, 1));
+        assert_eq!(stats.bytes_transferred, 4);
+        let error = sim.warm_start("nowhere", &[(5, 4)], &mut srams, &mut dram);
+        assert!(matches!(error, Err(SimError::UnknownRegion(_))));
+    }
+
+    #[test]
+    fn computes_only_wait_for_their_own_operands() {
+        let run = |queue: Option<usize>| {
+            let sram = SRAM::new(64).with_bandwidth(1.0);
+            let mut srams = HashMap::from([("sram".to_string(), sram)]);
+            let mut dram = DRAM::new();
+            let costs = HashMap::from([(10, 10.0), (11, 10.0)]);
+            let mut sim = JitSim::new(LRU::new()).with_compute_costs(costs);
+            sim = match queue {
+                Some(depth) => sim.with_dma_queue(depth),
+                None => {
+                    sim.overlap_transfers();
+                    sim
+                }
+            };
+            let schedule = vec![
+                load(1, 4).1,
+                load(2, 4).1,
+                compute(10, 3, vec![(1, Operators::NoOp)]),
+                compute(11, 4, vec![(2, Operators::NoOp), (3, Operators::NoOp)]),
+            ];
+            sim.run_schedule(&schedule, &mut srams, &mut dram).unwrap();
+            (sim.overlapped_latency(), sim.dma_stats().cloned())
+        };
+        // 3 waits for 2 as well
+        assert_eq!(run(None), (8.0 + 10.0 + 10.0, None));
+        // 3 starts once 1 is in, while 2 is on its way
+        let (latency, stats) = run(Some(2));
+        let stats = stats.unwrap();
+        assert_eq!(latency, 4.0 + 10.0 + 10.0);
+        assert_eq!((stats.transfers, stats.peak_occupancy), (2, 2));
+        assert_eq!((stats.stall_cycles, stats.full_cycles), (4.0, 0.0));
+        // 2 is only issued once 1 is done
+        let stats = run(Some(1)).1.unwrap();
+        assert_eq!((stats.peak_occupancy, stats.full_cycles), (1, 4.0));
+    }
+
+    #[test]
+    fn logs_instructions_to_any_sink() {
+        let mut out = vec![];
+        Operators::write_logs(read_twice().linearize().iter(), &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(text.lines().last(), Some("(compute sram 11 4 3 1)"));
+        assert_eq!(text.lines().count(), 6);
+
+        let (mut srams, mut dram) = memories(12);
+        let mut sim = JitSim::new(LRU::new());
+        let sink = Shared::default();
+        sim.log_instructions_to(sink.clone());
+        sim.run(&mut tight(), &mut srams, &mut dram).unwrap();
+        // streamed as they run, reloads and evictions included
+        let logged = sink.text();
+        let traced = sim.trace().iter().map(|x| x.compile() + "\n");
+        assert_eq!(logged, traced.collect::<String>());
+    }
+
+    #[test]
+    fn draws_programs_with_colored_regions_and_sized_edges() {
+        let expected = r##"digraph operators {
+  node [shape=box, style=filled];
+  n0 [label="load 1\nhost", fillcolor="#d9d9d9"];
+  n1 [label="load 1\nsram", fillcolor="#8dd3c7"];
+  n0 -> n1 [label="4"];
+  n2 [label="load 2\nhost", fillcolor="#d9d9d9"];
+  n3 [label="load 2\nsram", fillcolor="#8dd3c7"];
+  n2 -> n3 [label="4"];
+  n4 [label="compute 10\n3\nsram", fillcolor="#8dd3c7"];
+  n1 -> n4 [label="4"];
+  n3 -> n4 [label="4"];
+  n5 [label="compute 11\n4\nsram", fillcolor="#8dd3c7"];
+  n4 -> n5 [label="4"];
+  n1 -> n5 [label="4"];
+}
+"##;
+        // 1 is drawn once, with an edge to each of its users
+        assert_eq!(read_twice().to_dot(), expected);
+    }
+
+    #[test]
+    fn breaks_stats_down_by_region() {
+        let mut srams = HashMap::from([
+            ("a".to_string(), SRAM::new(12)),
+            ("b".to_string(), SRAM::new(64)),
+        ]);
+        let mut dram = DRAM::new();
+        let load_into = |region: &str, data| {
+            let host = Operators::Load("host".into(), (data, Box::new(Operators::NoOp)), 4);
+            Operators::Load(region.into(), (data, Box::new(host)), 4)
+        };
+        let compute_on = |region: &str, dst, args: &[u32]| {
+            let args = args.iter().map(|x| (*x, Operators::NoOp)).collect();
+            Operators::Compute(region.into(), 10, dst, args, 4.into())
+        };
+        let schedule = vec![
+            load_into("a", 1),
+            load_into("a", 2),
+            compute_on("a", 3, &[1, 2]),
+            load_into("b", 5),
+            compute_on("b", 6, &[5]),
+            // makes room on a only
+            compute_on("a", 4, &[3]),
+        ];
+        let mut sim = JitSim::new(LRU::new());
+        sim.run_schedule(&schedule, &mut srams, &mut dram).unwrap();
+        let regions = sim.region_stats();
+        assert_eq!((regions["a"].loads, regions["a"].evictions), (2, 1));
+        assert_eq!((regions["b"].loads, regions["b"].evictions), (1, 0));
+        assert_eq!(regions["a"].peak_residency["a"], 12);
+        assert_eq!(regions["b"].peak_residency["b"], 8);
+        let table = crate::compare::region_report(regions);
+        assert!(table.lines().nth(1).unwrap().starts_with("a "));
+    }
+
+    #[test]
+    fn step_sim_charges_each_instruction_by_its_type() {
+        let mut sim = StepSim::new(SRAM::new(64), DRAM::new()).with_cost(InsnType::Compute, 10);
+        Simulator::<Operators<u32>, u32>::initialize(&mut sim);
+        let costs = read_twice()
+            .linearize()
+            .into_iter()
+            .map(|x| sim.run_insn(x))
+            .collect::<Vec<_>>();
+        assert_eq!(costs, [1, 1, 1, 1, 10, 10]);
+        assert_eq!((sim.steps(), sim.cycles()), (6, 24));
+        assert_eq!(sim.sram.to_vec(), vec![&1, &2, &3, &4]);
+
+        // nothing runs after the compute of 3 fails
+        let mut sim = StepSim::new(SRAM::new(8), DRAM::new());
+        for insn in read_twice().linearize() {
+            sim.run_insn(insn);
+        }
+        assert_eq!(sim.steps(), 4);
+        assert!(matches!(
+            sim.error(),
+            Some((4, MemError::OutOfMemory { .. }))
+        ));
+    }
+
+    #[test]
+    fn host_computes_are_accounted_apart() {
+        let (mut srams, mut dram) = memories(64);
+        let mut sim = JitSim::new(LRU::new()).with_compute_costs(HashMap::from([(10, 7.0)]));
+        let host_load = |data| {
+            let op = Operators::Load("host".into(), (data, Box::new(Operators::NoOp)), 4);
+            (data, op)
+        };
+        let args = vec![host_load(1), host_load(2)];
+        let mut program = Operators::Compute("host".into(), 10, 3, args, 8.into());
+        sim.run(&mut program, &mut srams, &mut dram).unwrap();
+        let stats = sim.sim_stats();
+        assert_eq!((stats.host_computes, stats.host_allocations), (1, 3));
+        assert_eq!(stats.host_bytes_allocated, 4 + 4 + 8);
+        assert_eq!(sim.host_compute_cycles(), 7.0);
+        // the device did nothing
+        assert_eq!((sim.latency(), sim.compute_cycles()), (0.0, 0.0));
+        assert_eq!(sim.region_stats()["host"].host_computes, 1);
+        assert!(dram.contains(&3) && srams["sram"].size_allocated() == 0);
+    }
+
+    #[test]
+    fn write_through_stores_every_output_and_evicts_for_free() {
+        let run = |policy| {
+            let (mut srams, mut dram) = memories(12);
+            let mut sim = JitSim::new(LRU::new()).with_write_policy(policy);
+            sim.run(&mut tight(), &mut srams, &mut dram).unwrap();
+            let stats = sim.sim_stats();
+            (stats.stores, stats.deallocations, stats.evictions)
+        };
+        // 3 and 4 are written back when evicted
+        assert_eq!(run(WritePolicy::WriteBack), (2, 2, 4));
+        // 3, 4, 5 and 6 as they are produced
+        assert_eq!(run(WritePolicy::WriteThrough), (4, 4, 4));
+    }
+
+    #[test]
+    fn missing_data_is_explained_by_its_departure() {
+        let (mut srams, mut dram) = memories(64);
+        let mut sim = freeing().with_use_checks();
+        sim.run(&mut read_twice(), &mut srams, &mut dram).unwrap();
+        let mut late = compute(12, 5, vec![(1, Operators::NoOp)]);
+        let error = sim.run(&mut late, &mut srams, &mut dram).unwrap_err();
+        match error {
+            SimError::UseAfterEvict {
+                data, departure, ..
+            } => {
+                assert_eq!(data, "1");
+                // while computing 4, its last reader
+                assert_eq!((departure.step, departure.how), (5, "released"));
+                assert_eq!(departure.region, "sram");
+            }
+            error => panic!("{:?}", error),
+        }
+
+        let (mut srams, mut dram) = memories(12);
+        let mut sim = JitSim::new(LRU::new()).with_use_checks();
+        sim.run(&mut tight(), &mut srams, &mut dram).unwrap();
+        let departure = sim.departure(&3).unwrap();
+        assert_eq!(departure.how, "written back");
+        let decision = departure.decision.as_deref().unwrap();
+        assert!(decision.ends_with("LRU<u32> to make room for 5 (4 bytes)"));
+    }
+
+    #[test]
+    fn out_of_memory_reports_residents_and_recent_evictions() {
+        let (mut srams, mut dram) = memories(12);
+        let mut sim = JitSim::new(LRU::new());
+        sim.pin(1);
+        let error = sim.run(&mut tight(), &mut srams, &mut dram).unwrap_err();
+        let report = match &error {
+            SimError::Thrash(report) => report,
+            error => panic!("{:?}", error),
+        };
+        assert_eq!((report.data.as_str(), report.requested), ("6", 4));
+        assert_eq!(report.excluded, ["2", "5"]);
+        assert_eq!(report.pinned, ["1"]);
+        let evicted = report.recent_evictions.iter().map(|x| x.0.as_str());
+        assert_eq!(evicted.collect::<Vec<_>>(), ["2", "3", "4"]);
+        let text = error.to_string();
+        assert!(text.contains("residents:\n  1 4 (pinned)\n  2 4 (operand)\n  5 4 (operand)\n"));
+        assert!(text.contains("  3 written back from sram at step 6, chosen by"));
+    }
+
+    #[test]
+    fn thrashing_suggests_a_region_size_that_fits() {
+        let (mut srams, mut dram) = memories(12);
+        let mut sim = JitSim::new(LRU::new());
+        sim.pin(1);
+        let error = sim.run(&mut tight(), &mut srams, &mut dram).unwrap_err();
+        assert!(error.is_capacity());
+        let needed = match &error {
+            SimError::Thrash(report) => report.min_capacity(),
+            error => panic!("{:?}", error),
+        };
+        // 1 pinned, 5 and 2 read, 6 written
+        assert_eq!(needed, 16);
+        let text = error.to_string();
+        assert!(text.starts_with("thrashing on sram: nothing left to evict\n"));
+        assert!(text.ends_with("it needs a region of at least 16 bytes"));
+
+        let (mut srams, mut dram) = memories(needed);
+        let mut sim = JitSim::new(LRU::new());
+        sim.pin(1);
+        sim.run(&mut tight(), &mut srams, &mut dram).unwrap();
+    }
+
+    #[test]
+    fn barriers_hold_transfers_until_computes_finish() {
+        let dma = Operators::Dma(1, Box::new(load(1, 4).1));
+        assert_eq!(dma.insn_type(), InsnType::DMA);
+        assert_eq!(Operators::<u32>::Sync(1).insn_type(), InsnType::Sync);
+        assert_eq!(Operators::<u32>::Barrier.insn_type(), InsnType::Barrier);
+        assert_eq!(dma.compile(), "(dma 1 (load sram 1))");
+
+        let run = |barrier: bool| {
+            let sram = SRAM::new(64).with_bandwidth(1.0);
+            let mut srams = HashMap::from([("sram".to_string(), sram)]);
+            let mut dram = DRAM::new();
+            let mut sim = JitSim::new(LRU::new()).with_compute_costs(HashMap::from([(10, 10.0)]));
+            sim.overlap_transfers();
+            let mut schedule = vec![
+                load(1, 4).1,
+                compute(10, 3, vec![(1, Operators::NoOp)]),
+                load(2, 4).1,
+            ];
+            if barrier {
+                schedule.insert(2, Operators::Barrier);
+            }
+            sim.run_schedule(&schedule, &mut srams, &mut dram).unwrap();
+            sim.overlapped_latency()
+        };
+        // 2 is loaded while 3 is computed, or only after
+        assert_eq!(run(false), 4.0 + 10.0);
+        assert_eq!(run(true), 4.0 + 10.0 + 4.0);
+    }
+
+    #[test]
+    fn metrics_are_summed_by_name_and_region() {
+        let (mut srams, mut dram) = memories(12);
+        let mut sim = JitSim::new(LRU::new());
+        let counters = Arc::new(Mutex::new(MetricCounters::default()));
+        sim.report_metrics(counters.clone());
+        sim.run(&mut tight(), &mut srams, &mut dram).unwrap();
+        let counters = counters.lock().unwrap();
+        assert_eq!(counters.get("bytes_moved", "sram"), 20.0);
+        assert_eq!(counters.get("evictions", "sram"), 4.0);
+        // two loads and four computes; reloading 2 is not an instruction
+        assert_eq!(counters.get("ops_executed", "sram"), 6.0);
+        assert_eq!(counters.get("evictions", "nowhere"), 0.0);
+    }
+
+    #[test]
+    fn forks_run_apart_but_share_observers() {
+        let chosen = Arc::new(Mutex::new(vec![]));
+        let log = chosen.clone();
+        let lru = crate::heuristics::Observed::new(LRU::new())
+            .on_choose(move |x, _| log.lock().unwrap().push(x.cloned()));
+        let (mut srams, mut dram) = memories(12);
+        let mut sim = JitSim::new(lru);
+        sim.run(&mut tight(), &mut srams, &mut dram).unwrap();
+        let choices = chosen.lock().unwrap().len();
+        let (stats, steps) = (sim.sim_stats().clone(), sim.trace().len());
+        let (mut fork, mut fork_srams, mut fork_dram) = sim.fork(&srams, &dram);
+        fork.run(&mut tight(), &mut fork_srams, &mut fork_dram)
+            .unwrap();
+        // the original did not move
+        assert_eq!(sim.sim_stats(), &stats);
+        assert_eq!(sim.trace().len(), steps);
+        assert_ne!(fork.sim_stats(), &stats);
+        // and makes the same decisions from the same state
+        let forked = chosen.lock().unwrap().len();
+        sim.run(&mut tight(), &mut srams, &mut dram).unwrap();
+        assert_eq!(sim.trace().len(), fork.trace().len());
+        assert_eq!(sim.sim_stats(), fork.sim_stats());
+        assert!(forked > choices);
+        assert_eq!(chosen.lock().unwrap().len(), 2 * forked - choices);
+    }
+
+    #[test]
+    fn events_are_recorded_only_when_asked() {
+        let (mut srams, mut dram) = memories(12);
+        let mut sim = JitSim::new(LRU::new());
+        sim.run(&mut tight(), &mut srams, &mut dram).unwrap();
+        assert!(sim.events().is_empty());
+
+        let (mut srams, mut dram) = memories(12);
+        let mut sim = JitSim::new(LRU::new());
+        sim.record_events();
+        sim.run(&mut tight(), &mut srams, &mut dram).unwrap();
+        let events = sim.events();
+        let count = |f: fn(&SimEvent<u32>) -> bool| events.iter().filter(|x| f(x)).count();
+        assert_eq!(count(|x| matches!(x, SimEvent::Compute { .. })), 4);
+        assert_eq!(count(|x| matches!(x, SimEvent::Evict { .. })), 4);
+        assert_eq!(count(|x| matches!(x, SimEvent::Remat { .. })), 1);
+        assert!(events.iter().all(|x| x.region() == "sram"));
+        let remat = events
+            .iter()
+            .find(|x| matches!(x, SimEvent::Remat { .. }))
+            .unwrap();
+        assert_eq!(*remat.data(), 2);
+        assert_eq!(remat.to_string(), "reload 2 into sram (4 B)");
+        let json = serde_json::to_string(events).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Vec<SimEvent<u32>>>(&json).unwrap(),
+            events
+        );
+    }
+
+    #[test]
+    fn in_place_ends_with_the_instruction_it_wraps() {
+        let (mut srams, mut dram) = memories(64);
+        let mut sim = JitSim::new(LRU::new());
+        let host = Operators::Compute("host".into(), 10, 9, vec![], 4.into());
+        sim.run(
+            &mut Operators::InPlace(0, Box::new(host)),
+            &mut srams,
+            &mut dram,
+        )
+        .unwrap();
+        srams.get_mut("sram").unwrap().put(&2, 4, false).unwrap();
+        let mut next = compute(11, 3, vec![(2, Operators::NoOp)]);
+        sim.run(&mut next, &mut srams, &mut dram).unwrap();
+        assert!(srams["sram"].contains(&2));
+        assert!(srams["sram"].contains(&3));
+    }
+
+    #[test]
+    fn nested_wrappers_run_the_operands_they_wrap() {
+        let inner = compute(10, 3, vec![load(1, 4), load(2, 4)]);
+        let nested = [
+            Operators::Scratch(8, Box::new(Operators::InPlace(0, Box::new(inner.clone())))),
+            Operators::Dma(0, Box::new(Operators::Scratch(8, Box::new(inner.clone())))),
+            Operators::Scratch(8, Box::new(Operators::Dma(0, Box::new(inner)))),
+        ];
+        for mut op in nested {
+            let (mut srams, mut dram) = memories(64);
+            let mut sim = JitSim::new(LRU::new());
+            sim.run(&mut op, &mut srams, &mut dram).unwrap();
+            assert!(srams["sram"].contains(&3));
+            assert_eq!(sim.sim_stats().loads, 2);
+            // the wrappers stay on the compute, not on its operands
+            assert_eq!(sim.trace().last().unwrap().kind(), "compute");
+            assert!(matches!(
+                sim.trace().last(),
+                Some(Operators::Dma(..) | Operators::Scratch(..))
+            ));
+        }
+    }
+
+    #[test]
+    fn stepping_over_a_missing_store_is_an_error() {
+        let store = Operators::Store("sram".into(), true, (1, Box::new(Operators::NoOp)), 4);
+        let mut step = StepSim::new(SRAM::new(64), DRAM::new());
+        Simulator::<Operators<u32>, u32>::run_insn(&mut step, store);
+        assert_eq!(step.error(), Some(&(0, MemError::not_resident("SRAM", &1))));
+    }
+
+    #[test]
+    fn stepping_over_a_missing_eviction_is_an_error() {
+        let mut step = StepSim::new(SRAM::new(64), DRAM::new());
+        step.dram.put(&1, 4, false).unwrap();
+        Simulator::<Operators<u32>, u32>::run_insn(
+            &mut step,
+            Operators::Evict("sram".into(), 1, 4),
+        );
+        assert_eq!(step.error(), Some(&(0, MemError::not_resident("SRAM", &1))));
+    }
+
+    #[test]
+    fn device_instructions_without_a_memory_are_errors() {
+        let no_sram = MemError::NoMemory {
+            region: "sram".into(),
+        };
+        let mut dram = DRAM::new();
+        dram.put(&1, 4, false).unwrap();
+        for op in [
+            compute(10, 3, vec![]),
+            Operators::Load("sram".into(), (1, Box::new(Operators::NoOp)), 4),
+            Operators::Evict("sram".into(), 1, 4),
+            Operators::Copy("other".into(), "sram".into(), 1, 4),
+        ] {
+            let error = op
+                .run(None as Option<&mut SRAM<u32>>, &mut dram)
+                .unwrap_err();
+            assert_eq!(error, no_sram);
+        }
+    }
+
+    #[test]
+    fn stores_write_back_stale_residents() {
+        let (mut srams, mut dram) = memories(64);
+        dram.put(&1, 4, false).unwrap();
+        dram.put(&2, 4, false).unwrap();
+        let sram = srams.get_mut("sram").unwrap();
+        // 1 was overwritten on chip, 2 is unchanged
+        sram.put(&1, 4, true).unwrap();
+        sram.put(&2, 4, false).unwrap();
+        sram.put(&3, 4, true).unwrap();
+        let mut sim = JitSim::new(LRU::new());
+        let mut store = Operators::Store("sram".into(), true, (3, Box::new(Operators::NoOp)), 4);
+        sim.run(&mut store, &mut srams, &mut dram).unwrap();
+        assert_eq!(sim.sim_stats().stores, 2);
+        assert_eq!(srams["sram"].trips.store_trips, 2);
+        assert!(srams["sram"].to_vec().is_empty());
+    }
+
+    #[test]
+    fn prefetches_wrapped_loads() {
+        let (mut srams, mut dram) = memories(64);
+        let device_load = |data| {
+            let op = Operators::Load("sram".into(), (data, Box::new(Operators::NoOp)), 4);
+            (data, Operators::Dma(0, Box::new(op)))
+        };
+        for data in [1, 2, 5] {
+            dram.put(&data, 4, false).unwrap();
+        }
+        let mut sim = JitSim::new(LRU::new()).with_prefetch(1);
+        let inner = compute(10, 3, vec![device_load(1), device_load(2)]);
+        let mut program = compute(11, 4, vec![(3, inner), device_load(5)]);
+        sim.run(&mut program, &mut srams, &mut dram).unwrap();
+        let stats = sim.sim_stats();
+        assert_eq!((stats.prefetches, stats.prefetch_hits), (2, 2));
+        assert_eq!(stats.loads, 1);
+    }
+
+    #[test]
+    fn departures_are_found_by_data_not_by_name() {
+        /// Data whose `Debug` rendering is the same for every id
+        #[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        struct Anonymous(u32);
+        impl std::fmt::Debug for Anonymous {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "tensor")
+            }
+        }
+
+        let mut sim = JitSim::new(LRU::<Anonymous>::new()).with_use_checks();
+        for (id, how) in [(1, "dead"), (2, "released")] {
+            sim.steps = id as usize;
+            sim.depart(&Anonymous(id), "sram", how);
+        }
+        let missing = MemError::not_resident("DRAM", &Anonymous(1));
+        match sim.explain(&Anonymous(1), missing.clone()) {
+            SimError::UseAfterEvict { departure, .. } => {
+                assert_eq!((departure.step, departure.how), (1, "dead"))
+            }
+            error => panic!("{:?}", error),
+        }
+        let unknown = sim.explain(&Anonymous(3), missing.clone());
+        assert_eq!(unknown, SimError::Memory(missing));
+    }
 }
@@ -0,0 +1,321 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+use crate::{config::MemoryConfig, sim::Operators};
+
+/// The first thing wrong with a schedule. `step` is the position of the
+/// offending instruction; data is rendered with `Debug`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// The instruction uses data that is not resident on `region`
+    NotResident {
+        step: usize,
+        region: String,
+        data: String,
+    },
+    /// A load of data the host does not have
+    NotOnHost { step: usize, data: String },
+    /// `region` holds more than its capacity while the instruction runs
+    OverCapacity {
+        step: usize,
+        region: String,
+        allocated: usize,
+        capacity: usize,
+    },
+    /// The instruction names a region missing from the configuration
+    UnknownRegion { step: usize, region: String },
+    /// An `InPlace` names an operand past those of its compute
+    NoOperand {
+        step: usize,
+        operand: usize,
+        operands: usize,
+    },
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Violation::NotResident { step, region, data } => {
+                write!(f, "step {}: {} is not resident on {}", step, data, region)
+            }
+            Violation::NotOnHost { step, data } => {
+                write!(f, "step {}: {} is not on the host", step, data)
+            }
+            Violation::OverCapacity {
+                step,
+                region,
+                allocated,
+                capacity,
+            } => write!(
+                f,
+                "step {}: {} holds {} of {}",
+                step, region, allocated, capacity
+            ),
+            Violation::UnknownRegion { step, region } => {
+                write!(f, "step {}: unknown region {}", step, region)
+            }
+            Violation::NoOperand {
+                step,
+                operand,
+                operands,
+            } => write!(
+                f,
+                "step {}: in place over operand {} of {} operands",
+                step, operand, operands
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Violation {}
+
+/// Residency of every region and the host while walking a schedule.
+#[derive(Clone)]
+struct State<'a, D> {
+    config: &'a MemoryConfig,
+    regions: HashMap<String, HashMap<D, usize>>,
+    host: HashSet<D>,
+    step: usize,
+}
+
+impl<'a, D> State<'a, D>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    fn region(&mut self, region: &str) -> Result<&mut HashMap<D, usize>, Violation> {
+        if !self.config.regions.contains_key(region) {
+            return Err(Violation::UnknownRegion {
+                step: self.step,
+                region: region.into(),
+            });
+        }
+        Ok(self.regions.entry(region.into()).or_default())
+    }
+
+    fn require(&mut self, region: &str, data: &D) -> Result<(), Violation> {
+        let step = self.step;
+        if region == "host" {
+            if !self.host.contains(data) {
+                return Err(Violation::NotOnHost {
+                    step,
+                    data: format!("{:?}", data),
+                });
+            }
+        } else if !self.region(region)?.contains_key(data) {
+            return Err(Violation::NotResident {
+                step,
+                region: region.into(),
+                data: format!("{:?}", data),
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks that `region` fits, with `extra` transient bytes on top.
+    fn fits(&mut self, region: &str, extra: usize) -> Result<(), Violation> {
+        let alignment = self.config.regions[region].alignment.max(1);
+        let capacity = self.config.regions[region].size;
+        let allocated = self
+            .region(region)?
+            .values()
+            .map(|size| size.div_ceil(alignment) * alignment)
+            .sum::<usize>()
+            + extra;
+        if allocated > capacity {
+            return Err(Violation::OverCapacity {
+                step: self.step,
+                region: region.into(),
+                allocated,
+                capacity,
+            });
+        }
+        Ok(())
+    }
+
+    fn put(&mut self, region: &str, data: &D, size: usize) -> Result<(), Violation> {
+        if region == "host" {
+            self.host.insert(data.clone());
+        } else {
+            self.region(region)?.insert(data.clone(), size);
+        }
+        Ok(())
+    }
+
+    fn perform(&mut self, op: &Operators<D>, scratch: usize) -> Result<(), Violation> {
+        match op {
+            Operators::Load(region, (data, _), size) => {
+                if region != "host" {
+                    self.require("host", data)?;
+                    if !self.region(region)?.contains_key(data) {
+                        self.put(region, data, *size)?;
+                        self.fits(region, 0)?;
+                    }
+                } else {
+                    self.put(region, data, *size)?;
+                }
+            }
+            Operators::Compute(region, _, dst, args, sizes) => {
+                for (data, _) in args.iter() {
+                    self.require(region, data)?;
+                }
+                self.put(region, dst, sizes.output)?;
+                if region != "host" {
+                    self.fits(region, scratch + sizes.scratch)?;
+                }
+            }
+            Operators::InPlace(operand, op) => match op.as_ref() {
+                Operators::Compute(region, _, dst, args, sizes) if region != "host" => {
+                    let (src, _) = args.get(*operand).ok_or(Violation::NoOperand {
+                        step: self.step,
+                        operand: *operand,
+                        operands: args.len(),
+                    })?;
+                    for (data, _) in args.iter() {
+                        self.require(region, data)?;
+                    }
+                    // as in the simulator, the output takes over the buffer
+                    // of the operand only if it is at least as large
+                    let size = self.region(region)?.get(src).cloned().unwrap_or_default();
+                    if size >= sizes.output {
+                        self.region(region)?.remove(src);
+                    }
+                    self.put(region, dst, sizes.output)?;
+                    self.fits(region, scratch + sizes.scratch)?;
+                }
+                op => self.perform(op, scratch)?,
+            },
+            Operators::Store(region, _, (data, _), _) => {
+                self.require(region, data)?;
+                // a store writes everything back and empties the region
+                let residents = std::mem::take(self.region(region)?);
+                self.host.extend(residents.into_keys());
+            }
+            Operators::Evict(region, data, _) => {
+                if self.region(region)?.remove(data).is_some() {
+                    self.host.insert(data.clone());
+                }
+            }
+            Operators::Copy(src, dst, data, size) => {
+                self.require(src, data)?;
+                if !self.region(dst)?.contains_key(data) {
+                    self.put(dst, data, *size)?;
+                    self.fits(dst, 0)?;
+                }
+            }
+            Operators::Repeat(count, op) => {
+                let steps = op.linearize();
+                for _ in 0..*count {
+                    for step in steps.iter() {
+                        self.perform(step, scratch)?;
+                    }
+                }
+            }
+            Operators::If(_, then, otherwise) => {
+                // either branch may run: the other one is checked on a copy of
+                // the state that is dropped after it, and the walk goes on
+                // from the end of the first
+                let mut other = self.clone();
+                for step in otherwise.linearize() {
+                    other.perform(&step, scratch)?;
+                }
+                for step in then.linearize() {
+                    self.perform(&step, scratch)?;
+                }
+            }
+            Operators::Scratch(bytes, op) => self.perform(op, scratch + bytes)?,
+            Operators::Pool(_, op) | Operators::Backing(_, op) | Operators::Dma(_, op) => {
+                self.perform(op, scratch)?
+            }
+            Operators::Sync(_) | Operators::Barrier | Operators::NoOp => {}
+        }
+        Ok(())
+    }
+}
+
+/// Walks a flat schedule (e.g. `JitSim::schedule`) and checks that every
+/// operand is resident when it is used, every load finds its data on the
+/// host, and no region of `config` ever holds more than its size (allocations
+/// padded to its alignment). Nothing is evicted or reloaded implicitly: a
+/// `Store` empties its region, an `Evict` removes one piece of data.
+pub fn check<D>(schedule: &[Operators<D>], config: &MemoryConfig) -> Result<(), Violation>
+where
+    D: std::fmt::Debug + Hash + Eq + PartialEq + Clone,
+{
+    let mut state = State {
+        config,
+        regions: HashMap::default(),
+        host: HashSet::default(),
+        step: 0,
+    };
+    for (step, op) in schedule.iter().enumerate() {
+        state.step = step;
+        state.perform(op, 0)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(size: usize) -> MemoryConfig {
+        MemoryConfig::from_toml(&format!("[regions.sram]\nsize = {}\n", size)).unwrap()
+    }
+
+    fn load(region: &str, data: u32) -> Operators<u32> {
+        Operators::Load(region.into(), (data, Box::new(Operators::NoOp)), 4)
+    }
+
+    fn compute(dst: u32, args: &[u32]) -> Operators<u32> {
+        let args = args.iter().map(|&x| (x, Operators::NoOp)).collect();
+        Operators::Compute("sram".into(), 0, dst, args, 4.into())
+    }
+
+    #[test]
+    fn in_place_past_the_operands_is_a_violation() {
+        let schedule = [
+            load("host", 1),
+            load("sram", 1),
+            Operators::InPlace(1, Box::new(compute(2, &[1]))),
+        ];
+        assert_eq!(
+            check(&schedule, &config(64)),
+            Err(Violation::NoOperand {
+                step: 2,
+                operand: 1,
+                operands: 1,
+            })
+        );
+        let schedule = [
+            load("host", 1),
+            load("sram", 1),
+            Operators::InPlace(0, Box::new(compute(2, &[1]))),
+            compute(3, &[2]),
+        ];
+        assert_eq!(check(&schedule, &config(8)), Ok(()));
+    }
+
+    #[test]
+    fn both_branches_of_an_if_are_checked() {
+        let branch = Operators::If(0, Box::new(compute(2, &[1])), Box::new(compute(3, &[4])));
+        let schedule = [load("host", 1), load("sram", 1), branch];
+        assert!(matches!(
+            check(&schedule, &config(64)),
+            Err(Violation::NotResident { step: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn in_place_into_a_larger_output_keeps_the_operand() {
+        let wide = Operators::Compute("sram".into(), 0, 2, vec![(1, Operators::NoOp)], 8.into());
+        let schedule = [
+            load("host", 1),
+            load("sram", 1),
+            Operators::InPlace(0, Box::new(wide)),
+        ];
+        assert!(check(&schedule, &config(8)).is_err());
+        assert_eq!(check(&schedule, &config(12)), Ok(()));
+    }
+}
@@ -0,0 +1,215 @@
+use std::{fmt::Write as _, io::Write};
+
+use crate::sim::OccupancyTimeline;
+
+const WIDTH: f64 = 960.0;
+/// Room for the region names and byte labels
+const LEFT: f64 = 120.0;
+const RIGHT: f64 = 20.0;
+const LANE: f64 = 140.0;
+const GAP: f64 = 30.0;
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Renders `timeline` (see `JitSim::record_occupancy`) as an SVG image: one
+/// lane per region with its occupancy over simulated time against its
+/// capacity, and a mark for every eviction (red), reload (blue) and
+/// recomputation (green); hovering a mark names its data. Runs without any
+/// simulated cycles are drawn over the steps instead.
+pub fn occupancy_svg(timeline: &OccupancyTimeline) -> String {
+    let end = timeline.times.iter().cloned().fold(0.0, f64::max);
+    let by_time = end > 0.0;
+    let span = if by_time {
+        end
+    } else {
+        timeline.samples.len().max(1) as f64
+    };
+    let x = |t: f64| LEFT + t / span * (WIDTH - LEFT - RIGHT);
+    let height = timeline.regions.len() as f64 * (LANE + GAP) + GAP;
+    let mut svg = String::new();
+    writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" font-family="monospace" font-size="11">"#,
+        WIDTH, height
+    )
+    .unwrap();
+    writeln!(svg, r#"<rect width="100%" height="100%" fill="white"/>"#).unwrap();
+    for (lane, region) in timeline.regions.iter().enumerate() {
+        let top = GAP + lane as f64 * (LANE + GAP);
+        let bottom = top + LANE;
+        let peak = timeline
+            .samples
+            .iter()
+            .filter_map(|(_, sample)| sample.get(region))
+            .cloned()
+            .max()
+            .unwrap_or_default();
+        let capacity = timeline
+            .capacities
+            .get(region)
+            .cloned()
+            .unwrap_or_default()
+            .max(peak)
+            .max(1);
+        let y = |bytes: usize| bottom - bytes as f64 / capacity as f64 * LANE;
+        writeln!(
+            svg,
+            r##"<rect x="{}" y="{}" width="{}" height="{}" fill="#f4f4f4" stroke="#999"/>"##,
+            LEFT,
+            top,
+            WIDTH - LEFT - RIGHT,
+            LANE
+        )
+        .unwrap();
+        writeln!(
+            svg,
+            r#"<text x="8" y="{}">{}</text><text x="8" y="{}">{} B</text><text x="8" y="{}">0</text>"#,
+            top + LANE / 2.0,
+            escape(region),
+            top + 10.0,
+            capacity,
+            bottom
+        )
+        .unwrap();
+        // occupancy holds until the next sample
+        let mut points = vec![(LEFT, bottom)];
+        for (i, (_, sample)) in timeline.samples.iter().enumerate() {
+            let t = if by_time {
+                timeline.times.get(i).cloned().unwrap_or(end)
+            } else {
+                (i + 1) as f64
+            };
+            let level = y(sample.get(region).cloned().unwrap_or_default());
+            points.push((x(t), points.last().unwrap().1));
+            points.push((x(t), level));
+        }
+        points.push((x(span), points.last().unwrap().1));
+        let points = points
+            .iter()
+            .map(|(x, y)| format!("{:.1},{:.1}", x, y))
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(
+            svg,
+            r##"<polyline points="{}" fill="none" stroke="#333" stroke-width="1.5"/>"##,
+            points
+        )
+        .unwrap();
+        if !by_time {
+            continue;
+        }
+        for marker in timeline.markers.iter().filter(|m| m.region == *region) {
+            let (mx, my) = (x(marker.time), top - 6.0);
+            let (tag, attributes) = match marker.kind {
+                "evict" => (
+                    "path",
+                    format!(
+                        r##"d="M{:.1},{:.1} l-4,-6 h8 z" fill="#d33""##,
+                        mx,
+                        my + 3.0
+                    ),
+                ),
+                "reload" => (
+                    "circle",
+                    format!(r##"cx="{:.1}" cy="{:.1}" r="3.5" fill="#36c""##, mx, my),
+                ),
+                _ => (
+                    "rect",
+                    format!(
+                        r##"x="{:.1}" y="{:.1}" width="7" height="7" fill="#3a3""##,
+                        mx - 3.5,
+                        my - 3.5
+                    ),
+                ),
+            };
+            writeln!(
+                svg,
+                "<{} {}><title>{} {} @ {}</title></{}>",
+                tag,
+                attributes,
+                marker.kind,
+                escape(&marker.data),
+                marker.time,
+                tag
+            )
+            .unwrap();
+        }
+    }
+    writeln!(
+        svg,
+        r#"<text x="{}" y="{}" text-anchor="end">{} {}</text>"#,
+        WIDTH - RIGHT,
+        height - 8.0,
+        span,
+        if by_time { "cycles" } else { "steps" }
+    )
+    .unwrap();
+    svg.push_str("</svg>\n");
+    svg
+}
+
+pub fn write_occupancy_svg(
+    timeline: &OccupancyTimeline,
+    out: &mut impl Write,
+) -> std::io::Result<()> {
+    out.write_all(occupancy_svg(timeline).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heuristics::LRU;
+    use crate::memory::{DRAM, SRAM};
+    use crate::sim::{JitSim, Operators};
+    use std::collections::HashMap;
+
+    fn load(data: u32) -> (u32, Operators<u32>) {
+        let host = Operators::Load("host".into(), (data, Box::new(Operators::NoOp)), 4);
+        (
+            data,
+            Operators::Load("sram".into(), (data, Box::new(host)), 4),
+        )
+    }
+
+    fn compute(op: u32, dst: u32, args: Vec<(u32, Operators<u32>)>) -> Operators<u32> {
+        Operators::Compute("sram".into(), op, dst, args, 4.into())
+    }
+
+    /// Occupancy of 6 = k(5 = h(4 = g(3 = f(1, 2), 1), 1), 2) on 12 bytes,
+    /// which evicts four times and reloads 2.
+    fn timeline(bandwidth: f64) -> OccupancyTimeline {
+        let sram = SRAM::new(12).with_bandwidth(bandwidth);
+        let mut srams = HashMap::from([("sram".to_string(), sram)]);
+        let mut dram = DRAM::new();
+        let three = compute(10, 3, vec![load(1), load(2)]);
+        let four = compute(11, 4, vec![(3, three), (1, Operators::NoOp)]);
+        let five = compute(12, 5, vec![(4, four), (1, Operators::NoOp)]);
+        let mut program = compute(13, 6, vec![(5, five), (2, Operators::NoOp)]);
+        let mut sim = JitSim::new(LRU::new());
+        sim.record_occupancy();
+        sim.run(&mut program, &mut srams, &mut dram).unwrap();
+        sim.occupancy().unwrap().clone()
+    }
+
+    #[test]
+    fn marks_evictions_and_reloads_over_time() {
+        let svg = occupancy_svg(&timeline(1.0));
+        assert!(svg.starts_with("<svg") && svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches("<title>evict").count(), 4);
+        assert_eq!(svg.matches("<title>reload 2").count(), 1);
+        assert!(svg.contains(">sram</text>") && svg.contains(">12 B</text>"));
+        assert!(svg.contains("cycles</text>"));
+    }
+
+    #[test]
+    fn runs_without_cycles_are_drawn_over_steps() {
+        let svg = occupancy_svg(&timeline(f64::INFINITY));
+        assert!(svg.contains("steps</text>"));
+        assert!(!svg.contains("<title>"));
+    }
+}